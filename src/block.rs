@@ -0,0 +1,146 @@
+use faer::Mat;
+use qudit_core::accel::kron;
+use qudit_core::ComplexScalar;
+use qudit_core::HasParams;
+use qudit_expr::DifferentiationLevel;
+
+use crate::compiler::compile;
+use crate::qvm::QVM;
+use crate::tree::ExpressionTree;
+
+/// Evaluates a block-diagonal circuit one independent block at a time.
+///
+/// A tree whose root is a kronecker product of disjoint subsystems is split
+/// into its blocks (see [ExpressionTree::kron_blocks]), each compiled into
+/// its own QVM. The blocks can then be evaluated concurrently and combined
+/// with a final kronecker product.
+pub struct BlockQVM<C: ComplexScalar> {
+    blocks: Vec<QVM<C>>,
+    param_ranges: Vec<std::ops::Range<usize>>,
+}
+
+impl<C: ComplexScalar> BlockQVM<C> {
+    pub fn new(tree: &ExpressionTree, diff_lvl: DifferentiationLevel) -> Self {
+        let mut blocks = Vec::new();
+        let mut param_ranges = Vec::new();
+        let mut offset = 0;
+        for block in tree.kron_blocks() {
+            let num_params = block.num_params();
+            param_ranges.push(offset..offset + num_params);
+            offset += num_params;
+            blocks.push(QVM::new(compile(block), diff_lvl));
+        }
+
+        Self { blocks, param_ranges }
+    }
+
+    /// The number of independent blocks.
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Calculate the unitary of each block in turn, without combining them.
+    ///
+    /// # Returns
+    ///
+    /// The unitary of each block in tensor order, so the circuit unitary is
+    /// their kronecker product.
+    pub fn get_block_unitaries(&mut self, params: &[C::R]) -> Vec<Mat<C>> {
+        self.blocks
+            .iter_mut()
+            .zip(self.param_ranges.iter())
+            .map(|(qvm, range)| qvm.get_unitary(&params[range.clone()]).to_owned())
+            .collect()
+    }
+
+    /// Calculate the unitary by evaluating each block in turn.
+    pub fn get_unitary_sequential(&mut self, params: &[C::R]) -> Mat<C> {
+        Self::combine(self.get_block_unitaries(params))
+    }
+
+    /// Calculate the unitary by evaluating each block on its own thread.
+    pub fn get_unitary(&mut self, params: &[C::R]) -> Mat<C>
+    where
+        QVM<C>: Send,
+        C: Send,
+        C::R: Sync,
+    {
+        let block_utrys = std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .blocks
+                .iter_mut()
+                .zip(self.param_ranges.iter())
+                .map(|(qvm, range)| {
+                    let block_params = &params[range.clone()];
+                    s.spawn(move || qvm.get_unitary(block_params).to_owned())
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("Block evaluation panicked."))
+                .collect()
+        });
+        Self::combine(block_utrys)
+    }
+
+    fn combine(block_utrys: Vec<Mat<C>>) -> Mat<C> {
+        let mut block_utrys = block_utrys.into_iter();
+        let mut utry = block_utrys.next().expect("Tree contains no blocks.");
+        for block_utry in block_utrys {
+            let dim = utry.nrows() * block_utry.nrows();
+            let mut out = Mat::zeros(dim, dim);
+            kron(out.as_mut(), utry.as_ref(), block_utry.as_ref());
+            utry = out;
+        }
+        utry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+
+    use super::BlockQVM;
+    use crate::compiler::compile;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_blocks_combine_to_full_unitary() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2, 2]),
+            vec![vec![0, 1], vec![2, 3]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let params = [0.3, 1.2];
+
+        let mut block_qvm = BlockQVM::<c64>::new(&tree, DifferentiationLevel::None);
+        assert_eq!(block_qvm.num_blocks(), 2);
+        let blocks = block_qvm.get_block_unitaries(&params);
+        assert_eq!((blocks[0].nrows(), blocks[1].nrows()), (4, 4));
+
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let full = qvm.get_unitary(&params);
+        for r in 0..16 {
+            for c in 0..16 {
+                let kron = blocks[0][(r / 4, c / 4)] * blocks[1][(r % 4, c % 4)];
+                assert!((full[(r, c)] - kron).norm() < 1e-12);
+            }
+        }
+
+        let sequential = block_qvm.get_unitary_sequential(&params);
+        let parallel = block_qvm.get_unitary(&params);
+        assert_eq!(sequential, parallel);
+        for r in 0..16 {
+            for c in 0..16 {
+                assert!((parallel[(r, c)] - full[(r, c)]).norm() < 1e-12);
+            }
+        }
+    }
+}
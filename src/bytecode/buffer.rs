@@ -15,6 +15,8 @@ pub struct MatrixBuffer {
     pub nrows: usize,
     pub ncols: usize,
     pub num_params: usize,
+    /// How the buffer is initialized before the code first runs.
+    pub warmup: WarmupStrategy,
 }
 
 impl MatrixBuffer {
@@ -29,6 +31,7 @@ impl From<UnitaryExpression> for MatrixBuffer {
             nrows: expr.dimension(),
             ncols: expr.dimension(),
             num_params: expr.num_params(),
+            warmup: WarmupStrategy::Identity,
         }
     }
 }
@@ -39,10 +42,28 @@ impl From<&UnitaryExpression> for MatrixBuffer {
             nrows: expr.dimension(),
             ncols: expr.dimension(),
             num_params: expr.num_params(),
+            warmup: WarmupStrategy::Identity,
         }
     }
 }
 
+/// How a buffer is initialized before a QVM first runs its code.
+///
+/// The generator chooses each buffer's strategy from the buffer's first use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WarmupStrategy {
+    /// Fill the buffer with the identity. Expression writes only fill the
+    /// entries the expression defines, so their buffers start here.
+    Identity,
+
+    /// Fill the buffer with zeros. Buffers that are read before any
+    /// instruction writes them, such as runtime constants, start here.
+    Zero,
+
+    /// Leave the buffer as is, since its first use overwrites it.
+    None,
+}
+
 #[derive(Clone, Debug)]
 pub struct SizedMatrixBuffer {
     pub offset: usize,
@@ -54,6 +75,12 @@ pub struct SizedMatrixBuffer {
 }
 
 impl SizedMatrixBuffer {
+    /// This buffer in a copy of the memory placed `shift` elements later,
+    /// as in the stacked memory of [crate::QVM::get_unitaries_batched].
+    pub fn shifted(&self, shift: usize) -> SizedMatrixBuffer {
+        SizedMatrixBuffer { offset: self.offset + shift, ..self.clone() }
+    }
+
     pub fn as_matmut<'a, C: ComplexScalar>(
         &self,
         memory: &mut MemoryBuffer<C>,
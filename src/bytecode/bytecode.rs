@@ -6,13 +6,100 @@ use std::collections::HashMap;
 // use crate::sim::qvm::QVMType;
 
 use qudit_core::ComplexScalar;
+use crate::error::QuditTreeError;
+use qudit_core::HasParams;
+use qudit_core::QuditRadices;
+use qudit_core::QuditSystem;
 use qudit_expr::{DifferentiationLevel, Module, ModuleBuilder, UnitaryExpression};
 
 use super::{
-    GeneralizedInstruction, MatrixBuffer, SizedMatrixBuffer, SpecializedInstruction,
+    GeneralizedInstruction, MatrixBuffer, ParameterMap, SizedMatrixBuffer,
+    SpecializedInstruction, WarmupStrategy,
     // SpecializedInstruction,
 };
 
+/// The reason [Bytecode::merge_buffers] rejected a merge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferMergeError {
+    /// The buffer index is out of range.
+    InvalidBuffer(usize),
+
+    /// The two buffers already share storage.
+    SameStorage,
+
+    /// The mergee already shares another buffer's storage.
+    AlreadyMerged(usize),
+
+    /// The buffer must keep its own storage, because it is a runtime
+    /// constant, needs initializing before the code first runs, or other
+    /// buffers have been merged into it.
+    Pinned(usize),
+
+    /// The mergee has more rows, columns, or parameters than the merger.
+    TooLarge,
+
+    /// The buffers are live at the same time.
+    OverlappingLifespans,
+
+    /// A chain of merges starting at the buffer leads back to it.
+    Cyclic(usize),
+}
+
+impl std::fmt::Display for BufferMergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferMergeError::InvalidBuffer(b) => write!(f, "buffer {} does not exist", b),
+            BufferMergeError::SameStorage => write!(f, "buffers already share storage"),
+            BufferMergeError::AlreadyMerged(b) => write!(f, "buffer {} is already merged", b),
+            BufferMergeError::Pinned(b) => write!(f, "buffer {} must keep its own storage", b),
+            BufferMergeError::TooLarge => write!(f, "mergee does not fit in merger"),
+            BufferMergeError::OverlappingLifespans => write!(f, "buffer lifespans overlap"),
+            BufferMergeError::Cyclic(b) => write!(f, "buffer {} is merged into itself", b),
+        }
+    }
+}
+
+impl std::error::Error for BufferMergeError {}
+
+/// The memory a QVM needs for a program, split by what it stores.
+///
+/// See [Bytecode::memory_breakdown].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryFootprint {
+    /// Bytes holding the matrices themselves.
+    pub unitary_bytes: usize,
+
+    /// Bytes holding the gradient of each matrix.
+    pub gradient_bytes: usize,
+
+    /// Bytes holding the upper triangle of each matrix's hessian.
+    pub hessian_bytes: usize,
+}
+
+impl MemoryFootprint {
+    /// The total number of bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.unitary_bytes + self.gradient_bytes + self.hessian_bytes
+    }
+}
+
+/// The elements a buffer with `num_params` parameters and matrix stride
+/// `mat_stride` occupies at `diff_lvl`: its matrix, its gradient, and the
+/// upper triangle of its hessian.
+fn buffer_elements(
+    mat_stride: usize,
+    num_params: usize,
+    diff_lvl: DifferentiationLevel,
+) -> [usize; 3] {
+    let gradient = if diff_lvl.gradient_capable() { mat_stride * num_params } else { 0 };
+    let hessian = if diff_lvl.hessian_capable() {
+        mat_stride * (num_params * (num_params + 1)) / 2
+    } else {
+        0
+    };
+    [mat_stride, gradient, hessian]
+}
+
 #[derive(Clone)]
 pub struct Bytecode {
     pub expression_set: Vec<UnitaryExpression>,
@@ -20,6 +107,12 @@ pub struct Bytecode {
     pub dynamic_code: Vec<GeneralizedInstruction>,
     pub matrix_buffers: Vec<MatrixBuffer>,
     pub merged_buffers: HashMap<usize, usize>,
+    /// The buffers holding runtime constants, as `(id, buffer)` pairs.
+    pub runtime_constants: Vec<(usize, usize)>,
+    /// How the tree's parameters are tied to global parameters, if at all.
+    pub parameter_map: Option<ParameterMap>,
+    /// The radices of the qudits the program's unitary acts on.
+    pub radices: QuditRadices,
 }
 
 impl Bytecode {
@@ -30,18 +123,440 @@ impl Bytecode {
         }
     }
 
-    pub fn specialize<C: ComplexScalar>(
+    /// The expressions the program's module must provide functions for.
+    ///
+    /// Each distinct expression is compiled once, regardless of how many
+    /// times it is written, so this can be used to pre-warm or share JIT
+    /// modules between programs.
+    pub fn required_expressions(&self) -> &[UnitaryExpression] {
+        &self.expression_set
+    }
+
+    /// The names of the expressions in [Bytecode::required_expressions].
+    pub fn required_gate_names(&self) -> Vec<String> {
+        self.expression_set.iter().map(|e| e.name()).collect()
+    }
+
+    /// A canonical text form of the whole program.
+    ///
+    /// Unlike the [Debug](std::fmt::Debug) form, this includes every
+    /// expression in full, every instruction operand, the buffers, merges,
+    /// runtime constants, and parameter map, with unordered collections
+    /// listed in sorted order. Writes refer to their expression by its
+    /// position in the sorted expression list. Two programs with equal
+    /// canonical forms specialize identically, so this can be compared to
+    /// check that compilation is reproducible.
+    pub fn canonical_form(&self) -> String {
+        let mut out = String::new();
+        let mut expressions: Vec<String> =
+            self.expression_set.iter().map(|e| format!("{:?}", e)).collect();
+        expressions.sort();
+        expressions.dedup();
+        out += ".expressions\n";
+        for (i, expr) in expressions.iter().enumerate() {
+            out += &format!("{} {}\n", i, expr);
+        }
+
+        for (section, code) in [("static", &self.static_code), ("dynamic", &self.dynamic_code)] {
+            out += &format!(".{}\n", section);
+            for inst in code {
+                out += &match inst {
+                    GeneralizedInstruction::Write(expr, param_pointer, index) => {
+                        let expr = format!("{:?}", expr);
+                        let expr = expressions.binary_search(&expr).map_or(
+                            // Not in the expression set, so written in full
+                            expr,
+                            |i| format!("#{}", i),
+                        );
+                        format!("Write {} {} {}\n", expr, param_pointer, index)
+                    },
+                    GeneralizedInstruction::FRPR(a, shape, perm, d) => {
+                        format!("FRPR {} {:?} {:?} {}\n", a, shape, perm, d)
+                    },
+                    GeneralizedInstruction::Apply(a, b, shape, qudits, e) => {
+                        format!("Apply {} {} {:?} {:?} {}\n", a, b, shape, qudits, e)
+                    },
+                    GeneralizedInstruction::ApplyDiagonal(a, b, shape, qudits, e) => {
+                        format!("ApplyDiagonal {} {} {:?} {:?} {}\n", a, b, shape, qudits, e)
+                    },
+                    _ => format!("{:?}\n", inst),
+                };
+            }
+        }
+
+        for (i, buffer) in self.matrix_buffers.iter().enumerate() {
+            out += &format!("buffer {} {:?}\n", i, buffer);
+        }
+        let mut merges: Vec<_> = self.merged_buffers.iter().collect();
+        merges.sort();
+        out += &format!("merges {:?}\n", merges);
+        out += &format!("runtime constants {:?}\n", self.runtime_constants);
+        out += &format!("parameter map {:?}\n", self.parameter_map);
+        out += &format!("radices {}\n", self.radices);
+        out
+    }
+
+    /// Count the complex multiplications performed by one evaluation.
+    ///
+    /// Only dynamic code is counted, since static code runs once. Each
+    /// instruction is costed by the buffers it actually uses: a matmul of an
+    /// `m` by `k` and a `k` by `n` matrix performs `m * n * k`, a kron one per
+    /// output entry, and an FRPR is counted as one per entry copied.
+    /// Expression writes and conjugations are not counted.
+    pub fn exact_flops(&self) -> u128 {
+        self.dynamic_code
+            .iter()
+            .map(|inst| {
+                let (terms, entries) = self.instruction_cost(inst);
+                terms + entries
+            })
+            .sum()
+    }
+
+    /// Estimate the arithmetic cost of the static and dynamic code.
+    ///
+    /// A matmul of an `m x k` by a `k x n` buffer costs `2 * m * n * k`
+    /// flops, one multiply and one add per term. Krons and FRPRs cost one
+    /// operation per element of their output. Writes and conjugations are
+    /// not counted.
+    ///
+    /// # Returns
+    ///
+    /// The flops of the static code, which runs once, and of the dynamic
+    /// code, which runs on every evaluation.
+    pub fn estimated_flops(&self) -> (u128, u128) {
+        let flops = |code: &[GeneralizedInstruction]| -> u128 {
+            code.iter()
+                .map(|inst| {
+                    let (terms, entries) = self.instruction_cost(inst);
+                    2 * terms + entries
+                })
+                .sum()
+        };
+        (flops(&self.static_code), flops(&self.dynamic_code))
+    }
+
+    /// The cost of one instruction, counted from the buffers it uses.
+    ///
+    /// # Returns
+    ///
+    /// The multiply-add terms of a matmul of an `m` by `k` and a `k` by `n`
+    /// matrix, `m * n * k`, or of applying a `d` by `d` operator to a state
+    /// with `n` rows, `d * n` per column or `n` for its diagonal, and the
+    /// output entries of a kron or FRPR, each computed or copied once.
+    /// Writes and conjugations cost nothing.
+    fn instruction_cost(&self, inst: &GeneralizedInstruction) -> (u128, u128) {
+        match inst {
+            GeneralizedInstruction::Matmul(a, b, _) => {
+                let a = &self.matrix_buffers[*a];
+                let b = &self.matrix_buffers[*b];
+                ((a.nrows * a.ncols * b.ncols) as u128, 0)
+            },
+            GeneralizedInstruction::Kron(_, _, out)
+            | GeneralizedInstruction::FRPR(_, _, _, out) => {
+                let out = &self.matrix_buffers[*out];
+                (0, (out.nrows * out.ncols) as u128)
+            },
+            GeneralizedInstruction::Apply(op, state, _, _, _) => {
+                let op = &self.matrix_buffers[*op];
+                let state = &self.matrix_buffers[*state];
+                ((op.nrows * state.nrows * state.ncols) as u128, 0)
+            },
+            GeneralizedInstruction::ApplyDiagonal(_, state, _, _, _) => {
+                let state = &self.matrix_buffers[*state];
+                ((state.nrows * state.ncols) as u128, 0)
+            },
+            GeneralizedInstruction::Write(..) | GeneralizedInstruction::Conj(..) => (0, 0),
+        }
+    }
+
+    /// The buffer whose storage `buffer` uses, following recorded merges.
+    fn storage_of(&self, mut buffer: usize) -> usize {
+        while let Some(&merger) = self.merged_buffers.get(&buffer) {
+            buffer = merger;
+        }
+        buffer
+    }
+
+    /// Check that `merged_buffers` is a valid remapping.
+    ///
+    /// Merges may chain, as when the [BufferReuser](super::BufferReuser)
+    /// merges into a buffer it later merges elsewhere. Every buffer in a
+    /// chain uses the storage of the buffer at its end.
+    ///
+    /// # Errors
+    ///
+    /// - If a merge refers to a buffer that does not exist.
+    /// - If a chain of merges leads back to where it started.
+    /// - If a merged buffer is larger than the storage it resolves to.
+    pub fn validate_merged_buffers(&self) -> Result<(), BufferMergeError> {
+        for (&mergee, &merger) in &self.merged_buffers {
+            for buffer in [mergee, merger] {
+                if buffer >= self.matrix_buffers.len() {
+                    return Err(BufferMergeError::InvalidBuffer(buffer));
+                }
+            }
+        }
+
+        for &mergee in self.merged_buffers.keys() {
+            let mut storage = mergee;
+            let mut steps = 0;
+            while let Some(&merger) = self.merged_buffers.get(&storage) {
+                storage = merger;
+                steps += 1;
+                if steps > self.merged_buffers.len() {
+                    return Err(BufferMergeError::Cyclic(mergee));
+                }
+            }
+
+            let small = &self.matrix_buffers[mergee];
+            let large = &self.matrix_buffers[storage];
+            if small.nrows > large.nrows
+                || small.ncols > large.ncols
+                || small.num_params > large.num_params
+            {
+                return Err(BufferMergeError::TooLarge);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The buffer holding the input state, if this is a state or diagonal
+    /// program as generated by
+    /// [BytecodeGenerator::generate_for_state](super::BytecodeGenerator::generate_for_state)
+    /// or
+    /// [BytecodeGenerator::generate_for_diagonal](super::BytecodeGenerator::generate_for_diagonal).
+    ///
+    /// It is the state read by the first apply instruction, which the QVM
+    /// fills before running the code.
+    pub fn state_input(&self) -> Option<usize> {
+        self.dynamic_code.iter().find_map(|inst| match inst {
+            GeneralizedInstruction::Apply(_, state, _, _, _)
+            | GeneralizedInstruction::ApplyDiagonal(_, state, _, _, _) => Some(*state),
+            _ => None,
+        })
+    }
+
+    /// The first and last dynamic instruction using each buffer.
+    ///
+    /// Buffers used by static code, runtime constants, the input state of a
+    /// state program, and the output buffer stay live for the whole
+    /// program. Unused buffers have no lifespan.
+    fn buffer_lifespans(&self) -> Vec<Option<(usize, usize)>> {
+        let mut lifespans = vec![None; self.matrix_buffers.len()];
+        let forever = Some((0, usize::MAX));
+
+        for inst in &self.static_code {
+            lifespans[inst.out_buffer()] = forever;
+            for buffer in inst.in_buffers() {
+                lifespans[buffer] = forever;
+            }
+        }
+        for &(_, buffer) in &self.runtime_constants {
+            lifespans[buffer] = forever;
+        }
+        if let Some(input) = self.state_input() {
+            lifespans[input] = forever;
+        }
+
+        for (i, inst) in self.dynamic_code.iter().enumerate() {
+            let mut used = inst.in_buffers();
+            used.push(inst.out_buffer());
+            for buffer in used {
+                lifespans[buffer] = match lifespans[buffer] {
+                    None => Some((i, i)),
+                    Some((start, end)) => Some((start.min(i), end.max(i))),
+                };
+            }
+        }
+        if let Some(inst) = self.dynamic_code.last() {
+            lifespans[inst.out_buffer()] = forever;
+        }
+
+        lifespans
+    }
+
+    /// Record that `mergee` should share `merger`'s storage.
+    ///
+    /// This gives manual control over memory for cases the automatic
+    /// [BufferReuser](super::BufferReuser) handles poorly. The merge takes
+    /// effect the next time the bytecode is specialized.
+    ///
+    /// # Arguments
+    ///
+    /// * `mergee` - The buffer giving up its own storage.
+    /// * `merger` - The buffer whose storage is shared.
+    ///
+    /// # Errors
+    ///
+    /// - If either buffer does not exist or they already share storage.
+    /// - If `mergee` is already merged, or either buffer is pinned to its
+    ///   own storage.
+    /// - If `mergee` is larger than `merger` in any dimension or in its
+    ///   number of parameters.
+    /// - If `mergee` is live at the same time as any buffer sharing
+    ///   `merger`'s storage.
+    pub fn merge_buffers(&mut self, mergee: usize, merger: usize) -> Result<(), BufferMergeError> {
+        for buffer in [mergee, merger] {
+            if buffer >= self.matrix_buffers.len() {
+                return Err(BufferMergeError::InvalidBuffer(buffer));
+            }
+        }
+        if self.merged_buffers.contains_key(&mergee) {
+            return Err(BufferMergeError::AlreadyMerged(mergee));
+        }
+        let storage = self.storage_of(merger);
+        if storage == mergee {
+            return Err(BufferMergeError::SameStorage);
+        }
+
+        let group: Vec<usize> = (0..self.matrix_buffers.len())
+            .filter(|&b| b != mergee && self.storage_of(b) == storage)
+            .collect();
+        if self.merged_buffers.values().any(|&b| b == mergee) {
+            return Err(BufferMergeError::Pinned(mergee));
+        }
+        for &(_, buffer) in &self.runtime_constants {
+            if buffer == mergee || group.contains(&buffer) {
+                return Err(BufferMergeError::Pinned(buffer));
+            }
+        }
+        for &buffer in std::iter::once(&mergee).chain(group.iter()) {
+            if self.matrix_buffers[buffer].warmup != WarmupStrategy::None {
+                return Err(BufferMergeError::Pinned(buffer));
+            }
+        }
+
+        let small = &self.matrix_buffers[mergee];
+        let large = &self.matrix_buffers[storage];
+        if small.nrows > large.nrows
+            || small.ncols > large.ncols
+            || small.num_params > large.num_params
+        {
+            return Err(BufferMergeError::TooLarge);
+        }
+
+        let lifespans = self.buffer_lifespans();
+        if let Some((start, end)) = lifespans[mergee] {
+            for &buffer in &group {
+                if let Some((start2, end2)) = lifespans[buffer] {
+                    if start <= end2 && start2 <= end {
+                        return Err(BufferMergeError::OverlappingLifespans);
+                    }
+                }
+            }
+        }
+
+        self.merged_buffers.insert(mergee, merger);
+        Ok(())
+    }
+
+    /// Swap every use of one expression for another, in place.
+    ///
+    /// This avoids rebuilding and recompiling the tree when a single gate
+    /// changes. A new QVM must still be created from the updated bytecode.
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The expression to replace.
+    /// * `new` - The expression to replace it with.
+    ///
+    /// # Panics
+    ///
+    /// - If `old` is not used by this bytecode.
+    /// - If `new` does not have the same dimension and number of parameters
+    ///   as `old`.
+    pub fn replace_expression(
+        &mut self,
+        old: &UnitaryExpression,
+        new: UnitaryExpression,
+    ) {
+        let position = self
+            .expression_set
+            .iter()
+            .position(|e| e == old)
+            .expect("Expression to replace is not in the bytecode.");
+
+        if old.dimension() != new.dimension() || old.num_params() != new.num_params() {
+            panic!("Replacement expression must have the same dimension and number of parameters.");
+        }
+
+        if self.expression_set.contains(&new) {
+            self.expression_set.remove(position);
+        } else {
+            self.expression_set[position] = new.clone();
+        }
+
+        for inst in self.static_code.iter_mut().chain(self.dynamic_code.iter_mut()) {
+            if let GeneralizedInstruction::Write(expr, _, _) = inst {
+                if expr == old {
+                    *expr = new.clone();
+                }
+            }
+        }
+    }
+
+    /// The bytes of memory a QVM at `diff_lvl` needs for this program.
+    ///
+    /// This matches the memory size computed by [Bytecode::specialize],
+    /// without compiling a module. See [Bytecode::memory_breakdown] for how
+    /// it splits between matrices, gradients, and hessians.
+    pub fn memory_footprint<C: ComplexScalar>(&self, diff_lvl: DifferentiationLevel) -> usize {
+        self.memory_breakdown::<C>(diff_lvl).total_bytes()
+    }
+
+    /// The bytes of memory a QVM at `diff_lvl` needs for the matrices,
+    /// gradients, and hessians of this program.
+    ///
+    /// Merged buffers share their merger's storage and are not counted.
+    ///
+    /// # Panics
+    ///
+    /// If `merged_buffers` is invalid; see [Bytecode::validate_merged_buffers].
+    pub fn memory_breakdown<C: ComplexScalar>(
         &self,
         diff_lvl: DifferentiationLevel,
-    ) -> (
-        Vec<SpecializedInstruction<C>>,
-        Vec<SpecializedInstruction<C>>,
-        Module<C>,
-        usize,
-    ) {
+    ) -> MemoryFootprint {
+        let (sized_buffers, _) = self.sized_buffers::<C>(diff_lvl);
+        let mut footprint = MemoryFootprint::default();
+        for (i, buffer) in sized_buffers.iter().enumerate() {
+            if self.merged_buffers.contains_key(&i) {
+                continue;
+            }
+            let [unitary, gradient, hessian] =
+                buffer_elements(buffer.mat_stride as usize, buffer.num_params, diff_lvl);
+            footprint.unitary_bytes += unitary * std::mem::size_of::<C>();
+            footprint.gradient_bytes += gradient * std::mem::size_of::<C>();
+            footprint.hessian_bytes += hessian * std::mem::size_of::<C>();
+        }
+        footprint
+    }
+
+    /// Lay out every buffer in memory.
+    ///
+    /// Buffers recorded in `merged_buffers` are placed in their merger's
+    /// storage and do not add to the memory size.
+    ///
+    /// # Returns
+    ///
+    /// The sized buffers, indexed like `matrix_buffers`, and the total
+    /// memory size required.
+    ///
+    /// # Panics
+    ///
+    /// If `merged_buffers` is invalid; see [Bytecode::validate_merged_buffers].
+    fn sized_buffers<C: ComplexScalar>(
+        &self,
+        diff_lvl: DifferentiationLevel,
+    ) -> (Vec<SizedMatrixBuffer>, usize) {
+        if let Err(e) = self.validate_merged_buffers() {
+            panic!("Invalid buffer merges: {}", e);
+        }
+
         let mut sized_buffers = Vec::new();
         let mut offset = 0;
-        for buffer in &self.matrix_buffers {
+        for (i, buffer) in self.matrix_buffers.iter().enumerate() {
             let col_stride =
                 qudit_core::memory::calc_col_stride::<C>(buffer.nrows, buffer.ncols);
             let mat_stride = qudit_core::memory::calc_mat_stride::<C>(buffer.nrows, buffer.ncols, col_stride);
@@ -53,46 +568,77 @@ impl Bytecode {
                 mat_stride: mat_stride as isize,
                 num_params: buffer.num_params,
             });
-            offset += mat_stride;
-            if diff_lvl.gradient_capable() {
-                offset += mat_stride * buffer.num_params;
-            }
-            if diff_lvl.hessian_capable() {
-                offset += mat_stride
-                    * (buffer.num_params * (buffer.num_params + 1))
-                    / 2;
+
+            // Merged buffers are placed in their merger's storage below.
+            if self.merged_buffers.contains_key(&i) {
+                continue;
             }
+            offset += buffer_elements(mat_stride, buffer.num_params, diff_lvl)
+                .iter()
+                .sum::<usize>();
         }
         let memory_size = offset;
-        // println!("Memory size: {}", memory_size);
-
-        // TODO: can be done a lot more efficient
-        // for (mergee_buffer, merger_buffer) in &self.merged_buffers {
-        //     let mut mergee_size = sized_buffers[*mergee_buffer].ncols
-        //         * sized_buffers[*mergee_buffer].col_stride as usize;
-        //     if ty.gradient_capable() {
-        //         mergee_size +=
-        //             mergee_size * sized_buffers[*mergee_buffer].num_params;
-        //     }
-        //     if ty.hessian_capable() {
-        //         mergee_size += mergee_size
-        //             * (sized_buffers[*mergee_buffer].num_params
-        //                 * (sized_buffers[*mergee_buffer].num_params + 1))
-        //             / 2;
-        //     }
-
-        //     let offset = sized_buffers[*mergee_buffer].offset;
-
-        //     for buffer in &mut sized_buffers {
-        //         if buffer.offset >= offset {
-        //             buffer.offset -= mergee_size;
-        //         }
-        //     }
-        //     sized_buffers[*mergee_buffer].offset =
-        //         sized_buffers[*merger_buffer].offset;
-        //     memory_size -= mergee_size;
-        // }
-        // println!("Post Merged Memory size: {}", memory_size);
+
+        for &mergee in self.merged_buffers.keys() {
+            sized_buffers[mergee].offset = sized_buffers[self.storage_of(mergee)].offset;
+        }
+
+        (sized_buffers, memory_size)
+    }
+
+    /// The sized buffers of each runtime constant, paired with its id.
+    pub fn runtime_constant_buffers<C: ComplexScalar>(
+        &self,
+        diff_lvl: DifferentiationLevel,
+    ) -> Vec<(usize, SizedMatrixBuffer)> {
+        let (sized_buffers, _) = self.sized_buffers::<C>(diff_lvl);
+        self.runtime_constants
+            .iter()
+            .map(|&(id, buffer)| (id, sized_buffers[buffer].clone()))
+            .collect()
+    }
+
+    /// The sized buffers that need initializing before the code first runs,
+    /// paired with how to initialize them.
+    ///
+    /// Buffers that do not need initializing are omitted.
+    pub fn warmup_buffers<C: ComplexScalar>(
+        &self,
+        diff_lvl: DifferentiationLevel,
+    ) -> Vec<(SizedMatrixBuffer, WarmupStrategy)> {
+        let (sized_buffers, _) = self.sized_buffers::<C>(diff_lvl);
+        self.matrix_buffers
+            .iter()
+            .zip(sized_buffers)
+            .filter(|(buffer, _)| buffer.warmup != WarmupStrategy::None)
+            .map(|(buffer, sized)| (sized, buffer.warmup))
+            .collect()
+    }
+
+    pub fn specialize<C: ComplexScalar>(
+        &self,
+        diff_lvl: DifferentiationLevel,
+    ) -> (
+        Vec<SpecializedInstruction<C>>,
+        Vec<SpecializedInstruction<C>>,
+        Module<C>,
+        usize,
+    ) {
+        self.try_specialize(diff_lvl).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Specialize the program as in [Bytecode::specialize], returning an
+    /// error instead of panicking if an instruction cannot be specialized.
+    pub fn try_specialize<C: ComplexScalar>(
+        &self,
+        diff_lvl: DifferentiationLevel,
+    ) -> Result<(
+        Vec<SpecializedInstruction<C>>,
+        Vec<SpecializedInstruction<C>>,
+        Module<C>,
+        usize,
+    ), QuditTreeError> {
+        let (sized_buffers, memory_size) = self.sized_buffers::<C>(diff_lvl);
 
         let mut builder = ModuleBuilder::new("qvm", diff_lvl);
         for expr in &self.expression_set {
@@ -102,14 +648,14 @@ impl Bytecode {
 
         let mut static_out = Vec::new();
         for inst in &self.static_code {
-            static_out.push(inst.specialize(&sized_buffers, &module, diff_lvl));
+            static_out.push(inst.try_specialize(&sized_buffers, &module, diff_lvl)?);
         }
 
         let mut dynamic_out = Vec::new();
         for inst in &self.dynamic_code {
-            dynamic_out.push(inst.specialize(&sized_buffers, &module, diff_lvl));
+            dynamic_out.push(inst.try_specialize(&sized_buffers, &module, diff_lvl)?);
         }
-        (static_out, dynamic_out, module, memory_size)
+        Ok((static_out, dynamic_out, module, memory_size))
     }
 }
 
@@ -126,3 +672,273 @@ impl std::fmt::Debug for Bytecode {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use super::BufferMergeError;
+    use super::Bytecode;
+    use super::GeneralizedInstruction;
+    use super::MatrixBuffer;
+    use super::WarmupStrategy;
+    use crate::compiler::compile;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+    use crate::tree::TreeBuilder;
+
+    fn buffer(nrows: usize, ncols: usize) -> MatrixBuffer {
+        MatrixBuffer { nrows, ncols, num_params: 0, warmup: WarmupStrategy::None }
+    }
+
+    #[test]
+    fn test_replace_expression_matches_recompile() {
+        let p = fixtures::p();
+        let q = UnitaryExpression::new("Q(a) { [[e^(i*a), 0], [0, 1]] }");
+        let ry = fixtures::ry();
+        let cry = fixtures::cry();
+        let build = |single: &UnitaryExpression| {
+            compile(
+                &TreeBuilder::from_locations(
+                    QuditRadices::from_iter([2, 2]),
+                    vec![vec![0], vec![1], vec![0, 1]],
+                    |loc| match loc {
+                        [0] => single.clone(),
+                        [1] => ry.clone(),
+                        _ => cry.clone(),
+                    },
+                )
+                .build_tree(),
+            )
+        };
+        let params = [0.4, -1.2, 2.5];
+        let assert_evaluates_as = |code: Bytecode, expected: Bytecode| {
+            let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+            let mut expected_qvm = QVM::<c64>::new(expected, DifferentiationLevel::None);
+            assert_eq!(qvm.get_unitary(&params), expected_qvm.get_unitary(&params));
+        };
+
+        // A new expression takes the old one's place
+        let mut code = build(&p);
+        let num_expressions = code.expression_set.len();
+        code.replace_expression(&p, q.clone());
+        assert_eq!(code.expression_set.len(), num_expressions);
+        assert!(!code.expression_set.contains(&p));
+        assert_evaluates_as(code.clone(), build(&q));
+
+        // An expression already in use is shared rather than duplicated
+        code.replace_expression(&q, ry.clone());
+        assert_eq!(code.expression_set.len(), num_expressions - 1);
+        assert_evaluates_as(code, build(&ry));
+    }
+
+    #[test]
+    fn test_exact_flops() {
+        let code = Bytecode {
+            expression_set: vec![],
+            static_code: vec![GeneralizedInstruction::Kron(0, 1, 6)],
+            dynamic_code: vec![
+                GeneralizedInstruction::Kron(0, 1, 2),
+                GeneralizedInstruction::Matmul(2, 2, 3),
+                GeneralizedInstruction::FRPR(3, vec![4, 16], vec![1, 0], 4),
+                GeneralizedInstruction::Conj(4, 5),
+            ],
+            matrix_buffers: vec![
+                buffer(2, 2),
+                buffer(4, 4),
+                buffer(8, 8),
+                buffer(8, 8),
+                buffer(8, 8),
+                buffer(8, 8),
+                buffer(8, 8),
+            ],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2, 2, 2]),
+        };
+
+        // kron 8 * 8 + matmul 8 * 8 * 8 + FRPR 8 * 8
+        assert_eq!(code.exact_flops(), 64 + 512 + 64);
+    }
+
+    #[test]
+    fn test_estimated_flops() {
+        let square = Bytecode {
+            expression_set: vec![],
+            static_code: vec![GeneralizedInstruction::Kron(0, 0, 1)],
+            dynamic_code: vec![
+                GeneralizedInstruction::Matmul(1, 2, 3),
+                GeneralizedInstruction::FRPR(3, vec![2, 2, 4], vec![1, 0, 2], 4),
+                GeneralizedInstruction::Conj(4, 5),
+            ],
+            matrix_buffers: vec![
+                buffer(2, 2),
+                buffer(4, 4),
+                buffer(4, 4),
+                buffer(4, 4),
+                buffer(4, 4),
+                buffer(4, 4),
+            ],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2, 2]),
+        };
+        // kron 4 * 4 once; matmul 2 * 4 * 4 * 4 + FRPR 4 * 4 per run
+        assert_eq!(square.estimated_flops(), (16, 128 + 16));
+
+        let rectangular = Bytecode {
+            expression_set: vec![],
+            static_code: vec![],
+            dynamic_code: vec![GeneralizedInstruction::Matmul(0, 1, 2)],
+            matrix_buffers: vec![buffer(2, 4), buffer(4, 3), buffer(2, 3)],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2]),
+        };
+        assert_eq!(rectangular.estimated_flops(), (0, 2 * 2 * 3 * 4));
+    }
+
+    #[test]
+    fn test_memory_footprint_scales_with_differentiation_level() {
+        let code = Bytecode {
+            expression_set: vec![],
+            static_code: vec![],
+            dynamic_code: vec![],
+            matrix_buffers: vec![MatrixBuffer {
+                nrows: 4,
+                ncols: 4,
+                num_params: 3,
+                warmup: WarmupStrategy::None,
+            }],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2, 2]),
+        };
+
+        let none = code.memory_breakdown::<c64>(DifferentiationLevel::None);
+        assert_eq!(none.gradient_bytes, 0);
+        assert_eq!(none.hessian_bytes, 0);
+
+        // Three parameters have three gradient and 3 * 4 / 2 hessian matrices
+        let hess = code.memory_breakdown::<c64>(DifferentiationLevel::Hessian);
+        assert_eq!(hess.unitary_bytes, none.unitary_bytes);
+        assert_eq!(hess.gradient_bytes, 3 * hess.unitary_bytes);
+        assert_eq!(hess.hessian_bytes, 6 * hess.unitary_bytes);
+
+        for diff_lvl in [DifferentiationLevel::None, DifferentiationLevel::Hessian] {
+            let (_, memory_size) = code.sized_buffers::<c64>(diff_lvl);
+            assert_eq!(
+                code.memory_footprint::<c64>(diff_lvl),
+                memory_size * std::mem::size_of::<c64>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_buffers_reduces_memory() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1]; 5],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let params = [0.1, 0.7, 1.3, 2.1, 2.9];
+
+        // Start from the unmerged code, since compile already reuses buffers.
+        let mut code = compile(&tree);
+        code.merged_buffers.clear();
+        let (_, _, _, memory_size) = code.specialize::<c64>(DifferentiationLevel::None);
+        let expected = QVM::<c64>::new(code.clone(), DifferentiationLevel::None)
+            .get_unitary(&params)
+            .to_owned();
+
+        // Writes are pinned to their own storage, so only the products of
+        // the long multiplication chain can merge.
+        let mut merged = code.clone();
+        let n = merged.matrix_buffers.len();
+        let found = (0..n)
+            .flat_map(|a| (0..n).map(move |b| (a, b)))
+            .any(|(mergee, merger)| merged.merge_buffers(mergee, merger).is_ok());
+        assert!(found);
+        assert_eq!(merged.merged_buffers.len(), 1);
+
+        let (_, _, _, merged_size) = merged.specialize::<c64>(DifferentiationLevel::None);
+        assert!(merged_size < memory_size);
+
+        let mut qvm = QVM::<c64>::new(merged, DifferentiationLevel::None);
+        assert_eq!(qvm.get_unitary(&params), expected.as_ref());
+    }
+
+    #[test]
+    fn test_merge_chain_resolves_to_final_storage() {
+        let p = fixtures::p();
+        let sized = |num_params, warmup| MatrixBuffer { nrows: 2, ncols: 2, num_params, warmup };
+
+        // A chain of phase gates, where the product in buffer 2k is written
+        // by instruction 2k and read by instruction 2k + 2.
+        let mut dynamic_code = vec![GeneralizedInstruction::Write(p.clone(), 0, 0)];
+        let mut matrix_buffers = vec![sized(1, WarmupStrategy::Identity)];
+        for k in 1..=6 {
+            dynamic_code.push(GeneralizedInstruction::Write(p.clone(), k, 2 * k - 1));
+            dynamic_code.push(GeneralizedInstruction::Matmul(2 * k - 2, 2 * k - 1, 2 * k));
+            matrix_buffers.push(sized(1, WarmupStrategy::Identity));
+            matrix_buffers.push(sized(k + 1, WarmupStrategy::None));
+        }
+        let mut code = Bytecode {
+            expression_set: vec![p],
+            static_code: vec![],
+            dynamic_code,
+            matrix_buffers,
+            merged_buffers: HashMap::from([(2, 6), (6, 10)]),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2]),
+        };
+        assert_eq!(code.validate_merged_buffers(), Ok(()));
+
+        let (sized_buffers, _) = code.sized_buffers::<c64>(DifferentiationLevel::None);
+        assert_eq!(sized_buffers[2].offset, sized_buffers[10].offset);
+        assert_eq!(sized_buffers[6].offset, sized_buffers[10].offset);
+
+        let params = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7];
+        let total: f64 = params.iter().sum();
+        let mut qvm = QVM::<c64>::new(code.clone(), DifferentiationLevel::None);
+        let utry = qvm.get_unitary(&params);
+        assert!((utry[(1, 1)] - c64::new(total.cos(), total.sin())).norm() < 1e-10);
+
+        code.merged_buffers.insert(10, 2);
+        assert!(matches!(code.validate_merged_buffers(), Err(BufferMergeError::Cyclic(_))));
+    }
+
+    #[test]
+    fn test_merge_buffers_rejects_overlapping_lifespans() {
+        let mut code = Bytecode {
+            expression_set: vec![],
+            static_code: vec![],
+            dynamic_code: vec![
+                GeneralizedInstruction::Matmul(0, 1, 2),
+                GeneralizedInstruction::Matmul(2, 0, 3),
+            ],
+            matrix_buffers: vec![buffer(2, 2); 4],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2]),
+        };
+
+        assert_eq!(code.merge_buffers(2, 0), Err(BufferMergeError::OverlappingLifespans));
+        assert_eq!(code.merge_buffers(1, 7), Err(BufferMergeError::InvalidBuffer(7)));
+        assert_eq!(code.merge_buffers(1, 3), Ok(()));
+        assert_eq!(code.merge_buffers(1, 2), Err(BufferMergeError::AlreadyMerged(1)));
+    }
+}
@@ -3,7 +3,9 @@ use std::collections::HashMap;
 use qudit_core::ComplexScalar;
 use qudit_expr::{DifferentiationLevel, Module, UnitaryExpression};
 
-use super::{instructions::{FRPRStruct, KronStruct, MatmulStruct, WriteStruct}, SizedMatrixBuffer, SpecializedInstruction};
+use crate::error::QuditTreeError;
+
+use super::{instructions::{ApplyStruct, ConjStruct, FRPRStruct, KronStruct, MatmulStruct, WriteStruct}, SizedMatrixBuffer, SpecializedInstruction};
 
 // use super::{
     // instructions::{FRPRStruct, KronStruct, MatmulStruct, WriteStruct},
@@ -16,6 +18,14 @@ pub enum GeneralizedInstruction {
     Matmul(usize, usize, usize),
     Kron(usize, usize, usize),
     FRPR(usize, Vec<usize>, Vec<usize>, usize),
+    Conj(usize, usize),
+    /// Apply an operator to some qudits of a state: the operator's buffer,
+    /// the input state's buffer, the radices of the state's qudits, the
+    /// qudits acted on, and the output state's buffer.
+    Apply(usize, usize, Vec<usize>, Vec<usize>, usize),
+    /// Apply the diagonal of an operator to some qudits of a state, with
+    /// the operands of [GeneralizedInstruction::Apply].
+    ApplyDiagonal(usize, usize, Vec<usize>, Vec<usize>, usize),
 }
 
 impl std::fmt::Debug for GeneralizedInstruction {
@@ -33,11 +43,46 @@ impl std::fmt::Debug for GeneralizedInstruction {
             GeneralizedInstruction::FRPR(a, _, _, d) => {
                 write!(f, "FRPR {:?} {:?}", a, d)
             },
+            GeneralizedInstruction::Conj(a, b) => {
+                write!(f, "Conj {:?} {:?}", a, b)
+            },
+            GeneralizedInstruction::Apply(a, b, _, qudits, e) => {
+                write!(f, "Apply {:?} {:?} {:?} {:?}", a, b, qudits, e)
+            },
+            GeneralizedInstruction::ApplyDiagonal(a, b, _, qudits, e) => {
+                write!(f, "ApplyDiagonal {:?} {:?} {:?} {:?}", a, b, qudits, e)
+            },
         }
     }
 }
 
 impl GeneralizedInstruction {
+    /// The buffer this instruction writes its result into.
+    pub fn out_buffer(&self) -> usize {
+        match self {
+            GeneralizedInstruction::Write(_, _, index) => *index,
+            GeneralizedInstruction::Matmul(_, _, c) => *c,
+            GeneralizedInstruction::Kron(_, _, c) => *c,
+            GeneralizedInstruction::FRPR(_, _, _, d) => *d,
+            GeneralizedInstruction::Conj(_, b) => *b,
+            GeneralizedInstruction::Apply(_, _, _, _, e)
+            | GeneralizedInstruction::ApplyDiagonal(_, _, _, _, e) => *e,
+        }
+    }
+
+    /// The buffers this instruction reads from.
+    pub fn in_buffers(&self) -> Vec<usize> {
+        match self {
+            GeneralizedInstruction::Write(..) => vec![],
+            GeneralizedInstruction::Matmul(a, b, _) => vec![*a, *b],
+            GeneralizedInstruction::Kron(a, b, _) => vec![*a, *b],
+            GeneralizedInstruction::FRPR(a, _, _, _) => vec![*a],
+            GeneralizedInstruction::Conj(a, _) => vec![*a],
+            GeneralizedInstruction::Apply(a, b, _, _, _)
+            | GeneralizedInstruction::ApplyDiagonal(a, b, _, _, _) => vec![*a, *b],
+        }
+    }
+
     pub fn offset_buffer_indices(&mut self, offset: usize) {
         match self {
             GeneralizedInstruction::Write(_, _, index) => {
@@ -57,6 +102,16 @@ impl GeneralizedInstruction {
                 *a += offset;
                 *d += offset;
             },
+            GeneralizedInstruction::Conj(a, b) => {
+                *a += offset;
+                *b += offset;
+            },
+            GeneralizedInstruction::Apply(a, b, _, _, e)
+            | GeneralizedInstruction::ApplyDiagonal(a, b, _, _, e) => {
+                *a += offset;
+                *b += offset;
+                *e += offset;
+            },
         }
     }
 
@@ -100,6 +155,26 @@ impl GeneralizedInstruction {
                     *d = *new_index;
                 }
             },
+            GeneralizedInstruction::Conj(a, b) => {
+                if let Some(new_index) = buffer_map.get(a) {
+                    *a = *new_index;
+                }
+                if let Some(new_index) = buffer_map.get(b) {
+                    *b = *new_index;
+                }
+            },
+            GeneralizedInstruction::Apply(a, b, _, _, e)
+            | GeneralizedInstruction::ApplyDiagonal(a, b, _, _, e) => {
+                if let Some(new_index) = buffer_map.get(a) {
+                    *a = *new_index;
+                }
+                if let Some(new_index) = buffer_map.get(b) {
+                    *b = *new_index;
+                }
+                if let Some(new_index) = buffer_map.get(e) {
+                    *e = *new_index;
+                }
+            },
         }
     }
 
@@ -109,20 +184,37 @@ impl GeneralizedInstruction {
         module: &Module<C>,
         diff_lvl: DifferentiationLevel,
     ) -> SpecializedInstruction<C> {
-        match self {
+        self.try_specialize(buffers, module, diff_lvl).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Specialize this instruction as in [GeneralizedInstruction::specialize],
+    /// returning an error instead of panicking if it cannot be specialized.
+    pub fn try_specialize<C: ComplexScalar>(
+        &self,
+        buffers: &Vec<SizedMatrixBuffer>,
+        module: &Module<C>,
+        diff_lvl: DifferentiationLevel,
+    ) -> Result<SpecializedInstruction<C>, QuditTreeError> {
+        Ok(match self {
             GeneralizedInstruction::Write(expr, param_pointer, index) => {
-                let (utry_fn, grad_fn) = unsafe {
+                let (utry_fn, grad_fn, hess_fn) = unsafe {
                     let utry_fn = module.get_function_raw(&expr.name());
                     let grad_fn = if diff_lvl != DifferentiationLevel::None {
                         Some(module.get_function_and_gradient_raw(&expr.name()))
                     } else {
                         None
                     };
-                    (utry_fn, grad_fn)
+                    let hess_fn = if diff_lvl.hessian_capable() {
+                        Some(module.get_function_gradient_and_hessian_raw(&expr.name()))
+                    } else {
+                        None
+                    };
+                    (utry_fn, grad_fn, hess_fn)
                 };
                 SpecializedInstruction::Write(WriteStruct::new(
                     utry_fn,
                     grad_fn,
+                    hess_fn,
                     *param_pointer,
                     buffers[*index].clone(),
                 ))
@@ -146,10 +238,31 @@ impl GeneralizedInstruction {
             GeneralizedInstruction::FRPR(in_index, shape, perm, out_index) => {
                 let spec_a = buffers[*in_index].clone();
                 let spec_b = buffers[*out_index].clone();
-                SpecializedInstruction::FRPR(FRPRStruct::new(
+                SpecializedInstruction::FRPR(FRPRStruct::try_new(
                     spec_a, shape, perm, spec_b,
+                )?)
+            },
+            GeneralizedInstruction::Conj(in_index, out_index) => {
+                let spec_a = buffers[*in_index].clone();
+                let spec_b = buffers[*out_index].clone();
+                SpecializedInstruction::Conj(ConjStruct::new(spec_a, spec_b))
+            },
+            GeneralizedInstruction::Apply(op, state, shape, qudits, out) => {
+                let spec_op = buffers[*op].clone();
+                let spec_state = buffers[*state].clone();
+                let spec_out = buffers[*out].clone();
+                SpecializedInstruction::Apply(ApplyStruct::new(
+                    spec_op, spec_state, shape, qudits, spec_out,
                 ))
             },
-        }
+            GeneralizedInstruction::ApplyDiagonal(op, state, shape, qudits, out) => {
+                let spec_op = buffers[*op].clone();
+                let spec_state = buffers[*state].clone();
+                let spec_out = buffers[*out].clone();
+                SpecializedInstruction::Apply(ApplyStruct::new_diagonal(
+                    spec_op, spec_state, shape, qudits, spec_out,
+                ))
+            },
+        })
     }
 }
@@ -1,19 +1,33 @@
 use std::collections::{HashMap, HashSet};
 
 use super::MatrixBuffer;
-use super::{Bytecode, GeneralizedInstruction};
+use super::WarmupStrategy;
+use super::{Bytecode, GeneralizedInstruction, ParameterMap};
 use qudit_core::HasParams;
+use crate::error::QuditTreeError;
 use crate::tree::ExpressionTree;
+use crate::tree::identity_expression;
 use qudit_expr::UnitaryExpression;
+use qudit_core::QuditRadices;
 use qudit_core::QuditSystem;
 
 pub struct BytecodeGenerator {
+    /// The distinct expressions written by the program. Identical leaves,
+    /// such as the same gate applied in parallel, share a single entry and
+    /// hence a single JIT-compiled module function.
     expression_set: HashSet<UnitaryExpression>,
     static_code: Vec<GeneralizedInstruction>,
     dynamic_code: Vec<GeneralizedInstruction>,
     matrix_buffers: Vec<MatrixBuffer>,
     param_counter: usize,
     static_tree_cache: HashMap<ExpressionTree, usize>,
+    runtime_constants: Vec<(usize, usize)>,
+    parameter_map: Option<ParameterMap>,
+    /// The first leaf whose parameters could not be mapped, reported once
+    /// the whole tree has been parsed.
+    error: Option<QuditTreeError>,
+    /// Whether operators are lowered to the state by their diagonal only.
+    diagonal: bool,
 }
 
 impl BytecodeGenerator {
@@ -25,9 +39,22 @@ impl BytecodeGenerator {
             matrix_buffers: Vec::new(),
             param_counter: 0, // TODO: Handle parameters way better
             static_tree_cache: HashMap::new(),
+            runtime_constants: Vec::new(),
+            parameter_map: None,
+            error: None,
+            diagonal: false,
         }
     }
 
+    /// Tie the tree's parameters to global parameters with `parameter_map`.
+    ///
+    /// Expression writes then read their parameters from the global
+    /// offsets given by the map, instead of one new offset per leaf.
+    pub fn with_parameter_map(mut self, parameter_map: ParameterMap) -> Self {
+        self.parameter_map = Some(parameter_map);
+        self
+    }
+
     pub fn get_new_buffer(
         &mut self,
         nrows: usize,
@@ -39,27 +66,236 @@ impl BytecodeGenerator {
             nrows,
             ncols,
             num_params,
+            warmup: WarmupStrategy::None,
         });
         out
     }
 
-    pub fn generate(mut self, tree: &ExpressionTree) -> Bytecode {
+    pub fn generate(self, tree: &ExpressionTree) -> Bytecode {
+        self.try_generate(tree).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Generate bytecode for `tree` as in [BytecodeGenerator::generate],
+    /// returning an error instead of panicking if the parameter map does
+    /// not fit the tree.
+    pub fn try_generate(mut self, tree: &ExpressionTree) -> Result<Bytecode, QuditTreeError> {
+        self.check_parameter_map(tree)?;
         self.parse(tree);
+        self.finish(tree)
+    }
+
+    /// Generate bytecode that applies `tree` to a state instead of
+    /// computing its unitary.
+    ///
+    /// The state is a single column over all of the tree's qudits, held in
+    /// a buffer no instruction writes; the QVM fills it before running the
+    /// code, and it starts as the first basis state. Each leaf is written
+    /// on its own qudits and applied to the state with an
+    /// [GeneralizedInstruction::Apply], following the state through every
+    /// multiplication, kron, permutation, and contraction of the tree, so
+    /// no buffer spans more than the state or a single operand. Constants
+    /// and the conjugated operand of a contraction are computed as
+    /// operators, as in [BytecodeGenerator::generate], and then applied.
+    ///
+    /// The output of the code is the evolved state.
+    pub fn generate_for_state(self, tree: &ExpressionTree) -> Bytecode {
+        self.try_generate_for_state(tree).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Generate state bytecode as in [BytecodeGenerator::generate_for_state],
+    /// returning an error instead of panicking if the parameter map does
+    /// not fit the tree.
+    pub fn try_generate_for_state(
+        mut self,
+        tree: &ExpressionTree,
+    ) -> Result<Bytecode, QuditTreeError> {
+        self.check_parameter_map(tree)?;
+
+        let radices: Vec<usize> = tree.radices().iter().map(|&r| r as usize).collect();
+        let input = self.get_new_buffer(tree.dimension(), 1, 0);
+        self.matrix_buffers[input].warmup = WarmupStrategy::Identity;
+
+        let positions: Vec<usize> = (0..radices.len()).collect();
+        let out = self.lower_to_state(&tree.unfused(), &positions, &radices, input);
+        if out == input {
+            // An identity tree applies nothing, but the code still needs an
+            // instruction producing its output.
+            let identity = identity_expression(QuditRadices::from_iter([tree.radices()[0]]));
+            let op = self.parse(&ExpressionTree::Leaf(identity));
+            self.apply_to_state(op, &[0], &radices, input);
+        }
+        self.finish(tree)
+    }
+
+    /// Generate bytecode that computes the diagonal of `tree`, assuming
+    /// every operator in it is diagonal.
+    ///
+    /// The tree is lowered as in [BytecodeGenerator::generate_for_state],
+    /// but each operator is applied with an
+    /// [GeneralizedInstruction::ApplyDiagonal], which multiplies every
+    /// entry of the state by the matching entry of the operator's
+    /// diagonal. Multiplications become element-wise products and krons
+    /// products of the sides' diagonals, so run on the all-ones state the
+    /// code outputs the diagonal of the unitary without forming it.
+    ///
+    /// Expressions cannot report whether they are diagonal, so this is not
+    /// checked here; see [crate::QVM::get_diagonal].
+    pub fn generate_for_diagonal(self, tree: &ExpressionTree) -> Bytecode {
+        self.try_generate_for_diagonal(tree).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        Bytecode {
-            expression_set: self.expression_set.into_iter().collect(),
+    /// Generate diagonal bytecode as in
+    /// [BytecodeGenerator::generate_for_diagonal], returning an error
+    /// instead of panicking if the parameter map does not fit the tree.
+    pub fn try_generate_for_diagonal(
+        mut self,
+        tree: &ExpressionTree,
+    ) -> Result<Bytecode, QuditTreeError> {
+        self.diagonal = true;
+        self.try_generate_for_state(tree)
+    }
+
+    fn check_parameter_map(&self, tree: &ExpressionTree) -> Result<(), QuditTreeError> {
+        if let Some(map) = &self.parameter_map {
+            if map.num_tree_params() != tree.num_params() {
+                return Err(QuditTreeError::ParameterMapLength {
+                    expected: tree.num_params(),
+                    found: map.num_tree_params(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self, tree: &ExpressionTree) -> Result<Bytecode, QuditTreeError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        Ok(Bytecode {
+            expression_set: {
+                // Sorted, so the module is built the same way on every run.
+                // Distinct expressions may share a name, so ties are broken
+                // by the full expression.
+                let mut expressions: Vec<_> = self.expression_set.into_iter().collect();
+                expressions.sort_by_cached_key(|e| (e.name(), format!("{:?}", e)));
+                expressions
+            },
             static_code: self.static_code,
             dynamic_code: self.dynamic_code,
             matrix_buffers: self.matrix_buffers,
             merged_buffers: HashMap::new(),
+            runtime_constants: self.runtime_constants,
+            parameter_map: self.parameter_map,
+            radices: tree.radices(),
+        })
+    }
+
+    /// Apply the operator in buffer `op`, or only its diagonal when
+    /// generating a diagonal program, to the state in buffer `state`.
+    ///
+    /// The operator acts on the state qudits `qudits`, in that order, of a
+    /// state over qudits with radices `radices`.
+    ///
+    /// # Returns
+    ///
+    /// The buffer holding the new state, which carries the parameters of
+    /// the old state followed by those of the operator.
+    fn apply_to_state(
+        &mut self,
+        op: usize,
+        qudits: &[usize],
+        radices: &[usize],
+        state: usize,
+    ) -> usize {
+        let num_params =
+            self.matrix_buffers[state].num_params + self.matrix_buffers[op].num_params;
+        let out = self.get_new_buffer(self.matrix_buffers[state].nrows, 1, num_params);
+        let (shape, qudits) = (radices.to_vec(), qudits.to_vec());
+        self.dynamic_code.push(if self.diagonal {
+            GeneralizedInstruction::ApplyDiagonal(op, state, shape, qudits, out)
+        } else {
+            GeneralizedInstruction::Apply(op, state, shape, qudits, out)
+        });
+        out
+    }
+
+    /// Apply the unfused `tree` to the state in buffer `state`.
+    ///
+    /// Local qudit `k` of `tree` is state qudit `positions[k]`.
+    ///
+    /// # Returns
+    ///
+    /// The buffer holding the new state, which is `state` itself if the
+    /// tree is an identity.
+    fn lower_to_state(
+        &mut self,
+        tree: &ExpressionTree,
+        positions: &[usize],
+        radices: &[usize],
+        state: usize,
+    ) -> usize {
+        match tree {
+            ExpressionTree::Identity(_) => state,
+            ExpressionTree::Mul(n) => {
+                let state = self.lower_to_state(&n.left, positions, radices, state);
+                self.lower_to_state(&n.right, positions, radices, state)
+            },
+            ExpressionTree::Kron(n) => {
+                let (left, right) = positions.split_at(n.left.num_qudits());
+                let state = self.lower_to_state(&n.left, left, radices, state);
+                self.lower_to_state(&n.right, right, radices, state)
+            },
+            ExpressionTree::Perm(n) => {
+                // Output qudit i is input qudit perm[i]
+                let mut child_positions = vec![0; positions.len()];
+                for (i, &position) in positions.iter().enumerate() {
+                    child_positions[n.perm[i]] = position;
+                }
+                self.lower_to_state(&n.child, &child_positions, radices, state)
+            },
+            ExpressionTree::Contract(n) => {
+                let output = n
+                    .output_qudits()
+                    .expect("Contractions are unfused before lowering to a state.");
+                let position_of = |q: &usize| {
+                    positions[output.iter().position(|x| x == q).unwrap()]
+                };
+                let left_positions: Vec<usize> = n.left_qudits.iter().map(position_of).collect();
+                let right_positions: Vec<usize> = n.right_qudits.iter().map(position_of).collect();
+
+                let state = self.lower_to_state(&n.left, &left_positions, radices, state);
+                if !n.conjugate_right {
+                    return self.lower_to_state(&n.right, &right_positions, radices, state);
+                }
+
+                let right = self.parse(&n.right);
+                let buffer = self.matrix_buffers[right];
+                let conj = self.get_new_buffer(buffer.nrows, buffer.ncols, buffer.num_params);
+                let code = match *n.right {
+                    ExpressionTree::Constant(_) => &mut self.static_code,
+                    _ => &mut self.dynamic_code,
+                };
+                code.push(GeneralizedInstruction::Conj(right, conj));
+                self.apply_to_state(conj, &right_positions, radices, state)
+            },
+            ExpressionTree::Leaf(_)
+            | ExpressionTree::Constant(_)
+            | ExpressionTree::RuntimeConstant(_) => {
+                let op = self.parse(tree);
+                self.apply_to_state(op, positions, radices, state)
+            },
         }
     }
 
     pub fn parse(&mut self, tree: &ExpressionTree) -> usize {
         match tree {
-            ExpressionTree::Identity(_) => unreachable!(
-                "Identity should not even exist. Like in the code base."
-            ),
+            ExpressionTree::Identity(n) => {
+                // Written as an identity expression, which
+                // remove_identity_kron recognizes when padding idle qudits.
+                let identity = n.expression();
+                self.parse(&ExpressionTree::Leaf(identity))
+            },
             ExpressionTree::Kron(n) => {
                 let left = self.parse(&n.left);
                 let right = self.parse(&n.right);
@@ -104,9 +340,20 @@ impl BytecodeGenerator {
                     g.dimension(),
                     g.num_params(),
                 );
+                self.matrix_buffers[out].warmup = WarmupStrategy::Identity;
+                let param_offset = match &self.parameter_map {
+                    Some(map) => match map.try_leaf_offset(self.param_counter, g.num_params()) {
+                        Ok(offset) => offset,
+                        Err(e) => {
+                            self.error.get_or_insert(e);
+                            0
+                        },
+                    },
+                    None => self.param_counter,
+                };
                 self.dynamic_code.push(GeneralizedInstruction::Write(
                     g.clone(),
-                    self.param_counter,
+                    param_offset,
                     out.clone(),
                 ));
                 self.param_counter += g.num_params();
@@ -126,7 +373,12 @@ impl BytecodeGenerator {
                     self.matrix_buffers.push(buffer);
                 }
 
-                assert!(code.static_code.len() == 0);
+                // A constant nested inside this one was already hoisted by
+                // the sub-generator; all of its code is static here too.
+                for mut inst in code.static_code {
+                    inst.offset_buffer_indices(buffer_offset);
+                    self.static_code.push(inst);
+                }
 
                 for mut inst in code.dynamic_code {
                     inst.offset_buffer_indices(buffer_offset);
@@ -137,30 +389,69 @@ impl BytecodeGenerator {
                     self.expression_set.insert(expr);
                 }
 
+                for (id, buffer) in code.runtime_constants {
+                    self.runtime_constants.push((id, buffer + buffer_offset));
+                }
+
                 let out = self.matrix_buffers.len() - 1;
                 self.static_tree_cache.insert(tree.clone(), out);
                 out
             },
-            ExpressionTree::Perm(_n) => {
-                unreachable!();
-                // let child = self.parse(&n.child);
-                // let out = self.get_free_to_clobber(n.get_dimension(), n.get_dimension(), n.get_num_params());
-                // TODO: let (ins, outs, pshape) = n.get_permutation().as_frpr();
-                // self.bytecode.push(GeneralizedInstruction::FRPR(ins, outs, pshape, child.clone(), out.clone()));
-                // self.free_buffer(child);
-                // out
+            ExpressionTree::RuntimeConstant(n) => {
+                // No instruction writes this buffer; the QVM fills it with
+                // the supplied matrix before running any code.
+                let out = self.get_new_buffer(n.dimension(), n.dimension(), 0);
+                self.matrix_buffers[out].warmup = WarmupStrategy::Zero;
+                self.runtime_constants.push((n.id, out));
+                out
+            },
+            ExpressionTree::Perm(n) => {
+                let child = self.parse(&n.child);
+                let out = self.get_new_buffer(
+                    n.dimension(),
+                    n.dimension(),
+                    n.num_params(),
+                );
+
+                // Permute the row and column indices of the child alike
+                let num_qudits = n.child.num_qudits();
+                let shape = n
+                    .child
+                    .radices()
+                    .iter()
+                    .chain(n.child.radices().iter())
+                    .map(|&r| r as usize)
+                    .collect();
+                let perm = (0..num_qudits)
+                    .map(|i| n.perm[i])
+                    .chain((0..num_qudits).map(|i| n.perm[i] + num_qudits))
+                    .collect();
+
+                self.dynamic_code.push(GeneralizedInstruction::FRPR(
+                    child,
+                    shape,
+                    perm,
+                    out,
+                ));
+                out
             },
             ExpressionTree::Contract(n) => {
                 let mut left = self.parse(&n.left);
                 let mut right = self.parse(&n.right);
 
+                // Permuting a constant operand only needs to happen once,
+                // so its pre-permutation is hoisted into static code.
+                let left_is_static = matches!(*n.left, ExpressionTree::Constant(_));
+                let right_is_static = matches!(*n.right, ExpressionTree::Constant(_));
+
                 if !n.skip_left {
                     let out = self.get_new_buffer(
                         n.left_contraction_shape.0,
                         n.left_contraction_shape.1,
                         n.left.num_params(),
                     );
-                    self.dynamic_code.push(GeneralizedInstruction::FRPR(
+                    let code = if left_is_static { &mut self.static_code } else { &mut self.dynamic_code };
+                    code.push(GeneralizedInstruction::FRPR(
                         left.clone(),
                         n.left_tensor_shape.clone().into_iter().map(|x| x.try_into().unwrap()).collect(),
                         n.left_perm.clone(),
@@ -176,7 +467,8 @@ impl BytecodeGenerator {
                         n.right_contraction_shape.1,
                         n.right.num_params(),
                     );
-                    self.dynamic_code.push(GeneralizedInstruction::FRPR(
+                    let code = if right_is_static { &mut self.static_code } else { &mut self.dynamic_code };
+                    code.push(GeneralizedInstruction::FRPR(
                         right.clone(),
                         n.right_tensor_shape.clone().into_iter().map(|x| x.try_into().unwrap()).collect(),
                         n.right_perm.clone(),
@@ -186,6 +478,21 @@ impl BytecodeGenerator {
                     right = out;
                 }
 
+                if n.conjugate_right {
+                    let buffer = self.matrix_buffers[right];
+                    let out = self.get_new_buffer(
+                        buffer.nrows,
+                        buffer.ncols,
+                        buffer.num_params,
+                    );
+                    let code = if right_is_static { &mut self.static_code } else { &mut self.dynamic_code };
+                    code.push(GeneralizedInstruction::Conj(
+                        right,
+                        out,
+                    ));
+                    right = out;
+                }
+
                 let pre_out = self.get_new_buffer(
                     n.right_contraction_shape.0,
                     n.left_contraction_shape.1,
@@ -199,6 +506,11 @@ impl BytecodeGenerator {
                 // self.free_buffer(left);
                 // self.free_buffer(right);
 
+                if n.skips_output_permutation() {
+                    return pre_out;
+                }
+                let (pre_out_shape, pre_out_perm) = n.grouped_pre_out();
+
                 let out = self.get_new_buffer(
                     n.out_matrix_shape.0,
                     n.out_matrix_shape.1,
@@ -206,8 +518,8 @@ impl BytecodeGenerator {
                 );
                 self.dynamic_code.push(GeneralizedInstruction::FRPR(
                     pre_out.clone(),
-                    n.pre_out_tensor_shape.clone().into_iter().map(|x| x.try_into().unwrap()).collect(),
-                    n.pre_out_perm.clone().into_iter().map(|x| x.try_into().unwrap()).collect(),
+                    pre_out_shape,
+                    pre_out_perm,
                     out.clone(),
                 ));
                 // self.free_buffer(pre_out);
@@ -265,6 +577,12 @@ impl StaticBytecodeOptimizer {
     }
 
     fn replace_buffers(&mut self) {
+        for (_, buffer) in &mut self.bytecode.runtime_constants {
+            if let Some(new_buffer) = self.replaced_buffers.get(buffer) {
+                *buffer = *new_buffer;
+            }
+        }
+
         for inst in &mut self.bytecode.static_code {
             inst.replace_buffer_indices(&self.replaced_buffers);
         }
@@ -274,3 +592,59 @@ impl StaticBytecodeOptimizer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::c64;
+    use qudit_expr::DifferentiationLevel;
+
+    use super::BytecodeGenerator;
+    use super::GeneralizedInstruction;
+    use crate::compiler::compile;
+    use crate::fixtures;
+    use crate::fixtures::parallel_phases;
+    use crate::qvm::QVM;
+    use crate::tree::ExpressionTree;
+
+    #[test]
+    fn test_identical_parallel_gates_share_expression() {
+        let code = BytecodeGenerator::new().generate(&parallel_phases());
+        let num_writes = code
+            .dynamic_code
+            .iter()
+            .filter(|inst| matches!(inst, GeneralizedInstruction::Write(..)))
+            .count();
+        assert_eq!(num_writes, 3);
+        assert_eq!(code.expression_set.len(), 2);
+    }
+
+    #[test]
+    fn test_required_gate_names() {
+        let code = BytecodeGenerator::new().generate(&parallel_phases());
+        let mut names = code.required_gate_names();
+        names.sort();
+        assert_eq!(names, vec!["CRY".to_string(), "P".to_string()]);
+        assert_eq!(code.required_expressions().len(), 2);
+    }
+
+    #[test]
+    fn test_identities_of_different_sizes_are_distinct_expressions() {
+        let ry = || ExpressionTree::Leaf(fixtures::ry());
+        let tree = ry().on(&[0], 2).beside(ry().on(&[0], 3));
+
+        let code = BytecodeGenerator::new().generate(&tree);
+        let names: Vec<String> = code.expression_set.iter().map(|e| e.name()).collect();
+        assert!(names.contains(&"I_2".to_string()));
+        assert!(names.contains(&"I_2_2".to_string()));
+
+        let params = [0.4, 1.3];
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let expected = tree.evaluate_ref::<c64>(&params);
+        let utry = qvm.get_unitary(&params);
+        for r in 0..32 {
+            for c in 0..32 {
+                assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use faer::reborrow::ReborrowMut;
+use faer::Mat;
+use qudit_core::matrix::{MatMut, MatRef};
+use qudit_core::matrix::{SymSqMatMatMut, SymSqMatMatRef};
+use qudit_core::matrix::{MatVecMut, MatVecRef};
+use qudit_core::ComplexScalar;
+use qudit_expr::DifferentiationLevel;
+use crate::bytecode::MemoryView;
+use crate::bytecode::SizedMatrixBuffer;
+use qudit_core::memory::MemoryBuffer;
+
+/// Apply an operator on some qudits of a state as a local contraction.
+///
+/// The state is a matrix with one row per dimension of the whole system,
+/// whose columns are state vectors. The operator only mixes the rows that
+/// differ in its qudits, so each column is updated in blocks of the
+/// operator's dimension and no operator on the whole system is formed.
+///
+/// A diagonal apply only reads the operator's diagonal, multiplying each
+/// row of the state by the entry for its basis state on the operator's
+/// qudits.
+pub struct ApplyStruct {
+    pub op: SizedMatrixBuffer,
+    pub state: SizedMatrixBuffer,
+    pub out: SizedMatrixBuffer,
+    /// Whether only the operator's diagonal is applied.
+    pub diagonal: bool,
+    /// The row offset of each of the operator's basis states, relative to
+    /// the start of its block.
+    offsets: Vec<usize>,
+    /// The first row of each block, where every qudit the operator acts on
+    /// is zero.
+    bases: Vec<usize>,
+}
+
+impl ApplyStruct {
+    /// Prepare to apply `op` to the qudits `qudits` of `state`.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The operator, acting on `qudits` in that order.
+    /// * `state` - The input state, over qudits with radices `shape`.
+    /// * `shape` - The radices of the state's qudits.
+    /// * `qudits` - The state qudits the operator acts on.
+    /// * `out` - The output state, shaped like `state`.
+    pub fn new(
+        op: SizedMatrixBuffer,
+        state: SizedMatrixBuffer,
+        shape: &[usize],
+        qudits: &[usize],
+        out: SizedMatrixBuffer,
+    ) -> Self {
+        let mut strides = vec![1; shape.len()];
+        for q in (0..shape.len().saturating_sub(1)).rev() {
+            strides[q] = strides[q + 1] * shape[q + 1];
+        }
+
+        let offsets = (0..op.nrows)
+            .map(|mut idx| {
+                let mut offset = 0;
+                for &q in qudits.iter().rev() {
+                    offset += (idx % shape[q]) * strides[q];
+                    idx /= shape[q];
+                }
+                offset
+            })
+            .collect();
+
+        let bases = (0..state.nrows)
+            .filter(|&row| qudits.iter().all(|&q| (row / strides[q]) % shape[q] == 0))
+            .collect();
+
+        Self { op, state, out, diagonal: false, offsets, bases }
+    }
+
+    /// Prepare to apply the diagonal of `op` to the qudits `qudits` of
+    /// `state`, as in [ApplyStruct::new].
+    pub fn new_diagonal(
+        op: SizedMatrixBuffer,
+        state: SizedMatrixBuffer,
+        shape: &[usize],
+        qudits: &[usize],
+        out: SizedMatrixBuffer,
+    ) -> Self {
+        Self { diagonal: true, ..Self::new(op, state, shape, qudits, out) }
+    }
+
+    /// Whether every off-diagonal entry of the operator is exactly zero.
+    pub fn op_is_diagonal<C: ComplexScalar>(&self, memory: &MemoryBuffer<C>) -> bool {
+        let op = self.op.as_matref::<C>(memory);
+        (0..op.ncols()).all(|c| (0..op.nrows()).all(|r| r == c || op[(r, c)] == C::zero()))
+    }
+
+    #[inline(always)]
+    fn calculate_unitary<C: ComplexScalar>(
+        &self,
+        op: MatRef<C>,
+        state: MatRef<C>,
+        mut out: MatMut<C>,
+    ) {
+        if self.diagonal {
+            for c in 0..state.ncols() {
+                for &base in &self.bases {
+                    for (r, &offset) in self.offsets.iter().enumerate() {
+                        *out.rb_mut().get_mut(base + offset, c) =
+                            op[(r, r)] * state[(base + offset, c)];
+                    }
+                }
+            }
+            return;
+        }
+
+        for c in 0..state.ncols() {
+            for &base in &self.bases {
+                for r in 0..op.nrows() {
+                    let mut acc = C::zero();
+                    for k in 0..op.ncols() {
+                        acc = acc + op[(r, k)] * state[(base + self.offsets[k], c)];
+                    }
+                    *out.rb_mut().get_mut(base + self.offsets[r], c) = acc;
+                }
+            }
+        }
+    }
+
+    /// The derivatives of a real cost with respect to the operator and the
+    /// input state, given its derivative `adj` with respect to the output.
+    ///
+    /// # Returns
+    ///
+    /// The adjoints of the operator and the state, shaped like them.
+    pub fn adjoints<C: ComplexScalar>(
+        &self,
+        op: MatRef<C>,
+        state: MatRef<C>,
+        adj: MatRef<C>,
+    ) -> (Mat<C>, Mat<C>) {
+        let mut op_adj = Mat::zeros(op.nrows(), op.ncols());
+        let mut state_adj = Mat::zeros(state.nrows(), state.ncols());
+        for c in 0..state.ncols() {
+            for &base in &self.bases {
+                for r in 0..op.nrows() {
+                    let a = adj[(base + self.offsets[r], c)];
+                    if self.diagonal {
+                        let s = base + self.offsets[r];
+                        op_adj[(r, r)] = op_adj[(r, r)] + a * state[(s, c)].conj();
+                        state_adj[(s, c)] = state_adj[(s, c)] + op[(r, r)].conj() * a;
+                        continue;
+                    }
+                    for k in 0..op.ncols() {
+                        let s = base + self.offsets[k];
+                        op_adj[(r, k)] = op_adj[(r, k)] + a * state[(s, c)].conj();
+                        state_adj[(s, c)] = state_adj[(s, c)] + op[(r, k)].conj() * a;
+                    }
+                }
+            }
+        }
+        (op_adj, state_adj)
+    }
+
+    #[inline(always)]
+    fn calculate_gradient<C: ComplexScalar>(
+        &self,
+        op_utry: MatRef<C>,
+        op_grad: &MatVecRef<C>,
+        state_utry: MatRef<C>,
+        state_grad: &MatVecRef<C>,
+        mut out: MatVecMut<C>,
+    ) {
+        // The state carries the parameters of every operator applied
+        // before this one, so its parameters come first.
+        let mut grad_idx = 0;
+
+        for i in 0..self.state.num_params {
+            self.calculate_unitary(op_utry, state_grad.mat_ref(i), out.mat_mut(grad_idx));
+            grad_idx += 1;
+        }
+
+        for i in 0..self.op.num_params {
+            self.calculate_unitary(op_grad.mat_ref(i), state_utry, out.mat_mut(grad_idx));
+            grad_idx += 1;
+        }
+    }
+
+    #[inline(always)]
+    fn calculate_hessian<C: ComplexScalar>(
+        &self,
+        op_utry: MatRef<C>,
+        op_grad: &MatVecRef<C>,
+        op_hess: SymSqMatMatRef<C>,
+        state_utry: MatRef<C>,
+        state_grad: &MatVecRef<C>,
+        state_hess: SymSqMatMatRef<C>,
+        mut out: SymSqMatMatMut<C>,
+    ) {
+        let state_params = self.state.num_params;
+
+        // Upper left block: op applied to state_hess
+        for p1 in 0..state_params {
+            for p2 in p1..state_params {
+                self.calculate_unitary(op_utry, state_hess.mat_ref(p1, p2), out.mat_mut(p1, p2));
+            }
+        }
+
+        // Lower right block: op_hess applied to state
+        for p1 in 0..self.op.num_params {
+            for p2 in p1..self.op.num_params {
+                self.calculate_unitary(
+                    op_hess.mat_ref(p1, p2),
+                    state_utry,
+                    out.mat_mut(state_params + p1, state_params + p2),
+                );
+            }
+        }
+
+        // Upper right block: op_grad applied to state_grad
+        for p1 in 0..state_params {
+            for p2 in 0..self.op.num_params {
+                self.calculate_unitary(
+                    op_grad.mat_ref(p2),
+                    state_grad.mat_ref(p1),
+                    out.mat_mut(p1, state_params + p2),
+                );
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>) {
+        let mut view = MemoryView::new(memory, DifferentiationLevel::None);
+        let (op_matref, state_matref, out_matmut) =
+            view.inputs_output(&self.op, &self.state, &self.out);
+        self.calculate_unitary(op_matref, state_matref, out_matmut);
+    }
+
+    /// Execute as in `execute_unitary` on the copy of the memory placed
+    /// `shift` elements later.
+    #[inline(always)]
+    pub fn execute_unitary_at<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>, shift: usize) {
+        let (op, state) = (self.op.shifted(shift), self.state.shifted(shift));
+        let out = self.out.shifted(shift);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::None);
+        let (op_matref, state_matref, out_matmut) = view.inputs_output(&op, &state, &out);
+        self.calculate_unitary(op_matref, state_matref, out_matmut);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_and_gradient<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+    ) {
+        let mut view = MemoryView::new(memory, DifferentiationLevel::Gradient);
+        let (
+            (op_matref, op_matgradref),
+            (state_matref, state_matgradref),
+            (out_matmut, out_matgradmut),
+        ) = view.inputs_output_gradient(&self.op, &self.state, &self.out);
+        self.calculate_unitary(op_matref, state_matref, out_matmut);
+        self.calculate_gradient(
+            op_matref,
+            &op_matgradref,
+            state_matref,
+            &state_matgradref,
+            out_matgradmut,
+        );
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_gradient_and_hessian<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+    ) {
+        let mut view = MemoryView::new(memory, DifferentiationLevel::Hessian);
+        let (
+            (op_matref, op_matgradref, op_mathessref),
+            (state_matref, state_matgradref, state_mathessref),
+            (out_matmut, out_matgradmut, out_mathessmut),
+        ) = view.inputs_output_hessian(&self.op, &self.state, &self.out);
+        self.calculate_unitary(op_matref, state_matref, out_matmut);
+        self.calculate_gradient(
+            op_matref,
+            &op_matgradref,
+            state_matref,
+            &state_matgradref,
+            out_matgradmut,
+        );
+        self.calculate_hessian(
+            op_matref,
+            &op_matgradref,
+            op_mathessref,
+            state_matref,
+            &state_matgradref,
+            state_mathessref,
+            out_mathessmut,
+        );
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_into<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+        out: MatMut<C>,
+    ) {
+        let op_matref = self.op.as_matref::<C>(memory);
+        let state_matref = self.state.as_matref::<C>(memory);
+        self.calculate_unitary(op_matref, state_matref, out);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_and_gradient_into<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+        out: MatMut<C>,
+        out_grad: MatVecMut<C>,
+    ) {
+        let op_matref = self.op.as_matref::<C>(memory);
+        let op_matgradref = self.op.as_matvecref::<C>(memory);
+        let state_matref = self.state.as_matref::<C>(memory);
+        let state_matgradref = self.state.as_matvecref::<C>(memory);
+        self.calculate_unitary(op_matref, state_matref, out);
+        self.calculate_gradient(
+            op_matref,
+            &op_matgradref,
+            state_matref,
+            &state_matgradref,
+            out_grad,
+        );
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_gradient_and_hessian_into<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+        out: MatMut<C>,
+        out_grad: MatVecMut<C>,
+        out_hess: SymSqMatMatMut<C>,
+    ) {
+        let op_matref = self.op.as_matref::<C>(memory);
+        let op_matgradref = self.op.as_matvecref::<C>(memory);
+        let op_mathessref = self.op.as_symsqmatref::<C>(memory);
+        let state_matref = self.state.as_matref::<C>(memory);
+        let state_matgradref = self.state.as_matvecref::<C>(memory);
+        let state_mathessref = self.state.as_symsqmatref::<C>(memory);
+        self.calculate_unitary(op_matref, state_matref, out);
+        self.calculate_gradient(
+            op_matref,
+            &op_matgradref,
+            state_matref,
+            &state_matgradref,
+            out_grad,
+        );
+        self.calculate_hessian(
+            op_matref,
+            &op_matgradref,
+            op_mathessref,
+            state_matref,
+            &state_matgradref,
+            state_mathessref,
+            out_hess,
+        );
+    }
+}
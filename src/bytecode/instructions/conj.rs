@@ -0,0 +1,141 @@
+use faer::reborrow::ReborrowMut;
+use qudit_core::matrix::{MatMut, MatRef};
+use qudit_core::matrix::{SymSqMatMatMut, SymSqMatMatRef};
+use qudit_core::matrix::{MatVecMut, MatVecRef};
+use qudit_core::ComplexScalar;
+use crate::bytecode::SizedMatrixBuffer;
+use qudit_core::memory::MemoryBuffer;
+
+pub struct ConjStruct {
+    pub input: SizedMatrixBuffer,
+    pub out: SizedMatrixBuffer,
+}
+
+impl ConjStruct {
+    pub fn new(input: SizedMatrixBuffer, out: SizedMatrixBuffer) -> Self {
+        Self { input, out }
+    }
+
+    #[inline(always)]
+    fn calculate_unitary<C: ComplexScalar>(
+        &self,
+        input: MatRef<C>,
+        mut out: MatMut<C>,
+    ) {
+        for c in 0..input.ncols() {
+            for r in 0..input.nrows() {
+                *out.rb_mut().get_mut(r, c) = input[(r, c)].conj();
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn calculate_gradient<C: ComplexScalar>(
+        &self,
+        input: MatVecRef<C>,
+        mut out: MatVecMut<C>,
+    ) {
+        // Parameters are real, so the derivative of the conjugate is the
+        // conjugate of the derivative.
+        for i in 0..self.input.num_params {
+            self.calculate_unitary(input.mat_ref(i), out.mat_mut(i));
+        }
+    }
+
+    #[inline(always)]
+    fn calculate_hessian<C: ComplexScalar>(
+        &self,
+        input: SymSqMatMatRef<C>,
+        mut out: SymSqMatMatMut<C>,
+    ) {
+        for p1 in 0..self.input.num_params {
+            for p2 in p1..self.input.num_params {
+                self.calculate_unitary(input.mat_ref(p1, p2), out.mat_mut(p1, p2));
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>) {
+        let input_matref = self.input.as_matref::<C>(memory);
+        let out_matmut = self.out.as_matmut::<C>(memory);
+        self.calculate_unitary(input_matref, out_matmut);
+    }
+
+    /// Execute as in `execute_unitary` on the copy of the memory placed
+    /// `shift` elements later.
+    #[inline(always)]
+    pub fn execute_unitary_at<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>, shift: usize) {
+        let input_matref = self.input.shifted(shift).as_matref::<C>(memory);
+        let out_matmut = self.out.shifted(shift).as_matmut::<C>(memory);
+        self.calculate_unitary(input_matref, out_matmut);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_and_gradient<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+    ) {
+        let input_matref = self.input.as_matref::<C>(memory);
+        let input_gradref = self.input.as_matvecref::<C>(memory);
+        let out_matmut = self.out.as_matmut::<C>(memory);
+        let out_gradmut = self.out.as_matvecmut::<C>(memory);
+        self.calculate_unitary(input_matref, out_matmut);
+        self.calculate_gradient(input_gradref, out_gradmut);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_gradient_and_hessian<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+    ) {
+        let input_matref = self.input.as_matref::<C>(memory);
+        let input_gradref = self.input.as_matvecref::<C>(memory);
+        let input_hessref = self.input.as_symsqmatref::<C>(memory);
+        let out_matmut = self.out.as_matmut::<C>(memory);
+        let out_gradmut = self.out.as_matvecmut::<C>(memory);
+        let out_hessmut = self.out.as_symsqmatmut::<C>(memory);
+        self.calculate_unitary(input_matref, out_matmut);
+        self.calculate_gradient(input_gradref, out_gradmut);
+        self.calculate_hessian(input_hessref, out_hessmut);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_into<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+        out: MatMut<C>,
+    ) {
+        let input_matref = self.input.as_matref::<C>(memory);
+        self.calculate_unitary(input_matref, out);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_and_gradient_into<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+        out: MatMut<C>,
+        out_grad: MatVecMut<C>,
+    ) {
+        let input_matref = self.input.as_matref::<C>(memory);
+        let input_gradref = self.input.as_matvecref::<C>(memory);
+        self.calculate_unitary(input_matref, out);
+        self.calculate_gradient(input_gradref, out_grad);
+    }
+
+    #[inline(always)]
+    pub fn execute_unitary_gradient_and_hessian_into<C: ComplexScalar>(
+        &self,
+        memory: &mut MemoryBuffer<C>,
+        out: MatMut<C>,
+        out_grad: MatVecMut<C>,
+        out_hess: SymSqMatMatMut<C>,
+    ) {
+        let input_matref = self.input.as_matref::<C>(memory);
+        let input_gradref = self.input.as_matvecref::<C>(memory);
+        let input_hessref = self.input.as_symsqmatref::<C>(memory);
+        self.calculate_unitary(input_matref, out);
+        self.calculate_gradient(input_gradref, out_grad);
+        self.calculate_hessian(input_hessref, out_hess);
+    }
+}
@@ -4,16 +4,26 @@ use qudit_core::matrix::{MatVecMut, MatVecRef};
 use qudit_core::accel::fused_reshape_permute_reshape_into_prepare;
 use qudit_core::accel::fused_reshape_permute_reshape_into_impl;
 use qudit_core::ComplexScalar;
+use qudit_expr::DifferentiationLevel;
+use crate::bytecode::MemoryView;
 use crate::bytecode::SizedMatrixBuffer;
+use crate::error::QuditTreeError;
 use qudit_core::memory::MemoryBuffer;
 
+/// The most tensor indices a prepared FRPR can hold.
+pub const MAX_FRPR_INDICES: usize = 64;
+
 pub struct FRPRStruct {
     pub len: usize,
-    pub ins: [isize; 64],
-    pub outs: [isize; 64],
-    pub dims: [usize; 64],
+    pub ins: [isize; MAX_FRPR_INDICES],
+    pub outs: [isize; MAX_FRPR_INDICES],
+    pub dims: [usize; MAX_FRPR_INDICES],
     pub input: SizedMatrixBuffer,
     pub out: SizedMatrixBuffer,
+    /// The tensor shape the input is reshaped to.
+    pub shape: Vec<usize>,
+    /// The permutation of the tensor's axes.
+    pub perm: Vec<usize>,
 }
 
 impl FRPRStruct {
@@ -23,7 +33,22 @@ impl FRPRStruct {
         perm: &Vec<usize>,
         out: SizedMatrixBuffer,
     ) -> Self {
-        // TODO: Extract 64 to a library level constact (remove magic number)
+        Self::try_new(input, shape, perm, out).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Prepare an FRPR as in [FRPRStruct::new], returning an error instead
+    /// of panicking if it needs more than [MAX_FRPR_INDICES] tensor indices.
+    pub fn try_new(
+        input: SizedMatrixBuffer,
+        shape: &Vec<usize>,
+        perm: &Vec<usize>,
+        out: SizedMatrixBuffer,
+    ) -> Result<Self, QuditTreeError> {
+        // Preparing never adds indices, so an oversized shape is rejected
+        // before its strides are computed.
+        if shape.len() > MAX_FRPR_INDICES {
+            return Err(QuditTreeError::TooManyFrprIndices(shape.len()));
+        }
         let (ins, outs, dims) = fused_reshape_permute_reshape_into_prepare(
             input.nrows,
             input.ncols,
@@ -35,30 +60,47 @@ impl FRPRStruct {
             perm,
         );
         let len = ins.len();
-        if len > 64 {
-            // TODO: Better error message
-            panic!("Too many indices in FRPR operaiton!");
+        if len > MAX_FRPR_INDICES {
+            return Err(QuditTreeError::TooManyFrprIndices(len));
         }
-        let mut array_ins = [0; 64];
+        let mut array_ins = [0; MAX_FRPR_INDICES];
         for (i, v) in ins.iter().enumerate() {
             array_ins[i] = *v;
         }
-        let mut array_outs = [0; 64];
+        let mut array_outs = [0; MAX_FRPR_INDICES];
         for (i, v) in outs.iter().enumerate() {
             array_outs[i] = *v;
         }
-        let mut array_dims = [0; 64];
+        let mut array_dims = [0; MAX_FRPR_INDICES];
         for (i, v) in dims.iter().enumerate() {
             array_dims[i] = *v;
         }
-        Self {
+        Ok(Self {
             len,
             ins: array_ins,
             outs: array_outs,
             dims: array_dims,
             input,
             out,
+            shape: shape.clone(),
+            perm: perm.clone(),
+        })
+    }
+
+    /// The entry of the input that this permutation moves to entry
+    /// `(r, c)` of the output.
+    pub fn source_index(&self, r: usize, c: usize) -> (usize, usize) {
+        let mut in_strides = vec![1; self.shape.len()];
+        for ax in (0..self.shape.len().saturating_sub(1)).rev() {
+            in_strides[ax] = in_strides[ax + 1] * self.shape[ax + 1];
         }
+        let mut flat_out = r * self.out.ncols + c;
+        let mut flat_in = 0;
+        for ax in (0..self.perm.len()).rev() {
+            flat_in += (flat_out % self.shape[self.perm[ax]]) * in_strides[self.perm[ax]];
+            flat_out /= self.shape[self.perm[ax]];
+        }
+        (flat_in / self.input.ncols, flat_in % self.input.ncols)
     }
 
     #[inline(always)]
@@ -134,8 +176,18 @@ impl FRPRStruct {
 
     #[inline(always)]
     pub fn execute_unitary<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>) {
-        let input_matref = self.input.as_matref::<C>(memory);
-        let out_matmut = self.out.as_matmut::<C>(memory);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::None);
+        let (input_matref, out_matmut) = view.input_output(&self.input, &self.out);
+        self.calculate_unitary(input_matref, out_matmut);
+    }
+
+    /// Execute as in `execute_unitary` on the copy of the memory placed
+    /// `shift` elements later.
+    #[inline(always)]
+    pub fn execute_unitary_at<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>, shift: usize) {
+        let (input, out) = (self.input.shifted(shift), self.out.shifted(shift));
+        let mut view = MemoryView::new(memory, DifferentiationLevel::None);
+        let (input_matref, out_matmut) = view.input_output(&input, &out);
         self.calculate_unitary(input_matref, out_matmut);
     }
 
@@ -144,10 +196,9 @@ impl FRPRStruct {
         &self,
         memory: &mut MemoryBuffer<C>,
     ) {
-        let input_matref = self.input.as_matref::<C>(memory);
-        let input_gradref = self.input.as_matvecref::<C>(memory);
-        let out_matmut = self.out.as_matmut::<C>(memory);
-        let out_gradmut = self.out.as_matvecmut::<C>(memory);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::Gradient);
+        let ((input_matref, input_gradref), (out_matmut, out_gradmut)) =
+            view.input_output_gradient(&self.input, &self.out);
         self.calculate_unitary(input_matref, out_matmut);
         self.calculate_gradient(input_gradref, out_gradmut);
     }
@@ -157,12 +208,11 @@ impl FRPRStruct {
         &self,
         memory: &mut MemoryBuffer<C>,
     ) {
-        let input_matref = self.input.as_matref::<C>(memory);
-        let input_gradref = self.input.as_matvecref::<C>(memory);
-        let input_hessref = self.input.as_symsqmatref::<C>(memory);
-        let out_matmut = self.out.as_matmut::<C>(memory);
-        let out_gradmut = self.out.as_matvecmut::<C>(memory);
-        let out_hessmut = self.out.as_symsqmatmut::<C>(memory);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::Hessian);
+        let (
+            (input_matref, input_gradref, input_hessref),
+            (out_matmut, out_gradmut, out_hessmut),
+        ) = view.input_output_hessian(&self.input, &self.out);
         self.calculate_unitary(input_matref, out_matmut);
         self.calculate_gradient(input_gradref, out_gradmut);
         self.calculate_hessian(input_hessref, out_hessmut);
@@ -207,3 +257,30 @@ impl FRPRStruct {
         self.calculate_hessian(input_hessref, out_hess);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::FRPRStruct;
+    use super::MAX_FRPR_INDICES;
+    use crate::bytecode::SizedMatrixBuffer;
+    use crate::error::QuditTreeError;
+
+    #[test]
+    fn test_try_new_rejects_too_many_indices() {
+        let scalar = SizedMatrixBuffer {
+            offset: 0,
+            nrows: 1,
+            ncols: 1,
+            col_stride: 1,
+            mat_stride: 1,
+            num_params: 0,
+        };
+        let len = MAX_FRPR_INDICES + 1;
+        let shape = vec![1; len];
+        let perm = (0..len).rev().collect();
+        assert_eq!(
+            FRPRStruct::try_new(scalar.clone(), &shape, &perm, scalar).err(),
+            Some(QuditTreeError::TooManyFrprIndices(len)),
+        );
+    }
+}
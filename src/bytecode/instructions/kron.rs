@@ -113,6 +113,16 @@ impl KronStruct {
         self.calculate_unitary(left_matref, right_matref, out_matmut);
     }
 
+    /// Execute as in `execute_unitary` on the copy of the memory placed
+    /// `shift` elements later.
+    #[inline(always)]
+    pub fn execute_unitary_at<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>, shift: usize) {
+        let left_matref = self.left.shifted(shift).as_matref::<C>(memory);
+        let right_matref = self.right.shifted(shift).as_matref::<C>(memory);
+        let out_matmut = self.out.shifted(shift).as_matmut::<C>(memory);
+        self.calculate_unitary(left_matref, right_matref, out_matmut);
+    }
+
     #[inline(always)]
     pub fn execute_unitary_and_gradient<C: ComplexScalar>(
         &self,
@@ -3,6 +3,8 @@ use qudit_core::matrix::{SymSqMatMatMut, SymSqMatMatRef};
 use qudit_core::matrix::{MatVecMut, MatVecRef};
 use qudit_core::accel::matmul_unchecked;
 use qudit_core::ComplexScalar;
+use qudit_expr::DifferentiationLevel;
+use crate::bytecode::MemoryView;
 use crate::bytecode::SizedMatrixBuffer;
 use qudit_core::memory::MemoryBuffer;
 
@@ -39,9 +41,9 @@ impl MatmulStruct {
     fn calculate_gradient<C: ComplexScalar>(
         &self,
         left_utry: MatRef<C>,
-        left_grad: MatVecRef<C>,
+        left_grad: &MatVecRef<C>,
         right_utry: MatRef<C>,
-        right_grad: MatVecRef<C>,
+        right_grad: &MatVecRef<C>,
         mut out: MatVecMut<C>,
     ) {
         let mut grad_idx = 0;
@@ -77,10 +79,10 @@ impl MatmulStruct {
     fn calculate_hessian<C: ComplexScalar>(
         &self,
         left_utry: MatRef<C>,
-        left_grad: MatVecRef<C>,
+        left_grad: &MatVecRef<C>,
         left_hess: SymSqMatMatRef<C>,
         right_utry: MatRef<C>,
-        right_grad: MatVecRef<C>,
+        right_grad: &MatVecRef<C>,
         right_hess: SymSqMatMatRef<C>,
         out: SymSqMatMatMut<C>,
     ) {
@@ -135,9 +137,20 @@ impl MatmulStruct {
 
     #[inline(always)]
     pub fn execute_unitary<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>) {
-        let left_matref = self.left.as_matref::<C>(memory);
-        let right_matref = self.right.as_matref::<C>(memory);
-        let out_matmut = self.out.as_matmut::<C>(memory);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::None);
+        let (left_matref, right_matref, out_matmut) =
+            view.inputs_output(&self.left, &self.right, &self.out);
+        self.calculate_unitary(left_matref, right_matref, out_matmut);
+    }
+
+    /// Execute as in `execute_unitary` on the copy of the memory placed
+    /// `shift` elements later.
+    #[inline(always)]
+    pub fn execute_unitary_at<C: ComplexScalar>(&self, memory: &mut MemoryBuffer<C>, shift: usize) {
+        let (left, right) = (self.left.shifted(shift), self.right.shifted(shift));
+        let out = self.out.shifted(shift);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::None);
+        let (left_matref, right_matref, out_matmut) = view.inputs_output(&left, &right, &out);
         self.calculate_unitary(left_matref, right_matref, out_matmut);
     }
 
@@ -146,18 +159,18 @@ impl MatmulStruct {
         &self,
         memory: &mut MemoryBuffer<C>,
     ) {
-        let left_matref = self.left.as_matref::<C>(memory);
-        let left_matgradref = self.left.as_matvecref::<C>(memory);
-        let right_matref = self.right.as_matref::<C>(memory);
-        let right_matgradref = self.right.as_matvecref::<C>(memory);
-        let out_matmut = self.out.as_matmut::<C>(memory);
-        let out_matgradmut = self.out.as_matvecmut::<C>(memory);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::Gradient);
+        let (
+            (left_matref, left_matgradref),
+            (right_matref, right_matgradref),
+            (out_matmut, out_matgradmut),
+        ) = view.inputs_output_gradient(&self.left, &self.right, &self.out);
         self.calculate_unitary(left_matref, right_matref, out_matmut);
         self.calculate_gradient(
             left_matref,
-            left_matgradref,
+            &left_matgradref,
             right_matref,
-            right_matgradref,
+            &right_matgradref,
             out_matgradmut,
         );
     }
@@ -167,32 +180,26 @@ impl MatmulStruct {
         &self,
         memory: &mut MemoryBuffer<C>,
     ) {
-        let left_matref = self.left.as_matref::<C>(memory);
-        let left_matgradref = self.left.as_matvecref::<C>(memory);
-        let left_mathessref = self.left.as_symsqmatref::<C>(memory);
-        let right_matref = self.right.as_matref::<C>(memory);
-        let right_matgradref = self.right.as_matvecref::<C>(memory);
-        let right_mathessref = self.right.as_symsqmatref::<C>(memory);
-        let out_matmut = self.out.as_matmut::<C>(memory);
-        let out_matgradmut = self.out.as_matvecmut::<C>(memory);
-        let out_mathessmut = self.out.as_symsqmatmut::<C>(memory);
+        let mut view = MemoryView::new(memory, DifferentiationLevel::Hessian);
+        let (
+            (left_matref, left_matgradref, left_mathessref),
+            (right_matref, right_matgradref, right_mathessref),
+            (out_matmut, out_matgradmut, out_mathessmut),
+        ) = view.inputs_output_hessian(&self.left, &self.right, &self.out);
         self.calculate_unitary(left_matref, right_matref, out_matmut);
         self.calculate_gradient(
             left_matref,
-            left_matgradref,
+            &left_matgradref,
             right_matref,
-            right_matgradref,
+            &right_matgradref,
             out_matgradmut,
         );
-        // TODO: copy for ref traits... see kron
-        let left_matgradref = self.left.as_matvecref::<C>(memory);
-        let right_matgradref = self.right.as_matvecref::<C>(memory);
         self.calculate_hessian(
             left_matref,
-            left_matgradref,
+            &left_matgradref,
             left_mathessref,
             right_matref,
-            right_matgradref,
+            &right_matgradref,
             right_mathessref,
             out_mathessmut,
         );
@@ -223,9 +230,9 @@ impl MatmulStruct {
         self.calculate_unitary(left_matref, right_matref, out);
         self.calculate_gradient(
             left_matref,
-            left_matgradref,
+            &left_matgradref,
             right_matref,
-            right_matgradref,
+            &right_matgradref,
             out_grad,
         );
     }
@@ -247,20 +254,17 @@ impl MatmulStruct {
         self.calculate_unitary(left_matref, right_matref, out);
         self.calculate_gradient(
             left_matref,
-            left_matgradref,
+            &left_matgradref,
             right_matref,
-            right_matgradref,
+            &right_matgradref,
             out_grad,
         );
-        // TODO: copy for ref traits... see kron
-        let left_matgradref = self.left.as_matvecref::<C>(memory);
-        let right_matgradref = self.right.as_matvecref::<C>(memory);
         self.calculate_hessian(
             left_matref,
-            left_matgradref,
+            &left_matgradref,
             left_mathessref,
             right_matref,
-            right_matgradref,
+            &right_matgradref,
             right_mathessref,
             out_hess,
         );
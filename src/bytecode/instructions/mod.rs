@@ -1,8 +1,12 @@
+mod apply;
+mod conj;
 mod frpr;
 mod kron;
 mod matmul;
 mod write;
 
+pub use apply::ApplyStruct;
+pub use conj::ConjStruct;
 pub use frpr::FRPRStruct;
 pub use kron::KronStruct;
 pub use matmul::MatmulStruct;
@@ -6,17 +6,25 @@ use crate::bytecode::SizedMatrixBuffer;
 use qudit_core::memory::MemoryBuffer;
 use qudit_expr::UtryFunc;
 use qudit_expr::UtryGradFunc;
+use qudit_expr::UtryHessFunc;
 
 pub struct WriteStruct<C: ComplexScalar> {
     pub utry_fn: UtryFunc<C>,
     pub utry_grad_fn: Option<UtryGradFunc<C>>,
+    pub utry_hess_fn: Option<UtryHessFunc<C>>,
     pub idx: usize,
     pub buffer: SizedMatrixBuffer,
 }
 
 impl<C: ComplexScalar> WriteStruct<C> {
-    pub fn new(utry_fn: UtryFunc<C>, utry_grad_fn: Option<UtryGradFunc<C>>, idx: usize, buffer: SizedMatrixBuffer) -> Self {
-        Self { utry_fn, utry_grad_fn, idx, buffer }
+    pub fn new(
+        utry_fn: UtryFunc<C>,
+        utry_grad_fn: Option<UtryGradFunc<C>>,
+        utry_hess_fn: Option<UtryHessFunc<C>>,
+        idx: usize,
+        buffer: SizedMatrixBuffer,
+    ) -> Self {
+        Self { utry_fn, utry_grad_fn, utry_hess_fn, idx, buffer }
     }
 
     #[inline(always)]
@@ -34,6 +42,24 @@ impl<C: ComplexScalar> WriteStruct<C> {
         }
     }
 
+    /// Execute as in `execute_unitary` on the copy of the memory placed
+    /// `shift` elements later.
+    #[inline(always)]
+    pub fn execute_unitary_at(
+        &self,
+        params: &[C::R],
+        memory: &mut MemoryBuffer<C>,
+        shift: usize,
+    ) {
+        let gate_params =
+            &params[self.idx..self.idx + self.buffer.num_params];
+        let matmut = self.buffer.shifted(shift).as_matmut::<C>(memory);
+        unsafe {
+            let matmutptr = matmut.as_ptr_mut() as *mut C::R;
+            (self.utry_fn)(gate_params.as_ptr() as *const C::R, matmutptr);
+        }
+    }
+
     #[inline(always)]
     pub fn execute_unitary_and_gradient(
         &self,
@@ -54,21 +80,25 @@ impl<C: ComplexScalar> WriteStruct<C> {
     #[inline(always)]
     pub fn execute_unitary_gradient_and_hessian(
         &self,
-        _params: &[C::R],
-        _memory: &mut MemoryBuffer<C>,
+        params: &[C::R],
+        memory: &mut MemoryBuffer<C>,
     ) {
-        todo!()
-        // let gate_params =
-        //     &params[self.idx..self.idx + self.gate.get_num_params];
-        // let mut matmut = self.buffer.as_matmut::<C>(memory);
-        // let mut matgradmut = self.buffer.as_matvecmut::<C>(memory);
-        // let mut mathessmut = self.buffer.as_symsqmatmut::<C>(memory);
-        // self.gate.write_unitary_gradient_and_hessian(
-        //     gate_params,
-        //     &mut matmut,
-        //     &mut matgradmut,
-        //     &mut mathessmut,
-        // );
+        let gate_params =
+            &params[self.idx..self.idx + self.buffer.num_params];
+        let matmut = self.buffer.as_matmut::<C>(memory);
+        let matgradmut = self.buffer.as_matvecmut::<C>(memory);
+        let mathessmut = self.buffer.as_symsqmatmut::<C>(memory);
+        unsafe {
+            let matmutptr = matmut.as_ptr_mut() as *mut C::R;
+            let matgradmutptr = matgradmut.as_mut_ptr().as_ptr() as *mut C::R;
+            let mathessmutptr = mathessmut.as_mut_ptr().as_ptr() as *mut C::R;
+            self.utry_hess_fn.unwrap()(
+                gate_params.as_ptr() as *const C::R,
+                matmutptr,
+                matgradmutptr,
+                mathessmutptr,
+            );
+        }
     }
 
     #[inline(always)]
@@ -106,12 +136,24 @@ impl<C: ComplexScalar> WriteStruct<C> {
     #[inline(always)]
     pub fn execute_unitary_gradient_and_hessian_into(
         &self,
-        _params: &[C::R],
+        params: &[C::R],
         _memory: &mut MemoryBuffer<C>,
-        _out: MatMut<C>,
-        _matgradmut: MatVecMut<C>,
-        _mathessmut: SymSqMatMatMut<C>,
+        out: MatMut<C>,
+        matgradmut: MatVecMut<C>,
+        mathessmut: SymSqMatMatMut<C>,
     ) {
-        todo!()
+        let gate_params =
+            &params[self.idx..self.idx + self.buffer.num_params];
+        unsafe {
+            let outptr = out.as_ptr_mut() as *mut C::R;
+            let matgradmutptr = matgradmut.as_mut_ptr().as_ptr() as *mut C::R;
+            let mathessmutptr = mathessmut.as_mut_ptr().as_ptr() as *mut C::R;
+            self.utry_hess_fn.unwrap()(
+                gate_params.as_ptr() as *const C::R,
+                outptr,
+                matgradmutptr,
+                mathessmutptr,
+            );
+        }
     }
 }
@@ -4,16 +4,26 @@ mod generalized;
 mod generator;
 mod instructions;
 mod optimizer;
+mod params;
 mod specialized;
+mod view;
 
 
 pub use buffer::MatrixBuffer;
 pub use buffer::SizedMatrixBuffer;
+pub use buffer::WarmupStrategy;
+pub use bytecode::BufferMergeError;
 pub use bytecode::Bytecode;
+pub use bytecode::MemoryFootprint;
 pub use generalized::GeneralizedInstruction;
 pub use generator::BytecodeGenerator;
 pub use generator::StaticBytecodeOptimizer;
+pub use optimizer::fuse_frpr_across_matmul;
 pub use optimizer::remove_identity_frpr;
-// pub use optimizer::BufferOptimizer;
-// pub use optimizer::BufferReuser;
+pub use optimizer::remove_identity_kron;
+pub use optimizer::BufferOptimizer;
+pub use optimizer::BufferReuser;
+pub use optimizer::MergeObjective;
+pub use params::ParameterMap;
 pub use specialized::SpecializedInstruction;
+pub use view::MemoryView;
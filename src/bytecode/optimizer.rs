@@ -1,6 +1,11 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 
-use super::{Bytecode, GeneralizedInstruction, MatrixBuffer};
+use qudit_core::QuditSystem;
+use qudit_expr::UnitaryExpression;
+
+use super::{Bytecode, GeneralizedInstruction, MatrixBuffer, WarmupStrategy};
+use crate::tree::identity_expression;
 
 pub fn remove_identity_frpr(code: Bytecode) -> Bytecode {
     let mut opt_code = Vec::new();
@@ -42,171 +47,538 @@ pub fn remove_identity_frpr(code: Bytecode) -> Bytecode {
         dynamic_code: opt_code,
         matrix_buffers: code.matrix_buffers,
         merged_buffers: code.merged_buffers,
+        runtime_constants: code.runtime_constants,
+        parameter_map: code.parameter_map,
+        radices: code.radices,
+    }
+}
+
+/// Whether `expr` is an identity, as written for idle qudits.
+fn is_identity(expr: &UnitaryExpression) -> bool {
+    *expr == identity_expression(expr.radices())
+}
+
+/// Replace each kron of two identities in `region` with an identity write.
+///
+/// `identities` maps every buffer known to hold an identity to its
+/// expression, and is extended with the buffers written here. Those
+/// buffers are now written by an expression, so they are warmed up to the
+/// identity in `buffers`.
+fn remove_identity_kron_region(
+    region: Vec<GeneralizedInstruction>,
+    identities: &mut HashMap<usize, UnitaryExpression>,
+    expression_set: &mut Vec<UnitaryExpression>,
+    buffers: &mut [MatrixBuffer],
+) -> Vec<GeneralizedInstruction> {
+    let mut opt_code = Vec::new();
+
+    for inst in region {
+        match inst {
+            GeneralizedInstruction::Write(ref expr, _, out) if is_identity(expr) => {
+                identities.insert(out, expr.clone());
+                opt_code.push(inst);
+            },
+            GeneralizedInstruction::Kron(left, right, out)
+                if identities.contains_key(&left) && identities.contains_key(&right) =>
+            {
+                let radices = identities[&left].radices() + identities[&right].radices();
+                let expr = identity_expression(radices);
+                if !expression_set.contains(&expr) {
+                    expression_set.push(expr.clone());
+                }
+                identities.insert(out, expr.clone());
+                buffers[out].warmup = WarmupStrategy::Identity;
+                opt_code.push(GeneralizedInstruction::Write(expr, 0, out));
+            },
+            _ => opt_code.push(inst),
+        }
+    }
+
+    opt_code
+}
+
+/// Write the kron of two identities directly instead of computing it.
+///
+/// Idle qudits are padded with identity writes, and krons between them
+/// only ever produce larger identities. Each such kron is replaced with a
+/// single identity write into its output buffer, which is known to be an
+/// identity in turn, so any number of idle qudits collapse into one write.
+/// Identity writes left unread are then removed.
+pub fn remove_identity_kron(code: Bytecode) -> Bytecode {
+    let mut identities = HashMap::new();
+    let mut expression_set = code.expression_set;
+    let mut matrix_buffers = code.matrix_buffers;
+    let static_code = remove_identity_kron_region(
+        code.static_code,
+        &mut identities,
+        &mut expression_set,
+        &mut matrix_buffers,
+    );
+    let dynamic_code = remove_identity_kron_region(
+        code.dynamic_code,
+        &mut identities,
+        &mut expression_set,
+        &mut matrix_buffers,
+    );
+
+    let mut used: HashSet<usize> = static_code
+        .iter()
+        .chain(dynamic_code.iter())
+        .flat_map(|inst| inst.in_buffers())
+        .collect();
+    used.extend(code.runtime_constants.iter().map(|&(_, buffer)| buffer));
+    if let Some(inst) = dynamic_code.last().or(static_code.last()) {
+        used.insert(inst.out_buffer());
+    }
+
+    let is_live = |inst: &GeneralizedInstruction| match inst {
+        GeneralizedInstruction::Write(expr, _, out) => {
+            used.contains(out) || !is_identity(expr)
+        },
+        _ => true,
+    };
+    let static_code: Vec<_> = static_code.into_iter().filter(is_live).collect();
+    let dynamic_code: Vec<_> = dynamic_code.into_iter().filter(is_live).collect();
+
+    expression_set.retain(|expr| {
+        static_code.iter().chain(dynamic_code.iter()).any(|inst| {
+            matches!(inst, GeneralizedInstruction::Write(e, _, _) if e == expr)
+        })
+    });
+
+    Bytecode {
+        expression_set,
+        static_code,
+        dynamic_code,
+        matrix_buffers,
+        merged_buffers: code.merged_buffers,
+        runtime_constants: code.runtime_constants,
+        parameter_map: code.parameter_map,
+        radices: code.radices,
+    }
+}
+
+/// An FRPR that only permutes the axes on one side of a matrix.
+///
+/// Each variant holds the shape of that side's axes and their permutation.
+enum SidePermutation {
+    Rows(Vec<usize>, Vec<usize>),
+    Cols(Vec<usize>, Vec<usize>),
+}
+
+/// Recognize an FRPR that permutes only the rows or only the columns of
+/// its input, without reshaping it.
+fn side_permutation(
+    shape: &[usize],
+    perm: &[usize],
+    input: &MatrixBuffer,
+    output: &MatrixBuffer,
+) -> Option<SidePermutation> {
+    if input.nrows != output.nrows || input.ncols != output.ncols {
+        return None;
+    }
+
+    let n = shape.len();
+    for k in 0..=n {
+        if shape[..k].iter().product::<usize>() != input.nrows {
+            continue;
+        }
+        if (k..n).all(|ax| perm[ax] == ax) && perm[..k].iter().all(|&ax| ax < k) {
+            return Some(SidePermutation::Rows(shape[..k].to_vec(), perm[..k].to_vec()));
+        }
+        if (0..k).all(|ax| perm[ax] == ax) && perm[k..].iter().all(|&ax| ax >= k) {
+            let cols_perm = perm[k..].iter().map(|&ax| ax - k).collect();
+            return Some(SidePermutation::Cols(shape[k..].to_vec(), cols_perm));
+        }
+    }
+    None
+}
+
+/// Compose two axis permutations, applying `first` and then `second`.
+///
+/// Returns `None` unless `second` permutes axes of the shape `first`
+/// produces.
+fn compose_permutations(
+    (first_shape, first_perm): (Vec<usize>, Vec<usize>),
+    (second_shape, second_perm): (Vec<usize>, Vec<usize>),
+) -> Option<(Vec<usize>, Vec<usize>)> {
+    if first_perm.len() != second_perm.len()
+        || (0..second_shape.len()).any(|j| second_shape[j] != first_shape[first_perm[j]])
+    {
+        return None;
+    }
+    let perm = second_perm.iter().map(|&j| first_perm[j]).collect();
+    Some((first_shape, perm))
+}
+
+/// Collapse `FRPR; Matmul; FRPR` into `Matmul; FRPR` when legal.
+///
+/// A row permutation of a matmul's left operand commutes with the matmul,
+/// as does a column permutation of its right operand, so it can be folded
+/// into a following permutation of the same side of the product.
+fn fuse_frpr_triple(
+    pre: &GeneralizedInstruction,
+    matmul: &GeneralizedInstruction,
+    post: &GeneralizedInstruction,
+    buffers: &[MatrixBuffer],
+    reads: &[usize],
+) -> Option<[GeneralizedInstruction; 2]> {
+    let (pre_in, pre_shape, pre_perm, pre_out) = match pre {
+        GeneralizedInstruction::FRPR(a, shape, perm, d) => (*a, shape, perm, *d),
+        _ => return None,
+    };
+    let (left, right, mm_out) = match matmul {
+        GeneralizedInstruction::Matmul(a, b, c) => (*a, *b, *c),
+        _ => return None,
+    };
+    let (post_shape, post_perm, post_out) = match post {
+        GeneralizedInstruction::FRPR(a, shape, perm, d) if *a == mm_out => (shape, perm, *d),
+        _ => return None,
+    };
+
+    // Both intermediates must be consumed only by the instructions being
+    // collapsed.
+    if reads[pre_out] != 1 || reads[mm_out] != 1 || left == right {
+        return None;
+    }
+
+    let first = side_permutation(pre_shape, pre_perm, &buffers[pre_in], &buffers[pre_out])?;
+    let second = side_permutation(post_shape, post_perm, &buffers[mm_out], &buffers[post_out])?;
+    let out = &buffers[mm_out];
+    match (first, second) {
+        (SidePermutation::Rows(s1, p1), SidePermutation::Rows(s2, p2)) if left == pre_out => {
+            let (mut shape, mut perm) = compose_permutations((s1, p1), (s2, p2))?;
+            perm.push(shape.len());
+            shape.push(out.ncols);
+            Some([
+                GeneralizedInstruction::Matmul(pre_in, right, mm_out),
+                GeneralizedInstruction::FRPR(mm_out, shape, perm, post_out),
+            ])
+        },
+        (SidePermutation::Cols(s1, p1), SidePermutation::Cols(s2, p2)) if right == pre_out => {
+            let (cols_shape, cols_perm) = compose_permutations((s1, p1), (s2, p2))?;
+            let shape = std::iter::once(out.nrows).chain(cols_shape).collect();
+            let perm = std::iter::once(0).chain(cols_perm.into_iter().map(|ax| ax + 1)).collect();
+            Some([
+                GeneralizedInstruction::Matmul(left, pre_in, mm_out),
+                GeneralizedInstruction::FRPR(mm_out, shape, perm, post_out),
+            ])
+        },
+        _ => None,
+    }
+}
+
+/// Collapse every legal `FRPR; Matmul; FRPR` sequence in `code`.
+///
+/// See [fuse_frpr_triple] for when a sequence can be collapsed. Buffers
+/// made unused by the pass are left in place.
+pub fn fuse_frpr_across_matmul(code: Bytecode) -> Bytecode {
+    let mut reads = vec![0; code.matrix_buffers.len()];
+    for inst in code.static_code.iter().chain(code.dynamic_code.iter()) {
+        for buffer in inst.in_buffers() {
+            reads[buffer] += 1;
+        }
+    }
+
+    let fuse_region = |region: Vec<GeneralizedInstruction>| {
+        let mut opt_code = Vec::with_capacity(region.len());
+        let mut i = 0;
+        while i < region.len() {
+            if i + 2 < region.len() {
+                let fused = fuse_frpr_triple(
+                    &region[i],
+                    &region[i + 1],
+                    &region[i + 2],
+                    &code.matrix_buffers,
+                    &reads,
+                );
+                if let Some(fused) = fused {
+                    opt_code.extend(fused);
+                    i += 3;
+                    continue;
+                }
+            }
+            opt_code.push(region[i].clone());
+            i += 1;
+        }
+        opt_code
+    };
+
+    let static_code = fuse_region(code.static_code);
+    let dynamic_code = fuse_region(code.dynamic_code);
+
+    Bytecode {
+        expression_set: code.expression_set,
+        static_code,
+        dynamic_code,
+        matrix_buffers: code.matrix_buffers,
+        merged_buffers: code.merged_buffers,
+        runtime_constants: code.runtime_constants,
+        parameter_map: code.parameter_map,
+        radices: code.radices,
+    }
+}
+
+/// The index of the last instruction in `code` that reads each buffer.
+fn last_reads<'a>(
+    code: impl IntoIterator<Item = &'a GeneralizedInstruction>,
+) -> HashMap<usize, usize> {
+    let mut last_reads = HashMap::new();
+    for (i, inst) in code.into_iter().enumerate() {
+        for in_buffer in inst.in_buffers() {
+            last_reads.insert(in_buffer, i);
+        }
+    }
+    last_reads
+}
+
+/// Reassigns buffers so intermediates with the same shape share storage.
+///
+/// Each region of code is walked in order. Expression writes draw from a
+/// pool of buffers keyed by expression, and every other instruction draws its
+/// output from a pool of buffers keyed by shape. Input buffers are returned to
+/// their pool after their last read, so a buffer read more than once (such
+/// as a cached constant) is never clobbered early. Buffers still in use at
+/// the end of the static region are immortalized, since dynamic code reads
+/// them on every run.
+pub struct BufferOptimizer {
+    in_use_buffers: HashSet<usize>,
+    expression_buffers: HashMap<UnitaryExpression, Vec<usize>>,
+    clobber_buffers: HashMap<MatrixBuffer, Vec<usize>>,
+    buffer_remapping: HashMap<usize, usize>,
+    buffers: Vec<MatrixBuffer>,
+    immortal_buffers: HashSet<usize>,
+    old_buffers: Vec<MatrixBuffer>,
+    /// The last position, over static then dynamic code, reading each
+    /// original buffer.
+    last_reads: HashMap<usize, usize>,
+    /// The position of the instruction being optimized.
+    position: usize,
+}
+
+impl BufferOptimizer {
+    pub fn new() -> Self {
+        Self {
+            in_use_buffers: HashSet::new(),
+            expression_buffers: HashMap::new(),
+            clobber_buffers: HashMap::new(),
+            buffer_remapping: HashMap::new(),
+            buffers: Vec::new(),
+            immortal_buffers: HashSet::new(),
+            old_buffers: Vec::new(),
+            last_reads: HashMap::new(),
+            position: 0,
+        }
+    }
+
+    fn get_expression_buffer(
+        &mut self,
+        expr: &UnitaryExpression,
+        buffer: MatrixBuffer,
+    ) -> usize {
+        if let Some(buffer_list) = self.expression_buffers.get(expr) {
+            for buffer_index in buffer_list.iter() {
+                if !self.in_use_buffers.contains(buffer_index) {
+                    self.in_use_buffers.insert(*buffer_index);
+                    return *buffer_index;
+                }
+            }
+        }
+
+        let out = self.buffers.len();
+        self.buffers.push(buffer);
+        self.in_use_buffers.insert(out);
+        self.expression_buffers.entry(expr.clone()).or_default().push(out);
+        out
+    }
+
+    fn get_clobber_buffer(&mut self, buffer: MatrixBuffer) -> usize {
+        if let Some(buffer_list) = self.clobber_buffers.get(&buffer) {
+            for buffer_index in buffer_list.iter() {
+                if !self.in_use_buffers.contains(buffer_index) {
+                    self.in_use_buffers.insert(*buffer_index);
+                    return *buffer_index;
+                }
+            }
+        }
+
+        let out = self.buffers.len();
+        self.buffers.push(buffer);
+        self.in_use_buffers.insert(out);
+        self.clobber_buffers.entry(buffer).or_default().push(out);
+        out
+    }
+
+    fn free_buffer(&mut self, index: usize) {
+        if self.immortal_buffers.contains(&index) {
+            return;
+        }
+        self.in_use_buffers.remove(&index);
+    }
+
+    fn immortalize_in_use_buffers(&mut self) {
+        for &buffer_index in self.in_use_buffers.iter() {
+            self.immortal_buffers.insert(buffer_index);
+        }
+    }
+
+    fn optimize_region(
+        &mut self,
+        region: Vec<GeneralizedInstruction>,
+    ) -> Vec<GeneralizedInstruction> {
+        let mut opt_code = Vec::new();
+
+        for inst in region {
+            let old_ins = inst.in_buffers();
+            match inst {
+                GeneralizedInstruction::Write(expr, p, old_out) => {
+                    let out_buffer = self.old_buffers[old_out];
+                    let new_out = self.get_expression_buffer(&expr, out_buffer);
+                    opt_code.push(GeneralizedInstruction::Write(expr, p, new_out));
+                    self.buffer_remapping.insert(old_out, new_out);
+                },
+                GeneralizedInstruction::Matmul(left, right, old_out) => {
+                    let new_left = self.buffer_remapping[&left];
+                    let new_right = self.buffer_remapping[&right];
+
+                    let out_buffer = self.old_buffers[old_out];
+                    let new_out = self.get_clobber_buffer(out_buffer);
+                    opt_code.push(GeneralizedInstruction::Matmul(
+                        new_left, new_right, new_out,
+                    ));
+
+                    self.buffer_remapping.insert(old_out, new_out);
+                },
+                GeneralizedInstruction::Kron(left, right, old_out) => {
+                    let new_left = self.buffer_remapping[&left];
+                    let new_right = self.buffer_remapping[&right];
+
+                    let out_buffer = self.old_buffers[old_out];
+                    let new_out = self.get_clobber_buffer(out_buffer);
+                    opt_code.push(GeneralizedInstruction::Kron(
+                        new_left, new_right, new_out,
+                    ));
+
+                    self.buffer_remapping.insert(old_out, new_out);
+                },
+                GeneralizedInstruction::FRPR(old_in, shape, perm, old_out) => {
+                    let new_in = self.buffer_remapping[&old_in];
+
+                    let out_buffer = self.old_buffers[old_out];
+                    let new_out = self.get_clobber_buffer(out_buffer);
+                    opt_code.push(GeneralizedInstruction::FRPR(
+                        new_in, shape, perm, new_out,
+                    ));
+
+                    self.buffer_remapping.insert(old_out, new_out);
+                },
+                GeneralizedInstruction::Conj(old_in, old_out) => {
+                    let new_in = self.buffer_remapping[&old_in];
+
+                    let out_buffer = self.old_buffers[old_out];
+                    let new_out = self.get_clobber_buffer(out_buffer);
+                    opt_code.push(GeneralizedInstruction::Conj(new_in, new_out));
+
+                    self.buffer_remapping.insert(old_out, new_out);
+                },
+                GeneralizedInstruction::Apply(old_op, old_state, _, _, old_out)
+                | GeneralizedInstruction::ApplyDiagonal(old_op, old_state, _, _, old_out) => {
+                    // The input state of a state program is filled by the
+                    // QVM rather than by code, so it keeps its own storage.
+                    if !self.buffer_remapping.contains_key(&old_state) {
+                        let new_state = self.buffers.len();
+                        self.buffers.push(self.old_buffers[old_state]);
+                        self.immortal_buffers.insert(new_state);
+                        self.buffer_remapping.insert(old_state, new_state);
+                    }
+                    let new_op = self.buffer_remapping[&old_op];
+                    let new_state = self.buffer_remapping[&old_state];
+
+                    let out_buffer = self.old_buffers[old_out];
+                    let new_out = self.get_clobber_buffer(out_buffer);
+                    let mut new_inst = inst;
+                    new_inst.replace_buffer_indices(&HashMap::from([
+                        (old_op, new_op),
+                        (old_state, new_state),
+                        (old_out, new_out),
+                    ]));
+                    opt_code.push(new_inst);
+
+                    self.buffer_remapping.insert(old_out, new_out);
+                },
+            }
+
+            for old_in in old_ins {
+                if self.last_reads.get(&old_in) == Some(&self.position) {
+                    self.free_buffer(self.buffer_remapping[&old_in]);
+                }
+            }
+            self.position += 1;
+        }
+
+        opt_code
+    }
+
+    pub fn optimize(mut self, code: Bytecode) -> Bytecode {
+        self.old_buffers = code.matrix_buffers;
+        self.last_reads = last_reads(code.static_code.iter().chain(code.dynamic_code.iter()));
+
+        // Runtime constants are filled once at construction and never
+        // written by code, so they must keep their own storage.
+        let mut runtime_constants = Vec::new();
+        for (id, old_buffer) in code.runtime_constants {
+            let new_buffer = self.buffers.len();
+            self.buffers.push(self.old_buffers[old_buffer]);
+            self.immortal_buffers.insert(new_buffer);
+            self.buffer_remapping.insert(old_buffer, new_buffer);
+            runtime_constants.push((id, new_buffer));
+        }
+
+        let static_opt_code = self.optimize_region(code.static_code);
+        self.immortalize_in_use_buffers();
+        let dynamic_opt_code = self.optimize_region(code.dynamic_code);
+
+        Bytecode {
+            expression_set: code.expression_set,
+            static_code: static_opt_code,
+            dynamic_code: dynamic_opt_code,
+            matrix_buffers: self.buffers,
+            // Buffer indices have been renumbered, so any previous merges
+            // no longer apply.
+            merged_buffers: HashMap::new(),
+            runtime_constants,
+            parameter_map: code.parameter_map,
+            radices: code.radices,
+        }
     }
 }
 
-// pub struct BufferOptimizer {
-//     in_use_buffers: HashSet<usize>,
-//     gate_buffers: HashMap<UnitaryExpression, Vec<usize>>,
-//     clobber_buffers: HashMap<MatrixBuffer, Vec<usize>>,
-//     buffer_remapping: HashMap<usize, usize>,
-//     buffers: Vec<MatrixBuffer>,
-//     immortal_buffers: HashSet<usize>,
-//     old_buffers: Vec<MatrixBuffer>,
-// }
-
-// impl BufferOptimizer {
-//     pub fn new() -> Self {
-//         Self {
-//             in_use_buffers: HashSet::new(),
-//             gate_buffers: HashMap::new(),
-//             clobber_buffers: HashMap::new(),
-//             buffer_remapping: HashMap::new(),
-//             buffers: Vec::new(),
-//             immortal_buffers: HashSet::new(),
-//             old_buffers: Vec::new(),
-//         }
-//     }
-
-//     fn get_gate_buffer(&mut self, gate: Gate) -> usize {
-//         if let Some(buffer_list) = self.gate_buffers.get(&gate) {
-//             for buffer_index in buffer_list.iter() {
-//                 if !self.in_use_buffers.contains(buffer_index) {
-//                     self.in_use_buffers.insert(*buffer_index);
-//                     return *buffer_index;
-//                 }
-//             }
-//         }
-
-//         let out = self.buffers.len();
-//         self.buffers.push((&gate).into());
-//         self.in_use_buffers.insert(out);
-//         if self.gate_buffers.contains_key(&gate) {
-//             self.gate_buffers.get_mut(&gate).unwrap().push(out);
-//         } else {
-//             self.gate_buffers.insert(gate.clone(), vec![out]);
-//         }
-//         out
-//     }
-
-//     fn get_clobber_buffer(&mut self, buffer: MatrixBuffer) -> usize {
-//         if let Some(buffer_list) = self.clobber_buffers.get(&buffer) {
-//             for buffer_index in buffer_list.iter() {
-//                 if !self.in_use_buffers.contains(buffer_index) {
-//                     self.in_use_buffers.insert(*buffer_index);
-//                     return *buffer_index;
-//                 }
-//             }
-//         }
-
-//         let out = self.buffers.len();
-//         self.buffers.push(buffer.clone());
-//         self.in_use_buffers.insert(out);
-//         if self.clobber_buffers.contains_key(&buffer) {
-//             self.clobber_buffers.get_mut(&buffer).unwrap().push(out);
-//         } else {
-//             self.clobber_buffers.insert(buffer, vec![out]);
-//         }
-//         out
-//     }
-
-//     fn free_buffer(&mut self, index: usize) {
-//         if self.immortal_buffers.contains(&index) {
-//             return;
-//         }
-//         self.in_use_buffers.remove(&index);
-//     }
-
-//     fn immortalize_in_use_buffers(&mut self) {
-//         for &buffer_index in self.in_use_buffers.iter() {
-//             self.immortal_buffers.insert(buffer_index);
-//         }
-//     }
-
-//     fn optimize_region(
-//         &mut self,
-//         region: Vec<GeneralizedInstruction>,
-//     ) -> Vec<GeneralizedInstruction> {
-//         let mut opt_code = Vec::new();
-
-//         for inst in region {
-//             match inst {
-//                 GeneralizedInstruction::Write(g, p, old_buffer) => {
-//                     let new_buffer = self.get_gate_buffer(g.clone());
-//                     opt_code
-//                         .push(GeneralizedInstruction::Write(g, p, new_buffer));
-//                     self.buffer_remapping.insert(old_buffer, new_buffer);
-//                 },
-//                 GeneralizedInstruction::Matmul(left, right, out) => {
-//                     let new_left = self.buffer_remapping[&left];
-//                     let new_right = self.buffer_remapping[&right];
-
-//                     let out_buffer = self.old_buffers[out];
-//                     let new_out = self.get_clobber_buffer(out_buffer);
-//                     opt_code.push(GeneralizedInstruction::Matmul(
-//                         new_left, new_right, new_out,
-//                     ));
-
-//                     self.free_buffer(new_left);
-//                     self.free_buffer(new_right);
-//                     self.buffer_remapping.insert(out, new_out);
-//                 },
-//                 GeneralizedInstruction::FRPR(old_in, shape, perm, old_out) => {
-//                     let new_in = self.buffer_remapping[&old_in];
-
-//                     let out_buffer = self.old_buffers[old_out];
-//                     let new_out = self.get_clobber_buffer(out_buffer);
-//                     opt_code.push(GeneralizedInstruction::FRPR(
-//                         new_in,
-//                         shape.clone(),
-//                         perm.clone(),
-//                         new_out,
-//                     ));
-
-//                     self.free_buffer(new_in);
-//                     self.buffer_remapping.insert(old_out, new_out);
-//                 },
-//                 GeneralizedInstruction::Kron(left, right, out) => {
-//                     let new_left = self.buffer_remapping[&left];
-//                     let new_right = self.buffer_remapping[&right];
-
-//                     let out_buffer = self.old_buffers[out];
-//                     let new_out = self.get_clobber_buffer(out_buffer);
-//                     opt_code.push(GeneralizedInstruction::Kron(
-//                         new_left, new_right, new_out,
-//                     ));
-
-//                     self.free_buffer(new_left);
-//                     self.free_buffer(new_right);
-//                     self.buffer_remapping.insert(out, new_out);
-//                 },
-//             }
-//         }
-
-//         opt_code
-//     }
-
-//     pub fn optimize(mut self, code: Bytecode) -> Bytecode {
-//         self.old_buffers = code.matrix_buffers;
-//         let static_opt_code = self.optimize_region(code.static_code);
-//         self.immortalize_in_use_buffers();
-//         let dynamic_opt_code = self.optimize_region(code.dynamic_code);
-
-//         Bytecode {
-//             static_code: static_opt_code,
-//             dynamic_code: dynamic_opt_code,
-//             matrix_buffers: self.buffers,
-//             merged_buffers: code.merged_buffers,
-//         }
-//     }
-// }
-
-pub struct BufferReuser {}
+/// The goal used by [BufferReuser] when choosing which buffers to merge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeObjective {
+    /// Merge the largest mergeable buffer into the largest compatible
+    /// buffer. This greedily reduces the number of distinct buffers.
+    #[default]
+    MinCount,
+
+    /// Merge the largest mergeable buffer into the smallest compatible
+    /// buffer, keeping large buffers available for other large merges.
+    /// This favors a smaller total memory footprint.
+    MinPeakMemory,
+}
+
+pub struct BufferReuser {
+    objective: MergeObjective,
+}
 
 impl BufferReuser {
-    #[allow(dead_code)]
     pub fn new() -> Self {
-        Self {}
+        Self { objective: MergeObjective::default() }
+    }
+
+    /// Create a buffer reuser that merges buffers according to `objective`.
+    pub fn with_objective(objective: MergeObjective) -> Self {
+        Self { objective }
     }
 
     pub fn check_lifespan_overlap(
@@ -269,118 +641,58 @@ impl BufferReuser {
         buffer_lifespans: &mut HashMap<usize, Vec<(usize, usize)>>,
         mergeable_buffers: &HashMap<usize, Vec<usize>>,
         buffer_remap: &mut HashMap<usize, usize>,
+        objective: MergeObjective,
     ) {
+        // Ties in size are broken by buffer index, since the maps iterate
+        // in an arbitrary order that would otherwise leak into the merges.
         let mut mergeables_keys = mergeable_buffers.keys().collect::<Vec<&usize>>();
         mergeables_keys.sort_by(|&a, &b| {
-            matrix_buffers[*a].size().cmp(&matrix_buffers[*b].size())
+            matrix_buffers[*a].size().cmp(&matrix_buffers[*b].size()).then(a.cmp(b))
         });
 
         let mergeable = mergeables_keys.iter().rev().take(1).next().unwrap();
         let merge_list = mergeable_buffers.get(*mergeable).unwrap();
         let mut merge_vec = merge_list.iter().collect::<Vec<&usize>>();
         merge_vec.sort_by(|&a, &b| {
-                matrix_buffers[*a].size().cmp(&matrix_buffers[*b].size())
+                matrix_buffers[*a].size().cmp(&matrix_buffers[*b].size()).then(a.cmp(b))
             });
-        let biggest_mergeable = merge_vec
-            .iter()
-            .rev()
-            .take(1)
-            .next()
-            .unwrap();
-
-        buffer_remap.insert(**mergeable, **biggest_mergeable);
+        let target = match objective {
+            MergeObjective::MinCount => merge_vec.iter().rev().next().unwrap(),
+            MergeObjective::MinPeakMemory => merge_vec.iter().next().unwrap(),
+        };
+
+        buffer_remap.insert(**mergeable, **target);
         let old_lifespans = buffer_lifespans.remove(mergeable).unwrap();
         for old_lifespan in old_lifespans.iter() {
             buffer_lifespans
-                .get_mut(biggest_mergeable)
+                .get_mut(target)
                 .unwrap()
                 .push(*old_lifespan);
         }
     }
 
-    #[allow(dead_code)]
+    /// Merge intermediate buffers whose lifespans do not overlap.
+    ///
+    /// A buffer lives from the dynamic instruction that writes it to the
+    /// last one that reads it. Expression writes, the code's output, and
+    /// buffers only written by static code keep their own storage.
     pub fn reuse_buffers(self, code: Bytecode) -> Bytecode {
         let mut buffer_lifespans: HashMap<usize, Vec<(usize, usize)>> =
             HashMap::new();
-        let mut active_buffers = HashMap::new();
+        let last_reads = last_reads(&code.dynamic_code);
 
         for (i, inst) in code.dynamic_code.iter().enumerate() {
-            match inst {
-                GeneralizedInstruction::Write(_g, _p, _buffer) => {
-                    // active_buffers.insert(buffer, i);
-                    // println!("{:?}", active_buffers);
-                },
-                GeneralizedInstruction::Matmul(left, right, out) => {
-                    active_buffers.insert(out, i);
-                    let start_inst = active_buffers.remove(&left);
-                    if start_inst.is_some() {
-                        if let Some(lifespans) = buffer_lifespans.get_mut(&left)
-                        {
-                            lifespans.push((start_inst.unwrap(), i));
-                        } else {
-                            buffer_lifespans
-                                .insert(*left, vec![(start_inst.unwrap(), i)]);
-                        }
-                    }
-                    let start_inst = active_buffers.remove(&right);
-                    if start_inst.is_some() {
-                        if let Some(lifespans) =
-                            buffer_lifespans.get_mut(&right)
-                        {
-                            lifespans.push((start_inst.unwrap(), i));
-                        } else {
-                            buffer_lifespans
-                                .insert(*right, vec![(start_inst.unwrap(), i)]);
-                        }
-                    }
-                },
-                GeneralizedInstruction::FRPR(
-                    in_buffer,
-                    ref _shape,
-                    ref _perm,
-                    out_buffer,
-                ) => {
-                    active_buffers.insert(out_buffer, i);
-                    let start_inst = active_buffers.remove(in_buffer);
-                    if start_inst.is_some() {
-                        if let Some(lifespans) =
-                            buffer_lifespans.get_mut(in_buffer)
-                        {
-                            lifespans.push((start_inst.unwrap(), i));
-                        } else {
-                            buffer_lifespans.insert(
-                                *in_buffer,
-                                vec![(start_inst.unwrap(), i)],
-                            );
-                        }
-                    }
-                },
-                GeneralizedInstruction::Kron(left, right, out) => {
-                    active_buffers.insert(out, i);
-                    let start_inst = active_buffers.remove(&left);
-                    if start_inst.is_some() {
-                        if let Some(lifespans) = buffer_lifespans.get_mut(&left)
-                        {
-                            lifespans.push((start_inst.unwrap(), i));
-                        } else {
-                            buffer_lifespans
-                                .insert(*left, vec![(start_inst.unwrap(), i)]);
-                        }
-                    }
-                    let start_inst = active_buffers.remove(&right);
-                    if start_inst.is_some() {
-                        if let Some(lifespans) =
-                            buffer_lifespans.get_mut(&right)
-                        {
-                            lifespans.push((start_inst.unwrap(), i));
-                        } else {
-                            buffer_lifespans
-                                .insert(*right, vec![(start_inst.unwrap(), i)]);
-                        }
-                    }
-                },
+            // Expression writes rely on their buffer being warmed up to the
+            // identity, so it cannot be clobbered by another instruction.
+            if code.matrix_buffers[inst.out_buffer()].warmup != WarmupStrategy::None {
+                continue;
+            }
+            // A buffer that is never read is the output of the code.
+            if let Some(&end) = last_reads.get(&inst.out_buffer()) {
+                buffer_lifespans.insert(inst.out_buffer(), vec![(i, end)]);
             }
         }
+
         let mut mergeable_buffers = Self::get_mergeable_buffers(
             &code.matrix_buffers,
             &buffer_lifespans,
@@ -394,6 +706,7 @@ impl BufferReuser {
                 &mut buffer_lifespans,
                 &mergeable_buffers,
                 &mut merged_buffers,
+                self.objective,
             );
             mergeable_buffers = Self::get_mergeable_buffers(
                 &code.matrix_buffers,
@@ -407,6 +720,254 @@ impl BufferReuser {
             dynamic_code: code.dynamic_code,
             matrix_buffers: code.matrix_buffers,
             merged_buffers,
+            runtime_constants: code.runtime_constants,
+            parameter_map: code.parameter_map,
+            radices: code.radices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+
+    use crate::fixtures;
+    use crate::qvm::QVM;
+
+    fn merge_with(objective: MergeObjective) -> HashMap<usize, usize> {
+        let buffers = vec![
+            MatrixBuffer { nrows: 2, ncols: 2, num_params: 1, warmup: WarmupStrategy::None },
+            MatrixBuffer { nrows: 4, ncols: 4, num_params: 1, warmup: WarmupStrategy::None },
+            MatrixBuffer { nrows: 8, ncols: 8, num_params: 1, warmup: WarmupStrategy::None },
+        ];
+        let mut lifespans = HashMap::new();
+        lifespans.insert(0, vec![(0, 1)]);
+        lifespans.insert(1, vec![(2, 5)]);
+        lifespans.insert(2, vec![(3, 6)]);
+
+        let mergeable = BufferReuser::get_mergeable_buffers(&buffers, &lifespans);
+        let mut remap = HashMap::new();
+        BufferReuser::merge_one_buffer(
+            &buffers,
+            &mut lifespans,
+            &mergeable,
+            &mut remap,
+            objective,
+        );
+        remap
+    }
+
+    #[test]
+    fn test_merge_objective_targets() {
+        // Buffers 1 and 2 are alive at the same time, so only buffer 0 can
+        // be merged, and it fits into either of them.
+        assert_eq!(merge_with(MergeObjective::MinCount)[&0], 2);
+        assert_eq!(merge_with(MergeObjective::MinPeakMemory)[&0], 1);
+    }
+
+    #[test]
+    fn test_fuse_frpr_across_matmul() {
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        let buffer = |dim, num_params| MatrixBuffer {
+            nrows: dim,
+            ncols: dim,
+            num_params,
+            warmup: WarmupStrategy::None,
+        };
+        let written = |dim, num_params| MatrixBuffer {
+            warmup: WarmupStrategy::Identity,
+            ..buffer(dim, num_params)
+        };
+
+        // Cyclically permute the row qudits of P (x) CRY, multiply by
+        // CRY (x) P, and cyclically permute the product's rows again.
+        let shape = vec![2, 2, 2, 8];
+        let perm = vec![1, 2, 0, 3];
+        let code = Bytecode {
+            expression_set: vec![p.clone(), cry.clone()],
+            static_code: vec![],
+            dynamic_code: vec![
+                GeneralizedInstruction::Write(p.clone(), 0, 0),
+                GeneralizedInstruction::Write(cry.clone(), 1, 1),
+                GeneralizedInstruction::Kron(0, 1, 2),
+                GeneralizedInstruction::Write(cry, 2, 3),
+                GeneralizedInstruction::Write(p, 3, 4),
+                GeneralizedInstruction::Kron(3, 4, 5),
+                GeneralizedInstruction::FRPR(2, shape.clone(), perm.clone(), 6),
+                GeneralizedInstruction::Matmul(6, 5, 7),
+                GeneralizedInstruction::FRPR(7, shape, perm, 8),
+            ],
+            matrix_buffers: vec![
+                written(2, 1),
+                written(4, 1),
+                buffer(8, 2),
+                written(4, 1),
+                written(2, 1),
+                buffer(8, 2),
+                buffer(8, 2),
+                buffer(8, 4),
+                buffer(8, 4),
+            ],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2, 2, 2]),
+        };
+
+        let fused = fuse_frpr_across_matmul(code.clone());
+        assert_eq!(fused.dynamic_code.len(), code.dynamic_code.len() - 1);
+        match &fused.dynamic_code[7] {
+            GeneralizedInstruction::FRPR(7, _, perm, 8) => assert_eq!(perm, &vec![2, 0, 1, 3]),
+            inst => panic!("Expected a fused FRPR, found {:?}.", inst),
+        }
+
+        let params = [0.3, 1.1, 2.3, 0.7];
+        let mut expected = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let mut qvm = QVM::<c64>::new(fused, DifferentiationLevel::None);
+        let expected = expected.get_unitary(&params).to_owned();
+        let utry = qvm.get_unitary(&params);
+        for r in 0..8 {
+            for c in 0..8 {
+                assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_identity_kron() {
+        let cry = fixtures::cry();
+        let identity = identity_expression(QuditRadices::from_iter([2]));
+        let buffer = |dim, num_params| MatrixBuffer {
+            nrows: dim,
+            ncols: dim,
+            num_params,
+            warmup: WarmupStrategy::None,
+        };
+        let written = |dim, num_params| MatrixBuffer {
+            warmup: WarmupStrategy::Identity,
+            ..buffer(dim, num_params)
+        };
+
+        // A CRY on the first two of four qudits, with the last two idle.
+        let code = Bytecode {
+            expression_set: vec![cry.clone(), identity.clone()],
+            static_code: vec![],
+            dynamic_code: vec![
+                GeneralizedInstruction::Write(cry, 0, 0),
+                GeneralizedInstruction::Write(identity.clone(), 1, 1),
+                GeneralizedInstruction::Write(identity.clone(), 1, 2),
+                GeneralizedInstruction::Kron(1, 2, 3),
+                GeneralizedInstruction::Kron(0, 3, 4),
+            ],
+            matrix_buffers: vec![
+                written(4, 1),
+                written(2, 0),
+                written(2, 0),
+                buffer(4, 0),
+                buffer(16, 1),
+            ],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2, 2, 2, 2]),
+        };
+
+        let optimized = remove_identity_kron(code.clone());
+        let num_krons = |code: &Bytecode| {
+            code.dynamic_code
+                .iter()
+                .filter(|inst| matches!(inst, GeneralizedInstruction::Kron(..)))
+                .count()
+        };
+        assert_eq!(num_krons(&code), 2);
+        assert_eq!(num_krons(&optimized), 1);
+        assert_eq!(optimized.dynamic_code.len(), 3);
+        let merged = identity_expression(QuditRadices::from_iter([2, 2]));
+        assert!(optimized.expression_set.contains(&merged));
+        assert_ne!(merged.name(), identity.name());
+
+        let params = [0.8];
+        let mut expected = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let mut qvm = QVM::<c64>::new(optimized, DifferentiationLevel::None);
+        let expected = expected.get_unitary(&params).to_owned();
+        let utry = qvm.get_unitary(&params);
+        for r in 0..16 {
+            for c in 0..16 {
+                assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_buffer_optimizer_keeps_static_buffers_until_last_read() {
+        let cx = fixtures::cx();
+        let cry = fixtures::cry();
+        let buffer = |num_params| MatrixBuffer {
+            nrows: 4,
+            ncols: 4,
+            num_params,
+            warmup: WarmupStrategy::None,
+        };
+        let written = |num_params| MatrixBuffer {
+            warmup: WarmupStrategy::Identity,
+            ..buffer(num_params)
+        };
+        let shape = vec![2, 2, 2, 2];
+        let perm = vec![1, 0, 3, 2];
+
+        // Buffer 1 is read by both static and dynamic code, and a dynamic
+        // FRPR of the same shape runs in between.
+        let code = Bytecode {
+            expression_set: vec![cry.clone(), cx.clone()],
+            static_code: vec![
+                GeneralizedInstruction::Write(cx, 0, 0),
+                GeneralizedInstruction::FRPR(0, shape.clone(), perm.clone(), 1),
+                GeneralizedInstruction::Matmul(1, 0, 2),
+            ],
+            dynamic_code: vec![
+                GeneralizedInstruction::Write(cry, 0, 3),
+                GeneralizedInstruction::FRPR(2, shape, perm, 4),
+                GeneralizedInstruction::Matmul(4, 1, 5),
+                GeneralizedInstruction::Matmul(3, 5, 6),
+            ],
+            matrix_buffers: vec![
+                written(0),
+                buffer(0),
+                buffer(0),
+                written(1),
+                buffer(0),
+                buffer(0),
+                buffer(1),
+            ],
+            merged_buffers: HashMap::new(),
+            runtime_constants: vec![],
+            parameter_map: None,
+            radices: QuditRadices::from_iter([2, 2]),
+        };
+
+        let optimized = BufferOptimizer::new().optimize(code.clone());
+        let GeneralizedInstruction::FRPR(_, _, _, frpr_out) = optimized.dynamic_code[1] else {
+            panic!("Expected an FRPR, found {:?}.", optimized.dynamic_code[1]);
+        };
+        let GeneralizedInstruction::Matmul(_, constant, _) = optimized.dynamic_code[2] else {
+            panic!("Expected a matmul, found {:?}.", optimized.dynamic_code[2]);
+        };
+        assert_ne!(frpr_out, constant);
+
+        let params = [1.3];
+        let mut expected = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let mut qvm = QVM::<c64>::new(optimized, DifferentiationLevel::None);
+        let expected = expected.get_unitary(&params).to_owned();
+        let utry = qvm.get_unitary(&params);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-12);
+            }
         }
     }
 }
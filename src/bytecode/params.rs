@@ -0,0 +1,81 @@
+use crate::error::QuditTreeError;
+
+/// Ties the parameters of a tree to a smaller set of global parameters.
+///
+/// Tree parameter `i`, counted in the order the tree's leaves are
+/// generated, reads global parameter `indices[i]`. Several tree parameters
+/// may read the same global parameter, as in variational ansätze that
+/// share one angle across many gates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParameterMap {
+    indices: Vec<usize>,
+    num_global_params: usize,
+}
+
+impl ParameterMap {
+    /// Create a parameter map from the global index of each tree parameter.
+    pub fn new(indices: Vec<usize>) -> Self {
+        let num_global_params = indices.iter().map(|&i| i + 1).max().unwrap_or(0);
+        Self { indices, num_global_params }
+    }
+
+    /// The number of tree parameters mapped.
+    pub fn num_tree_params(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// The number of global parameters supplied on evaluation.
+    pub fn num_global_params(&self) -> usize {
+        self.num_global_params
+    }
+
+    /// The global parameter read by tree parameter `tree_param`.
+    ///
+    /// # Panics
+    ///
+    /// If `tree_param` is not mapped.
+    pub fn global_index(&self, tree_param: usize) -> usize {
+        self.indices[tree_param]
+    }
+
+    /// The tree parameters reading each global parameter.
+    pub fn tied_params(&self) -> Vec<Vec<usize>> {
+        let mut tied = vec![Vec::new(); self.num_global_params];
+        for (tree_param, &global) in self.indices.iter().enumerate() {
+            tied[global].push(tree_param);
+        }
+        tied
+    }
+
+    /// The global offset at which a leaf's parameters start.
+    ///
+    /// Expression writes read their parameters contiguously, so the leaf's
+    /// tree parameters must map to consecutive global parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The leaf's first tree parameter.
+    /// * `num_params` - The number of parameters of the leaf.
+    ///
+    /// # Panics
+    ///
+    /// If the leaf's parameters are not mapped to consecutive global
+    /// parameters.
+    pub fn leaf_offset(&self, start: usize, num_params: usize) -> usize {
+        self.try_leaf_offset(start, num_params).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// The global offset of a leaf as in [ParameterMap::leaf_offset],
+    /// returning an error instead of panicking if the leaf's parameters are
+    /// not mapped to consecutive global parameters.
+    pub fn try_leaf_offset(&self, start: usize, num_params: usize) -> Result<usize, QuditTreeError> {
+        if num_params == 0 {
+            return Ok(0);
+        }
+        let offset = self.global_index(start);
+        if (0..num_params).any(|k| self.global_index(start + k) != offset + k) {
+            return Err(QuditTreeError::NonConsecutiveLeafParameters(start));
+        }
+        Ok(offset)
+    }
+}
@@ -1,16 +1,43 @@
 use faer::MatMut;
 use qudit_core::{matrix::{MatVecMut, SymSqMatMatMut}, memory::MemoryBuffer, ComplexScalar};
 
-use super::instructions::{FRPRStruct, KronStruct, MatmulStruct, WriteStruct};
+use super::SizedMatrixBuffer;
+use super::instructions::{ApplyStruct, ConjStruct, FRPRStruct, KronStruct, MatmulStruct, WriteStruct};
 
 pub enum SpecializedInstruction<C: ComplexScalar> {
     Write(WriteStruct<C>),
     Matmul(MatmulStruct),
     Kron(KronStruct),
     FRPR(FRPRStruct),
+    Conj(ConjStruct),
+    Apply(ApplyStruct),
 }
 
 impl<C: ComplexScalar> SpecializedInstruction<C> {
+    /// The buffer this instruction writes its result into.
+    pub fn out_buffer(&self) -> &SizedMatrixBuffer {
+        match self {
+            SpecializedInstruction::Write(w) => &w.buffer,
+            SpecializedInstruction::Matmul(m) => &m.out,
+            SpecializedInstruction::Kron(k) => &k.out,
+            SpecializedInstruction::FRPR(f) => &f.out,
+            SpecializedInstruction::Conj(c) => &c.out,
+            SpecializedInstruction::Apply(a) => &a.out,
+        }
+    }
+
+    /// The buffers this instruction reads from.
+    pub fn in_buffers(&self) -> Vec<&SizedMatrixBuffer> {
+        match self {
+            SpecializedInstruction::Write(_) => vec![],
+            SpecializedInstruction::Matmul(m) => vec![&m.left, &m.right],
+            SpecializedInstruction::Kron(k) => vec![&k.left, &k.right],
+            SpecializedInstruction::FRPR(f) => vec![&f.input],
+            SpecializedInstruction::Conj(c) => vec![&c.input],
+            SpecializedInstruction::Apply(a) => vec![&a.op, &a.state],
+        }
+    }
+
     #[inline(always)]
     pub fn execute_unitary (
         &self,
@@ -24,6 +51,54 @@ impl<C: ComplexScalar> SpecializedInstruction<C> {
             SpecializedInstruction::Matmul(m) => m.execute_unitary::<C>(memory),
             SpecializedInstruction::Kron(k) => k.execute_unitary::<C>(memory),
             SpecializedInstruction::FRPR(f) => f.execute_unitary::<C>(memory),
+            SpecializedInstruction::Conj(c) => c.execute_unitary::<C>(memory),
+            SpecializedInstruction::Apply(a) => a.execute_unitary::<C>(memory),
+        }
+    }
+
+    /// Execute the instruction on every instance of a stacked memory.
+    ///
+    /// Instance `b` is a copy of the memory starting `b * instance_stride`
+    /// elements in, evaluated at `param_batch[b]`. The instruction, with
+    /// any setup it prepared such as an FRPR's strides, is shared by the
+    /// whole stack.
+    pub fn execute_unitary_batched<P: AsRef<[C::R]>>(
+        &self,
+        param_batch: &[P],
+        memory: &mut MemoryBuffer<C>,
+        instance_stride: usize,
+    ) {
+        match self {
+            SpecializedInstruction::Write(w) => {
+                for (b, params) in param_batch.iter().enumerate() {
+                    w.execute_unitary_at(params.as_ref(), memory, b * instance_stride)
+                }
+            },
+            SpecializedInstruction::Matmul(m) => {
+                for b in 0..param_batch.len() {
+                    m.execute_unitary_at::<C>(memory, b * instance_stride)
+                }
+            },
+            SpecializedInstruction::Kron(k) => {
+                for b in 0..param_batch.len() {
+                    k.execute_unitary_at::<C>(memory, b * instance_stride)
+                }
+            },
+            SpecializedInstruction::FRPR(f) => {
+                for b in 0..param_batch.len() {
+                    f.execute_unitary_at::<C>(memory, b * instance_stride)
+                }
+            },
+            SpecializedInstruction::Conj(c) => {
+                for b in 0..param_batch.len() {
+                    c.execute_unitary_at::<C>(memory, b * instance_stride)
+                }
+            },
+            SpecializedInstruction::Apply(a) => {
+                for b in 0..param_batch.len() {
+                    a.execute_unitary_at::<C>(memory, b * instance_stride)
+                }
+            },
         }
     }
 
@@ -45,6 +120,12 @@ impl<C: ComplexScalar> SpecializedInstruction<C> {
             SpecializedInstruction::FRPR(f) => {
                 f.execute_unitary_and_gradient::<C>(memory)
             },
+            SpecializedInstruction::Conj(c) => {
+                c.execute_unitary_and_gradient::<C>(memory)
+            },
+            SpecializedInstruction::Apply(a) => {
+                a.execute_unitary_and_gradient::<C>(memory)
+            },
         }
     }
 
@@ -66,6 +147,12 @@ impl<C: ComplexScalar> SpecializedInstruction<C> {
             SpecializedInstruction::FRPR(f) => {
                 f.execute_unitary_gradient_and_hessian::<C>(memory)
             },
+            SpecializedInstruction::Conj(c) => {
+                c.execute_unitary_gradient_and_hessian::<C>(memory)
+            },
+            SpecializedInstruction::Apply(a) => {
+                a.execute_unitary_gradient_and_hessian::<C>(memory)
+            },
         }
     }
 
@@ -88,6 +175,12 @@ impl<C: ComplexScalar> SpecializedInstruction<C> {
             SpecializedInstruction::FRPR(f) => {
                 f.execute_unitary_into::<C>(memory, out)
             },
+            SpecializedInstruction::Conj(c) => {
+                c.execute_unitary_into::<C>(memory, out)
+            },
+            SpecializedInstruction::Apply(a) => {
+                a.execute_unitary_into::<C>(memory, out)
+            },
         }
     }
 
@@ -112,6 +205,12 @@ impl<C: ComplexScalar> SpecializedInstruction<C> {
             SpecializedInstruction::FRPR(f) => {
                 f.execute_unitary_and_gradient_into::<C>(memory, out, grad)
             },
+            SpecializedInstruction::Conj(c) => {
+                c.execute_unitary_and_gradient_into::<C>(memory, out, grad)
+            },
+            SpecializedInstruction::Apply(a) => {
+                a.execute_unitary_and_gradient_into::<C>(memory, out, grad)
+            },
         }
     }
 
@@ -140,6 +239,14 @@ impl<C: ComplexScalar> SpecializedInstruction<C> {
                 .execute_unitary_gradient_and_hessian_into::<C>(
                     memory, out, grad, hess,
                 ),
+            SpecializedInstruction::Conj(c) => c
+                .execute_unitary_gradient_and_hessian_into::<C>(
+                    memory, out, grad, hess,
+                ),
+            SpecializedInstruction::Apply(a) => a
+                .execute_unitary_gradient_and_hessian_into::<C>(
+                    memory, out, grad, hess,
+                ),
         }
     }
 }
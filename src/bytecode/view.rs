@@ -0,0 +1,295 @@
+use qudit_core::matrix::MatMut;
+use qudit_core::matrix::MatRef;
+use qudit_core::matrix::MatVecMut;
+use qudit_core::matrix::MatVecRef;
+use qudit_core::matrix::SymSqMatMatMut;
+use qudit_core::matrix::SymSqMatMatRef;
+use qudit_core::memory::MemoryBuffer;
+use qudit_core::ComplexScalar;
+use qudit_expr::DifferentiationLevel;
+
+use super::SizedMatrixBuffer;
+
+/// A buffer's unitary and gradient, borrowed for reading.
+pub type GradientRef<'a, C> = (MatRef<'a, C>, MatVecRef<'a, C>);
+
+/// A buffer's unitary and gradient, borrowed for writing.
+pub type GradientMut<'a, C> = (MatMut<'a, C>, MatVecMut<'a, C>);
+
+/// A buffer's unitary, gradient, and hessian, borrowed for reading.
+pub type HessianRef<'a, C> = (MatRef<'a, C>, MatVecRef<'a, C>, SymSqMatMatRef<'a, C>);
+
+/// A buffer's unitary, gradient, and hessian, borrowed for writing.
+pub type HessianMut<'a, C> = (MatMut<'a, C>, MatVecMut<'a, C>, SymSqMatMatMut<'a, C>);
+
+/// Borrow-checked access to distinct buffers in a [MemoryBuffer].
+///
+/// Every buffer handed out by a view is validated against the others
+/// requested alongside it, so an instruction can read its inputs and write
+/// its output without re-fetching raw pointers after a write.
+pub struct MemoryView<'a, C: ComplexScalar> {
+    memory: &'a mut MemoryBuffer<C>,
+    diff_lvl: DifferentiationLevel,
+}
+
+impl<'a, C: ComplexScalar> MemoryView<'a, C> {
+    pub fn new(
+        memory: &'a mut MemoryBuffer<C>,
+        diff_lvl: DifferentiationLevel,
+    ) -> Self {
+        Self { memory, diff_lvl }
+    }
+
+    /// The region of memory, as a (start, end) pair, occupied by `buffer`
+    /// including its gradient and hessian where applicable.
+    fn region(&self, buffer: &SizedMatrixBuffer) -> (usize, usize) {
+        let mat_stride = buffer.mat_stride as usize;
+        let mut size = mat_stride;
+        if self.diff_lvl.gradient_capable() {
+            size += mat_stride * buffer.num_params;
+        }
+        if self.diff_lvl.hessian_capable() {
+            size += mat_stride * (buffer.num_params * (buffer.num_params + 1)) / 2;
+        }
+        (buffer.offset, buffer.offset + size)
+    }
+
+    /// Borrow `input` for reading and `out` for writing.
+    ///
+    /// # Panics
+    ///
+    /// If the memory regions of `input` and `out` overlap.
+    pub fn input_output(
+        &mut self,
+        input: &SizedMatrixBuffer,
+        out: &SizedMatrixBuffer,
+    ) -> (MatRef<'_, C>, MatMut<'_, C>) {
+        check_disjoint(&[self.region(input)], self.region(out));
+        (input.as_matref(self.memory), out.as_matmut(self.memory))
+    }
+
+    /// Borrow the unitary and gradient of `input` for reading and of `out`
+    /// for writing.
+    ///
+    /// # Panics
+    ///
+    /// If the view is not gradient capable, or if the memory regions of
+    /// `input` and `out` overlap.
+    pub fn input_output_gradient(
+        &mut self,
+        input: &SizedMatrixBuffer,
+        out: &SizedMatrixBuffer,
+    ) -> (GradientRef<'_, C>, GradientMut<'_, C>) {
+        assert!(self.diff_lvl.gradient_capable(), "View is not gradient capable.");
+        check_disjoint(&[self.region(input)], self.region(out));
+        (self.gradient_ref(input), self.gradient_mut(out))
+    }
+
+    /// Borrow the unitary, gradient, and hessian of `input` for reading and
+    /// of `out` for writing.
+    ///
+    /// # Panics
+    ///
+    /// If the view is not hessian capable, or if the memory regions of
+    /// `input` and `out` overlap.
+    pub fn input_output_hessian(
+        &mut self,
+        input: &SizedMatrixBuffer,
+        out: &SizedMatrixBuffer,
+    ) -> (HessianRef<'_, C>, HessianMut<'_, C>) {
+        assert!(self.diff_lvl.hessian_capable(), "View is not hessian capable.");
+        check_disjoint(&[self.region(input)], self.region(out));
+        (self.hessian_ref(input), self.hessian_mut(out))
+    }
+
+    /// Borrow `left` and `right` for reading and `out` for writing.
+    ///
+    /// The two inputs may alias each other, since both are only read.
+    ///
+    /// # Panics
+    ///
+    /// If the memory region of `out` overlaps either input.
+    pub fn inputs_output(
+        &mut self,
+        left: &SizedMatrixBuffer,
+        right: &SizedMatrixBuffer,
+        out: &SizedMatrixBuffer,
+    ) -> (MatRef<'_, C>, MatRef<'_, C>, MatMut<'_, C>) {
+        check_disjoint(&[self.region(left), self.region(right)], self.region(out));
+        (
+            left.as_matref(self.memory),
+            right.as_matref(self.memory),
+            out.as_matmut(self.memory),
+        )
+    }
+
+    /// Borrow the unitaries and gradients of `left` and `right` for reading
+    /// and of `out` for writing.
+    ///
+    /// # Panics
+    ///
+    /// If the view is not gradient capable, or if the memory region of `out`
+    /// overlaps either input.
+    pub fn inputs_output_gradient(
+        &mut self,
+        left: &SizedMatrixBuffer,
+        right: &SizedMatrixBuffer,
+        out: &SizedMatrixBuffer,
+    ) -> (GradientRef<'_, C>, GradientRef<'_, C>, GradientMut<'_, C>) {
+        assert!(self.diff_lvl.gradient_capable(), "View is not gradient capable.");
+        check_disjoint(&[self.region(left), self.region(right)], self.region(out));
+        (self.gradient_ref(left), self.gradient_ref(right), self.gradient_mut(out))
+    }
+
+    /// Borrow the unitaries, gradients, and hessians of `left` and `right`
+    /// for reading and of `out` for writing.
+    ///
+    /// # Panics
+    ///
+    /// If the view is not hessian capable, or if the memory region of `out`
+    /// overlaps either input.
+    pub fn inputs_output_hessian(
+        &mut self,
+        left: &SizedMatrixBuffer,
+        right: &SizedMatrixBuffer,
+        out: &SizedMatrixBuffer,
+    ) -> (HessianRef<'_, C>, HessianRef<'_, C>, HessianMut<'_, C>) {
+        assert!(self.diff_lvl.hessian_capable(), "View is not hessian capable.");
+        check_disjoint(&[self.region(left), self.region(right)], self.region(out));
+        (self.hessian_ref(left), self.hessian_ref(right), self.hessian_mut(out))
+    }
+
+    // Like the buffer methods they wrap, these accessors are not tied to the
+    // view's borrow, so they stay private and are only called once the
+    // regions they hand out have been checked.
+
+    fn gradient_ref<'b>(&self, buffer: &SizedMatrixBuffer) -> GradientRef<'b, C> {
+        (buffer.as_matref(self.memory), buffer.as_matvecref(self.memory))
+    }
+
+    fn gradient_mut<'b>(&mut self, buffer: &SizedMatrixBuffer) -> GradientMut<'b, C> {
+        (buffer.as_matmut(self.memory), buffer.as_matvecmut(self.memory))
+    }
+
+    fn hessian_ref<'b>(&self, buffer: &SizedMatrixBuffer) -> HessianRef<'b, C> {
+        (
+            buffer.as_matref(self.memory),
+            buffer.as_matvecref(self.memory),
+            buffer.as_symsqmatref(self.memory),
+        )
+    }
+
+    fn hessian_mut<'b>(&mut self, buffer: &SizedMatrixBuffer) -> HessianMut<'b, C> {
+        (
+            buffer.as_matmut(self.memory),
+            buffer.as_matvecmut(self.memory),
+            buffer.as_symsqmatmut(self.memory),
+        )
+    }
+}
+
+/// Panics if the `write` region overlaps any of the `reads` regions.
+fn check_disjoint(reads: &[(usize, usize)], write: (usize, usize)) {
+    for &(start, end) in reads {
+        if start < write.1 && write.0 < end {
+            panic!(
+                "Buffer region {}..{} overlaps output buffer region {}..{}.",
+                start, end, write.0, write.1,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::c64;
+    use qudit_core::memory::alloc_zeroed_memory;
+    use qudit_core::memory::calc_col_stride;
+    use qudit_core::memory::calc_mat_stride;
+    use qudit_expr::DifferentiationLevel;
+
+    use super::check_disjoint;
+    use super::MemoryView;
+    use crate::bytecode::SizedMatrixBuffer;
+
+    /// A 2x2 buffer with `num_params` parameters starting `mats` matrix
+    /// strides into memory.
+    fn buffer_at(mats: usize, num_params: usize) -> SizedMatrixBuffer {
+        let col_stride = calc_col_stride::<c64>(2, 2);
+        let mat_stride = calc_mat_stride::<c64>(2, 2, col_stride);
+        SizedMatrixBuffer {
+            offset: mats * mat_stride,
+            nrows: 2,
+            ncols: 2,
+            col_stride: col_stride as isize,
+            mat_stride: mat_stride as isize,
+            num_params,
+        }
+    }
+
+    #[test]
+    fn test_disjoint_regions() {
+        check_disjoint(&[(0, 4), (8, 12)], (4, 8));
+        check_disjoint(&[(0, 4), (0, 4)], (12, 16));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_overlapping_regions() {
+        check_disjoint(&[(0, 4), (8, 12)], (3, 8));
+    }
+
+    #[test]
+    fn test_view_copies_between_disjoint_buffers() {
+        let (input, out) = (buffer_at(0, 0), buffer_at(1, 0));
+        let mut memory = alloc_zeroed_memory::<c64>(2 * input.mat_stride as usize);
+        let mut view = MemoryView::new(&mut memory, DifferentiationLevel::None);
+
+        // Inputs may alias each other as long as the output is distinct
+        let (left, right, mut written) = view.inputs_output(&input, &input, &out);
+        assert_eq!(left[(1, 1)], right[(1, 1)]);
+        *written.rb_mut().get_mut(1, 0) = c64::new(2.0, 0.0);
+
+        let (read, mut written) = view.input_output(&out, &input);
+        *written.rb_mut().get_mut(0, 1) = read[(1, 0)];
+        assert_eq!(input.as_matref(&memory)[(0, 1)], c64::new(2.0, 0.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_view_rejects_aliased_output() {
+        let buffer = buffer_at(0, 0);
+        let mut memory = alloc_zeroed_memory::<c64>(buffer.mat_stride as usize);
+        let mut view = MemoryView::new(&mut memory, DifferentiationLevel::None);
+        view.input_output(&buffer, &buffer);
+    }
+
+    #[test]
+    fn test_view_unitaries_ignore_derivatives() {
+        let (input, out) = (buffer_at(0, 1), buffer_at(1, 1));
+        let mut memory = alloc_zeroed_memory::<c64>(4 * input.mat_stride as usize);
+        MemoryView::new(&mut memory, DifferentiationLevel::None).input_output(&input, &out);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_view_region_includes_derivatives() {
+        // The output starts where the input's gradient is stored
+        let (input, out) = (buffer_at(0, 1), buffer_at(1, 1));
+        let mut memory = alloc_zeroed_memory::<c64>(4 * input.mat_stride as usize);
+        MemoryView::new(&mut memory, DifferentiationLevel::Gradient)
+            .input_output_gradient(&input, &out);
+    }
+
+    #[test]
+    fn test_view_gradient_of_disjoint_buffers() {
+        let (input, out) = (buffer_at(0, 1), buffer_at(2, 1));
+        let mut memory = alloc_zeroed_memory::<c64>(4 * input.mat_stride as usize);
+        input.as_matvecmut(&mut memory).write(0, 1, 1, c64::new(3.0, 0.0));
+
+        let mut view = MemoryView::new(&mut memory, DifferentiationLevel::Gradient);
+        let ((_, read_grad), (_, mut written_grad)) = view.input_output_gradient(&input, &out);
+        written_grad.write(0, 0, 0, read_grad.mat_ref(0)[(1, 1)]);
+        assert_eq!(out.as_matvecref(&memory).mat_ref(0)[(0, 0)], c64::new(3.0, 0.0));
+    }
+}
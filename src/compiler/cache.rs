@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use crate::tree::ExpressionTree;
+use crate::bytecode::Bytecode;
+
+use super::compile;
+
+/// A memoization cache for compiled expression trees.
+///
+/// Compiling the same tree repeatedly, e.g. when a parameter sweep rebuilds
+/// its circuit, returns a clone of the previously generated bytecode.
+#[derive(Default)]
+pub struct CompileCache {
+    cache: HashMap<ExpressionTree, Bytecode>,
+    misses: usize,
+}
+
+impl CompileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of trees stored in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// The number of lookups that missed the cache and ran the compiler.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Remove all cached bytecode.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+}
+
+/// Compile `tree`, reusing a previous result from `cache` if available.
+pub fn compile_cached(tree: &ExpressionTree, cache: &mut CompileCache) -> Bytecode {
+    if let Some(code) = cache.cache.get(tree) {
+        return code.clone();
+    }
+
+    cache.misses += 1;
+    let code = compile(tree);
+    cache.cache.insert(tree.clone(), code.clone());
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::QuditRadices;
+
+    use super::compile_cached;
+    use super::CompileCache;
+    use crate::compiler::compile;
+    use crate::fixtures;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_cache_hit_matches_fresh_compile() {
+        let cx = fixtures::cx();
+        let ry = fixtures::ry();
+        let build = |locations: Vec<Vec<usize>>| {
+            TreeBuilder::from_locations(QuditRadices::from_iter([2, 2]), locations, |loc| {
+                if loc.len() == 1 { ry.clone() } else { cx.clone() }
+            })
+            .build_tree()
+        };
+
+        let mut cache = CompileCache::new();
+        let tree = build(vec![vec![0], vec![0, 1], vec![1]]);
+        compile_cached(&tree, &mut cache);
+        assert_eq!(cache.misses(), 1);
+
+        // A rebuilt but identical tree is served without compiling
+        let hit = compile_cached(&build(vec![vec![0], vec![0, 1], vec![1]]), &mut cache);
+        assert_eq!(cache.misses(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(format!("{:?}", hit), format!("{:?}", compile(&tree)));
+
+        compile_cached(&build(vec![vec![1], vec![0, 1]]), &mut cache);
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -7,13 +7,239 @@ use crate::tree::ExpressionTree;
 use crate::bytecode::{Bytecode, BytecodeGenerator};
 use crate::bytecode::StaticBytecodeOptimizer;
 use crate::bytecode::remove_identity_frpr;
-// use crate::bytecode::BufferOptimizer;
+use crate::bytecode::remove_identity_kron;
+use crate::bytecode::fuse_frpr_across_matmul;
+use crate::bytecode::BufferOptimizer;
+use crate::bytecode::BufferReuser;
+use crate::bytecode::ParameterMap;
+use crate::error::QuditTreeError;
 
+/// Compile `tree` into bytecode.
+///
+/// A tree that is a single leaf compiles to exactly one expression write
+/// with no intermediate buffers, so the QVM returns the written buffer
+/// directly and a single-gate circuit has no evaluation overhead.
+///
+/// Intermediate buffers that are never live at the same time share storage,
+/// as merged by the [BufferReuser].
 pub fn compile(tree: &ExpressionTree) -> Bytecode {
-    let code = BytecodeGenerator::new().generate(tree);
+    try_compile(tree).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Compile `tree` as in [compile], tying its parameters to global
+/// parameters with `parameter_map`.
+///
+/// The resulting QVM takes one value per global parameter, and its
+/// [gradient matrices](crate::QVM::get_gradient_matrices) sum the
+/// contributions of every tree parameter tied to each global parameter.
+///
+/// # Panics
+///
+/// - If `parameter_map` does not map every parameter of `tree`.
+/// - If a leaf's parameters are not mapped to consecutive global parameters.
+pub fn compile_with_parameter_map(tree: &ExpressionTree, parameter_map: ParameterMap) -> Bytecode {
+    try_compile_with_parameter_map(tree, parameter_map).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Compile `tree` as in [compile], returning an error instead of panicking.
+pub fn try_compile(tree: &ExpressionTree) -> Result<Bytecode, QuditTreeError> {
+    let code = BytecodeGenerator::new().try_generate(tree)?;
+    Ok(optimize(code))
+}
+
+/// Compile `tree` as in [compile_with_parameter_map], returning an error
+/// instead of panicking if `parameter_map` does not fit the tree.
+pub fn try_compile_with_parameter_map(
+    tree: &ExpressionTree,
+    parameter_map: ParameterMap,
+) -> Result<Bytecode, QuditTreeError> {
+    let code = BytecodeGenerator::new().with_parameter_map(parameter_map).try_generate(tree)?;
+    Ok(optimize(code))
+}
+
+/// Compile `tree` into bytecode that applies it to a state vector.
+///
+/// The QVM then evolves states with [crate::QVM::apply_to_state] without
+/// allocating a buffer over the whole system's unitary: every leaf is
+/// applied to the state as a local contraction on its own qudits, as
+/// lowered by [BytecodeGenerator::generate_for_state]. The peak memory is
+/// a few states plus the largest operand, rather than the unitary.
+///
+/// Any tree can be lowered this way, including krons of disjoint systems,
+/// whose sides are applied to the state one after the other. Constant
+/// subtrees and conjugated contraction operands are still computed as
+/// operators on their own qudits before being applied.
+pub fn compile_for_state(tree: &ExpressionTree) -> Bytecode {
+    try_compile_for_state(tree).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Compile `tree` as in [compile_for_state], returning an error instead of
+/// panicking.
+pub fn try_compile_for_state(tree: &ExpressionTree) -> Result<Bytecode, QuditTreeError> {
+    let code = BytecodeGenerator::new().try_generate_for_state(tree)?;
+    Ok(optimize(code))
+}
+
+/// Compile `tree`, whose operators must all be diagonal, into bytecode
+/// that computes the diagonal of its unitary.
+///
+/// Every buffer spans at most one column over the whole system, as for
+/// [compile_for_state], and each gate costs one multiplication per entry
+/// of the diagonal. Expressions cannot report whether they are diagonal,
+/// so [crate::QVM::get_diagonal] checks each gate as it is applied.
+pub fn compile_for_diagonal(tree: &ExpressionTree) -> Bytecode {
+    try_compile_for_diagonal(tree).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Compile `tree` as in [compile_for_diagonal], returning an error instead
+/// of panicking.
+pub fn try_compile_for_diagonal(tree: &ExpressionTree) -> Result<Bytecode, QuditTreeError> {
+    let code = BytecodeGenerator::new().try_generate_for_diagonal(tree)?;
+    Ok(optimize(code))
+}
+
+/// Run the optimization passes shared by every compile entry point.
+fn optimize(code: Bytecode) -> Bytecode {
     let code = StaticBytecodeOptimizer::new(code).optimize();
     let code = remove_identity_frpr(code);
-    // let code = BufferOptimizer::new().optimize(code);
-    // let code = BufferReuser::new().reuse_buffers(code);
-    code
+    let code = remove_identity_kron(code);
+    let code = fuse_frpr_across_matmul(code);
+    BufferReuser::new().reuse_buffers(code)
+}
+
+/// Compile `tree` as in [compile], then share storage between intermediate
+/// buffers of the same shape with the [BufferOptimizer].
+///
+/// The [BufferOptimizer] renumbers buffers, replacing the merges made by
+/// the [BufferReuser].
+pub fn compile_with_buffer_optimizer(tree: &ExpressionTree) -> Bytecode {
+    let code = compile(tree);
+    BufferOptimizer::new().optimize(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use super::compile;
+    use super::compile_with_buffer_optimizer;
+    use crate::bytecode::GeneralizedInstruction;
+    use crate::bytecode::Bytecode;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+    use crate::tree::ExpressionTree;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_single_leaf_compiles_to_one_write() {
+        let p = fixtures::p();
+        let code = compile(&ExpressionTree::Leaf(p));
+
+        assert!(code.static_code.is_empty());
+        assert_eq!(code.dynamic_code.len(), 1);
+        assert!(matches!(code.dynamic_code[0], GeneralizedInstruction::Write(..)));
+        assert_eq!(code.matrix_buffers.len(), 1);
+
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let utry = qvm.get_unitary(&[0.5]);
+        assert!((utry[(1, 1)] - c64::new(0.5f64.cos(), 0.5f64.sin())).norm() < 1e-12);
+        assert_eq!(utry[(0, 1)], c64::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn test_compile_reuses_buffers() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1]; 8],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let params: Vec<f64> = (0..8).map(|i| 0.3 * i as f64 + 0.1).collect();
+
+        let reused = compile(&tree);
+        assert!(!reused.merged_buffers.is_empty());
+        let unreused = Bytecode { merged_buffers: HashMap::new(), ..reused.clone() };
+
+        let (_, _, _, reused_size) = reused.specialize::<c64>(DifferentiationLevel::Gradient);
+        let (_, _, _, unreused_size) = unreused.specialize::<c64>(DifferentiationLevel::Gradient);
+        assert!(reused_size < unreused_size);
+
+        // The chain of CRYs is a single CRY by the sum of the angles.
+        let total: f64 = params.iter().sum();
+        let mut qvm = QVM::<c64>::new(reused, DifferentiationLevel::None);
+        let utry = qvm.get_unitary(&params);
+        assert!((utry[(2, 2)] - c64::new((total / 2.0).cos(), 0.0)).norm() < 1e-10);
+        assert!((utry[(3, 2)] - c64::new((total / 2.0).sin(), 0.0)).norm() < 1e-10);
+        assert!((utry[(0, 0)] - c64::new(1.0, 0.0)).norm() < 1e-10);
+    }
+
+    #[test]
+    fn test_compile_is_deterministic() {
+        let cry = fixtures::cry();
+        let cp = UnitaryExpression::new(
+            "CP(t) { [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, e^(i*t)]] }",
+        );
+        let locations = vec![vec![0, 1], vec![1, 2], vec![2, 3], vec![0, 1], vec![1, 2], vec![2, 3], vec![1, 2]];
+        let build = || {
+            TreeBuilder::from_locations(QuditRadices::from_iter([2, 2, 2, 2]), locations.clone(), |loc| {
+                if loc[0] == 1 { cry.clone() } else { cp.clone() }
+            })
+            .build_tree()
+        };
+
+        // Every builder and compile pass gets fresh hash maps with their own
+        // random iteration order.
+        let expected = compile(&build()).canonical_form();
+        for _ in 0..20 {
+            assert_eq!(compile(&build()).canonical_form(), expected);
+        }
+    }
+
+    #[test]
+    fn test_canonical_form_includes_expression_bodies() {
+        let phase = ExpressionTree::Leaf(UnitaryExpression::new("G(t) { [[1, 0], [0, e^(i*t)]] }"));
+        let rotation = ExpressionTree::Leaf(UnitaryExpression::new(
+            "G(t) { [[cos(t/2), ~sin(t/2)], [sin(t/2), cos(t/2)]] }",
+        ));
+        assert_ne!(compile(&phase).canonical_form(), compile(&rotation).canonical_form());
+
+        // Same-named expressions are still ordered the same way every time
+        let build = || phase.clone().beside(rotation.clone());
+        let expected = compile(&build()).canonical_form();
+        for _ in 0..20 {
+            assert_eq!(compile(&build()).canonical_form(), expected);
+        }
+    }
+
+    #[test]
+    fn test_buffer_optimizer_reduces_buffer_count() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1]; 8],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let params: Vec<f64> = (0..8).map(|i| 0.3 * i as f64 + 0.1).collect();
+
+        let code = compile(&tree);
+        let optimized = compile_with_buffer_optimizer(&tree);
+        assert!(optimized.matrix_buffers.len() < code.matrix_buffers.len());
+
+        let mut expected = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let mut qvm = QVM::<c64>::new(optimized, DifferentiationLevel::None);
+        let expected = expected.get_unitary(&params).to_owned();
+        let utry = qvm.get_unitary(&params);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
 }
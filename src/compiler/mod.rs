@@ -1,3 +1,17 @@
+mod cache;
 mod compiler;
+mod timings;
 
+pub use cache::compile_cached;
+pub use cache::CompileCache;
 pub use compiler::compile;
+pub use compiler::compile_for_diagonal;
+pub use compiler::compile_for_state;
+pub use compiler::compile_with_buffer_optimizer;
+pub use compiler::compile_with_parameter_map;
+pub use compiler::try_compile;
+pub use compiler::try_compile_for_diagonal;
+pub use compiler::try_compile_for_state;
+pub use compiler::try_compile_with_parameter_map;
+pub use timings::compile_and_time;
+pub use timings::Timings;
@@ -0,0 +1,125 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::tree::ExpressionTree;
+use crate::bytecode::{Bytecode, BytecodeGenerator};
+use crate::bytecode::StaticBytecodeOptimizer;
+use crate::bytecode::remove_identity_frpr;
+use crate::bytecode::remove_identity_kron;
+use crate::bytecode::fuse_frpr_across_matmul;
+use crate::bytecode::BufferReuser;
+
+/// Wall-clock durations of the compilation and execution phases.
+///
+/// Compile phases are populated by [compile_and_time]; execution phases are
+/// populated by [crate::QVM::time_unitary].
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    /// Time spent generating bytecode from the tree.
+    pub generate: Option<Duration>,
+
+    /// Time spent in the static bytecode optimizer.
+    pub static_optimize: Option<Duration>,
+
+    /// Time spent removing identity FRPR instructions.
+    pub remove_identity_frpr: Option<Duration>,
+
+    /// Time spent replacing krons of identities.
+    pub remove_identity_kron: Option<Duration>,
+
+    /// Time spent fusing FRPR instructions across matmuls.
+    pub fuse_frpr: Option<Duration>,
+
+    /// Time spent merging buffers with the buffer reuser.
+    pub reuse_buffers: Option<Duration>,
+
+    /// Time of the first unitary evaluation, including static code and
+    /// buffer warm up.
+    pub first_run: Option<Duration>,
+
+    /// Time of a subsequent unitary evaluation.
+    pub steady_state: Option<Duration>,
+}
+
+impl Timings {
+    /// The total time spent compiling, if all compile phases were recorded.
+    pub fn compile_total(&self) -> Option<Duration> {
+        Some(
+            self.generate?
+                + self.static_optimize?
+                + self.remove_identity_frpr?
+                + self.remove_identity_kron?
+                + self.fuse_frpr?
+                + self.reuse_buffers?,
+        )
+    }
+}
+
+/// Compile `tree` as in [super::compile], recording the duration of each phase.
+pub fn compile_and_time(tree: &ExpressionTree) -> (Bytecode, Timings) {
+    let mut timings = Timings::default();
+
+    let now = Instant::now();
+    let code = BytecodeGenerator::new().generate(tree);
+    timings.generate = Some(now.elapsed());
+
+    let now = Instant::now();
+    let code = StaticBytecodeOptimizer::new(code).optimize();
+    timings.static_optimize = Some(now.elapsed());
+
+    let now = Instant::now();
+    let code = remove_identity_frpr(code);
+    timings.remove_identity_frpr = Some(now.elapsed());
+
+    let now = Instant::now();
+    let code = remove_identity_kron(code);
+    timings.remove_identity_kron = Some(now.elapsed());
+
+    let now = Instant::now();
+    let code = fuse_frpr_across_matmul(code);
+    timings.fuse_frpr = Some(now.elapsed());
+
+    let now = Instant::now();
+    let code = BufferReuser::new().reuse_buffers(code);
+    timings.reuse_buffers = Some(now.elapsed());
+
+    (code, timings)
+}
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+
+    use super::compile_and_time;
+    use crate::compiler::compile;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_all_timings_populated() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2], vec![0, 1]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+
+        let (code, mut timings) = compile_and_time(&tree);
+        assert!(timings.compile_total().is_some());
+        assert!(timings.first_run.is_none());
+
+        let params = [0.4, -1.2, 2.5];
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        qvm.time_unitary(&params, &mut timings);
+        assert!(timings.first_run.is_some());
+        assert!(timings.steady_state.is_some());
+
+        // Timing the phases does not change what is compiled
+        let mut expected = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        assert_eq!(qvm.get_unitary(&params), expected.get_unitary(&params));
+    }
+}
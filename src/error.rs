@@ -0,0 +1,144 @@
+/// An error raised while building, compiling, or instantiating a tree.
+///
+/// The `try_` entry points, such as [crate::TreeBuilder::try_new],
+/// [crate::try_compile], and [crate::QVM::try_new], return these instead of
+/// panicking. Their panicking counterparts panic with the same message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuditTreeError {
+    /// The expression, qudit, next, and prev lists have different lengths.
+    InvalidInputLengths,
+
+    /// The circuit has no qudits.
+    NoQudits,
+
+    /// The circuit has no operations.
+    NoOperations,
+
+    /// The operation's qudit, next, or prev list does not have one entry
+    /// per qudit of its expression.
+    InvalidOperationQudits(usize),
+
+    /// The input state with this operation index has a previous operation.
+    StateNotAtBoundary(usize),
+
+    /// Two operations assign the same qudit different radices.
+    InconsistentRadix {
+        qudit: usize,
+        first_op: usize,
+        first_radix: u8,
+        op: usize,
+        radix: u8,
+    },
+
+    /// No operation acts on this qudit and its radix was not given, so it
+    /// cannot be covered by an identity.
+    UnknownIdleRadix(usize),
+
+    /// The circuit has more parameters than the builder allows.
+    TooManyParams { num_params: usize, max_params: usize },
+
+    /// The operands of a contraction share no qudits.
+    NoContractedQudits,
+
+    /// The contracted qudit has different radices in the two operands.
+    ContractedRadixMismatch(usize),
+
+    /// The radices given for a contraction operand do not factor its
+    /// dimension, or do not have one radix per qudit of the operand.
+    IncompatibleFactorization,
+
+    /// The parameter map does not have one entry per tree parameter.
+    ParameterMapLength { expected: usize, found: usize },
+
+    /// The leaf, identified by its first tree parameter, has parameters
+    /// mapped to non-consecutive global parameters.
+    NonConsecutiveLeafParameters(usize),
+
+    /// An FRPR needs more tensor indices than are supported.
+    TooManyFrprIndices(usize),
+
+    /// The QVM was not built with a differentiation level that computes
+    /// gradients.
+    NotGradientCapable,
+
+    /// The QVM was not built with a differentiation level that computes
+    /// hessians.
+    NotHessianCapable,
+
+    /// No matrix was supplied for the runtime constant with this id.
+    MissingRuntimeConstant(usize),
+
+    /// The matrix supplied for the runtime constant with this id has the
+    /// wrong shape.
+    RuntimeConstantShape(usize),
+}
+
+impl std::fmt::Display for QuditTreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuditTreeError::InvalidInputLengths => write!(f, "Invalid input lengths"),
+            QuditTreeError::NoQudits => write!(f, "Invalid number of qudits"),
+            QuditTreeError::NoOperations => write!(f, "Invalid number of operations"),
+            QuditTreeError::InvalidOperationQudits(op) => {
+                write!(f, "Invalid number of qudits in operation {}", op)
+            },
+            QuditTreeError::StateNotAtBoundary(op) => {
+                write!(f, "Input state in operation {} must not follow another operation", op)
+            },
+            QuditTreeError::InconsistentRadix { qudit, first_op, first_radix, op, radix } => {
+                write!(
+                    f,
+                    "Inconsistent radix for qudit {}: operation {} uses radix {} but operation {} uses radix {}",
+                    qudit, first_op, first_radix, op, radix,
+                )
+            },
+            QuditTreeError::UnknownIdleRadix(qudit) => {
+                write!(f, "Idle qudit {} has no known radix", qudit)
+            },
+            QuditTreeError::TooManyParams { num_params, max_params } => write!(
+                f,
+                "Circuit has {} parameters, exceeding the maximum of {}",
+                num_params, max_params
+            ),
+            QuditTreeError::NoContractedQudits => write!(
+                f,
+                "There must be at least one overlapping qudit between the left and right nodes."
+            ),
+            QuditTreeError::ContractedRadixMismatch(qudit) => write!(
+                f,
+                "The indices being contracted must have the same dimension/radix, but qudit {} does not.",
+                qudit
+            ),
+            QuditTreeError::IncompatibleFactorization => write!(
+                f,
+                "The radices of a contraction operand must factor its dimension, one radix per qudit."
+            ),
+            QuditTreeError::ParameterMapLength { expected, found } => write!(
+                f,
+                "Parameter map must map every parameter of the tree: expected {} entries, found {}.",
+                expected, found
+            ),
+            QuditTreeError::NonConsecutiveLeafParameters(_) => write!(
+                f,
+                "Parameters of a single leaf must map to consecutive global parameters."
+            ),
+            QuditTreeError::TooManyFrprIndices(len) => {
+                write!(f, "Too many indices in FRPR operation: {}.", len)
+            },
+            QuditTreeError::NotGradientCapable => {
+                write!(f, "QVM is not gradient capable, cannot calculate gradient.")
+            },
+            QuditTreeError::NotHessianCapable => {
+                write!(f, "QVM is not hessian capable, cannot calculate hessian.")
+            },
+            QuditTreeError::MissingRuntimeConstant(id) => {
+                write!(f, "No matrix supplied for runtime constant {}.", id)
+            },
+            QuditTreeError::RuntimeConstantShape(id) => {
+                write!(f, "Matrix for runtime constant {} has the wrong shape.", id)
+            },
+        }
+    }
+}
+
+impl std::error::Error for QuditTreeError {}
@@ -0,0 +1,45 @@
+//! Gates and circuits shared by the unit tests.
+
+use qudit_expr::UnitaryExpression;
+
+use crate::tree::BuilderExpressionInput;
+use crate::tree::ExpressionTree;
+use crate::tree::TreeBuilder;
+
+/// A phase on the one state of a qubit.
+pub fn p() -> UnitaryExpression {
+    UnitaryExpression::new("P(a) { [[1, 0], [0, e^(i*a)]] }")
+}
+
+/// A Y rotation of a qubit.
+pub fn ry() -> UnitaryExpression {
+    UnitaryExpression::new("RY(t) { [[cos(t/2), ~sin(t/2)], [sin(t/2), cos(t/2)]] }")
+}
+
+/// A controlled Y rotation of two qubits.
+pub fn cry() -> UnitaryExpression {
+    UnitaryExpression::new(
+        "CRY(t) { [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, cos(t/2), ~sin(t/2)], [0, 0, sin(t/2), cos(t/2)]] }",
+    )
+}
+
+/// A controlled not of two qubits.
+pub fn cx() -> UnitaryExpression {
+    UnitaryExpression::new("CX() { [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 0, 1], [0, 0, 1, 0]] }")
+}
+
+/// Two phase gates on separate qubits followed by an entangling gate.
+pub fn parallel_phases() -> ExpressionTree {
+    TreeBuilder::new(
+        2,
+        vec![
+            BuilderExpressionInput::Unitary(p()),
+            BuilderExpressionInput::Unitary(p()),
+            BuilderExpressionInput::Unitary(cry()),
+        ],
+        vec![vec![0], vec![1], vec![0, 1]],
+        vec![vec![Some(2)], vec![Some(2)], vec![None, None]],
+        vec![vec![None], vec![None], vec![Some(0), Some(1)]],
+    )
+    .build_tree()
+}
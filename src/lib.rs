@@ -2,13 +2,51 @@ mod tree;
 mod bytecode;
 mod compiler;
 mod qvm;
+mod block;
+mod simulate;
+mod error;
+
+#[cfg(test)]
+mod fixtures;
 
 pub use tree::TreeOptimizer;
 pub use tree::BuilderExpressionInput;
+pub use tree::ClassicalCondition;
+pub use tree::ContractCandidate;
+pub use tree::PairBlocker;
+pub use tree::PairDecision;
 pub use tree::TreeBuilder;
 pub use tree::ExpressionTree;
+pub use tree::NodeKind;
+pub use tree::ContractMeta;
+pub use tree::ContractNode;
+pub use tree::ContractTemplate;
+pub use tree::ContractionStep;
+pub use tree::ContractionStats;
+pub use tree::HardwareProfile;
+pub use tree::RuntimeConstantNode;
 pub use compiler::compile;
+pub use compiler::compile_for_diagonal;
+pub use compiler::compile_for_state;
+pub use compiler::compile_with_buffer_optimizer;
+pub use compiler::compile_with_parameter_map;
+pub use compiler::try_compile;
+pub use compiler::try_compile_for_diagonal;
+pub use compiler::try_compile_for_state;
+pub use compiler::try_compile_with_parameter_map;
+pub use compiler::compile_cached;
+pub use compiler::CompileCache;
+pub use compiler::compile_and_time;
+pub use compiler::Timings;
+pub use bytecode::BufferMergeError;
+pub use bytecode::BufferReuser;
+pub use bytecode::MemoryFootprint;
+pub use bytecode::MergeObjective;
+pub use bytecode::ParameterMap;
 pub use qvm::QVM;
+pub use block::BlockQVM;
+pub use simulate::simulate;
+pub use error::QuditTreeError;
 
 #[cfg(test)]
 mod tests {
@@ -1,20 +1,38 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::time::Instant;
+
 // use aligned_vec::{avec, AVec};
 // use bytemuck::Zeroable;
+use faer::reborrow::Reborrow;
 use faer::reborrow::ReborrowMut;
+use faer::linalg::solvers::DenseSolveCore;
+use faer::Col;
+use faer::Mat;
 use qudit_expr::DifferentiationLevel;
 use qudit_expr::Module;
 
 use super::bytecode::Bytecode;
+use super::error::QuditTreeError;
+use super::compiler::Timings;
+use super::bytecode::ParameterMap;
+use super::bytecode::SizedMatrixBuffer;
 use super::bytecode::SpecializedInstruction;
-use qudit_core::accel::fused_reshape_permute_reshape_into_impl;
+use super::bytecode::WarmupStrategy;
+use qudit_core::accel::matmul_unchecked;
 use qudit_core::matrix::MatVecMut;
 use qudit_core::matrix::MatVecRef;
 use qudit_core::matrix::SymSqMatMatMut;
+use qudit_core::matrix::SymSqMatMatRef;
 use qudit_core::matrix::MatMut;
 use qudit_core::matrix::MatRef;
 use qudit_core::memory::MemoryBuffer;
 use qudit_core::memory::alloc_zeroed_memory;
+use qudit_core::memory::calc_col_stride;
+use qudit_core::memory::calc_mat_stride;
 use qudit_core::ComplexScalar;
+use qudit_core::QuditRadices;
 
 pub struct QVM<C: ComplexScalar> {
     first_run: bool,
@@ -23,21 +41,212 @@ pub struct QVM<C: ComplexScalar> {
     #[allow(dead_code)]
     module: Module<C>,
     memory: MemoryBuffer<C>,
+    /// The number of elements of `memory` the program uses.
+    mem_size: usize,
     diff_lvl: DifferentiationLevel,
+    runtime_constants: Vec<(SizedMatrixBuffer, Mat<C>)>,
+    warmups: Vec<(SizedMatrixBuffer, WarmupStrategy)>,
+    parameter_map: Option<ParameterMap>,
+    radices: QuditRadices,
+    /// Storage for the gradient summed onto global parameters, when the
+    /// program ties its parameters.
+    tied_gradient: Option<(SizedMatrixBuffer, MemoryBuffer<C>)>,
+}
+
+// A BlockQVM evaluates each of its blocks' QVMs on its own thread.
+const _: () = {
+    fn assert_send<T: Send>() {}
+    #[allow(dead_code)]
+    fn assert_qvm_send() {
+        assert_send::<QVM<qudit_core::c64>>();
+    }
+};
+
+/// The most parameter vectors [QVM::get_unitaries_batched] evaluates in
+/// one stack; larger batches are evaluated a stack at a time.
+const MAX_BATCH_INSTANCES: usize = 64;
+
+/// The granularity, in elements, of the instances in a stacked memory, so
+/// each instance keeps the alignment of the QVM's own memory.
+const BATCH_INSTANCE_ALIGN: usize = 64;
+
+/// Sum the gradient of each tree parameter onto its global parameter.
+fn tie_gradient<C: ComplexScalar>(
+    map: &ParameterMap,
+    grad: MatVecRef<C>,
+    nrows: usize,
+    ncols: usize,
+    mut out: MatVecMut<C>,
+) {
+    for (global, tree_params) in map.tied_params().iter().enumerate() {
+        for c in 0..ncols {
+            for r in 0..nrows {
+                let mut acc = C::zero();
+                for &i in tree_params {
+                    acc = acc + grad.mat_ref(i)[(r, c)];
+                }
+                out.write(global, r, c, acc);
+            }
+        }
+    }
+}
+
+/// Sum the hessian of each pair of tree parameters onto the pair of global
+/// parameters they are tied to.
+///
+/// Only the upper triangle of either hessian is stored, so every ordered
+/// pair of tree parameters is read from its upper-triangle entry.
+fn tie_hessian<C: ComplexScalar>(
+    map: &ParameterMap,
+    hess: SymSqMatMatRef<C>,
+    nrows: usize,
+    ncols: usize,
+    mut out: SymSqMatMatMut<C>,
+) {
+    let tied = map.tied_params();
+    for g1 in 0..tied.len() {
+        for g2 in g1..tied.len() {
+            for c in 0..ncols {
+                for r in 0..nrows {
+                    let mut acc = C::zero();
+                    for &i in &tied[g1] {
+                        for &j in &tied[g2] {
+                            acc = acc + hess.mat_ref(i.min(j), i.max(j))[(r, c)];
+                        }
+                    }
+                    out.write(g1, g2, r, c, acc);
+                }
+            }
+        }
+    }
+}
+
+/// Initialize `mat` according to `strategy`.
+fn warm_up<C: ComplexScalar>(mut mat: MatMut<C>, strategy: WarmupStrategy) {
+    if strategy == WarmupStrategy::None {
+        return;
+    }
+
+    for c in 0..mat.ncols() {
+        for r in 0..mat.nrows() {
+            let diagonal = strategy == WarmupStrategy::Identity && r == c;
+            *mat.rb_mut().get_mut(r, c) = if diagonal { C::one() } else { C::zero() };
+        }
+    }
 }
 
 impl<C: ComplexScalar> QVM<C> {
     pub fn new(program: Bytecode, diff_lvl: DifferentiationLevel) -> Self {
-        let (sinsts, dinsts, module, mem_size) = program.specialize::<C>(diff_lvl);
+        Self::new_with_runtime_constants(program, diff_lvl, HashMap::new())
+    }
+
+    /// The square operator that stands in for an input state.
+    ///
+    /// The first column of the operator is `state` and every other entry is
+    /// zero. Supply it for a [crate::BuilderExpressionInput::State] to
+    /// [QVM::new_with_runtime_constants]; the first column of the circuit's
+    /// unitary is then the output state.
+    ///
+    /// # Panics
+    ///
+    /// If `state` is not a single column.
+    pub fn state_operator(state: MatRef<C>) -> Mat<C> {
+        if state.ncols() != 1 {
+            panic!("State must be a single column.");
+        }
+        Mat::from_fn(state.nrows(), state.nrows(), |r, c| {
+            if c == 0 { state[(r, 0)] } else { C::zero() }
+        })
+    }
+
+    /// Create a QVM as in [QVM::new], returning an error instead of
+    /// panicking if the program cannot be instantiated.
+    pub fn try_new(program: Bytecode, diff_lvl: DifferentiationLevel) -> Result<Self, QuditTreeError> {
+        Self::try_new_with_runtime_constants(program, diff_lvl, HashMap::new())
+    }
 
-        Self {
+    /// Create a QVM, supplying the matrices of the program's runtime constants.
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - The bytecode to execute.
+    /// * `diff_lvl` - The highest derivative order the QVM can compute.
+    /// * `constants` - The matrix of each [crate::RuntimeConstantNode], keyed
+    ///   by its id. They are written into memory once, before the static code
+    ///   first runs.
+    ///
+    /// # Panics
+    ///
+    /// If a runtime constant's matrix is missing or has the wrong shape.
+    pub fn new_with_runtime_constants(
+        program: Bytecode,
+        diff_lvl: DifferentiationLevel,
+        constants: HashMap<usize, Mat<C>>,
+    ) -> Self {
+        Self::try_new_with_runtime_constants(program, diff_lvl, constants)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Create a QVM as in [QVM::new_with_runtime_constants], returning an
+    /// error instead of panicking if a runtime constant's matrix is missing
+    /// or has the wrong shape, or the program cannot be specialized.
+    pub fn try_new_with_runtime_constants(
+        program: Bytecode,
+        diff_lvl: DifferentiationLevel,
+        constants: HashMap<usize, Mat<C>>,
+    ) -> Result<Self, QuditTreeError> {
+        let runtime_constants = program
+            .runtime_constant_buffers::<C>(diff_lvl)
+            .into_iter()
+            .map(|(id, buffer)| {
+                let mat = constants
+                    .get(&id)
+                    .ok_or(QuditTreeError::MissingRuntimeConstant(id))?;
+                if mat.nrows() != buffer.nrows || mat.ncols() != buffer.ncols {
+                    return Err(QuditTreeError::RuntimeConstantShape(id));
+                }
+                Ok((buffer, mat.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let warmups = program.warmup_buffers::<C>(diff_lvl);
+        let parameter_map = program.parameter_map.clone();
+        let radices = program.radices.clone();
+        let (sinsts, dinsts, module, mem_size) = program.try_specialize::<C>(diff_lvl)?;
+
+        let tied_gradient = match (&parameter_map, dinsts.last().or(sinsts.last())) {
+            (Some(map), Some(inst)) if diff_lvl.gradient_capable() => {
+                let out = inst.out_buffer();
+                let col_stride = calc_col_stride::<C>(out.nrows, out.ncols);
+                let mat_stride = calc_mat_stride::<C>(out.nrows, out.ncols, col_stride);
+                let buffer = SizedMatrixBuffer {
+                    offset: 0,
+                    nrows: out.nrows,
+                    ncols: out.ncols,
+                    col_stride: col_stride as isize,
+                    mat_stride: mat_stride as isize,
+                    num_params: map.num_global_params(),
+                };
+                let memory = alloc_zeroed_memory::<C>(mat_stride * (1 + map.num_global_params()));
+                Some((buffer, memory))
+            },
+            _ => None,
+        };
+
+        Ok(Self {
             first_run: true,
             static_instructions: sinsts,
             dynamic_instructions: dinsts,
             module,
             memory: alloc_zeroed_memory::<C>(mem_size),
+            mem_size,
             diff_lvl,
-        }
+            runtime_constants,
+            warmups,
+            parameter_map,
+            radices,
+            tied_gradient,
+        })
     }
 
     #[inline(always)]
@@ -46,35 +255,131 @@ impl<C: ComplexScalar> QVM<C> {
             return;
         }
 
-        // Warm up necessary unitary buffers to identity
-        // TODO: Evaluate if any other buffers need to be warmed up here
-        for inst in self.static_instructions.iter() {
-            if let SpecializedInstruction::Write(w) = inst {
-                let mut matmut = w.buffer.as_matmut(&mut self.memory);
-                for i in 0..matmut.nrows() {
-                    *matmut.rb_mut().get_mut(i, i) = C::one();
-                }
-            }
+        // Warm up buffers as chosen by the generator. The full buffer is
+        // written, since memory may have been touched by a previous run.
+        for (buffer, strategy) in self.warmups.iter() {
+            warm_up(buffer.as_matmut(&mut self.memory), *strategy);
         }
 
-        for inst in self.dynamic_instructions.iter() {
-            if let SpecializedInstruction::Write(w) = inst {
-                let mut matmut = w.buffer.as_matmut(&mut self.memory);
-                for i in 0..matmut.nrows() {
-                    *matmut.rb_mut().get_mut(i, i) = C::one();
-                }
-            }
+        for (buffer, mat) in self.runtime_constants.iter() {
+            buffer.as_matmut::<C>(&mut self.memory).copy_from(mat);
         }
 
         // Evaluate static code
         for inst in &self.static_instructions {
             inst.execute_unitary(&[], &mut self.memory);
-            // TODO: what happens if all code is static?
         }
 
         self.first_run = false;
     }
 
+    /// Re-run warmup and the static code on the next evaluation.
+    pub fn reset(&mut self) {
+        self.first_run = true;
+    }
+
+    /// The buffer holding the final result of the program.
+    ///
+    /// This is the output of the last dynamic instruction, or of the last
+    /// static instruction if the entire program is constant.
+    fn output_buffer(&self) -> &SizedMatrixBuffer {
+        self.dynamic_instructions
+            .last()
+            .or(self.static_instructions.last())
+            .expect("Program contains no instructions.")
+            .out_buffer()
+    }
+
+    /// The most recent dynamic instruction before `idx` that writes
+    /// `buffer`, or `None` if it is produced by static code.
+    fn producer_of(&self, idx: usize, buffer: &SizedMatrixBuffer) -> Option<usize> {
+        (0..idx)
+            .rev()
+            .find(|&j| self.dynamic_instructions[j].out_buffer().offset == buffer.offset)
+    }
+
+    fn check_gradient_capable(&self) -> Result<(), QuditTreeError> {
+        if !self.diff_lvl.gradient_capable() {
+            return Err(QuditTreeError::NotGradientCapable);
+        }
+        Ok(())
+    }
+
+    fn check_hessian_capable(&self) -> Result<(), QuditTreeError> {
+        if !self.diff_lvl.hessian_capable() {
+            return Err(QuditTreeError::NotHessianCapable);
+        }
+        Ok(())
+    }
+
+    /// Copy the result of an all-static program into `out_utry`.
+    fn write_static_output(&mut self, mut out_utry: MatMut<C>) {
+        let out_matref = self.output_buffer().as_matref::<C>(&self.memory);
+        for i in 0..out_matref.nrows() {
+            for j in 0..out_matref.ncols() {
+                *out_utry.rb_mut().get_mut(i, j) = out_matref[(i, j)];
+            }
+        }
+    }
+
+    /// Zero all intermediate buffers produced by dynamic code.
+    ///
+    /// Every intermediate buffer is fully overwritten on each evaluation,
+    /// so this is never required for correctness. It is provided for
+    /// debugging and for callers that do not want results from a previous
+    /// evaluation to linger in memory. Buffers written by expressions and
+    /// buffers produced by static code are left untouched, since they are
+    /// only initialized once.
+    pub fn clear_intermediates(&mut self) {
+        let mut preserved = HashSet::new();
+        for inst in self.static_instructions.iter() {
+            preserved.insert(inst.out_buffer().offset);
+        }
+        for inst in self.dynamic_instructions.iter() {
+            if let SpecializedInstruction::Write(w) = inst {
+                preserved.insert(w.buffer.offset);
+            }
+        }
+
+        for inst in self.dynamic_instructions.iter() {
+            let buffer = inst.out_buffer();
+            if preserved.contains(&buffer.offset) {
+                continue;
+            }
+
+            let mut matmut = buffer.as_matmut::<C>(&mut self.memory);
+            for c in 0..matmut.ncols() {
+                for r in 0..matmut.nrows() {
+                    *matmut.rb_mut().get_mut(r, c) = C::zero();
+                }
+            }
+
+            if self.diff_lvl.gradient_capable() {
+                let mut gradmut = buffer.as_matvecmut::<C>(&mut self.memory);
+                for p in 0..buffer.num_params {
+                    for c in 0..buffer.ncols {
+                        for r in 0..buffer.nrows {
+                            gradmut.write(p, r, c, C::zero());
+                        }
+                    }
+                }
+            }
+
+            if self.diff_lvl.hessian_capable() {
+                let mut hessmut = buffer.as_symsqmatmut::<C>(&mut self.memory);
+                for p1 in 0..buffer.num_params {
+                    for p2 in p1..buffer.num_params {
+                        for c in 0..buffer.ncols {
+                            for r in 0..buffer.nrows {
+                                hessmut.write(p1, p2, r, c, C::zero());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn get_unitary(&mut self, params: &[C::R]) -> MatRef<C> {
         self.first_run();
 
@@ -82,315 +387,2380 @@ impl<C: ComplexScalar> QVM<C> {
             inst.execute_unitary(params, &mut self.memory);
         }
 
-        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
-            SpecializedInstruction::Write(w) => {
-                w.buffer.as_matref(&mut self.memory)
-            },
-            SpecializedInstruction::Matmul(m) => {
-                m.out.as_matref(&mut self.memory)
-            },
-            SpecializedInstruction::Kron(k) => {
-                k.out.as_matref(&mut self.memory)
-            },
-            SpecializedInstruction::FRPR(f) => {
-                f.out.as_matref(&mut self.memory)
-            },
-        }
+        self.output_buffer().as_matref(&self.memory)
     }
 
-    pub fn get_unitary_and_gradient(
+    /// Calculate the block of the circuit unitary at `rows` and `cols`.
+    ///
+    /// When the program ends in a matrix multiplication, only the rows of
+    /// its left operand and the columns of its right operand that make up
+    /// the block are multiplied. Otherwise, the full unitary is computed and
+    /// the block is copied out of it.
+    ///
+    /// # Panics
+    ///
+    /// If `rows` or `cols` extend past the dimension of the circuit.
+    pub fn get_unitary_block(
         &mut self,
         params: &[C::R],
-    ) -> (MatRef<C>, MatVecRef<C>) {
-        if !self.diff_lvl.gradient_capable() {
-            panic!("QVM is not gradient capable, cannot calculate gradient.");
+        rows: Range<usize>,
+        cols: Range<usize>,
+    ) -> Mat<C> {
+        let out_buffer = self.output_buffer();
+        if rows.start > rows.end || rows.end > out_buffer.nrows
+            || cols.start > cols.end || cols.end > out_buffer.ncols
+        {
+            panic!("Block must lie within the circuit unitary.");
         }
 
+        let terminal = match self.dynamic_instructions.last() {
+            Some(SpecializedInstruction::Matmul(m)) => Some((m.left.clone(), m.right.clone())),
+            _ => None,
+        };
+
+        let Some((left, right)) = terminal else {
+            return self
+                .get_unitary(params)
+                .submatrix(rows.start, cols.start, rows.len(), cols.len())
+                .to_owned();
+        };
+
         self.first_run();
+        let n = self.dynamic_instructions.len();
+        for inst in &self.dynamic_instructions[..n - 1] {
+            inst.execute_unitary(params, &mut self.memory);
+        }
 
-        for inst in &self.dynamic_instructions {
-            inst.execute_unitary_and_gradient(params, &mut self.memory);
+        let left = left.as_matref::<C>(&self.memory).subrows(rows.start, rows.len());
+        let right = right.as_matref::<C>(&self.memory).subcols(cols.start, cols.len());
+        let mut out = Mat::zeros(rows.len(), cols.len());
+        matmul_unchecked(left, right, out.as_mut());
+        out
+    }
+
+    /// Calculate the circuit unitary and its Frobenius distance to `target`.
+    ///
+    /// The distance is accumulated directly from the output buffer, without
+    /// copying the unitary, which is convenient in synthesis loops that
+    /// stop once the distance is small enough.
+    ///
+    /// # Panics
+    ///
+    /// If `target` does not have the same shape as the circuit unitary.
+    pub fn get_unitary_and_norm(
+        &mut self,
+        params: &[C::R],
+        target: MatRef<C>,
+    ) -> (MatRef<C>, C::R) {
+        let utry = self.get_unitary(params);
+        if utry.nrows() != target.nrows() || utry.ncols() != target.ncols() {
+            panic!("Target must have the same shape as the circuit unitary.");
         }
 
-        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
-            SpecializedInstruction::Write(w) => (
-                w.buffer.as_matref(&mut self.memory),
-                w.buffer.as_matvecref(&mut self.memory),
-            ),
-            SpecializedInstruction::Matmul(m) => (
-                m.out.as_matref(&mut self.memory),
-                m.out.as_matvecref(&mut self.memory),
-            ),
-            SpecializedInstruction::Kron(k) => (
-                k.out.as_matref(&mut self.memory),
-                k.out.as_matvecref(&mut self.memory),
-            ),
-            SpecializedInstruction::FRPR(f) => (
-                f.out.as_matref(&mut self.memory),
-                f.out.as_matvecref(&mut self.memory),
-            ),
+        let mut acc = C::R::zero();
+        for c in 0..utry.ncols() {
+            for r in 0..utry.nrows() {
+                let diff = (utry[(r, c)] - target[(r, c)]).abs();
+                acc = acc + diff * diff;
+            }
         }
+        (utry, acc.sqrt())
     }
 
-    pub fn write_unitary(&mut self, params: &[C::R], mut out_utry: MatMut<C>) {
+    /// Whether the circuit unitary is within `tol` of `target` in
+    /// Frobenius distance.
+    ///
+    /// # Panics
+    ///
+    /// If `target` does not have the same shape as the circuit unitary.
+    pub fn matches(&mut self, params: &[C::R], target: MatRef<C>, tol: C::R) -> bool {
+        let (_, distance) = self.get_unitary_and_norm(params, target);
+        distance <= tol
+    }
+
+    /// Calculate the circuit unitary for each parameter vector in a batch.
+    ///
+    /// The batch is evaluated on a stacked memory holding one copy of the
+    /// QVM's memory per parameter vector. Every copy starts from the
+    /// warmed-up memory, so the static code runs at most once and its
+    /// results, like the runtime constants, are broadcast over the stack.
+    /// Each dynamic instruction then runs over the whole stack before the
+    /// next, sharing its prepared setup, e.g. an FRPR's strides, between
+    /// every instance.
+    ///
+    /// Batches larger than 64 vectors are evaluated 64 at a time, reusing
+    /// the same stack.
+    pub fn get_unitaries_batched<P: AsRef<[C::R]>>(
+        &mut self,
+        param_batch: &[P],
+    ) -> Vec<Mat<C>> {
+        if param_batch.is_empty() {
+            return Vec::new();
+        }
         self.first_run();
 
-        for inst in
-            &self.dynamic_instructions[..self.dynamic_instructions.len() - 1]
-        {
-            inst.execute_unitary(params, &mut self.memory);
+        if self.dynamic_instructions.is_empty() {
+            let utry = self.output_buffer().as_matref(&self.memory).to_owned();
+            return vec![utry; param_batch.len()];
         }
 
-        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
-            SpecializedInstruction::Write(w) => {
-                w.execute_unitary_into(params, &mut self.memory, out_utry)
-            },
-            SpecializedInstruction::Matmul(m) => {
-                m.execute_unitary_into(&mut self.memory, out_utry)
-            },
-            SpecializedInstruction::Kron(k) => {
-                k.execute_unitary_into(&mut self.memory, out_utry)
-            },
-            SpecializedInstruction::FRPR(f) => {
-                let input_matref = f.input.as_matref(&mut self.memory);
-                unsafe {
-                    fused_reshape_permute_reshape_into_impl(
-                        input_matref,
-                        f.out.as_matmut::<C>(&mut self.memory),
-                        &f.ins[..f.len],
-                        &f.outs[..f.len],
-                        &f.dims[..f.len],
-                    );
-                }
-
-                // CODE SMELL: Read after write aliasing; no UB yet, but lets get rid of this asap
-                let out_matref = f.out.as_matref::<C>(&mut self.memory);
-
-                // TODO: In buffer optimization, track output buffer, ensure it lines up with faer
-                // standards to avoid this:
-                // Need to manually copy the data over since the col_stride of out_utry may be
-                // different than the frpr is designed for... bummer
-                for i in 0..out_matref.nrows() {
-                    for j in 0..out_matref.ncols() {
-                        *out_utry.rb_mut().get_mut(i, j) = out_matref[(i, j)];
-                    }
-                }
-            },
+        let stride = self.mem_size.next_multiple_of(BATCH_INSTANCE_ALIGN);
+        let num_instances = param_batch.len().min(MAX_BATCH_INSTANCES);
+        let mut stack = alloc_zeroed_memory::<C>(stride * num_instances);
+        for b in 0..num_instances {
+            // Safety: Each instance holds `stride >= mem_size` elements
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    self.memory.as_ptr(),
+                    stack.as_mut_ptr().add(b * stride),
+                    self.mem_size,
+                );
+            }
         }
-    }
 
-    pub fn write_unitary_and_gradient(
-        &mut self,
-        params: &[C::R],
-        mut out_utry: MatMut<C>,
-        mut out_grad: MatVecMut<C>,
-    ) {
-        if !self.diff_lvl.gradient_capable() {
-            panic!("QVM is not gradient capable, cannot calculate gradient.");
+        // Intermediates are overwritten and expressions rewrite the entries
+        // they define, so later chunks reuse the stack without a fresh copy
+        let out_buffer = self.output_buffer().clone();
+        let mut out = Vec::with_capacity(param_batch.len());
+        for chunk in param_batch.chunks(MAX_BATCH_INSTANCES) {
+            for inst in &self.dynamic_instructions {
+                inst.execute_unitary_batched(chunk, &mut stack, stride);
+            }
+            out.extend(
+                (0..chunk.len())
+                    .map(|b| out_buffer.shifted(b * stride).as_matref::<C>(&stack).to_owned()),
+            );
         }
+        out
+    }
 
-        self.first_run();
+    /// Calculate the circuit unitary for each parameter set.
+    ///
+    /// See [QVM::get_unitaries_batched], which also accepts owned
+    /// parameter vectors.
+    pub fn get_unitaries(&mut self, param_sets: &[&[C::R]]) -> Vec<Mat<C>> {
+        self.get_unitaries_batched(param_sets)
+    }
 
-        for inst in
-            &self.dynamic_instructions[..self.dynamic_instructions.len() - 1]
-        {
-            inst.execute_unitary_and_gradient(params, &mut self.memory);
+    /// Apply the circuit to many input states at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    /// * `inputs` - A matrix whose columns are the input state vectors.
+    ///
+    /// # Returns
+    ///
+    /// A matrix whose columns are the evolved states, in the same order.
+    ///
+    /// # Panics
+    ///
+    /// If `inputs` does not have one row per dimension of the circuit.
+    pub fn apply_to_states(&mut self, params: &[C::R], inputs: MatRef<C>) -> Mat<C> {
+        self.apply_to_state(params, inputs)
+    }
+
+    /// Apply the circuit to `state`.
+    ///
+    /// A state program, compiled with [crate::compile_for_state], carries
+    /// the state through the whole tree: each column of `state` is written
+    /// into the program's input state, and every gate is applied to it as a
+    /// local contraction on its own qudits. No buffer spans the unitary of
+    /// the whole circuit, so this is the way to evolve states of systems
+    /// whose unitary does not fit in memory. Krons of disjoint systems are
+    /// handled alike, by applying each side to the state in turn.
+    ///
+    /// Any other program computes operators. When it ends in a chain of
+    /// matrix multiplications, as built for gates multiplied on the same
+    /// qudits, each factor is applied to the state as soon as it has been
+    /// computed, so the chain's products are never formed; every factor
+    /// still spans the whole circuit. Otherwise, including most trees from
+    /// [crate::TreeBuilder] and the kron of disjoint systems, the unitary
+    /// is formed and multiplied by `state`.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    /// * `state` - A matrix whose columns are the input state vectors.
+    ///
+    /// # Panics
+    ///
+    /// If `state` does not have one row per dimension of the circuit.
+    pub fn apply_to_state(&mut self, params: &[C::R], state: MatRef<C>) -> Mat<C> {
+        if let Some(input) = self.state_input() {
+            let input = input.clone();
+            return self.run_state_program(params, &input, state);
         }
 
-        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
-            SpecializedInstruction::Write(w) => w
-                .execute_unitary_and_gradient_into(
-                    params,
-                    &mut self.memory,
-                    out_utry,
-                    out_grad,
-                ),
-            SpecializedInstruction::Matmul(m) => m
-                .execute_unitary_and_gradient_into(
-                    &mut self.memory,
-                    out_utry,
-                    out_grad,
-                ),
-            SpecializedInstruction::Kron(k) => k
-                .execute_unitary_and_gradient_into(
-                    &mut self.memory,
-                    out_utry,
-                    out_grad,
-                ),
-            SpecializedInstruction::FRPR(f) => {
-                let input_matref = f.input.as_matref::<C>(&mut self.memory);
-                let out_matmut = f.out.as_matmut(&mut self.memory);
-                unsafe {
-                    fused_reshape_permute_reshape_into_impl(
-                        input_matref,
-                        out_matmut,
-                        &f.ins[..f.len],
-                        &f.outs[..f.len],
-                        &f.dims[..f.len],
-                    );
-                }
-
-                // CODE SMELL: Read after write aliasing; no UB yet, but lets get rid of this asap
-                let out_matref = f.out.as_matref::<C>(&mut self.memory);
-
-                // TODO: Seriously, get on this
-                // TODO: In buffer optimization, track output buffer, ensure it lines up with faer
-                // standards to avoid this:
-                // Need to manually copy the data over since the col_stride of out_utry may be
-                // different than the frpr is designed for... bummer
-                for i in 0..out_matref.nrows() {
-                    for j in 0..out_matref.ncols() {
-                        *out_utry.rb_mut().get_mut(i, j) = out_matref[(i, j)];
-                    }
-                }
+        if state.nrows() != self.output_buffer().ncols {
+            panic!("Input states must have one row per dimension of the circuit.");
+        }
 
-                for i in 0..f.input.num_params as isize {
-                    let input_gradref =
-                        f.input.as_matref::<C>(&mut self.memory);
-                    let out_gradmut = f.out.as_matmut::<C>(&mut self.memory);
-                    unsafe {
-                        fused_reshape_permute_reshape_into_impl(
-                            input_gradref,
-                            out_gradmut,
-                            &f.ins[..f.len],
-                            &f.outs[..f.len],
-                            &f.dims[..f.len],
-                        );
-                    }
-                    // CODE SMELL: Read after write aliasing; no UB yet, but lets get rid of this asap
-                    let out_gradref = f.out.as_matref(&mut self.memory);
-
-                    // TODO: In buffer optimization, track output buffer, ensure it lines up with faer
-                    // standards to avoid this:
-                    // Need to manually copy the data over since the col_stride of out_utry may be
-                    // different than the frpr is designed for... bummer
-                    for r in 0..out_gradref.nrows() {
-                        for c in 0..out_gradref.ncols() {
-                            out_grad.write(
-                                i as usize,
-                                r,
-                                c,
-                                out_gradref[(r, c)],
-                            );
-                        }
-                    }
-                }
-            },
+        let chain = self.state_chain();
+        if !chain.iter().any(|&c| c) {
+            return self.get_unitary(params) * state;
+        }
+
+        self.first_run();
+        let mut out: Option<Mat<C>> = None;
+        for (inst, &in_chain) in self.dynamic_instructions.iter().zip(chain.iter()) {
+            match inst {
+                SpecializedInstruction::Matmul(m) if in_chain => {
+                    // The chain's deepest factor is its right operand; every
+                    // other right operand is the chain itself.
+                    let applied = match out.take() {
+                        Some(applied) => applied,
+                        None => m.right.as_matref::<C>(&self.memory) * state,
+                    };
+                    out = Some(m.left.as_matref::<C>(&self.memory) * applied.as_ref());
+                },
+                _ => inst.execute_unitary(params, &mut self.memory),
+            }
         }
+        out.expect("State chain contains at least one multiplication.")
     }
 
-    pub fn write_unitary_gradient_and_hessian(
+    /// The buffer holding the input state, if this is a state program.
+    ///
+    /// See [crate::bytecode::Bytecode::state_input].
+    fn state_input(&self) -> Option<&SizedMatrixBuffer> {
+        self.dynamic_instructions.iter().find_map(|inst| match inst {
+            SpecializedInstruction::Apply(a) => Some(&a.state),
+            _ => None,
+        })
+    }
+
+    /// Evolve each column of `state` through a state program in turn.
+    fn run_state_program(
         &mut self,
         params: &[C::R],
-        mut out_utry: MatMut<C>,
-        mut out_grad: MatVecMut<C>,
-        mut out_hess: SymSqMatMatMut<C>,
-    ) {
-        if !self.diff_lvl.hessian_capable() {
-            panic!("QVM is not gradient capable, cannot calculate gradient.");
+        input: &SizedMatrixBuffer,
+        state: MatRef<C>,
+    ) -> Mat<C> {
+        if state.nrows() != input.nrows {
+            panic!("Input states must have one row per dimension of the circuit.");
         }
 
         self.first_run();
+        let mut out = Mat::zeros(state.nrows(), state.ncols());
+        for c in 0..state.ncols() {
+            let mut input_matmut = input.as_matmut::<C>(&mut self.memory);
+            for r in 0..state.nrows() {
+                *input_matmut.rb_mut().get_mut(r, 0) = state[(r, c)];
+            }
 
-        for inst in
-            &self.dynamic_instructions[..self.dynamic_instructions.len() - 1]
-        {
-            inst.execute_unitary_gradient_and_hessian(
-                params,
-                &mut self.memory,
-            );
+            for inst in &self.dynamic_instructions {
+                inst.execute_unitary(params, &mut self.memory);
+            }
+
+            let evolved = self.output_buffer().as_matref::<C>(&self.memory);
+            for r in 0..state.nrows() {
+                out[(r, c)] = evolved[(r, 0)];
+            }
         }
+        out
+    }
 
-        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
-            SpecializedInstruction::Write(w) => w
-                .execute_unitary_gradient_and_hessian_into(
-                    params,
-                    &mut self.memory,
-                    out_utry,
-                    out_grad,
-                    out_hess,
-                ),
-            SpecializedInstruction::Matmul(m) => m
-                .execute_unitary_gradient_and_hessian_into(
-                    &mut self.memory,
-                    out_utry,
-                    out_grad,
-                    out_hess,
-                ),
-            SpecializedInstruction::Kron(k) => k
-                .execute_unitary_gradient_and_hessian_into(
-                    &mut self.memory,
-                    out_utry,
-                    out_grad,
-                    out_hess,
-                ),
-            SpecializedInstruction::FRPR(f) => {
-                f.execute_unitary_gradient_and_hessian::<C>(&mut self.memory);
+    /// Mark the dynamic instructions that form the multiplication chain
+    /// ending the program.
+    ///
+    /// The chain starts at a terminal matmul and follows the producer of
+    /// each right operand while it is another matmul.
+    fn state_chain(&self) -> Vec<bool> {
+        let mut chain = vec![false; self.dynamic_instructions.len()];
+        let mut idx = self.dynamic_instructions.len().checked_sub(1);
+        while let Some(i) = idx {
+            let SpecializedInstruction::Matmul(m) = &self.dynamic_instructions[i] else {
+                break;
+            };
+            chain[i] = true;
+            idx = self.producer_of(i, &m.right);
+        }
+        chain
+    }
 
-                // CODE SMELL: Read after write aliasing; no UB yet, but lets get rid of this asap
-                let out_matref = f.out.as_matref::<C>(&mut self.memory);
-
-                // TODO: Seriously, get on this
-                // TODO: In buffer optimization, track output buffer, ensure it lines up with faer
-                // standards to avoid this:
-                // Need to manually copy the data over since the col_stride of out_utry may be
-                // different than the frpr is designed for... bummer
-                for i in 0..out_matref.nrows() {
-                    for j in 0..out_matref.ncols() {
-                        *out_utry.rb_mut().get_mut(i, j) = out_matref[(i, j)];
-                    }
-                }
+    /// Calculate the partial trace of the circuit unitary.
+    ///
+    /// When the program ends in a matrix multiplication, possibly followed
+    /// by a permutation of its output, the traced output indices are
+    /// contracted against their input indices within that multiplication:
+    /// only the entries of the product on the traced diagonal are computed,
+    /// so the full unitary is never formed. Otherwise, the unitary is
+    /// computed and traced afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    /// * `traced` - The qudits to trace over.
+    ///
+    /// # Returns
+    ///
+    /// A square matrix over the remaining qudits, in their original order.
+    ///
+    /// # Panics
+    ///
+    /// If `traced` contains an invalid or duplicate qudit.
+    pub fn get_partial_trace(&mut self, params: &[C::R], traced: &[usize]) -> Mat<C> {
+        let radices = self.radices.clone();
+        let mut is_traced = vec![false; radices.len()];
+        for &q in traced {
+            if q >= radices.len() || is_traced[q] {
+                panic!("Traced qudits must be distinct and within the circuit.");
+            }
+            is_traced[q] = true;
+        }
 
-                for i in 0..f.input.num_params as isize {
-                    // CODE SMELL: Read after write aliasing; no UB yet, but lets get rid of this asap
-                    let out_gradref = f.out.as_matref::<C>(&mut self.memory);
-
-                    // TODO: In buffer optimization, track output buffer, ensure it lines up with faer
-                    // standards to avoid this:
-                    // Need to manually copy the data over since the col_stride of out_utry may be
-                    // different than the frpr is designed for... bummer
-                    for r in 0..out_gradref.nrows() {
-                        for c in 0..out_gradref.ncols() {
-                            out_grad.write(
-                                i as usize,
-                                r,
-                                c,
-                                out_gradref[(r, c)],
-                            );
-                        }
-                    }
-                }
+        let kept_dim: usize = (0..radices.len())
+            .filter(|&q| !is_traced[q])
+            .map(|q| radices[q] as usize)
+            .product();
+        let traced_dim = radices.dimension() / kept_dim;
 
-                // TODO: URGENT: BAD: WARNING: BUG: FIX: Since I removed the
-                // matrix index to as_matref this hack doesn't even work now.
-                // Seriouslly fix this.
-
-                for p1 in 0..f.input.num_params as isize {
-                    for p2 in p1..f.input.num_params as isize {
-                        // CODE SMELL: Read after write aliasing; no UB yet, but lets get rid of this asap
-                        let out_hessref =
-                            f.out.as_matref::<C>(&mut self.memory);
-
-                        // TODO: In buffer optimization, track output buffer, ensure it lines up with faer
-                        // standards to avoid this:
-                        // Need to manually copy the data over since the col_stride of out_utry may be
-                        // different than the frpr is designed for... bummer
-                        for r in 0..out_hessref.nrows() {
-                            for c in 0..out_hessref.ncols() {
-                                out_hess.write(
-                                    p1 as usize,
-                                    p2 as usize,
-                                    r,
-                                    c,
-                                    out_hessref[(r, c)],
-                                );
-                            }
-                        }
-                    }
-                }
+        // Interleave a kept index and a traced index into a full index.
+        let full_index = |mut kept: usize, mut tr: usize| {
+            let mut idx = 0;
+            let mut stride = 1;
+            for q in (0..radices.len()).rev() {
+                let radix = radices[q] as usize;
+                let digit = if is_traced[q] {
+                    let d = tr % radix;
+                    tr /= radix;
+                    d
+                } else {
+                    let d = kept % radix;
+                    kept /= radix;
+                    d
+                };
+                idx += digit * stride;
+                stride *= radix;
+            }
+            idx
+        };
+
+        let n = self.dynamic_instructions.len();
+        let terminal = match &self.dynamic_instructions[..] {
+            [.., SpecializedInstruction::Matmul(m), SpecializedInstruction::FRPR(f)]
+                if f.input.offset == m.out.offset =>
+            {
+                Some((n - 2, m.left.clone(), m.right.clone(), Some(n - 1)))
             },
-        }
-    }
+            [.., SpecializedInstruction::Matmul(m)] => {
+                Some((n - 1, m.left.clone(), m.right.clone(), None))
+            },
+            _ => None,
+        };
+
+        let Some((start, left, right, frpr)) = terminal else {
+            let utry = self.get_unitary(params);
+            return Mat::from_fn(kept_dim, kept_dim, |r, c| {
+                let mut acc = C::zero();
+                for t in 0..traced_dim {
+                    acc = acc + utry[(full_index(r, t), full_index(c, t))];
+                }
+                acc
+            });
+        };
+
+        self.first_run();
+        for inst in &self.dynamic_instructions[..start] {
+            inst.execute_unitary(params, &mut self.memory);
+        }
+
+        // The entry of the product that the output permutation, if any,
+        // moves to each entry of the unitary.
+        let source = |r: usize, c: usize| match frpr.map(|i| &self.dynamic_instructions[i]) {
+            Some(SpecializedInstruction::FRPR(f)) => f.source_index(r, c),
+            _ => (r, c),
+        };
+
+        let left = left.as_matref::<C>(&self.memory);
+        let right = right.as_matref::<C>(&self.memory);
+        Mat::from_fn(kept_dim, kept_dim, |kr, kc| {
+            let mut acc = C::zero();
+            for t in 0..traced_dim {
+                let (i, j) = source(full_index(kr, t), full_index(kc, t));
+                for k in 0..left.ncols() {
+                    acc = acc + left[(i, k)] * right[(k, j)];
+                }
+            }
+            acc
+        })
+    }
+
+    /// Calculate the effective Hamiltonian `H = i log(U)` of the circuit.
+    ///
+    /// The logarithm is taken through an eigendecomposition of the unitary
+    /// on the principal branch, so a circuit implementing `exp(-iH)` is
+    /// recovered exactly when every eigenvalue of `H` lies in `[-pi, pi)`.
+    ///
+    /// # Panics
+    ///
+    /// If the eigendecomposition of the unitary fails.
+    pub fn effective_hamiltonian(&mut self, params: &[C::R]) -> Mat<C> {
+        let utry = self.get_unitary(params);
+        let evd = utry
+            .eigen()
+            .expect("Failed to compute the eigendecomposition of the circuit unitary.");
+        let vecs = evd.U();
+        let vals = evd.S().column_vector();
+
+        // Eigenvectors of degenerate eigenvalues need not be orthonormal,
+        // so invert rather than take the adjoint.
+        let vecs_inv = vecs.partial_piv_lu().inverse();
+        let log_vals = Col::<C>::from_fn(vals.nrows(), |i| {
+            // i * (a + ib) = -b + ia
+            let log = vals[i].ln();
+            C::new(-log.imag(), log.real())
+        });
+
+        let scaled = Mat::<C>::from_fn(vecs.nrows(), vecs.ncols(), |r, c| vecs[(r, c)] * log_vals[c]);
+        scaled * vecs_inv
+    }
+
+    /// Calculate the diagonal of the circuit unitary.
+    ///
+    /// A diagonal program, compiled with [crate::compile_for_diagonal], is
+    /// run on the all-ones state: each gate multiplies it by the gate's
+    /// diagonal, so the output is the diagonal of the unitary, and no
+    /// buffer spans more than one column over the whole system. Each gate
+    /// is checked to be diagonal at `params` before it is applied, since
+    /// expressions cannot report whether they are.
+    ///
+    /// Any other operator program computes the full unitary and copies its
+    /// diagonal out.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    ///
+    /// # Returns
+    ///
+    /// A column vector of length equal to the dimension of the circuit.
+    ///
+    /// # Panics
+    ///
+    /// - If a gate of a diagonal program has a nonzero off-diagonal entry.
+    /// - If the program was compiled with [crate::compile_for_state], whose
+    ///   output is a state rather than the unitary.
+    pub fn get_diagonal(&mut self, params: &[C::R]) -> Col<C> {
+        let Some(input) = self.state_input() else {
+            let utry = self.get_unitary(params);
+            return Col::from_fn(utry.nrows(), |i| utry[(i, i)]);
+        };
+        let input = input.clone();
+
+        self.first_run();
+        let mut input_matmut = input.as_matmut::<C>(&mut self.memory);
+        for r in 0..input.nrows {
+            *input_matmut.rb_mut().get_mut(r, 0) = C::one();
+        }
+
+        for inst in &self.dynamic_instructions {
+            if let SpecializedInstruction::Apply(a) = inst {
+                if !a.diagonal {
+                    panic!("The diagonal of a state program cannot be computed.");
+                }
+                if !a.op_is_diagonal(&self.memory) {
+                    panic!("Circuit is not diagonal at the given parameters.");
+                }
+            }
+            inst.execute_unitary(params, &mut self.memory);
+        }
+
+        let diagonal = self.output_buffer().as_matref::<C>(&self.memory);
+        Col::from_fn(input.nrows, |i| diagonal[(i, 0)])
+    }
+
+    /// Estimate the sparsity of the circuit unitary.
+    ///
+    /// This is useful to decide whether a sparse representation of the
+    /// unitary is worthwhile for downstream solvers.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    /// * `tol` - Entries with a magnitude at most `tol` are considered zero.
+    ///
+    /// # Returns
+    ///
+    /// The fraction of entries of the unitary that are near zero.
+    pub fn estimate_sparsity(&mut self, params: &[C::R], tol: C::R) -> f64 {
+        let utry = self.get_unitary(params);
+        let mut num_zeros = 0usize;
+        for c in 0..utry.ncols() {
+            for r in 0..utry.nrows() {
+                if utry[(r, c)].abs() <= tol {
+                    num_zeros += 1;
+                }
+            }
+        }
+        num_zeros as f64 / (utry.nrows() * utry.ncols()) as f64
+    }
+
+    /// Record the duration of the first and a subsequent unitary evaluation.
+    ///
+    /// The first-run timing is only meaningful on a freshly constructed QVM,
+    /// otherwise both fields measure steady-state evaluations.
+    pub fn time_unitary(&mut self, params: &[C::R], timings: &mut Timings) {
+        let now = Instant::now();
+        self.get_unitary(params);
+        timings.first_run = Some(now.elapsed());
+
+        let now = Instant::now();
+        self.get_unitary(params);
+        timings.steady_state = Some(now.elapsed());
+    }
+
+    /// Find the dynamic instructions on the critical path of an evaluation.
+    ///
+    /// Each dynamic instruction is timed over several evaluations, keeping
+    /// its fastest run to reduce noise. An instruction depends on the most
+    /// recent instruction that wrote each buffer it reads, and the critical
+    /// path is the dependency chain with the longest total duration.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    ///
+    /// # Returns
+    ///
+    /// The indices of the instructions on the critical path in the dynamic
+    /// code, in execution order. This is empty if there is no dynamic code.
+    pub fn critical_path(&mut self, params: &[C::R]) -> Vec<usize> {
+        const NUM_RUNS: usize = 8;
+
+        self.first_run();
+        let num_insts = self.dynamic_instructions.len();
+        let mut durations = vec![f64::INFINITY; num_insts];
+        for _ in 0..NUM_RUNS {
+            for (i, inst) in self.dynamic_instructions.iter().enumerate() {
+                let now = Instant::now();
+                inst.execute_unitary(params, &mut self.memory);
+                durations[i] = durations[i].min(now.elapsed().as_secs_f64());
+            }
+        }
+
+        // Longest finishing time of a dependency chain ending at each instruction
+        let mut finish = vec![0.0; num_insts];
+        let mut pred: Vec<Option<usize>> = vec![None; num_insts];
+        for (i, inst) in self.dynamic_instructions.iter().enumerate() {
+            for input in inst.in_buffers() {
+                if let Some(j) = self.producer_of(i, input) {
+                    if pred[i].is_none() || finish[j] > finish[pred[i].unwrap()] {
+                        pred[i] = Some(j);
+                    }
+                }
+            }
+            finish[i] = durations[i] + pred[i].map_or(0.0, |j| finish[j]);
+        }
+
+        let mut path = Vec::new();
+        let mut idx = (0..num_insts).max_by(|&a, &b| finish[a].total_cmp(&finish[b]));
+        while let Some(i) = idx {
+            path.push(i);
+            idx = pred[i];
+        }
+        path.reverse();
+        path
+    }
+
+    /// Calculate the circuit unitary and its gradient.
+    ///
+    /// If the program ties its parameters with a [ParameterMap], there is
+    /// one gradient matrix per global parameter.
+    ///
+    /// # Panics
+    ///
+    /// If the QVM is not gradient capable.
+    pub fn get_unitary_and_gradient(
+        &mut self,
+        params: &[C::R],
+    ) -> (MatRef<C>, MatVecRef<C>) {
+        self.try_get_unitary_and_gradient(params).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Calculate the unitary and gradient as in
+    /// [QVM::get_unitary_and_gradient], returning an error instead of
+    /// panicking if the QVM is not gradient capable.
+    pub fn try_get_unitary_and_gradient(
+        &mut self,
+        params: &[C::R],
+    ) -> Result<(MatRef<C>, MatVecRef<C>), QuditTreeError> {
+        self.check_gradient_capable()?;
+
+        self.first_run();
+
+        for inst in &self.dynamic_instructions {
+            inst.execute_unitary_and_gradient(params, &mut self.memory);
+        }
+
+        let out = self.output_buffer().clone();
+        match (&self.parameter_map, &mut self.tied_gradient) {
+            (Some(map), Some((tied, memory))) => {
+                let grad = out.as_matvecref(&self.memory);
+                tie_gradient(map, grad, out.nrows, out.ncols, tied.as_matvecmut(memory));
+                Ok((out.as_matref(&self.memory), tied.as_matvecref(memory)))
+            },
+            _ => Ok((out.as_matref(&self.memory), out.as_matvecref(&self.memory))),
+        }
+    }
+
+    /// Calculate the gradient as one owned matrix per parameter.
+    ///
+    /// If the program ties its parameters with a [ParameterMap], there is
+    /// one matrix per global parameter, as with every gradient the QVM
+    /// computes.
+    ///
+    /// # Panics
+    ///
+    /// If the QVM is not gradient capable.
+    pub fn get_gradient_matrices(&mut self, params: &[C::R]) -> Vec<Mat<C>> {
+        let (_, grad) = self.get_unitary_and_gradient(params);
+        (0..grad.nmats()).map(|i| grad.mat_ref(i).to_owned()).collect()
+    }
+
+    /// Write the gradient's real and imaginary parts into separate arrays.
+    ///
+    /// This structure-of-arrays layout maps directly onto device buffers.
+    /// The derivative for parameter `i` at row `r` and column `c` is stored
+    /// at index `i * nrows * ncols + c * nrows + r` of both arrays.
+    ///
+    /// # Panics
+    ///
+    /// - If the QVM is not gradient capable.
+    /// - If either output does not have one entry per gradient element.
+    pub fn write_gradient_soa(
+        &mut self,
+        params: &[C::R],
+        out_real: &mut [C::R],
+        out_imag: &mut [C::R],
+    ) {
+        let (utry, grad) = self.get_unitary_and_gradient(params);
+        let (nrows, ncols) = (utry.nrows(), utry.ncols());
+        let len = grad.nmats() * nrows * ncols;
+        if out_real.len() != len || out_imag.len() != len {
+            panic!("Outputs must have one entry per gradient element.");
+        }
+
+        for i in 0..grad.nmats() {
+            let mat = grad.mat_ref(i);
+            for c in 0..ncols {
+                for r in 0..nrows {
+                    let idx = i * nrows * ncols + c * nrows + r;
+                    out_real[idx] = mat[(r, c)].real();
+                    out_imag[idx] = mat[(r, c)].imag();
+                }
+            }
+        }
+    }
+
+    /// Calculate a scalar cost of the circuit unitary and its gradient.
+    ///
+    /// The cost's derivative with respect to the unitary is supplied by
+    /// `cost_grad` as a matrix `G`, so that each parameter's derivative is
+    /// `Re(sum_rc conj(G[r, c]) * dU[r, c]/dparams[i])`. For example, the
+    /// squared Frobenius distance `|U - T|^2` has `G = 2(U - T)`.
+    ///
+    /// The gradient is computed in reverse mode: after a forward evaluation
+    /// of the unitary, `G` is propagated backwards through the dynamic
+    /// instructions to each expression, so the derivative of the unitary
+    /// with respect to every parameter is never formed. Only the operands
+    /// of each multiplication and kronecker product are kept from the
+    /// forward pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    /// * `cost_fn` - The cost as a function of the unitary.
+    /// * `cost_grad` - The derivative of the cost with respect to the unitary.
+    ///
+    /// # Returns
+    ///
+    /// The cost and its derivative with respect to each parameter, or each
+    /// global parameter if the program ties its parameters.
+    ///
+    /// # Panics
+    ///
+    /// If the QVM is not gradient capable.
+    pub fn cost_gradient(
+        &mut self,
+        params: &[C::R],
+        cost_fn: impl Fn(MatRef<C>) -> C::R,
+        cost_grad: impl Fn(MatRef<C>) -> Mat<C>,
+    ) -> (C::R, Vec<C::R>) {
+        self.check_gradient_capable().unwrap_or_else(|e| panic!("{}", e));
+
+        self.first_run();
+
+        // Operand buffers may be reused by later instructions, so the
+        // operands needed by the backward pass are copied out.
+        let mut operands: Vec<Vec<Mat<C>>> = Vec::with_capacity(self.dynamic_instructions.len());
+        for inst in &self.dynamic_instructions {
+            operands.push(match inst {
+                SpecializedInstruction::Matmul(_)
+                | SpecializedInstruction::Kron(_)
+                | SpecializedInstruction::Apply(_) => inst
+                    .in_buffers()
+                    .iter()
+                    .map(|b| b.as_matref::<C>(&self.memory).to_owned())
+                    .collect(),
+                _ => Vec::new(),
+            });
+            inst.execute_unitary(params, &mut self.memory);
+        }
+
+        let utry = self.output_buffer().as_matref::<C>(&self.memory);
+        let cost = cost_fn(utry);
+        let g = cost_grad(utry);
+
+        let num_params = match &self.parameter_map {
+            Some(map) => map.num_global_params(),
+            None => self.output_buffer().num_params,
+        };
+        let mut param_grads = vec![C::R::zero(); num_params];
+
+        // The derivative of the cost with respect to each instruction's output
+        let mut adjoints: Vec<Option<Mat<C>>> = vec![None; self.dynamic_instructions.len()];
+        if let Some(last) = adjoints.last_mut() {
+            *last = Some(g);
+        }
+
+        for i in (0..self.dynamic_instructions.len()).rev() {
+            let Some(adj) = adjoints[i].take() else {
+                continue;
+            };
+
+            let in_adjoints = match &self.dynamic_instructions[i] {
+                SpecializedInstruction::Write(w) => {
+                    // Expression writes read consecutive global parameters
+                    // starting at `idx`, so tied parameters accumulate here.
+                    w.execute_unitary_and_gradient(params, &mut self.memory);
+                    let grad = w.buffer.as_matvecref::<C>(&self.memory);
+                    for p in 0..w.buffer.num_params {
+                        let d_utry = grad.mat_ref(p);
+                        let mut acc = C::zero();
+                        for c in 0..adj.ncols() {
+                            for r in 0..adj.nrows() {
+                                acc = acc + adj[(r, c)].conj() * d_utry[(r, c)];
+                            }
+                        }
+                        param_grads[w.idx + p] = param_grads[w.idx + p] + acc.real();
+                    }
+                    Vec::new()
+                },
+                SpecializedInstruction::Matmul(_) => {
+                    // out = left * right, so the adjoints are
+                    // adj * right^H and left^H * adj.
+                    let (left, right) = (&operands[i][0], &operands[i][1]);
+                    let left_h = Mat::from_fn(left.ncols(), left.nrows(), |r, c| left[(c, r)].conj());
+                    let right_h = Mat::from_fn(right.ncols(), right.nrows(), |r, c| right[(c, r)].conj());
+                    let mut left_adj = Mat::zeros(left.nrows(), left.ncols());
+                    let mut right_adj = Mat::zeros(right.nrows(), right.ncols());
+                    matmul_unchecked(adj.as_ref(), right_h.as_ref(), left_adj.as_mut());
+                    matmul_unchecked(left_h.as_ref(), adj.as_ref(), right_adj.as_mut());
+                    vec![left_adj, right_adj]
+                },
+                SpecializedInstruction::Kron(_) => {
+                    let (left, right) = (&operands[i][0], &operands[i][1]);
+                    let (rr, rc) = (right.nrows(), right.ncols());
+                    let left_adj = Mat::from_fn(left.nrows(), left.ncols(), |lr, lc| {
+                        let mut acc = C::zero();
+                        for c in 0..rc {
+                            for r in 0..rr {
+                                acc = acc + adj[(lr * rr + r, lc * rc + c)] * right[(r, c)].conj();
+                            }
+                        }
+                        acc
+                    });
+                    let right_adj = Mat::from_fn(rr, rc, |r, c| {
+                        let mut acc = C::zero();
+                        for lc in 0..left.ncols() {
+                            for lr in 0..left.nrows() {
+                                acc = acc + adj[(lr * rr + r, lc * rc + c)] * left[(lr, lc)].conj();
+                            }
+                        }
+                        acc
+                    });
+                    vec![left_adj, right_adj]
+                },
+                SpecializedInstruction::FRPR(f) => {
+                    let mut in_adj = Mat::zeros(f.input.nrows, f.input.ncols);
+                    for c in 0..adj.ncols() {
+                        for r in 0..adj.nrows() {
+                            let (sr, sc) = f.source_index(r, c);
+                            in_adj[(sr, sc)] = in_adj[(sr, sc)] + adj[(r, c)];
+                        }
+                    }
+                    vec![in_adj]
+                },
+                SpecializedInstruction::Conj(_) => {
+                    vec![Mat::from_fn(adj.nrows(), adj.ncols(), |r, c| adj[(r, c)].conj())]
+                },
+                SpecializedInstruction::Apply(a) => {
+                    let (op, state) = (&operands[i][0], &operands[i][1]);
+                    let (op_adj, state_adj) = a.adjoints(op.as_ref(), state.as_ref(), adj.as_ref());
+                    vec![op_adj, state_adj]
+                },
+            };
+
+            // Operands produced by static code do not depend on parameters.
+            let inputs = self.dynamic_instructions[i].in_buffers();
+            for (input, in_adj) in inputs.into_iter().zip(in_adjoints) {
+                let Some(j) = self.producer_of(i, input) else {
+                    continue;
+                };
+                match &mut adjoints[j] {
+                    Some(acc) => {
+                        for c in 0..acc.ncols() {
+                            for r in 0..acc.nrows() {
+                                acc[(r, c)] = acc[(r, c)] + in_adj[(r, c)];
+                            }
+                        }
+                    },
+                    slot => *slot = Some(in_adj),
+                }
+            }
+        }
+
+        (cost, param_grads)
+    }
+
+    /// Calculate the derivative of the circuit unitary along a direction in
+    /// parameter space.
+    ///
+    /// This is the contraction of the gradient with `direction`, i.e.
+    /// `sum_i direction[i] * dU/dparams[i]`, and does not require forming
+    /// the hessian.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    /// * `direction` - The direction to differentiate along, with one entry
+    ///   per parameter.
+    ///
+    /// # Panics
+    ///
+    /// - If the QVM is not gradient capable.
+    /// - If `direction` does not have one entry per parameter.
+    pub fn get_directional_derivative(
+        &mut self,
+        params: &[C::R],
+        direction: &[C::R],
+    ) -> Mat<C> {
+        let (utry, grad) = self.get_unitary_and_gradient(params);
+
+        if direction.len() != grad.nmats() {
+            panic!("Direction must have one entry per parameter.");
+        }
+
+        Mat::from_fn(utry.nrows(), utry.ncols(), |r, c| {
+            let mut acc = C::zero();
+            for (i, d) in direction.iter().enumerate() {
+                acc = acc + C::from_real(*d) * grad.mat_ref(i)[(r, c)];
+            }
+            acc
+        })
+    }
+
+    /// Calculate the Choi matrix of the channel described by the circuit.
+    ///
+    /// The Choi matrix is `(I ⊗ U)|Ω⟩⟨Ω|(I ⊗ U)†` where `|Ω⟩ = Σ_i |ii⟩` is the
+    /// unnormalized maximally-entangled state on a reference system.
+    ///
+    /// Only unitary circuits are supported, as every tree leaf is a unitary
+    /// expression, so the result is always rank one. General channels, e.g.
+    /// given by Kraus operators, are out of scope.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to evaluate the circuit at.
+    ///
+    /// # Returns
+    ///
+    /// A square matrix of dimension `d^2`, where `d` is the dimension of the
+    /// circuit, with the reference system as the first tensor factor.
+    pub fn get_choi(&mut self, params: &[C::R]) -> Mat<C> {
+        let utry = self.get_unitary(params);
+        let dim = utry.nrows();
+
+        // (I ⊗ U)|Ω⟩ has entry U[k, i] at index (i, k)
+        let state = Col::<C>::from_fn(dim * dim, |idx| utry[(idx % dim, idx / dim)]);
+        Mat::from_fn(dim * dim, dim * dim, |r, c| state[r] * state[c].conj())
+    }
+
+    pub fn write_unitary(&mut self, params: &[C::R], mut out_utry: MatMut<C>) {
+        self.first_run();
+
+        if self.dynamic_instructions.is_empty() {
+            self.write_static_output(out_utry);
+            return;
+        }
+
+        for inst in
+            &self.dynamic_instructions[..self.dynamic_instructions.len() - 1]
+        {
+            inst.execute_unitary(params, &mut self.memory);
+        }
+
+        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
+            SpecializedInstruction::Write(w) => {
+                w.execute_unitary_into(params, &mut self.memory, out_utry)
+            },
+            SpecializedInstruction::Matmul(m) => {
+                m.execute_unitary_into(&mut self.memory, out_utry)
+            },
+            SpecializedInstruction::Kron(k) => {
+                k.execute_unitary_into(&mut self.memory, out_utry)
+            },
+            SpecializedInstruction::Conj(c) => {
+                c.execute_unitary_into(&mut self.memory, out_utry)
+            },
+            SpecializedInstruction::Apply(a) => {
+                a.execute_unitary_into(&mut self.memory, out_utry)
+            },
+            SpecializedInstruction::FRPR(f) => {
+                // The FRPR is prepared for the strides of its own output
+                // buffer, which may differ from the caller's, so permute
+                // into it before copying out.
+                f.execute_unitary::<C>(&mut self.memory);
+
+                let out_matref = f.out.as_matref::<C>(&self.memory);
+                for c in 0..out_matref.ncols() {
+                    for r in 0..out_matref.nrows() {
+                        *out_utry.rb_mut().get_mut(r, c) = out_matref[(r, c)];
+                    }
+                }
+            },
+        }
+    }
+
+    /// Write the circuit unitary and its gradient into `out_utry` and
+    /// `out_grad`.
+    ///
+    /// # Panics
+    ///
+    /// If the QVM is not gradient capable.
+    pub fn write_unitary_and_gradient(
+        &mut self,
+        params: &[C::R],
+        out_utry: MatMut<C>,
+        out_grad: MatVecMut<C>,
+    ) {
+        self.try_write_unitary_and_gradient(params, out_utry, out_grad)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Write the unitary and gradient as in
+    /// [QVM::write_unitary_and_gradient], returning an error instead of
+    /// panicking if the QVM is not gradient capable.
+    pub fn try_write_unitary_and_gradient(
+        &mut self,
+        params: &[C::R],
+        mut out_utry: MatMut<C>,
+        mut out_grad: MatVecMut<C>,
+    ) -> Result<(), QuditTreeError> {
+        self.check_gradient_capable()?;
+
+        self.first_run();
+
+        if self.dynamic_instructions.is_empty() {
+            // A constant program has no parameters, so no gradient to write.
+            self.write_static_output(out_utry);
+            return Ok(());
+        }
+
+        if self.parameter_map.is_some() {
+            // The tree gradient is summed onto global parameters in the
+            // QVM's own memory, then copied out.
+            let (utry, grad) = self.get_unitary_and_gradient(params);
+            for c in 0..utry.ncols() {
+                for r in 0..utry.nrows() {
+                    *out_utry.rb_mut().get_mut(r, c) = utry[(r, c)];
+                    for i in 0..grad.nmats() {
+                        out_grad.write(i, r, c, grad.mat_ref(i)[(r, c)]);
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        for inst in
+            &self.dynamic_instructions[..self.dynamic_instructions.len() - 1]
+        {
+            inst.execute_unitary_and_gradient(params, &mut self.memory);
+        }
+
+        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
+            SpecializedInstruction::Write(w) => w
+                .execute_unitary_and_gradient_into(
+                    params,
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                ),
+            SpecializedInstruction::Matmul(m) => m
+                .execute_unitary_and_gradient_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                ),
+            SpecializedInstruction::Kron(k) => k
+                .execute_unitary_and_gradient_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                ),
+            SpecializedInstruction::Conj(c) => c
+                .execute_unitary_and_gradient_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                ),
+            SpecializedInstruction::Apply(a) => a
+                .execute_unitary_and_gradient_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                ),
+            SpecializedInstruction::FRPR(f) => {
+                // The FRPR is prepared for the strides of its own output
+                // buffer, which may differ from the caller's, so permute the
+                // unitary and each gradient matrix into it before copying
+                // them out.
+                f.execute_unitary_and_gradient::<C>(&mut self.memory);
+
+                let out_matref = f.out.as_matref::<C>(&self.memory);
+                let out_gradref = f.out.as_matvecref::<C>(&self.memory);
+                let (nrows, ncols) = (out_matref.nrows(), out_matref.ncols());
+
+                for r in 0..nrows {
+                    for c in 0..ncols {
+                        *out_utry.rb_mut().get_mut(r, c) = out_matref[(r, c)];
+                    }
+                }
+
+                for i in 0..f.out.num_params {
+                    let gradref = out_gradref.mat_ref(i);
+                    for r in 0..nrows {
+                        for c in 0..ncols {
+                            out_grad.write(i, r, c, gradref[(r, c)]);
+                        }
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Write the circuit unitary, its gradient, and its hessian into
+    /// `out_utry`, `out_grad`, and `out_hess`.
+    ///
+    /// # Panics
+    ///
+    /// If the QVM is not hessian capable.
+    pub fn write_unitary_gradient_and_hessian(
+        &mut self,
+        params: &[C::R],
+        out_utry: MatMut<C>,
+        out_grad: MatVecMut<C>,
+        out_hess: SymSqMatMatMut<C>,
+    ) {
+        self.try_write_unitary_gradient_and_hessian(params, out_utry, out_grad, out_hess)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Write the unitary, gradient, and hessian as in
+    /// [QVM::write_unitary_gradient_and_hessian], returning an error instead
+    /// of panicking if the QVM is not hessian capable.
+    pub fn try_write_unitary_gradient_and_hessian(
+        &mut self,
+        params: &[C::R],
+        mut out_utry: MatMut<C>,
+        mut out_grad: MatVecMut<C>,
+        mut out_hess: SymSqMatMatMut<C>,
+    ) -> Result<(), QuditTreeError> {
+        self.check_hessian_capable()?;
+
+        self.first_run();
+
+        if self.dynamic_instructions.is_empty() {
+            // A constant program has no parameters, so no gradient or
+            // hessian to write.
+            self.write_static_output(out_utry);
+            return Ok(());
+        }
+
+        if let Some(map) = &self.parameter_map {
+            // The tree gradient and hessian are summed onto global
+            // parameters as they are copied out.
+            for inst in &self.dynamic_instructions {
+                inst.execute_unitary_gradient_and_hessian(params, &mut self.memory);
+            }
+            let out = self.output_buffer();
+            let utry = out.as_matref::<C>(&self.memory);
+            for c in 0..out.ncols {
+                for r in 0..out.nrows {
+                    *out_utry.rb_mut().get_mut(r, c) = utry[(r, c)];
+                }
+            }
+            tie_gradient(map, out.as_matvecref(&self.memory), out.nrows, out.ncols, out_grad);
+            tie_hessian(map, out.as_symsqmatref(&self.memory), out.nrows, out.ncols, out_hess);
+            return Ok(());
+        }
+
+        for inst in
+            &self.dynamic_instructions[..self.dynamic_instructions.len() - 1]
+        {
+            inst.execute_unitary_gradient_and_hessian(
+                params,
+                &mut self.memory,
+            );
+        }
+
+        match &self.dynamic_instructions[self.dynamic_instructions.len() - 1] {
+            SpecializedInstruction::Write(w) => w
+                .execute_unitary_gradient_and_hessian_into(
+                    params,
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                    out_hess,
+                ),
+            SpecializedInstruction::Matmul(m) => m
+                .execute_unitary_gradient_and_hessian_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                    out_hess,
+                ),
+            SpecializedInstruction::Kron(k) => k
+                .execute_unitary_gradient_and_hessian_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                    out_hess,
+                ),
+            SpecializedInstruction::Conj(c) => c
+                .execute_unitary_gradient_and_hessian_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                    out_hess,
+                ),
+            SpecializedInstruction::Apply(a) => a
+                .execute_unitary_gradient_and_hessian_into(
+                    &mut self.memory,
+                    out_utry,
+                    out_grad,
+                    out_hess,
+                ),
+            SpecializedInstruction::FRPR(f) => {
+                // The FRPR is prepared for the strides of its own output
+                // buffer, which may differ from the caller's, so permute the
+                // unitary, each gradient matrix, and each hessian matrix into
+                // it before copying them out.
+                f.execute_unitary_gradient_and_hessian::<C>(&mut self.memory);
+
+                let out_matref = f.out.as_matref::<C>(&self.memory);
+                let out_gradref = f.out.as_matvecref::<C>(&self.memory);
+                let out_hessref = f.out.as_symsqmatref::<C>(&self.memory);
+                let (nrows, ncols) = (out_matref.nrows(), out_matref.ncols());
+
+                for r in 0..nrows {
+                    for c in 0..ncols {
+                        *out_utry.rb_mut().get_mut(r, c) = out_matref[(r, c)];
+                    }
+                }
+
+                for i in 0..f.out.num_params {
+                    let gradref = out_gradref.mat_ref(i);
+                    for r in 0..nrows {
+                        for c in 0..ncols {
+                            out_grad.write(i, r, c, gradref[(r, c)]);
+                        }
+                    }
+                }
+
+                for p1 in 0..f.out.num_params {
+                    for p2 in p1..f.out.num_params {
+                        let hessref = out_hessref.mat_ref(p1, p2);
+                        for r in 0..nrows {
+                            for c in 0..ncols {
+                                out_hess.write(p1, p2, r, c, hessref[(r, c)]);
+                            }
+                        }
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
 }
 
-// TODO: TEST: No params in entire circuit, constant everything
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use faer::Mat;
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use qudit_core::memory::alloc_zeroed_memory;
+    use qudit_core::memory::calc_col_stride;
+    use qudit_core::memory::calc_mat_stride;
+    use qudit_core::memory::MemoryBuffer;
+    use qudit_core::QuditSystem;
+
+    use super::warm_up;
+    use super::QVM;
+    use crate::bytecode::GeneralizedInstruction;
+    use crate::bytecode::ParameterMap;
+    use crate::bytecode::SizedMatrixBuffer;
+    use crate::bytecode::SpecializedInstruction;
+    use crate::bytecode::WarmupStrategy;
+    use crate::compiler::compile;
+    use crate::compiler::compile_for_diagonal;
+    use crate::compiler::compile_for_state;
+    use crate::compiler::compile_with_parameter_map;
+    use crate::compiler::try_compile_with_parameter_map;
+    use crate::error::QuditTreeError;
+    use crate::fixtures;
+    use crate::fixtures::parallel_phases;
+    use crate::tree::BuilderExpressionInput;
+    use crate::tree::ContractNode;
+    use crate::tree::ExpressionTree;
+    use crate::tree::RuntimeConstantNode;
+    use crate::tree::TreeBuilder;
+    use crate::tree::TreeOptimizer;
+
+    /// Check the hessian from `write_unitary_gradient_and_hessian` against
+    /// central finite differences of the gradient.
+    fn assert_hessian_matches_finite_differences(tree: &ExpressionTree, params: &[f64]) {
+        let mut qvm = QVM::<c64>::new(compile(tree), DifferentiationLevel::Hessian);
+        let dim = tree.dimension();
+        let n = params.len();
+
+        let col_stride = calc_col_stride::<c64>(dim, dim);
+        let mat_stride = calc_mat_stride::<c64>(dim, dim, col_stride);
+        let buffer = SizedMatrixBuffer {
+            offset: 0,
+            nrows: dim,
+            ncols: dim,
+            col_stride: col_stride as isize,
+            mat_stride: mat_stride as isize,
+            num_params: n,
+        };
+        let size = mat_stride * (1 + n + n * (n + 1) / 2);
+        let mut utry = alloc_zeroed_memory::<c64>(size);
+        let mut grad = alloc_zeroed_memory::<c64>(size);
+        let mut hess = alloc_zeroed_memory::<c64>(size);
+        qvm.write_unitary_gradient_and_hessian(
+            params,
+            buffer.as_matmut(&mut utry),
+            buffer.as_matvecmut(&mut grad),
+            buffer.as_symsqmatmut(&mut hess),
+        );
+
+        let h = 1e-5;
+        for p1 in 0..n {
+            let mut plus = params.to_vec();
+            let mut minus = params.to_vec();
+            plus[p1] += h;
+            minus[p1] -= h;
+            let grad_plus = qvm.get_gradient_matrices(&plus);
+            let grad_minus = qvm.get_gradient_matrices(&minus);
+
+            for p2 in 0..n {
+                let actual = buffer.as_symsqmatref(&hess).mat_ref(p1.min(p2), p1.max(p2)).to_owned();
+                for r in 0..dim {
+                    for c in 0..dim {
+                        let expected = (grad_plus[p2][(r, c)] - grad_minus[p2][(r, c)]) / c64::new(2.0 * h, 0.0);
+                        assert!((actual[(r, c)] - expected).norm() < 1e-6);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_leaf_hessian_matches_finite_differences() {
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        assert_hessian_matches_finite_differences(&ry, &[0.9]);
+    }
+
+    #[test]
+    fn test_contraction_root_hessian_matches_finite_differences() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let code = compile(&tree);
+        assert!(matches!(
+            code.dynamic_code.last(),
+            Some(GeneralizedInstruction::FRPR(..))
+        ));
+
+        assert_hessian_matches_finite_differences(&tree, &[0.7, 1.9]);
+    }
+
+    #[test]
+    fn test_get_unitary_block_matches_full_unitary() {
+        let cry = fixtures::cry();
+        let chain = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1]; 3],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let code = compile(&chain);
+        assert!(matches!(
+            code.dynamic_code.last(),
+            Some(GeneralizedInstruction::Matmul(..))
+        ));
+
+        for tree in [chain, parallel_phases()] {
+            let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+            let params = [0.3, 1.7, -0.8];
+            let full = qvm.get_unitary(&params).to_owned();
+
+            for (rows, cols) in [(0..2, 0..2), (1..4, 2..3), (0..4, 0..4)] {
+                let block = qvm.get_unitary_block(&params, rows.clone(), cols.clone());
+                assert_eq!(block.nrows(), rows.len());
+                assert_eq!(block.ncols(), cols.len());
+                for (i, r) in rows.clone().enumerate() {
+                    for (j, c) in cols.clone().enumerate() {
+                        assert!((block[(i, j)] - full[(r, c)]).norm() < 1e-12);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_batched_unitaries_of_three_qudit_circuit() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2], vec![0, 1]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+
+        let param_sets: [&[f64]; 3] = [&[0.1, 0.2, 0.3], &[1.4, -0.7, 2.2], &[3.0, 0.0, -1.1]];
+        let batch = qvm.get_unitaries(&param_sets);
+
+        assert_eq!(batch.len(), param_sets.len());
+        for (params, utry) in param_sets.iter().zip(batch.iter()) {
+            let expected = qvm.get_unitary(params);
+            for r in 0..8 {
+                for c in 0..8 {
+                    assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparsity_of_permutation_and_dense_circuits() {
+        let radices = QuditRadices::from_iter([2, 2, 2]);
+        let cx = fixtures::cx();
+        let ry = fixtures::ry();
+
+        let permutation = TreeBuilder::from_locations(
+            radices.clone(),
+            vec![vec![0, 1], vec![1, 2]],
+            |_| cx.clone(),
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&permutation), DifferentiationLevel::None);
+        assert_eq!(qvm.estimate_sparsity(&[], 1e-12), 56.0 / 64.0);
+
+        let dense = TreeBuilder::from_locations(
+            radices,
+            vec![vec![0], vec![1], vec![2], vec![0, 1], vec![1, 2], vec![0], vec![1], vec![2]],
+            |loc| if loc.len() == 1 { ry.clone() } else { cx.clone() },
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&dense), DifferentiationLevel::None);
+        let params = [0.3, 1.1, -0.7, 2.1, 0.9, -1.3];
+        assert!(qvm.estimate_sparsity(&params, 1e-12) < 0.5);
+    }
+
+    #[test]
+    fn test_critical_path_follows_bottleneck() {
+        let cry = fixtures::cry();
+        let p = fixtures::p();
+
+        // The chain of multiplications on the first two qubits dominates
+        // the phase on the last qubit.
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![0, 1], vec![0, 1], vec![0, 1], vec![2]],
+            |loc| if loc.len() == 1 { p.clone() } else { cry.clone() },
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let path = qvm.critical_path(&[0.1, 0.2, 0.3, 0.4, 0.5]);
+
+        let insts = &qvm.dynamic_instructions;
+        assert_eq!(path.last(), Some(&(insts.len() - 1)));
+        assert!(path.windows(2).all(|w| w[0] < w[1]));
+        assert!(path.iter().any(|&i| matches!(insts[i], SpecializedInstruction::Matmul(_))));
+        assert!(!path.iter().any(|&i| insts[i].out_buffer().nrows == 2));
+    }
+
+    #[test]
+    fn test_warm_up_after_prior_use() {
+        let mut mat = Mat::<c64>::from_fn(4, 4, |r, c| c64::new(r as f64, c as f64));
+        warm_up(mat.as_mut(), WarmupStrategy::Identity);
+        assert_eq!(mat, Mat::<c64>::identity(4, 4));
+
+        // Write buffers left dirty by a previous run are restored by reset
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::None);
+        let params = [0.4, 2.1, 1.2];
+        let expected = qvm.get_unitary(&params).to_owned();
+        assert!(qvm.warmups.iter().any(|(_, s)| *s == WarmupStrategy::Identity));
+        for (buffer, strategy) in qvm.warmups.clone() {
+            if strategy != WarmupStrategy::Identity {
+                continue;
+            }
+            let mut mat = buffer.as_matmut::<c64>(&mut qvm.memory);
+            for c in 0..mat.ncols() {
+                for r in 0..mat.nrows() {
+                    *mat.rb_mut().get_mut(r, c) = c64::new(5.0, -1.0);
+                }
+            }
+        }
+
+        qvm.reset();
+        assert_eq!(qvm.get_unitary(&params), expected.as_ref());
+    }
+
+    #[test]
+    fn test_warm_up_strategies() {
+        let prior = Mat::<c64>::from_fn(3, 3, |r, c| c64::new(r as f64 + 1.0, c as f64));
+
+        let mut mat = prior.clone();
+        warm_up(mat.as_mut(), WarmupStrategy::Zero);
+        assert_eq!(mat, Mat::<c64>::zeros(3, 3));
+
+        let mut mat = prior.clone();
+        warm_up(mat.as_mut(), WarmupStrategy::None);
+        assert_eq!(mat, prior);
+    }
+
+    #[test]
+    fn test_single_gate_gradient_matches_analytic() {
+        let p = ExpressionTree::Leaf(fixtures::p());
+        let mut qvm = QVM::<c64>::new(compile(&p), DifferentiationLevel::Gradient);
+
+        let a: f64 = 0.7;
+        let (utry, grad) = qvm.get_unitary_and_gradient(&[a]);
+        assert_eq!(grad.nmats(), 1);
+
+        // d/da e^(ia) = i e^(ia)
+        let zero = c64::new(0.0, 0.0);
+        let one = c64::new(1.0, 0.0);
+        let phase = c64::new(a.cos(), a.sin());
+        let expected_utry = [[one, zero], [zero, phase]];
+        let expected_grad = [[zero, zero], [zero, phase * c64::new(0.0, 1.0)]];
+        for r in 0..2 {
+            for c in 0..2 {
+                assert!((utry[(r, c)] - expected_utry[r][c]).norm() < 1e-12);
+                assert!((grad.mat_ref(0)[(r, c)] - expected_grad[r][c]).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gradient_matrices_match_gradient() {
+        let tree = parallel_phases();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::Gradient);
+        let params = [0.3, 1.7, 0.5];
+
+        let grads = qvm.get_gradient_matrices(&params);
+        let (_, grad) = qvm.get_unitary_and_gradient(&params);
+        assert_eq!(grads.len(), grad.nmats());
+        for (i, g) in grads.iter().enumerate() {
+            assert_eq!(g.as_ref(), grad.mat_ref(i));
+        }
+    }
+
+    #[test]
+    fn test_tied_parameters_sum_gradients() {
+        let rz = UnitaryExpression::new("RZ(t) { [[e^(~i*t/2), 0], [0, e^(i*t/2)]] }");
+        let tree = TreeBuilder::new(
+            1,
+            vec![
+                BuilderExpressionInput::Unitary(rz.clone()),
+                BuilderExpressionInput::Unitary(rz),
+            ],
+            vec![vec![0], vec![0]],
+            vec![vec![Some(1)], vec![None]],
+            vec![vec![None], vec![Some(0)]],
+        )
+        .build_tree();
+        let code = compile_with_parameter_map(&tree, ParameterMap::new(vec![0, 0]));
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::Gradient);
+
+        // RZ(t) RZ(t) = RZ(2t), so d/dt = diag(-i e^(-it), i e^(it)).
+        let t: f64 = 0.4;
+        let grads = qvm.get_gradient_matrices(&[t]);
+        assert_eq!(grads.len(), 1);
+        let i = c64::new(0.0, 1.0);
+        let expected = [-i * c64::new(t.cos(), -t.sin()), i * c64::new(t.cos(), t.sin())];
+        for k in 0..2 {
+            assert!((grads[0][(k, k)] - expected[k]).norm() < 1e-12);
+        }
+        assert!(grads[0][(0, 1)].norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_tied_parameters_in_every_gradient_path() {
+        let rz = UnitaryExpression::new("RZ(t) { [[e^(~i*t/2), 0], [0, e^(i*t/2)]] }");
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2]),
+            vec![vec![0]; 3],
+            |_| rz.clone(),
+        )
+        .build_tree();
+        let code = compile_with_parameter_map(&tree, ParameterMap::new(vec![0, 0, 0]));
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::Hessian);
+
+        // RZ(t)^3 = RZ(3t), so each derivative brings down a factor of
+        // -3i/2 on the first diagonal entry and 3i/2 on the second.
+        let t: f64 = 0.7;
+        let i = c64::new(0.0, 1.0);
+        let phase = [c64::new(0.0, -1.5 * t).exp(), c64::new(0.0, 1.5 * t).exp()];
+        let rate = [-1.5 * i, 1.5 * i];
+
+        let (_, grad) = qvm.get_unitary_and_gradient(&[t]);
+        assert_eq!(grad.nmats(), 1);
+        for k in 0..2 {
+            assert!((grad.mat_ref(0)[(k, k)] - rate[k] * phase[k]).norm() < 1e-12);
+        }
+
+        let buffer = SizedMatrixBuffer {
+            offset: 0,
+            nrows: 2,
+            ncols: 2,
+            col_stride: calc_col_stride::<c64>(2, 2) as isize,
+            mat_stride: calc_mat_stride::<c64>(2, 2, calc_col_stride::<c64>(2, 2)) as isize,
+            num_params: 1,
+        };
+        let size = 3 * buffer.mat_stride as usize;
+        let (mut utry, mut grad, mut hess) =
+            (alloc_zeroed_memory::<c64>(size), alloc_zeroed_memory::<c64>(size), alloc_zeroed_memory::<c64>(size));
+        qvm.write_unitary_and_gradient(&[t], buffer.as_matmut(&mut utry), buffer.as_matvecmut(&mut grad));
+        for k in 0..2 {
+            let written = buffer.as_matvecref::<c64>(&grad).mat_ref(0)[(k, k)];
+            assert!((written - rate[k] * phase[k]).norm() < 1e-12);
+        }
+
+        qvm.write_unitary_gradient_and_hessian(
+            &[t],
+            buffer.as_matmut(&mut utry),
+            buffer.as_matvecmut(&mut grad),
+            buffer.as_symsqmatmut(&mut hess),
+        );
+        for k in 0..2 {
+            let written = buffer.as_matvecref::<c64>(&grad).mat_ref(0)[(k, k)];
+            assert!((written - rate[k] * phase[k]).norm() < 1e-12);
+            let second = buffer.as_symsqmatref::<c64>(&hess).mat_ref(0, 0)[(k, k)];
+            assert!((second - rate[k] * rate[k] * phase[k]).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_write_paths_through_final_frpr() {
+        let cry = ExpressionTree::Leaf(fixtures::cry());
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let tree = ExpressionTree::Contract(ContractNode::new(cry, ry, vec![1, 0], vec![1]));
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::Hessian);
+        assert!(matches!(qvm.dynamic_instructions.last(), Some(SpecializedInstruction::FRPR(_))));
+
+        let params = [0.7, -1.3];
+        let (utry, grad) = qvm.get_unitary_and_gradient(&params);
+        let expected_utry = utry.to_owned();
+        let expected_grad = [grad.mat_ref(0).to_owned(), grad.mat_ref(1).to_owned()];
+
+        let col_stride = calc_col_stride::<c64>(4, 4);
+        let buffer = SizedMatrixBuffer {
+            offset: 0,
+            nrows: 4,
+            ncols: 4,
+            col_stride: col_stride as isize,
+            mat_stride: calc_mat_stride::<c64>(4, 4, col_stride) as isize,
+            num_params: 2,
+        };
+        let size = 4 * buffer.mat_stride as usize;
+        let (mut utry, mut grad, mut hess) =
+            (alloc_zeroed_memory::<c64>(size), alloc_zeroed_memory::<c64>(size), alloc_zeroed_memory::<c64>(size));
+        let check = |utry: &MemoryBuffer<c64>, grad: &MemoryBuffer<c64>| {
+            for r in 0..4 {
+                for c in 0..4 {
+                    let written = buffer.as_matref::<c64>(utry)[(r, c)];
+                    assert!((written - expected_utry[(r, c)]).norm() < 1e-12);
+                    for (i, expected) in expected_grad.iter().enumerate() {
+                        let written = buffer.as_matvecref::<c64>(grad).mat_ref(i)[(r, c)];
+                        assert!((written - expected[(r, c)]).norm() < 1e-12);
+                    }
+                }
+            }
+        };
+
+        qvm.write_unitary(&params, buffer.as_matmut(&mut utry));
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((buffer.as_matref::<c64>(&utry)[(r, c)] - expected_utry[(r, c)]).norm() < 1e-12);
+            }
+        }
+
+        qvm.write_unitary_and_gradient(&params, buffer.as_matmut(&mut utry), buffer.as_matvecmut(&mut grad));
+        check(&utry, &grad);
+
+        qvm.write_unitary_gradient_and_hessian(
+            &params,
+            buffer.as_matmut(&mut utry),
+            buffer.as_matvecmut(&mut grad),
+            buffer.as_symsqmatmut(&mut hess),
+        );
+        check(&utry, &grad);
+    }
+
+    #[test]
+    fn test_gradient_soa_matches_interleaved() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::Gradient);
+        let params = [0.3, 1.7, 0.5];
+        let mut real = vec![0.0; 3 * 16];
+        let mut imag = vec![0.0; 3 * 16];
+        qvm.write_gradient_soa(&params, &mut real, &mut imag);
+
+        let (_, grad) = qvm.get_unitary_and_gradient(&params);
+        for i in 0..3 {
+            for c in 0..4 {
+                for r in 0..4 {
+                    let idx = i * 16 + c * 4 + r;
+                    assert_eq!(real[idx], grad.mat_ref(i)[(r, c)].re);
+                    assert_eq!(imag[idx], grad.mat_ref(i)[(r, c)].im);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_batched_unitaries_match_individual() {
+        let tree = parallel_phases();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let batch = vec![vec![0.1, 0.2, 0.3], vec![1.5, 0.0, 2.0], vec![3.0, 2.5, 1.0]];
+
+        let utrys = qvm.get_unitaries_batched(&batch);
+        assert_eq!(utrys.len(), batch.len());
+        for (utry, params) in utrys.iter().zip(batch.iter()) {
+            assert_eq!(utry.as_ref(), qvm.get_unitary(params));
+        }
+    }
+
+    #[test]
+    fn test_batched_contractions_span_several_stacks() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2], vec![0, 1]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+
+        // More vectors than fit in one stack
+        let batch: Vec<Vec<f64>> = (0..70)
+            .map(|i| vec![0.1 * i as f64, 1.0 - 0.05 * i as f64, (i % 7) as f64])
+            .collect();
+        let utrys = qvm.get_unitaries_batched(&batch);
+        assert_eq!(utrys.len(), batch.len());
+        for (utry, params) in utrys.iter().zip(batch.iter()) {
+            let expected = qvm.get_unitary(params);
+            for r in 0..8 {
+                for c in 0..8 {
+                    assert!((utry[(r, c)] - expected[(r, c)]).norm() < 1e-12);
+                }
+            }
+        }
+        assert!(qvm.get_unitaries_batched::<Vec<f64>>(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_apply_to_states_matches_columnwise() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::None);
+        let params = [0.2, 1.4, 0.9];
+        let inputs = Mat::<c64>::from_fn(4, 3, |r, c| c64::new((r + c) as f64, r as f64 - c as f64));
+
+        let outputs = qvm.apply_to_states(&params, inputs.as_ref());
+        let utry = qvm.get_unitary(&params).to_owned();
+        for c in 0..3 {
+            let expected = &utry * inputs.col(c);
+            for r in 0..4 {
+                assert!((outputs[(r, c)] - expected[r]).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_to_state_threads_multiplication_chain() {
+        let cry = fixtures::cry();
+        let chain = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1]; 4],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&chain), DifferentiationLevel::None);
+        assert!(qvm.state_chain().iter().filter(|&&c| c).count() > 1);
+
+        let params = [0.4, -1.2, 2.5, 0.8];
+        let state = Mat::<c64>::from_fn(4, 1, |r, _| c64::new(r as f64 + 1.0, 0.5 - r as f64));
+        let applied = qvm.apply_to_state(&params, state.as_ref());
+        let expected = qvm.get_unitary(&params) * state.as_ref();
+        for r in 0..4 {
+            assert!((applied[(r, 0)] - expected[(r, 0)]).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_state_program_never_allocates_the_unitary() {
+        let cry = fixtures::cry();
+        let locations = vec![
+            vec![0, 1],
+            vec![1, 2],
+            vec![3, 2],
+            vec![3, 4],
+            vec![4, 5],
+            vec![5, 0],
+            vec![2, 4],
+        ];
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2; 6]),
+            locations,
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let tree = TreeOptimizer::new().optimize(tree);
+        let dim = 64;
+
+        // No buffer is larger than the state itself.
+        let code = compile_for_state(&tree);
+        let peak = code.matrix_buffers.iter().map(|b| b.nrows * b.ncols).max().unwrap();
+        assert_eq!(peak, dim);
+        let footprint = code.memory_footprint::<c64>(DifferentiationLevel::None);
+        assert!(footprint < dim * dim * std::mem::size_of::<c64>());
+
+        let params: Vec<f64> = (0..7).map(|i| 0.4 * i as f64 - 1.1).collect();
+        let state = Mat::<c64>::from_fn(dim, 2, |r, c| {
+            c64::new((r % 5) as f64 - c as f64, (r % 3) as f64 * 0.5)
+        });
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let applied = qvm.apply_to_state(&params, state.as_ref());
+
+        let mut operator_qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let expected = operator_qvm.get_unitary(&params) * state.as_ref();
+        for c in 0..2 {
+            for r in 0..dim {
+                assert!((applied[(r, c)] - expected[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_state_program_gradient_matches_operator_column() {
+        // Two disjoint systems, whose sides are applied to the state in turn.
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2, 2]),
+            vec![vec![0, 1], vec![2, 3], vec![1, 0]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let params = [0.3, -0.7, 1.9];
+
+        // The input state starts as the first basis state, so the program
+        // computes the first column of the unitary and of its gradient.
+        let mut qvm = QVM::<c64>::new(compile_for_state(&tree), DifferentiationLevel::Gradient);
+        let grad = qvm.get_gradient_matrices(&params);
+        let mut operator_qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::Gradient);
+        let expected = operator_qvm.get_gradient_matrices(&params);
+
+        assert_eq!(grad.len(), expected.len());
+        for (g, e) in grad.iter().zip(expected.iter()) {
+            assert_eq!(g.ncols(), 1);
+            for r in 0..16 {
+                assert!((g[(r, 0)] - e[(r, 0)]).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_diagonal_program_matches_unitary_diagonal() {
+        let p = fixtures::p();
+        let cp = UnitaryExpression::new(
+            "CP(a) { [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, e^(i*a)]] }",
+        );
+        let locations = vec![vec![0], vec![1], vec![0, 2], vec![2], vec![3, 1], vec![3]];
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2, 2]),
+            locations,
+            |loc| if loc.len() == 1 { p.clone() } else { cp.clone() },
+        )
+        .build_tree();
+        let dim = 16;
+
+        // No dense operator over the whole system is evaluated.
+        let code = compile_for_diagonal(&tree);
+        let peak = code.matrix_buffers.iter().map(|b| b.nrows * b.ncols).max().unwrap();
+        assert_eq!(peak, dim);
+        assert!(code.exact_flops() < compile(&tree).exact_flops());
+
+        let params = [0.3, -1.2, 0.8, 2.1, -0.4, 1.5];
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let diagonal = qvm.get_diagonal(&params);
+
+        let mut operator_qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let utry = operator_qvm.get_unitary(&params);
+        for i in 0..dim {
+            assert!((diagonal[i] - utry[(i, i)]).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Circuit is not diagonal")]
+    fn test_diagonal_program_rejects_non_diagonal_gates() {
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1]],
+            |_| fixtures::cry(),
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile_for_diagonal(&tree), DifferentiationLevel::None);
+        qvm.get_diagonal(&[0.7]);
+    }
+
+    #[test]
+    fn test_partial_trace_matches_full_unitary() {
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2], vec![0, 2], vec![0, 1]],
+            |_| cry.clone(),
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        assert!(matches!(
+            qvm.dynamic_instructions.last(),
+            Some(SpecializedInstruction::Matmul(_) | SpecializedInstruction::FRPR(_))
+        ));
+
+        let params = [0.5, 1.3, 2.2, -0.7];
+        let utry = qvm.get_unitary(&params).to_owned();
+        let keep_outer = qvm.get_partial_trace(&params, &[1]);
+        let keep_middle = qvm.get_partial_trace(&params, &[2, 0]);
+
+        let index = |q0: usize, q1: usize, q2: usize| 4 * q0 + 2 * q1 + q2;
+        for r in 0..4 {
+            for c in 0..4 {
+                let (r0, r2, c0, c2) = (r / 2, r % 2, c / 2, c % 2);
+                let expected = (0..2)
+                    .map(|t| utry[(index(r0, t, r2), index(c0, t, c2))])
+                    .fold(c64::new(0.0, 0.0), |acc, x| acc + x);
+                assert!((keep_outer[(r, c)] - expected).norm() < 1e-12);
+            }
+        }
+        for r in 0..2 {
+            for c in 0..2 {
+                let mut expected = c64::new(0.0, 0.0);
+                for t0 in 0..2 {
+                    for t2 in 0..2 {
+                        expected += utry[(index(t0, r, t2), index(t0, c, t2))];
+                    }
+                }
+                assert!((keep_middle[(r, c)] - expected).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_effective_hamiltonian_of_rotation() {
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let mut qvm = QVM::<c64>::new(compile(&ry), DifferentiationLevel::None);
+
+        // RY(t) = exp(-i t/2 Y), so the generator is t/2 Y.
+        let t: f64 = 0.9;
+        let h = qvm.effective_hamiltonian(&[t]);
+        let zero = c64::new(0.0, 0.0);
+        let expected = [[zero, c64::new(0.0, -t / 2.0)], [c64::new(0.0, t / 2.0), zero]];
+        for r in 0..2 {
+            for c in 0..2 {
+                assert!((h[(r, c)] - expected[r][c]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unitary_and_norm_matches_frobenius_distance() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::None);
+        let target = Mat::<c64>::identity(4, 4);
+        let (utry, norm) = qvm.get_unitary_and_norm(&[0.4, 2.1, 1.2], target.as_ref());
+
+        let mut expected = 0.0;
+        for c in 0..4 {
+            for r in 0..4 {
+                expected += (utry[(r, c)] - target[(r, c)]).norm_sqr();
+            }
+        }
+        assert!((norm - expected.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_matches_target_within_tolerance() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::None);
+        let params = [0.4, 2.1, 1.2];
+        let target = qvm.get_unitary(&params).to_owned();
+
+        assert!(qvm.matches(&params, target.as_ref(), 1e-10));
+        assert!(!qvm.matches(&[0.4, 2.1, 1.3], target.as_ref(), 1e-10));
+        assert!(qvm.matches(&[0.4, 2.1, 1.2 + 1e-12], target.as_ref(), 1e-10));
+    }
+
+    #[test]
+    fn test_try_entry_points_report_errors() {
+        let x = ExpressionTree::RuntimeConstant(RuntimeConstantNode::new(
+            7,
+            QuditRadices::from_iter([2]),
+        ));
+        let p = fixtures::p();
+        let tree = TreeBuilder::new(
+            1,
+            vec![BuilderExpressionInput::Tree(x), BuilderExpressionInput::Unitary(p)],
+            vec![vec![0], vec![0]],
+            vec![vec![Some(1)], vec![None]],
+            vec![vec![None], vec![Some(0)]],
+        )
+        .build_tree();
+
+        assert_eq!(
+            try_compile_with_parameter_map(&tree, ParameterMap::new(vec![0, 1])).err(),
+            Some(QuditTreeError::ParameterMapLength { expected: 1, found: 2 }),
+        );
+
+        let missing = QVM::<c64>::try_new(compile(&tree), DifferentiationLevel::None);
+        assert_eq!(missing.err(), Some(QuditTreeError::MissingRuntimeConstant(7)));
+
+        let wrong_shape = QVM::<c64>::try_new_with_runtime_constants(
+            compile(&tree),
+            DifferentiationLevel::None,
+            HashMap::from([(7, Mat::<c64>::identity(4, 4))]),
+        );
+        assert_eq!(wrong_shape.err(), Some(QuditTreeError::RuntimeConstantShape(7)));
+
+        // Both parameters of a leaf must read consecutive global parameters
+        let xy = UnitaryExpression::new("XY(a, b) { [[cos(a), ~sin(b)], [sin(b), cos(a)]] }");
+        let tree = ExpressionTree::Leaf(xy);
+        assert_eq!(
+            try_compile_with_parameter_map(&tree, ParameterMap::new(vec![1, 0])).err(),
+            Some(QuditTreeError::NonConsecutiveLeafParameters(0)),
+        );
+
+        let params = [0.3, 0.4];
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        assert_eq!(
+            qvm.try_get_unitary_and_gradient(&params).err(),
+            Some(QuditTreeError::NotGradientCapable),
+        );
+
+        let col_stride = calc_col_stride::<c64>(2, 2);
+        let mat_stride = calc_mat_stride::<c64>(2, 2, col_stride);
+        let buffer = SizedMatrixBuffer {
+            offset: 0,
+            nrows: 2,
+            ncols: 2,
+            col_stride: col_stride as isize,
+            mat_stride: mat_stride as isize,
+            num_params: 2,
+        };
+        let mut utry = alloc_zeroed_memory::<c64>(mat_stride * 6);
+        let mut grad = alloc_zeroed_memory::<c64>(mat_stride * 6);
+        let mut hess = alloc_zeroed_memory::<c64>(mat_stride * 6);
+        assert_eq!(
+            qvm.try_write_unitary_and_gradient(
+                &params,
+                buffer.as_matmut(&mut utry),
+                buffer.as_matvecmut(&mut grad),
+            )
+            .err(),
+            Some(QuditTreeError::NotGradientCapable),
+        );
+
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::Gradient);
+        assert!(qvm.try_get_unitary_and_gradient(&params).is_ok());
+        assert_eq!(
+            qvm.try_write_unitary_gradient_and_hessian(
+                &params,
+                buffer.as_matmut(&mut utry),
+                buffer.as_matvecmut(&mut grad),
+                buffer.as_symsqmatmut(&mut hess),
+            )
+            .err(),
+            Some(QuditTreeError::NotHessianCapable),
+        );
+    }
+
+    #[test]
+    fn test_input_state_is_evolved() {
+        let state = RuntimeConstantNode::new(3, QuditRadices::from_iter([2, 2]));
+        let cx = fixtures::cx();
+        let tree = TreeBuilder::new(
+            2,
+            vec![BuilderExpressionInput::State(state), BuilderExpressionInput::Unitary(cx)],
+            vec![vec![0, 1], vec![0, 1]],
+            vec![vec![Some(1), Some(1)], vec![None, None]],
+            vec![vec![None, None], vec![Some(0), Some(0)]],
+        )
+        .build_tree();
+
+        let psi = [c64::new(0.1, 0.0), c64::new(0.2, 0.3), c64::new(0.0, 0.4), c64::new(0.5, 0.0)];
+        let state = Mat::from_fn(4, 1, |r, _| psi[r]);
+        let mut qvm = QVM::<c64>::new_with_runtime_constants(
+            compile(&tree),
+            DifferentiationLevel::None,
+            HashMap::from([(3, QVM::<c64>::state_operator(state.as_ref()))]),
+        );
+
+        // CX swaps the amplitudes of |10> and |11>
+        let expected = [psi[0], psi[1], psi[3], psi[2]];
+        let out = qvm.get_unitary(&[]);
+        for r in 0..4 {
+            assert!((out[(r, 0)] - expected[r]).norm() < 1e-12);
+            for c in 1..4 {
+                assert!(out[(r, c)].norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_runtime_constant_is_used() {
+        let x = ExpressionTree::RuntimeConstant(RuntimeConstantNode::new(
+            7,
+            QuditRadices::from_iter([2]),
+        ));
+        let p = fixtures::p();
+        let tree = TreeBuilder::new(
+            1,
+            vec![BuilderExpressionInput::Tree(x), BuilderExpressionInput::Unitary(p)],
+            vec![vec![0], vec![0]],
+            vec![vec![Some(1)], vec![None]],
+            vec![vec![None], vec![Some(0)]],
+        )
+        .build_tree();
+
+        let zero = c64::new(0.0, 0.0);
+        let one = c64::new(1.0, 0.0);
+        let pauli_x = Mat::from_fn(2, 2, |r, c| if r != c { one } else { zero });
+        let mut qvm = QVM::<c64>::new_with_runtime_constants(
+            compile(&tree),
+            DifferentiationLevel::None,
+            HashMap::from([(7, pauli_x)]),
+        );
+
+        let a: f64 = 0.9;
+        let phase = c64::new(a.cos(), a.sin());
+        let expected = [[zero, one], [phase, zero]];
+        let utry = qvm.get_unitary(&[a]);
+        for r in 0..2 {
+            for c in 0..2 {
+                assert!((utry[(r, c)] - expected[r][c]).norm() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_directional_derivative_matches_finite_differences() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::Gradient);
+        let params = [0.4, 2.1, 1.2];
+        let direction = [0.5, -1.0, 2.0];
+        let derivative = qvm.get_directional_derivative(&params, &direction);
+
+        let h = 1e-6;
+        let step = |sign: f64| -> Vec<f64> {
+            params.iter().zip(direction.iter()).map(|(p, d)| p + sign * h * d).collect()
+        };
+        let plus = qvm.get_unitary(&step(1.0)).to_owned();
+        let minus = qvm.get_unitary(&step(-1.0)).to_owned();
+        for r in 0..4 {
+            for c in 0..4 {
+                let expected = (plus[(r, c)] - minus[(r, c)]) / c64::new(2.0 * h, 0.0);
+                assert!((derivative[(r, c)] - expected).norm() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_choi_of_unitary_channel_is_rank_one() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::None);
+        let params = [0.4, 2.1, 1.2];
+        let choi = qvm.get_choi(&params);
+        assert_eq!((choi.nrows(), choi.ncols()), (16, 16));
+
+        // A rank-one Choi matrix v v† squares to (v† v) times itself, and v† v
+        // is the trace, which is the dimension for a unitary channel.
+        let trace = (0..16).map(|i| choi[(i, i)]).fold(c64::new(0.0, 0.0), |acc, x| acc + x);
+        assert!((trace - c64::new(4.0, 0.0)).norm() < 1e-10);
+        let squared = &choi * &choi;
+        for r in 0..16 {
+            for c in 0..16 {
+                assert!((squared[(r, c)] - trace * choi[(r, c)]).norm() < 1e-10);
+                assert!((choi[(r, c)] - choi[(c, r)].conj()).norm() < 1e-10);
+            }
+        }
+
+        // Tracing out the system leaves the identity on the reference
+        for i in 0..4 {
+            for j in 0..4 {
+                let reduced = (0..4)
+                    .map(|k| choi[(4 * i + k, 4 * j + k)])
+                    .fold(c64::new(0.0, 0.0), |acc, x| acc + x);
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((reduced - c64::new(expected, 0.0)).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fully_constant_circuit() {
+        let cx = fixtures::cx();
+        let x = UnitaryExpression::new("X() { [[0, 1], [1, 0]] }");
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![0, 1], vec![0]],
+            |loc| if loc.len() == 1 { x.clone() } else { cx.clone() },
+        )
+        .build_tree();
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::Gradient);
+        assert!(qvm.dynamic_instructions.is_empty());
+
+        // (X ⊗ I) CX sends |00>, |01>, |10>, |11> to |10>, |11>, |01>, |00>
+        let image = [2, 3, 1, 0];
+        let expected = Mat::<c64>::from_fn(4, 4, |r, c| {
+            if r == image[c] { c64::new(1.0, 0.0) } else { c64::new(0.0, 0.0) }
+        });
+        assert_eq!(qvm.get_unitary(&[]), expected.as_ref());
+        let (utry, grad) = qvm.get_unitary_and_gradient(&[]);
+        assert_eq!(utry, expected.as_ref());
+        assert_eq!(grad.nmats(), 0);
+
+        let col_stride = calc_col_stride::<c64>(4, 4);
+        let buffer = SizedMatrixBuffer {
+            offset: 0,
+            nrows: 4,
+            ncols: 4,
+            col_stride: col_stride as isize,
+            mat_stride: calc_mat_stride::<c64>(4, 4, col_stride) as isize,
+            num_params: 0,
+        };
+        let mut utry = alloc_zeroed_memory::<c64>(buffer.mat_stride as usize);
+        let mut grad = alloc_zeroed_memory::<c64>(buffer.mat_stride as usize);
+        qvm.write_unitary(&[], buffer.as_matmut(&mut utry));
+        assert_eq!(buffer.as_matref::<c64>(&utry), expected.as_ref());
+
+        let mut utry = alloc_zeroed_memory::<c64>(buffer.mat_stride as usize);
+        qvm.write_unitary_and_gradient(&[], buffer.as_matmut(&mut utry), buffer.as_matvecmut(&mut grad));
+        assert_eq!(buffer.as_matref::<c64>(&utry), expected.as_ref());
+    }
+
+    #[test]
+    fn test_clear_intermediates_keeps_output() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::None);
+        let params = [0.4, 2.1, 1.2];
+        let expected = qvm.get_unitary(&params).to_owned();
+
+        qvm.clear_intermediates();
+        let zero = c64::new(0.0, 0.0);
+        let written: Vec<_> = qvm
+            .dynamic_instructions
+            .iter()
+            .filter(|inst| matches!(inst, SpecializedInstruction::Write(_)))
+            .map(|inst| inst.out_buffer().offset)
+            .collect();
+        let cleared: Vec<_> = qvm
+            .dynamic_instructions
+            .iter()
+            .map(|inst| inst.out_buffer().clone())
+            .filter(|buffer| !written.contains(&buffer.offset))
+            .collect();
+        assert!(!cleared.is_empty());
+        for buffer in cleared.iter() {
+            let mat = buffer.as_matref::<c64>(&qvm.memory);
+            for c in 0..mat.ncols() {
+                for r in 0..mat.nrows() {
+                    assert_eq!(mat[(r, c)], zero);
+                }
+            }
+        }
+
+        // No evaluation depends on what a previous one left behind
+        assert_eq!(qvm.get_unitary(&params), expected.as_ref());
+    }
+
+    #[test]
+    fn test_generator_chooses_warmup_from_first_use() {
+        let x = ExpressionTree::RuntimeConstant(RuntimeConstantNode::new(
+            7,
+            QuditRadices::from_iter([2]),
+        ));
+        let p = fixtures::p();
+        let tree = TreeBuilder::new(
+            1,
+            vec![BuilderExpressionInput::Tree(x), BuilderExpressionInput::Unitary(p)],
+            vec![vec![0], vec![0]],
+            vec![vec![Some(1)], vec![None]],
+            vec![vec![None], vec![Some(0)]],
+        )
+        .build_tree();
+
+        // The runtime constant is read before it is written, the phase is
+        // written by its expression, and the product is overwritten.
+        let code = compile(&tree);
+        let (_, constant) = code.runtime_constants[0];
+        assert_eq!(code.matrix_buffers[constant].warmup, WarmupStrategy::Zero);
+        for inst in code.static_code.iter().chain(code.dynamic_code.iter()) {
+            let expected = match inst {
+                GeneralizedInstruction::Write(..) => WarmupStrategy::Identity,
+                _ => WarmupStrategy::None,
+            };
+            assert_eq!(code.matrix_buffers[inst.out_buffer()].warmup, expected);
+        }
+
+        let one = c64::new(1.0, 0.0);
+        let pauli_x = Mat::from_fn(2, 2, |r, c| if r != c { one } else { c64::new(0.0, 0.0) });
+        let mut qvm = QVM::<c64>::new_with_runtime_constants(
+            code,
+            DifferentiationLevel::None,
+            HashMap::from([(7, pauli_x)]),
+        );
+        let strategies: Vec<_> = qvm.warmups.iter().map(|(_, s)| *s).collect();
+        assert!(strategies.contains(&WarmupStrategy::Identity));
+        assert!(strategies.contains(&WarmupStrategy::Zero));
+        let expected = qvm.get_unitary(&[0.9]).to_owned();
+
+        // Warming up again restores every buffer the code relies on.
+        let stale = c64::new(3.0, -2.0);
+        for (buffer, _) in qvm.warmups.iter() {
+            let mut mat = buffer.as_matmut::<c64>(&mut qvm.memory);
+            for c in 0..mat.ncols() {
+                for r in 0..mat.nrows() {
+                    *mat.rb_mut().get_mut(r, c) = stale;
+                }
+            }
+        }
+        qvm.reset();
+        assert_eq!(qvm.get_unitary(&[0.9]), expected.as_ref());
+    }
+
+    #[test]
+    fn test_cost_gradient_matches_finite_differences() {
+        let mut qvm = QVM::<c64>::new(compile(&parallel_phases()), DifferentiationLevel::Gradient);
+        let target = Mat::<c64>::identity(4, 4);
+        let cost_fn = |utry: faer::MatRef<c64>| {
+            let mut acc = 0.0;
+            for c in 0..4 {
+                for r in 0..4 {
+                    acc += (utry[(r, c)] - target[(r, c)]).norm_sqr();
+                }
+            }
+            acc
+        };
+        let cost_grad = |utry: faer::MatRef<c64>| {
+            Mat::from_fn(4, 4, |r, c| (utry[(r, c)] - target[(r, c)]) * 2.0)
+        };
+
+        let params = [0.3, 1.1, 0.8];
+        let (cost, grads) = qvm.cost_gradient(&params, cost_fn, cost_grad);
+        assert!((cost - cost_fn(qvm.get_unitary(&params))).abs() < 1e-12);
+
+        let eps = 1e-6;
+        for i in 0..params.len() {
+            let mut plus = params;
+            plus[i] += eps;
+            let mut minus = params;
+            minus[i] -= eps;
+            let fd = (cost_fn(qvm.get_unitary(&plus)) - cost_fn(qvm.get_unitary(&minus))) / (2.0 * eps);
+            assert!((grads[i] - fd).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_cost_gradient_matches_forward_gradient() {
+        let ry = fixtures::ry();
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        let gate_for = |loc: &[usize]| match loc {
+            [0] => ry.clone(),
+            [1] => p.clone(),
+            _ => cry.clone(),
+        };
+        let mut tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0], vec![2, 0], vec![1]],
+            gate_for,
+        )
+        .build_tree();
+
+        // Conjugating contracted operands also exercises the backward pass
+        // of conjugation.
+        tree.traverse_mut(&|n| {
+            if let ExpressionTree::Contract(c) = n {
+                c.conjugate_right = true;
+            }
+        });
+
+        // A target away from the circuit, so that no derivative vanishes
+        let target = Mat::<c64>::from_fn(8, 8, |r, c| c64::new((r + 2 * c) as f64 / 8.0, 0.1));
+        let cost_fn = |utry: faer::MatRef<c64>| {
+            let mut acc = 0.0;
+            for c in 0..8 {
+                for r in 0..8 {
+                    acc += (utry[(r, c)] - target[(r, c)]).norm_sqr();
+                }
+            }
+            acc
+        };
+        let cost_grad = |utry: faer::MatRef<c64>| {
+            Mat::from_fn(8, 8, |r, c| (utry[(r, c)] - target[(r, c)]) * 2.0)
+        };
+
+        let programs = [
+            (compile(&tree), vec![0.3, -1.1, 0.8]),
+            (compile_with_parameter_map(&tree, ParameterMap::new(vec![0, 1, 0])), vec![0.3, -1.1]),
+        ];
+        for (code, params) in programs {
+            let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::Gradient);
+            let (_, grads) = qvm.cost_gradient(&params, cost_fn, cost_grad);
+
+            let g = cost_grad(qvm.get_unitary(&params));
+            let d_utrys = qvm.get_gradient_matrices(&params);
+            assert_eq!(grads.len(), d_utrys.len());
+            for (grad, d_utry) in grads.iter().zip(d_utrys.iter()) {
+                let mut expected = c64::new(0.0, 0.0);
+                for c in 0..8 {
+                    for r in 0..8 {
+                        expected += g[(r, c)].conj() * d_utry[(r, c)];
+                    }
+                }
+                assert!((grad - expected.re).abs() < 1e-10);
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use faer::Mat;
+use qudit_core::ComplexScalar;
+use qudit_expr::DifferentiationLevel;
+use qudit_expr::UnitaryExpression;
+
+use crate::compiler::compile;
+use crate::qvm::QVM;
+use crate::tree::BuilderExpressionInput;
+use crate::tree::TreeBuilder;
+use crate::tree::TreeOptimizer;
+
+/// Calculate the unitary of a circuit given as a list of operations.
+///
+/// This builds the circuit's tensor network, builds and optimizes the
+/// expression tree, compiles it, and evaluates it once.
+///
+/// # Arguments
+///
+/// * `num_qudits` - The number of qudits in the circuit.
+/// * `ops` - The circuit's operations in order, each with the qudits it acts on.
+/// * `params` - The parameters of all operations, in operation order.
+///
+/// # Panics
+///
+/// If the operations do not describe a valid circuit; see
+/// [TreeBuilder::from_prev_only].
+pub fn simulate<C: ComplexScalar>(
+    num_qudits: usize,
+    ops: Vec<(UnitaryExpression, Vec<usize>)>,
+    params: &[C::R],
+) -> Mat<C> {
+    // Each operation follows the last operation on each of its qudits
+    let mut frontier: Vec<Option<usize>> = vec![None; num_qudits];
+    let mut prev_list: Vec<Vec<Option<usize>>> = Vec::with_capacity(ops.len());
+    for (op_idx, (_, loc)) in ops.iter().enumerate() {
+        prev_list.push(loc.iter().map(|&q| frontier[q]).collect());
+        for &q in loc.iter() {
+            frontier[q] = Some(op_idx);
+        }
+    }
+
+    let (expression_list, qudits_list): (Vec<_>, Vec<_>) = ops
+        .into_iter()
+        .map(|(expr, loc)| (BuilderExpressionInput::Unitary(expr), loc))
+        .unzip();
+
+    let tree = TreeBuilder::from_prev_only(
+        num_qudits,
+        expression_list,
+        qudits_list,
+        prev_list,
+    ).build_tree();
+    let tree = TreeOptimizer::new().optimize(tree);
+    let code = compile(&tree);
+
+    let mut qvm = QVM::<C>::new(code, DifferentiationLevel::None);
+    qvm.get_unitary(params).to_owned()
+}
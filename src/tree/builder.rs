@@ -6,20 +6,30 @@ use std::collections::VecDeque;
 use qudit_expr::UnitaryExpression;
 
 use super::contract::ContractNode;
+use super::contract::ContractTemplate;
+use super::hardware::HardwareProfile;
+use super::identity::IdentityNode;
 use super::kron::KronNode;
 use super::mul::MulNode;
 use super::perm::PermNode;
+use super::runtime::RuntimeConstantNode;
 use super::tree::ExpressionTree;
+use crate::bytecode::ParameterMap;
+use crate::error::QuditTreeError;
+use qudit_core::HasParams;
 use qudit_core::QuditPermutation;
+use qudit_core::QuditRadices;
 use qudit_core::QuditSystem;
 
 /// A node in a DAG of comp tree nodes
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Node {
     pub node: ExpressionTree,
     pub qudits: Vec<usize>,
     pub next: Vec<Option<usize>>,
     pub prev: Vec<Option<usize>>,
+    /// The circuit operation indices of this node's leaves in tree order.
+    pub ops: Vec<usize>,
 }
 
 // TODO: remove this after it  is properly moved somewhere else
@@ -57,23 +67,171 @@ fn difference(qudits1: &Vec<usize>, qudits2: &Vec<usize>) -> Vec<usize> {
     diff_qudits
 }
 
+/// Contract two nodes, or kron them if they share no qudits.
+///
+/// The result acts on the union of both nodes' qudits in ascending order.
+/// Contraction plans are taken from `template`, if given.
+fn contract_or_kron(
+    left: ExpressionTree,
+    right: ExpressionTree,
+    left_qudits: Vec<usize>,
+    right_qudits: Vec<usize>,
+    template: Option<&mut ContractTemplate>,
+) -> ExpressionTree {
+    if !intersect(&left_qudits, &right_qudits).is_empty() {
+        let node = match template {
+            Some(template) => template.contract(left, right, left_qudits, right_qudits),
+            None => ContractNode::new(left, right, left_qudits, right_qudits),
+        };
+        return ExpressionTree::Contract(node);
+    }
+
+    // Disjoint operators kron rather than contract
+    let kron_qudits: Vec<usize> =
+        left_qudits.iter().chain(right_qudits.iter()).copied().collect();
+    let mut sorted_qudits = kron_qudits.clone();
+    sorted_qudits.sort();
+    let order: Vec<usize> = sorted_qudits
+        .iter()
+        .map(|q| kron_qudits.iter().position(|x| x == q).unwrap())
+        .collect();
+
+    let kron = ExpressionTree::Kron(KronNode::new(left, right));
+    if order.iter().enumerate().all(|(i, &o)| i == o) {
+        kron
+    } else {
+        kron.apply_output_permutation(order)
+    }
+}
+
 /// A builder for a computation tree.
 /// This builder is used to build a computation tree from a circuit.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TreeBuilder {
     /// The number of qudits in the circuit.
     num_qudits: usize,
 
+    /// The radix of each qudit, if known.
+    radices: Vec<Option<u8>>,
+
     /// Map from node index to node encoding a DAG of nodes.
     dag: HashMap<usize, Node>,
 
     /// The index of the next node to be added to the tree.
     index_counter: usize,
+
+    /// The number of parameters of each circuit operation.
+    op_num_params: Vec<usize>,
+
+    /// The maximum number of parameters the built tree may have.
+    max_params: Option<usize>,
+
+    /// The order of the qudits in the output of the built tree.
+    output_order: Option<Vec<usize>>,
+
+    /// The hardware used to estimate the cost of each contraction.
+    hardware_profile: Option<HardwareProfile>,
+
+    /// The classical bit controlling each classically-controlled operation.
+    classical_controls: HashMap<usize, usize>,
+
+    /// The cache of contraction plans used while building, if any.
+    contract_template: Option<ContractTemplate>,
+
+    /// Pairs of qudits whose nodes are only joined once nothing else can be.
+    cut_points: Vec<(usize, usize)>,
+
+    /// Whether consecutive contractions pass intermediates in tensor form.
+    tensor_intermediates: bool,
+}
+
+/// An assignment of values to classical bits, selecting one branch of a
+/// classically-controlled circuit.
+///
+/// See [TreeBuilder::build_conditional].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClassicalCondition {
+    /// The value of each classical bit, sorted by bit.
+    pub values: Vec<(usize, bool)>,
+}
+
+impl ClassicalCondition {
+    /// The value of `bit` in this condition, if it is assigned.
+    pub fn value(&self, bit: usize) -> Option<bool> {
+        self.values.iter().find(|(b, _)| *b == bit).map(|(_, v)| *v)
+    }
+}
+
+/// A pair of nodes the [TreeBuilder] may contract next.
+///
+/// See [TreeBuilder::build_tree_with_cost].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractCandidate {
+    /// The qudits the earlier node acts on, in ascending order.
+    pub left_qudits: Vec<usize>,
+
+    /// The qudits the later node acts on, in ascending order.
+    pub right_qudits: Vec<usize>,
+
+    /// The dimension of the contracted node.
+    pub dimension: usize,
+}
+
+/// The way two nodes in a [TreeBuilder] DAG relate to each other.
+///
+/// See [TreeBuilder::explain_pair].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PairDecision {
+    /// The nodes act on the same qudits and are directly adjacent, so they
+    /// can be multiplied.
+    Multiply,
+
+    /// The nodes act on disjoint qudits and have no dependency between them,
+    /// so they can be kroneckered together.
+    Kron,
+
+    /// The nodes are directly adjacent but act on different qudits. They will
+    /// be contracted in the round where `disjoint_size` mismatched qudits are
+    /// allowed.
+    Contract { disjoint_size: usize },
+
+    /// The nodes cannot be combined.
+    Blocked(PairBlocker),
+}
+
+/// The reason two nodes in a [TreeBuilder] DAG cannot be combined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PairBlocker {
+    /// The node index is not in the DAG.
+    MissingNode(usize),
+
+    /// Both indices refer to the same node.
+    SameNode,
+
+    /// The nodes share a qudit but another node sits between them.
+    NotAdjacent,
+
+    /// There is a path between the nodes that passes through another node,
+    /// so combining them would create a cycle.
+    NonDirectDependency,
 }
 
 pub enum BuilderExpressionInput {
     Unitary(UnitaryExpression),
     Tree(ExpressionTree),
+
+    /// An input state, whose vector is supplied when the QVM is constructed.
+    ///
+    /// A state must be at the left boundary of the circuit, with no previous
+    /// operations on its qudits. It is stored as the square operator with
+    /// the state in its first column and zeros elsewhere, see
+    /// [crate::QVM::state_operator], so the first column of the circuit's
+    /// unitary is the output state.
+    ///
+    /// The padding costs a factor of the state's dimension in work and
+    /// memory over a single column. Contractions and the bytecode assume
+    /// square operators, so a state cannot be stored as a rectangular leaf.
+    State(RuntimeConstantNode),
 }
 
 impl BuilderExpressionInput {
@@ -81,6 +239,23 @@ impl BuilderExpressionInput {
         match self {
             BuilderExpressionInput::Unitary(expr) => expr.num_qudits(),
             BuilderExpressionInput::Tree(expr) => expr.num_qudits(),
+            BuilderExpressionInput::State(state) => state.num_qudits(),
+        }
+    }
+
+    pub fn num_params(&self) -> usize {
+        match self {
+            BuilderExpressionInput::Unitary(expr) => expr.num_params(),
+            BuilderExpressionInput::Tree(expr) => expr.num_params(),
+            BuilderExpressionInput::State(_) => 0,
+        }
+    }
+
+    pub fn radices(&self) -> QuditRadices {
+        match self {
+            BuilderExpressionInput::Unitary(expr) => expr.radices(),
+            BuilderExpressionInput::Tree(expr) => expr.radices(),
+            BuilderExpressionInput::State(state) => state.radices(),
         }
     }
 }
@@ -112,6 +287,18 @@ impl TreeBuilder {
     /// - If the number of operations does not match the number of next and prev lists.
     /// - If the number of qudits in an operation does not match the number of next and prev lists.
     /// - If the number of qudits in an operation does not match the number of qudits in the qudits list.
+    /// - If the same qudit index is assigned different radices by different operations.
+    ///
+    /// # Factored operations
+    ///
+    /// The builder needs one radix per qudit, so every operation must be
+    /// given over the same factorization. An operation over a coarser one,
+    /// such as a radix-4 gate on a pair of qubits, is not reshaped here:
+    /// contract it with its neighbours using [ContractNode::new_factored]
+    /// and pass the result as a [BuilderExpressionInput::Tree] over the
+    /// finer qudits.
+    ///
+    /// [ContractNode::new_factored]: crate::ContractNode::new_factored
     pub fn new(
         num_qudits: usize,
         expression_list: Vec<BuilderExpressionInput>,
@@ -119,33 +306,84 @@ impl TreeBuilder {
         next_list: Vec<Vec<Option<usize>>>,
         prev_list: Vec<Vec<Option<usize>>>,
     ) -> TreeBuilder {
-        // TODO: Add support for input states, via StateExpression
+        Self::try_new(num_qudits, expression_list, qudits_list, next_list, prev_list)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Create a new tree builder as in [TreeBuilder::new], returning an
+    /// error instead of panicking on invalid input.
+    pub fn try_new(
+        num_qudits: usize,
+        expression_list: Vec<BuilderExpressionInput>,
+        qudits_list: Vec<Vec<usize>>,
+        next_list: Vec<Vec<Option<usize>>>,
+        prev_list: Vec<Vec<Option<usize>>>,
+    ) -> Result<TreeBuilder, QuditTreeError> {
         if expression_list.len() != next_list.len()
             || expression_list.len() != prev_list.len()
             || expression_list.len() != qudits_list.len()
         {
-            panic!("Invalid input lengths");
+            return Err(QuditTreeError::InvalidInputLengths);
         }
 
         if num_qudits == 0 {
-            panic!("Invalid number of qudits");
+            return Err(QuditTreeError::NoQudits);
         }
 
         if expression_list.len() == 0 {
-            panic!("Invalid number of operations");
+            return Err(QuditTreeError::NoOperations);
         }
 
-        if expression_list.iter().enumerate().any(
+        if let Some(op) = expression_list.iter().enumerate().position(
             |(i, e)|
             e.num_qudits() != next_list[i].len()
             || e.num_qudits() != prev_list[i].len()
             || e.num_qudits() != qudits_list[i].len()
         ) {
-            panic!("Invalid number of qudits in operation");
+            return Err(QuditTreeError::InvalidOperationQudits(op));
+        }
+
+        if let Some(op) = expression_list.iter().enumerate().position(
+            |(i, e)| matches!(e, BuilderExpressionInput::State(_))
+                && prev_list[i].iter().any(|p| p.is_some())
+        ) {
+            return Err(QuditTreeError::StateNotAtBoundary(op));
+        }
+
+        // Every operation acting on a qudit must agree on its radix
+        let mut qudit_radices: HashMap<usize, (u8, usize)> = HashMap::new();
+        for (op_idx, (expr, loc)) in expression_list.iter().zip(qudits_list.iter()).enumerate() {
+            let radices = expr.radices();
+            for (i, qudit) in loc.iter().enumerate() {
+                let radix = radices[i];
+                match qudit_radices.get(qudit) {
+                    Some(&(seen_radix, seen_op)) if seen_radix != radix => {
+                        return Err(QuditTreeError::InconsistentRadix {
+                            qudit: *qudit,
+                            first_op: seen_op,
+                            first_radix: seen_radix,
+                            op: op_idx,
+                            radix,
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        qudit_radices.insert(*qudit, (radix, op_idx));
+                    }
+                }
+            }
+        }
+
+        let mut radices = vec![None; num_qudits];
+        for (&qudit, &(radix, _)) in qudit_radices.iter() {
+            if qudit < num_qudits {
+                radices[qudit] = Some(radix);
+            }
         }
 
         let mut dag = HashMap::new();
         let num_ops = expression_list.len();
+        let op_num_params = expression_list.iter().map(|e| e.num_params()).collect();
         let zipped_list = expression_list
             .into_iter()
             .zip(qudits_list.into_iter())
@@ -160,6 +398,7 @@ impl TreeBuilder {
             let leaf = match expr {
                 BuilderExpressionInput::Unitary(expr) => ExpressionTree::Leaf(expr),
                 BuilderExpressionInput::Tree(expr) => expr,
+                BuilderExpressionInput::State(state) => ExpressionTree::RuntimeConstant(state),
             };
             let node = if loc.iter().zip(loc.iter().skip(1)).all(|(a, b)| a < b) {
                 // node is locally sorted
@@ -168,6 +407,7 @@ impl TreeBuilder {
                     qudits: loc,
                     next: nexts,
                     prev: prevs,
+                    ops: vec![idx],
                 }
             } else {
                 // node needs to be permuted
@@ -179,16 +419,412 @@ impl TreeBuilder {
                     qudits: loc,
                     next: nexts,
                     prev: prevs,
+                    ops: vec![idx],
                 }
             };
 
             dag.insert(idx, node);
         }
 
-        TreeBuilder {
+        Ok(TreeBuilder {
             num_qudits,
+            radices,
             dag,
             index_counter: num_ops,
+            op_num_params,
+            max_params: None,
+            output_order: None,
+            hardware_profile: None,
+            classical_controls: HashMap::new(),
+            contract_template: None,
+            cut_points: Vec::new(),
+            tensor_intermediates: false,
+        })
+    }
+
+    /// Create a new tree builder, deriving each operation's successors from
+    /// its predecessors.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_qudits` - The number of qudits in the circuit.
+    /// * `expression_list` - A list of unitary expressions for each operation in the network.
+    /// * `qudits_list` - A list of qudit indices for each expression, equal in length to the number of
+    ///   qudits in the expression.
+    /// * `prev_list` - A list of indices for each expression, equal in length to the number of
+    ///   qudits in the expression. Element i of this list is the index of the previous operation for
+    ///   the i-th qudit in the expression.
+    ///
+    /// # Returns
+    ///
+    /// A new tree builder, identical to one created by [TreeBuilder::new]
+    /// with the derived next list.
+    ///
+    /// # Panics
+    ///
+    /// - If an operation's predecessor does not act on the shared qudit.
+    /// - If two operations claim the same predecessor on the same qudit.
+    /// - In any case where [TreeBuilder::new] panics.
+    pub fn from_prev_only(
+        num_qudits: usize,
+        expression_list: Vec<BuilderExpressionInput>,
+        qudits_list: Vec<Vec<usize>>,
+        prev_list: Vec<Vec<Option<usize>>>,
+    ) -> TreeBuilder {
+        if qudits_list.len() != prev_list.len() {
+            panic!("Invalid input lengths");
+        }
+
+        let mut next_list: Vec<Vec<Option<usize>>> =
+            qudits_list.iter().map(|loc| vec![None; loc.len()]).collect();
+
+        for (op_idx, (loc, prevs)) in qudits_list.iter().zip(prev_list.iter()).enumerate() {
+            for (qudit, prev) in loc.iter().zip(prevs.iter()) {
+                let Some(prev) = *prev else { continue };
+                let pos = qudits_list
+                    .get(prev)
+                    .and_then(|prev_loc| prev_loc.iter().position(|q| q == qudit))
+                    .unwrap_or_else(|| panic!(
+                        "Operation {} is not a predecessor of operation {} on qudit {}",
+                        prev, op_idx, qudit,
+                    ));
+                if next_list[prev][pos].is_some() {
+                    panic!("Operation {} has multiple successors on qudit {}", prev, qudit);
+                }
+                next_list[prev][pos] = Some(op_idx);
+            }
+        }
+
+        TreeBuilder::new(num_qudits, expression_list, qudits_list, next_list, prev_list)
+    }
+
+    /// Create a new tree builder for a sequential circuit.
+    ///
+    /// Operations are applied in the order given, and each operation's
+    /// predecessors and successors are found by tracking the last operation
+    /// applied to every qudit.
+    ///
+    /// # Arguments
+    ///
+    /// * `radices` - The radices of the circuit's qudits.
+    /// * `locations` - The qudits each operation acts on, in order.
+    /// * `gate_for` - Produces the expression applied at a location.
+    ///
+    /// # Panics
+    ///
+    /// - If a location is empty, out of range, or repeats a qudit.
+    /// - If an expression's radices do not match the radices of its location.
+    /// - In any case where [TreeBuilder::new] panics.
+    pub fn from_locations(
+        radices: QuditRadices,
+        locations: Vec<Vec<usize>>,
+        gate_for: impl Fn(&[usize]) -> UnitaryExpression,
+    ) -> TreeBuilder {
+        let mut frontier: Vec<Option<usize>> = vec![None; radices.len()];
+        let mut expression_list = Vec::with_capacity(locations.len());
+        let mut prev_list = Vec::with_capacity(locations.len());
+
+        for (op_idx, location) in locations.iter().enumerate() {
+            if location.is_empty() {
+                panic!("Operation {} has an empty location", op_idx);
+            }
+            for (i, &qudit) in location.iter().enumerate() {
+                if qudit >= radices.len() || location[..i].contains(&qudit) {
+                    panic!("Operation {} has an invalid location {:?}", op_idx, location);
+                }
+            }
+
+            let expr = gate_for(location);
+            let expected = QuditRadices::from_iter(location.iter().map(|&q| radices[q]));
+            if expr.radices() != expected {
+                panic!(
+                    "Operation {} has radices {} but its location has radices {}",
+                    op_idx, expr.radices(), expected,
+                );
+            }
+
+            prev_list.push(location.iter().map(|&q| frontier[q]).collect());
+            for &qudit in location.iter() {
+                frontier[qudit] = Some(op_idx);
+            }
+            expression_list.push(BuilderExpressionInput::Unitary(expr));
+        }
+
+        // Idle qudits are only known by the given radices
+        let mut builder =
+            TreeBuilder::from_prev_only(radices.len(), expression_list, locations, prev_list);
+        builder.radices = radices.iter().map(|&r| Some(r)).collect();
+        builder
+    }
+
+    /// The radix of each qudit in the circuit.
+    ///
+    /// Radices are derived from the operations acting on each qudit, which
+    /// are checked to agree when the builder is created, or given directly
+    /// to [TreeBuilder::from_locations].
+    ///
+    /// # Panics
+    ///
+    /// If no operation acts on a qudit whose radix was not given.
+    pub fn qudit_radices(&self) -> QuditRadices {
+        QuditRadices::from_iter(self.radices.iter().enumerate().map(|(q, radix)| {
+            radix.unwrap_or_else(|| panic!("Qudit {} has no known radix", q))
+        }))
+    }
+
+    /// Bound the total number of parameters of the built tree.
+    ///
+    /// Gradient and hessian memory grow with the parameter count, so this
+    /// guards against accidentally building an intractable problem. Use
+    /// [TreeBuilder::try_build_tree] to handle an exceeded bound as an error.
+    pub fn with_max_params(mut self, max_params: usize) -> Self {
+        self.max_params = Some(max_params);
+        self
+    }
+
+    /// Produce the output qudits of the built tree in the given order.
+    ///
+    /// By default, the tree acts on the circuit qudits in ascending order.
+    ///
+    /// # Arguments
+    ///
+    /// * `order` - Output qudit `i` of the built tree is circuit qudit
+    ///   `order[i]`.
+    ///
+    /// # Panics
+    ///
+    /// If `order` is not a permutation of the circuit qudits.
+    pub fn with_output_order(mut self, order: Vec<usize>) -> Self {
+        if order.len() != self.num_qudits {
+            panic!("Output order must have one entry per qudit");
+        }
+
+        let mut seen = vec![false; self.num_qudits];
+        for &q in order.iter() {
+            if q >= self.num_qudits || seen[q] {
+                panic!("Output order must be a permutation of the circuit qudits");
+            }
+            seen[q] = true;
+        }
+
+        self.output_order = Some(order);
+        self
+    }
+
+    /// Order contractions by their estimated run time on `profile`.
+    ///
+    /// By default, contractions producing fewer qudits are performed first.
+    pub fn with_hardware_profile(mut self, profile: HardwareProfile) -> Self {
+        self.hardware_profile = Some(profile);
+        self
+    }
+
+    /// Keep the interface between each pair of qudits in `cuts` as an
+    /// explicit contraction.
+    ///
+    /// Two nodes cross a cut `(a, b)` when one acts on `a` but not `b` and
+    /// the other acts on `b` but not `a`. Such nodes are only combined once
+    /// nothing else in the circuit can be, so each side of the cut is fused
+    /// on its own and the sides are joined by contractions near the root.
+    ///
+    /// # Panics
+    ///
+    /// If a cut does not name two distinct qudits of the circuit.
+    pub fn with_cut_points(mut self, cuts: Vec<(usize, usize)>) -> Self {
+        for &(a, b) in cuts.iter() {
+            if a == b || a >= self.num_qudits || b >= self.num_qudits {
+                panic!("Cut point ({}, {}) must name two distinct qudits", a, b);
+            }
+        }
+        self.cut_points = cuts;
+        self
+    }
+
+    /// Pass intermediate results between consecutive contractions in
+    /// tensor form.
+    ///
+    /// By default, each contraction reshapes its result back into a matrix,
+    /// which the contraction consuming it immediately permutes again. In
+    /// this mode, a contraction instead writes its result already permuted
+    /// for its consumer, and only the root is returned to matrix form. This
+    /// removes one reshape-permute per consecutive pair of contractions.
+    pub fn with_tensor_intermediates(mut self) -> Self {
+        self.tensor_intermediates = true;
+        self
+    }
+
+    /// Whether combining nodes acting on `qudits1` and `qudits2` crosses a
+    /// cut point.
+    fn crosses_cut(&self, qudits1: &[usize], qudits2: &[usize]) -> bool {
+        self.cut_points.iter().any(|(a, b)| {
+            let one_side = |x: &[usize], y: &[usize]| {
+                x.contains(a) && !x.contains(b) && y.contains(b) && !y.contains(a)
+            };
+            one_side(qudits1, qudits2) || one_side(qudits2, qudits1)
+        })
+    }
+
+    /// Apply operation `op` only when classical bit `bit` is set.
+    ///
+    /// See [TreeBuilder::build_conditional].
+    ///
+    /// # Panics
+    ///
+    /// If `op` is not an operation of the circuit.
+    pub fn with_classical_control(mut self, op: usize, bit: usize) -> Self {
+        if !self.dag.contains_key(&op) {
+            panic!("Operation {} is not in the circuit", op);
+        }
+        self.classical_controls.insert(op, bit);
+        self
+    }
+
+    /// Build one tree per branch of a classically-controlled circuit.
+    ///
+    /// Every assignment of the classical bits given to
+    /// [TreeBuilder::with_classical_control] is a branch. In each branch,
+    /// controlled operations whose bit is unset are removed from the
+    /// circuit, along with their parameters.
+    ///
+    /// The operations that precede every controlled operation form a
+    /// prefix, and those that follow every controlled operation form a
+    /// suffix. Both are built once and shared by every branch, whose tree
+    /// multiplies them around its own build of the remaining operations.
+    /// Each branch tree takes the prefix's parameters, then the remaining
+    /// operations', then the suffix's.
+    ///
+    /// # Returns
+    ///
+    /// Each branch's condition and tree, ordered by the bits' values as a
+    /// binary number with the lowest bit least significant.
+    ///
+    /// # Panics
+    ///
+    /// - If there are too many classical bits to enumerate their branches.
+    /// - If a branch removes every operation of the circuit.
+    /// - In any case where [TreeBuilder::build_tree] panics.
+    pub fn build_conditional(mut self) -> Vec<(ClassicalCondition, ExpressionTree)> {
+        let mut bits: Vec<usize> = self.classical_controls.values().copied().collect();
+        bits.sort();
+        bits.dedup();
+        if bits.len() >= usize::BITS as usize {
+            panic!("Cannot enumerate the branches of {} classical bits", bits.len());
+        }
+
+        let controlled: HashSet<usize> = self.classical_controls.keys().copied().collect();
+        let after = self.reachable_from(&controlled, true);
+        let before = self.reachable_from(&controlled, false);
+        let prefix_ops: HashSet<usize> = self
+            .dag
+            .keys()
+            .filter(|op| !controlled.contains(op) && !after.contains(op))
+            .copied()
+            .collect();
+        let suffix_ops: HashSet<usize> = self
+            .dag
+            .keys()
+            .filter(|op| !controlled.contains(op) && !before.contains(op))
+            .filter(|op| !prefix_ops.contains(op))
+            .copied()
+            .collect();
+        let middle_ops: HashSet<usize> = self
+            .dag
+            .keys()
+            .filter(|op| !prefix_ops.contains(op) && !suffix_ops.contains(op))
+            .copied()
+            .collect();
+
+        // The output order applies to the product of the parts
+        let output_order = self.output_order.take();
+        let prefix = self.build_part(&prefix_ops);
+        let suffix = self.build_part(&suffix_ops);
+
+        (0..1usize << bits.len())
+            .map(|assignment| {
+                let condition = ClassicalCondition {
+                    values: bits
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &bit)| (bit, assignment & (1 << i) != 0))
+                        .collect(),
+                };
+
+                let branch_ops: HashSet<usize> = middle_ops
+                    .iter()
+                    .filter(|op| match self.classical_controls.get(op) {
+                        Some(&bit) => condition.value(bit) == Some(true),
+                        None => true,
+                    })
+                    .copied()
+                    .collect();
+                let middle = self.build_part(&branch_ops);
+
+                let tree = [prefix.clone(), middle, suffix.clone()]
+                    .into_iter()
+                    .flatten()
+                    .reduce(|acc, part| ExpressionTree::Mul(MulNode::new(acc, part)))
+                    .unwrap_or_else(|| panic!("Branch {:?} removes every operation", condition));
+                let tree = match &output_order {
+                    Some(order) => tree.apply_output_permutation(order.clone()),
+                    None => tree,
+                };
+
+                (condition, tree)
+            })
+            .collect()
+    }
+
+    /// Build the circuit made of only the operations in `ops`, or `None`
+    /// if there are none.
+    fn build_part(&self, ops: &HashSet<usize>) -> Option<ExpressionTree> {
+        if ops.is_empty() {
+            return None;
+        }
+
+        let mut part = self.clone();
+        let mut removed: Vec<usize> =
+            self.dag.keys().filter(|op| !ops.contains(op)).copied().collect();
+        removed.sort();
+        for op in removed {
+            part.remove_operation(op);
+        }
+        Some(part.build_tree())
+    }
+
+    /// The operations that follow any of `ops` in the DAG, or that precede
+    /// any of them if `forward` is false, excluding `ops` themselves unless
+    /// they are reached from one another.
+    fn reachable_from(&self, ops: &HashSet<usize>, forward: bool) -> HashSet<usize> {
+        let mut reached = HashSet::new();
+        let mut stack: Vec<usize> = ops.iter().copied().collect();
+        while let Some(idx) = stack.pop() {
+            let node = &self.dag[&idx];
+            let neighbors = if forward { &node.next } else { &node.prev };
+            for &neighbor in neighbors.iter().flatten() {
+                if reached.insert(neighbor) {
+                    stack.push(neighbor);
+                }
+            }
+        }
+        reached
+    }
+
+    /// Remove a node from the DAG, connecting its neighbors on each qudit.
+    fn remove_operation(&mut self, idx: usize) {
+        let node = self.dag.remove(&idx).unwrap();
+        for (loc_idx, qudit) in node.qudits.iter().enumerate() {
+            let prev = node.prev[loc_idx];
+            let next = node.next[loc_idx];
+            if let Some(prev_idx) = prev {
+                let prev_node = self.dag.get_mut(&prev_idx).unwrap();
+                let pos = prev_node.qudits.iter().position(|q| q == qudit).unwrap();
+                prev_node.next[pos] = next;
+            }
+            if let Some(next_idx) = next {
+                let next_node = self.dag.get_mut(&next_idx).unwrap();
+                let pos = next_node.qudits.iter().position(|q| q == qudit).unwrap();
+                next_node.prev[pos] = prev;
+            }
         }
     }
 
@@ -211,8 +847,261 @@ impl TreeBuilder {
        dag_vec.into_iter()
     }
 
+   /// Relabel the circuit qudits before building.
+   ///
+   /// Every qudit index `q` in the DAG is replaced with `perm[q]`. Nodes whose
+   /// relabeled location is no longer sorted are wrapped in a permutation
+   /// node, exactly as in [TreeBuilder::new].
+   ///
+   /// # Arguments
+   ///
+   /// * `perm` - The new label for each circuit qudit.
+   ///
+   /// # Panics
+   ///
+   /// If `perm` is not a permutation of `0..num_qudits`.
+   pub fn relabel(mut self, perm: &[usize]) -> TreeBuilder {
+       if perm.len() != self.num_qudits {
+           panic!("Relabeling permutation must have one entry per qudit");
+       }
+
+       let mut seen = vec![false; self.num_qudits];
+       for &q in perm.iter() {
+           if q >= self.num_qudits || seen[q] {
+               panic!("Relabeling must be a permutation of the circuit qudits");
+           }
+           seen[q] = true;
+       }
+
+       let mut dag = HashMap::new();
+       for (idx, node) in self.dag.drain() {
+           let loc: Vec<usize> = node.qudits.iter().map(|&q| perm[q]).collect();
+           let new_node = if loc.iter().zip(loc.iter().skip(1)).all(|(a, b)| a < b) {
+               Node {
+                   node: node.node,
+                   qudits: loc,
+                   next: node.next,
+                   prev: node.prev,
+                   ops: node.ops,
+               }
+           } else {
+               let qudit_perm = QuditPermutation::locally_invert_location(node.node.radices(), &loc);
+               let mut order: Vec<usize> = (0..loc.len()).collect();
+               order.sort_by_key(|&i| loc[i]);
+               Node {
+                   node: ExpressionTree::Perm(PermNode::new(node.node, qudit_perm)),
+                   qudits: order.iter().map(|&i| loc[i]).collect(),
+                   next: order.iter().map(|&i| node.next[i]).collect(),
+                   prev: order.iter().map(|&i| node.prev[i]).collect(),
+                   ops: node.ops,
+               }
+           };
+           dag.insert(idx, new_node);
+       }
+
+       let mut radices = vec![None; self.num_qudits];
+       for (q, &radix) in self.radices.iter().enumerate() {
+           radices[perm[q]] = radix;
+       }
+
+       self.dag = dag;
+       self.radices = radices;
+       self
+   }
+
+   /// Explain how two nodes in the DAG could be combined, or why not.
+   ///
+   /// This is a diagnostic for inspecting the builder's merge decisions and
+   /// does not modify the DAG.
+   ///
+   /// # Arguments
+   ///
+   /// * `a` - The index of the first node.
+   /// * `b` - The index of the second node.
+   pub fn explain_pair(&self, a: usize, b: usize) -> PairDecision {
+       if !self.dag.contains_key(&a) {
+           return PairDecision::Blocked(PairBlocker::MissingNode(a));
+       }
+       if !self.dag.contains_key(&b) {
+           return PairDecision::Blocked(PairBlocker::MissingNode(b));
+       }
+       if a == b {
+           return PairDecision::Blocked(PairBlocker::SameNode);
+       }
+
+       let a_node = &self.dag[&a];
+       let b_node = &self.dag[&b];
+
+       let (left, right) = if a_node.next.contains(&Some(b)) {
+           (a, b)
+       } else if b_node.next.contains(&Some(a)) {
+           (b, a)
+       } else {
+           if !intersect(&a_node.qudits, &b_node.qudits).is_empty() {
+               return PairDecision::Blocked(PairBlocker::NotAdjacent);
+           }
+           if self.has_non_direct_dependency(a, b)
+               || self.has_non_direct_dependency(b, a)
+           {
+               return PairDecision::Blocked(PairBlocker::NonDirectDependency);
+           }
+           return PairDecision::Kron;
+       };
+
+       if self.has_non_direct_dependency(left, right) {
+           return PairDecision::Blocked(PairBlocker::NonDirectDependency);
+       }
+
+       let left_node = &self.dag[&left];
+       let right_node = &self.dag[&right];
+       let right_prevs: HashSet<usize> =
+           right_node.prev.iter().filter_map(|idx| *idx).collect();
+       if left_node.qudits == right_node.qudits && right_prevs.len() == 1 {
+           return PairDecision::Multiply;
+       }
+
+       let union = union(&left_node.qudits, &right_node.qudits);
+       let intersect = intersect(&left_node.qudits, &right_node.qudits);
+       let disjoint = difference(&union, &intersect);
+       PairDecision::Contract { disjoint_size: disjoint.len() }
+   }
+
    /// Build the computation tree.
-   pub fn build_tree(mut self) -> ExpressionTree {
+   ///
+   /// # Panics
+   ///
+   /// If the circuit has more parameters than allowed by
+   /// [TreeBuilder::with_max_params].
+   pub fn build_tree(self) -> ExpressionTree {
+       self.try_build_tree().unwrap_or_else(|e| panic!("{}", e))
+   }
+
+   /// Build the computation tree as in [TreeBuilder::build_tree], returning
+   /// an error instead of panicking if the circuit has more parameters than
+   /// allowed by [TreeBuilder::with_max_params].
+   pub fn try_build_tree(mut self) -> Result<ExpressionTree, QuditTreeError> {
+       self.build(None).map(|(tree, _)| tree)
+   }
+
+   /// Build the computation tree, along with its parameter ordering.
+   ///
+   /// The built tree takes its parameters in tree order, which generally
+   /// differs from the order of the circuit operations. The returned map
+   /// relates the two: tree parameter `i` reads circuit parameter
+   /// `map.global_index(i)`, where circuit parameters are laid out
+   /// operation by operation. Compile the tree with
+   /// [crate::compile_with_parameter_map] to evaluate it, and differentiate
+   /// it, with parameters in circuit order.
+   ///
+   /// # Panics
+   ///
+   /// If the circuit has more parameters than allowed by
+   /// [TreeBuilder::with_max_params].
+   pub fn build_tree_with_param_map(mut self) -> (ExpressionTree, ParameterMap) {
+       let (tree, indices) = self.build(None).unwrap_or_else(|e| panic!("{}", e));
+       (tree, ParameterMap::new(indices))
+   }
+
+   /// Build the computation tree, ordering contractions by `cost`.
+   ///
+   /// In each round, the candidate contractions are performed from lowest
+   /// to highest cost, skipping those involving a node that was already
+   /// contracted in the round. By default, the cost of a contraction is the
+   /// number of qudits it produces.
+   ///
+   /// # Panics
+   ///
+   /// If the circuit has more parameters than allowed by
+   /// [TreeBuilder::with_max_params].
+   pub fn build_tree_with_cost(
+       mut self,
+       cost: impl Fn(&ContractCandidate) -> u64,
+   ) -> ExpressionTree {
+       self.build(Some(&cost)).unwrap_or_else(|e| panic!("{}", e)).0
+   }
+
+   /// Build the computation tree, reusing contraction plans from `template`.
+   ///
+   /// Plans computed during the build are added to `template`, so building
+   /// another circuit with the same structure skips recomputing them.
+   ///
+   /// # Panics
+   ///
+   /// If the circuit has more parameters than allowed by
+   /// [TreeBuilder::with_max_params].
+   pub fn build_tree_with_template(mut self, template: &mut ContractTemplate) -> ExpressionTree {
+       self.contract_template = Some(std::mem::take(template));
+       let built = self.build(None);
+       *template = self.contract_template.take().unwrap();
+       built.unwrap_or_else(|e| panic!("{}", e)).0
+   }
+
+   fn build(
+       &mut self,
+       cost: Option<&dyn Fn(&ContractCandidate) -> u64>,
+   ) -> Result<(ExpressionTree, Vec<usize>), QuditTreeError> {
+       if let Some(max_params) = self.max_params {
+           let num_params: usize = self.op_num_params.iter().sum();
+           if num_params > max_params {
+               return Err(QuditTreeError::TooManyParams { num_params, max_params });
+           }
+       }
+
+       self.combine_all(cost);
+
+       // Nodes across a cut point are only combined once nothing else can be.
+       if self.dag.len() != 1 && !self.cut_points.is_empty() {
+           let cut_points = std::mem::take(&mut self.cut_points);
+           self.combine_all(cost);
+           self.cut_points = cut_points;
+       }
+
+       // If there are still disjoint graphs, then we need to handle them.
+       // This can only arise with full separately systems of qudits.
+       // Therefore, we kronecker all of them together, along with any
+       // idle qudits.
+       let has_idle = (0..self.num_qudits)
+           .any(|q| !self.dag.values().any(|n| n.qudits.contains(&q)));
+       if self.dag.len() != 1 || has_idle {
+           self.kron_all_completely_disjoint()?;
+       }
+
+       // Finally, we should have a single node left in the DAG.
+       assert!(self.dag.len() == 1);
+
+       let mut op_offsets = Vec::with_capacity(self.op_num_params.len());
+       let mut offset = 0;
+       for num_params in self.op_num_params.iter() {
+           op_offsets.push(offset);
+           offset += num_params;
+       }
+
+       for (_, v) in self.dag.drain().take(1) {
+           let param_map = v
+               .ops
+               .iter()
+               .flat_map(|&op| op_offsets[op]..op_offsets[op] + self.op_num_params[op])
+               .collect();
+           let mut node = match self.output_order.take() {
+               Some(order) => v.node.apply_output_permutation(order),
+               None => v.node,
+           };
+           if self.tensor_intermediates {
+               node.traverse_mut(&|n| {
+                   if let ExpressionTree::Contract(c) = n {
+                       c.fuse_operand_permutations();
+                   }
+               });
+           }
+           return Ok((node, param_map));
+       }
+
+       panic!("Should never reach here");
+   }
+
+   /// Multiply, kron, and contract nodes in rounds of growing size until
+   /// no more nodes can be combined.
+   fn combine_all(&mut self, cost: Option<&dyn Fn(&ContractCandidate) -> u64>) {
        // First step is to multiply everything possible.
        // This while ensure there are no trivially combinable nodes.
        self.multiply_all_possible();
@@ -233,28 +1122,11 @@ impl TreeBuilder {
            // Contract all nodes that are disjoint by at most disjoint_size.
            // After calling this function all nodes will with not be disjoint,
            // or be disjoint by at least disjoint_size + 1.
-           self.contract_all(disjoint_size);
+           self.contract_all(disjoint_size, cost);
 
            // Multiply all nodes that can be multiplied.
            self.multiply_all_possible();
        }
-
-       // If there are still disjoint graphs, then we need to handle them.
-       // This can only arise with full separately systems of qudits.
-       // Therefore, we kronecker all of them together.
-       if self.dag.len() != 1 {
-           self.kron_all_completely_disjoint();
-           // TODO: enlarge
-       }
-
-       // Finally, we should have a single node left in the DAG.
-       assert!(self.dag.len() == 1);
-
-       for (_, v) in self.dag.drain().take(1) {
-           return v.node;
-       }
-
-       panic!("Should never reach here");
    }
 
    /// Multiply all nodes that can be simply multiplied together.
@@ -344,6 +1216,7 @@ impl TreeBuilder {
                qudits: left.qudits,
                next: right.next,
                prev: left.prev,
+               ops: left.ops.into_iter().chain(right.ops).collect(),
            };
            assert!(self.dag.insert(new_node_id, new_node).is_none());
        }
@@ -402,6 +1275,10 @@ impl TreeBuilder {
                        continue;
                    }
 
+                   if self.crosses_cut(&prev_next.qudits, &node.qudits) {
+                       continue;
+                   }
+
                    if self.has_non_direct_dependency(*idx, prev_next_idx) {
                        continue;
                    }
@@ -480,6 +1357,10 @@ impl TreeBuilder {
                        continue;
                    }
 
+                   if self.crosses_cut(&next_prev.qudits, &node.qudits) {
+                       continue;
+                   }
+
                    if self.has_non_direct_dependency(next_prev_idx, *idx) {
                        continue;
                    }
@@ -578,6 +1459,7 @@ impl TreeBuilder {
                    .chain(ndn_right.prev.iter())
                    .cloned()
                    .collect(),
+               ops: ndn_left.ops.into_iter().chain(ndn_right.ops).collect(),
            };
            assert!(self.dag.insert(new_node_id, new_ndn).is_none());
        }
@@ -587,17 +1469,25 @@ impl TreeBuilder {
 
    /// Contract all pairs of gates with at most `disjoint_size` mismatched
    /// qudits.
-   fn contract_all(&mut self, disjoint_size: usize) {
+   fn contract_all(
+       &mut self,
+       disjoint_size: usize,
+       cost: Option<&dyn Fn(&ContractCandidate) -> u64>,
+   ) {
        loop {
            let num_nodes = self.dag.len();
-           self.contract_all_single_step(disjoint_size);
+           self.contract_all_single_step(disjoint_size, cost);
            if num_nodes == self.dag.len() {
                break;
            }
        }
    }
 
-   fn contract_all_single_step(&mut self, disjoint_size: usize) {
+   fn contract_all_single_step(
+       &mut self,
+       disjoint_size: usize,
+       cost: Option<&dyn Fn(&ContractCandidate) -> u64>,
+   ) {
        let mut candidate_contract_pairs = Vec::new();
 
        // Find all gates that can contract with their previous
@@ -635,7 +1525,47 @@ impl TreeBuilder {
                    continue;
                }
 
-               candidate_contract_pairs.push((union.len(), prev, *idx));
+               if self.crosses_cut(&prev_node.qudits, &node.qudits) {
+                   continue;
+               }
+
+               let cost = match (cost, &self.hardware_profile) {
+                   (Some(cost), _) => {
+                       let dimension = union
+                           .iter()
+                           .map(|q| {
+                               match node.qudits.iter().position(|x| x == q) {
+                                   Some(pos) => node.node.radices()[pos] as usize,
+                                   None => {
+                                       let pos = prev_node.qudits.iter().position(|x| x == q).unwrap();
+                                       prev_node.node.radices()[pos] as usize
+                                   },
+                               }
+                           })
+                           .product();
+                       cost(&ContractCandidate {
+                           left_qudits: prev_node.qudits.clone(),
+                           right_qudits: node.qudits.clone(),
+                           dimension,
+                       }) as f64
+                   },
+                   (None, Some(profile)) => {
+                       let overlap_dimension = intersect
+                           .iter()
+                           .map(|q| {
+                               let pos = node.qudits.iter().position(|x| x == q).unwrap();
+                               node.node.radices()[pos] as usize
+                           })
+                           .product();
+                       profile.contraction_time(
+                           prev_node.node.dimension(),
+                           node.node.dimension(),
+                           overlap_dimension,
+                       )
+                   },
+                   (None, None) => union.len() as f64,
+               };
+               candidate_contract_pairs.push((cost, prev, *idx));
 
                // if best_idx.is_none() || best_size.unwrap() > union.len() {
                //     best_idx = Some(prev);
@@ -691,7 +1621,7 @@ impl TreeBuilder {
        let mut already_in_contract_this_round = HashSet::new();
 
        candidate_contract_pairs
-           .sort_by(|(a_size, _, _), (b_size, _, _)| a_size.cmp(b_size));
+           .sort_by(|(a_cost, _, _), (b_cost, _, _)| a_cost.total_cmp(b_cost));
 
        for (_, idx_left, idx_right) in candidate_contract_pairs.iter() {
            if already_in_contract_this_round.contains(idx_left) {
@@ -800,51 +1730,96 @@ impl TreeBuilder {
 
            // Insert new node
            let new_ndn = Node {
-               node: ExpressionTree::Contract(ContractNode::new(
+               node: contract_or_kron(
                    ndn_left.node,
                    ndn_right.node,
                    ndn_left.qudits.to_vec(),
                    ndn_right.qudits.to_vec(),
-               )),
+                   self.contract_template.as_mut(),
+               ),
                qudits: new_location,
                next: new_next,
                prev: new_prev,
+               ops: ndn_left.ops.into_iter().chain(ndn_right.ops).collect(),
            };
            assert!(self.dag.insert(new_node_id, new_ndn).is_none());
        }
    }
 
-   fn kron_all_completely_disjoint(&mut self) {
-       // TODO: Also handle idle qubits
-       // loop {
-       //     // break if there are no completely disjoint pairs
-       //dag_ordered_iter()
-       //     for (idx1, node1) in self.dag.iter() {
-       //         for (idx2, node2) in self.dag.iter() {
-       //             if idx1 == idx2 {
-       //                 continue;
-       //             }
-
-       //             if node1.location.intersect(&node2.location).len() == 0 {
-       //                 assert!(node1.next.iter().all(|n| n.is_none()));
-       //                 assert!(node1.prev.iter().all(|n| n.is_none()));
-       //                 assert!(node2.next.iter().all(|n| n.is_none()));
-       //                 assert!(node2.prev.iter().all(|n| n.is_none()));
-
-       //                 // let node1 = self.dag.remove(idx_left).unwrap();
-       //                 // let node2 = self.dag.remove(idx_right).unwrap();
-       //                 // let new_node_id = self.index_counter;
-       //                 // self.index_counter += 1;
-
-       //                 // TODO: Need to add permutations here since the
-       // disjoints doesn't                 // mean separable by an
-       // integer index
-
-       //                 break;
-       //             }
-       //         }
-       //     }
-       // }
+   /// Kron all remaining nodes and idle qudits into a single root node.
+   ///
+   /// Idle qudits are covered by an identity. The nodes are joined through
+   /// [contract_or_kron], which krons disjoint nodes. The root acts on its
+   /// qudits in ascending order, which may require permutations since
+   /// disjoint nodes need not act on contiguous blocks of qudits.
+   ///
+   /// # Errors
+   ///
+   /// If an idle qudit has no known radix, as when no radix is given for a
+   /// qudit no operation acts on.
+   fn kron_all_completely_disjoint(&mut self) -> Result<(), QuditTreeError> {
+       let idle: Vec<usize> = (0..self.num_qudits)
+           .filter(|q| !self.dag.values().any(|n| n.qudits.contains(q)))
+           .collect();
+       if let Some(&q) = idle.iter().find(|&&q| self.radices[q].is_none()) {
+           return Err(QuditTreeError::UnknownIdleRadix(q));
+       }
+
+       let mut indices: Vec<usize> = self.dag.keys().copied().collect();
+       indices.sort();
+       let mut nodes: Vec<Node> = indices
+           .into_iter()
+           .map(|idx| self.dag.remove(&idx).unwrap())
+           .collect();
+
+       for node in nodes.iter() {
+           assert!(node.next.iter().all(|n| n.is_none()));
+           assert!(node.prev.iter().all(|n| n.is_none()));
+       }
+
+       if !idle.is_empty() {
+           let radices = QuditRadices::from_iter(idle.iter().map(|&q| self.radices[q].unwrap()));
+           nodes.push(Node {
+               node: ExpressionTree::Identity(IdentityNode::new(radices)),
+               next: vec![None; idle.len()],
+               prev: vec![None; idle.len()],
+               qudits: idle,
+               ops: vec![],
+           });
+       }
+
+       // Join in order of each node's lowest qudit. The nodes are disjoint,
+       // so each join is a kron, permuted back to ascending qudits as needed
+       nodes.sort_by_key(|n| n.qudits[0]);
+       let mut nodes = nodes.into_iter();
+       let first = nodes.next().unwrap();
+       let mut tree = first.node;
+       let mut sorted_qudits = first.qudits;
+       let mut ops = first.ops;
+       for node in nodes {
+           tree = contract_or_kron(
+               tree,
+               node.node,
+               sorted_qudits.clone(),
+               node.qudits.clone(),
+               self.contract_template.as_mut(),
+           );
+           sorted_qudits = union(&sorted_qudits, &node.qudits);
+           sorted_qudits.sort();
+           ops.extend(node.ops);
+       }
+
+       let new_node_id = self.index_counter;
+       self.index_counter += 1;
+       let new_node = Node {
+           node: tree,
+           next: vec![None; sorted_qudits.len()],
+           prev: vec![None; sorted_qudits.len()],
+           qudits: sorted_qudits,
+           ops,
+       };
+       assert!(self.dag.insert(new_node_id, new_node).is_none());
+       Ok(())
    }
 
    /// Returns true if there is a non-direct dependency between the two nodes.
@@ -1059,6 +2034,618 @@ pub mod strategies {
 
 #[cfg(test)]
 mod tests {
+    use qudit_core::c64;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use qudit_core::HasParams;
+    use qudit_core::QuditRadices;
+    use qudit_core::QuditSystem;
+
+    use crate::error::QuditTreeError;
+    use super::contract_or_kron;
+    use super::BuilderExpressionInput;
+    use super::ContractCandidate;
+    use super::ContractTemplate;
+    use super::HardwareProfile;
+    use super::ExpressionTree;
+    use super::PairBlocker;
+    use super::PairDecision;
+    use super::RuntimeConstantNode;
+    use super::TreeBuilder;
+    use super::super::identity::IdentityNode;
+    use crate::bytecode::Bytecode;
+    use crate::bytecode::GeneralizedInstruction;
+    use crate::compiler::compile;
+    use crate::compiler::compile_with_parameter_map;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+
+    /// An RY and a phase on separate qubits followed by an entangling gate.
+    fn ry_and_p() -> TreeBuilder {
+        let ry = fixtures::ry();
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        TreeBuilder::new(
+            2,
+            vec![
+                BuilderExpressionInput::Unitary(ry),
+                BuilderExpressionInput::Unitary(p),
+                BuilderExpressionInput::Unitary(cry),
+            ],
+            vec![vec![0], vec![1], vec![0, 1]],
+            vec![vec![Some(2)], vec![Some(2)], vec![None, None]],
+            vec![vec![None], vec![None], vec![Some(0), Some(1)]],
+        )
+    }
+
+    #[test]
+    fn test_output_order_permutes_qudits() {
+        let ry = fixtures::ry();
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        let gate_for = |loc: &[usize]| match loc {
+            [0] => ry.clone(),
+            [2] => p.clone(),
+            _ => cry.clone(),
+        };
+        let builder = || {
+            TreeBuilder::from_locations(
+                QuditRadices::from_iter([2, 2, 2]),
+                vec![vec![0], vec![0, 1], vec![2], vec![1, 2]],
+                gate_for,
+            )
+        };
+
+        // A 3-cycle is not its own inverse, so this also checks the
+        // direction of the permutation.
+        let order = [1, 2, 0];
+        let params = [0.7, 1.3, 0.4, -0.9];
+        let default = compile(&builder().build_tree());
+        let cycled = compile(&builder().with_output_order(order.to_vec()).build_tree());
+        let default = QVM::<c64>::new(default, DifferentiationLevel::None)
+            .get_unitary(&params)
+            .to_owned();
+        let mut cycled_qvm = QVM::<c64>::new(cycled, DifferentiationLevel::None);
+        let cycled = cycled_qvm.get_unitary(&params);
+
+        // Output qubit i is circuit qubit order[i], with qubit 0 the most
+        // significant bit of an index.
+        let source = |idx: usize| {
+            (0..3).fold(0, |acc, i| acc | (((idx >> (2 - i)) & 1) << (2 - order[i])))
+        };
+        for r in 0..8 {
+            for c in 0..8 {
+                assert!((cycled[(r, c)] - default[(source(r), source(c))]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_disjoint_contraction_krons() {
+        let identity = |r: u8| {
+            ExpressionTree::Identity(IdentityNode::new(QuditRadices::from_iter([r])))
+        };
+
+        let in_order = contract_or_kron(identity(2), identity(3), vec![0], vec![1], None);
+        assert!(matches!(in_order, ExpressionTree::Kron(_)));
+        assert_eq!(in_order.radices(), QuditRadices::from_iter([2, 3]));
+
+        let reversed = contract_or_kron(identity(2), identity(3), vec![1], vec![0], None);
+        assert!(matches!(reversed, ExpressionTree::Perm(_)));
+        assert_eq!(reversed.radices(), QuditRadices::from_iter([3, 2]));
+
+        // The builder joins disjoint gates the same way
+        let cx = fixtures::cx();
+        let shift = UnitaryExpression::new("X3() { [[0, 0, 1], [1, 0, 0], [0, 1, 0]] }");
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 3, 2]),
+            vec![vec![0, 2], vec![1]],
+            |loc| if loc.len() == 2 { cx.clone() } else { shift.clone() },
+        )
+        .build_tree();
+        let expected = contract_or_kron(
+            ExpressionTree::Leaf(cx.clone()),
+            ExpressionTree::Leaf(shift.clone()),
+            vec![0, 2],
+            vec![1],
+            None,
+        );
+        assert!(matches!(tree, ExpressionTree::Perm(_)));
+        assert_eq!(tree, expected);
+    }
+
+    #[test]
+    fn test_from_prev_only_matches_new() {
+        let ry = fixtures::ry();
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        let derived = TreeBuilder::from_prev_only(
+            2,
+            vec![
+                BuilderExpressionInput::Unitary(ry),
+                BuilderExpressionInput::Unitary(p),
+                BuilderExpressionInput::Unitary(cry),
+            ],
+            vec![vec![0], vec![1], vec![0, 1]],
+            vec![vec![None], vec![None], vec![Some(0), Some(1)]],
+        );
+        let explicit = ry_and_p();
+
+        assert_eq!(derived.dag.len(), explicit.dag.len());
+        for (idx, node) in explicit.dag.iter() {
+            assert_eq!(derived.dag[idx].next, node.next);
+            assert_eq!(derived.dag[idx].prev, node.prev);
+            assert_eq!(derived.dag[idx].qudits, node.qudits);
+        }
+    }
+
+    fn cx(_location: &[usize]) -> UnitaryExpression {
+        fixtures::cx()
+    }
+
+    #[test]
+    fn test_explain_pair_reasons() {
+        let x = UnitaryExpression::new("X() { [[0, 1], [1, 0]] }");
+        let builder = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![0, 1], vec![2], vec![1, 2], vec![0, 1]],
+            |loc| if loc.len() == 1 { x.clone() } else { cx(loc) },
+        );
+
+        assert_eq!(builder.explain_pair(0, 1), PairDecision::Multiply);
+        assert_eq!(builder.explain_pair(2, 0), PairDecision::Kron);
+        assert_eq!(builder.explain_pair(1, 3), PairDecision::Contract { disjoint_size: 2 });
+        assert_eq!(builder.explain_pair(0, 3), PairDecision::Blocked(PairBlocker::NotAdjacent));
+
+        // Operation 2 reaches operation 4 only through operation 3
+        assert_eq!(builder.explain_pair(2, 4), PairDecision::Blocked(PairBlocker::NonDirectDependency));
+        // Operation 1 feeds operation 4 directly on qudit 0 and through operation 3 on qudit 1
+        assert_eq!(builder.explain_pair(1, 4), PairDecision::Blocked(PairBlocker::NonDirectDependency));
+
+        assert_eq!(builder.explain_pair(3, 3), PairDecision::Blocked(PairBlocker::SameNode));
+        assert_eq!(builder.explain_pair(3, 9), PairDecision::Blocked(PairBlocker::MissingNode(9)));
+    }
+
+    #[test]
+    fn test_qudit_radices_of_mixed_radix_circuit() {
+        let p3 = UnitaryExpression::new("P3(a) { [[1, 0, 0], [0, e^(i*a), 0], [0, 0, 1]] }");
+        let builder = TreeBuilder::new(
+            3,
+            vec![BuilderExpressionInput::Unitary(p3), BuilderExpressionInput::Unitary(cx(&[]))],
+            vec![vec![0], vec![2, 1]],
+            vec![vec![None], vec![None, None]],
+            vec![vec![None], vec![None, None]],
+        );
+        assert_eq!(builder.qudit_radices(), QuditRadices::from_iter([3, 2, 2]));
+        assert_eq!(builder.relabel(&[2, 0, 1]).qudit_radices(), QuditRadices::from_iter([2, 2, 3]));
+    }
+
+    #[test]
+    fn test_relabel_matches_permuted_unitary() {
+        let ry = fixtures::ry();
+        let cry = fixtures::cry();
+        let builder = || TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2], vec![2], vec![0, 2]],
+            |loc| if loc.len() == 1 { ry.clone() } else { cry.clone() },
+        );
+        let evaluate = |builder: TreeBuilder, params: &[f64]| {
+            let (tree, param_map) = builder.build_tree_with_param_map();
+            QVM::<c64>::new(compile_with_parameter_map(&tree, param_map), DifferentiationLevel::None)
+                .get_unitary(params)
+                .to_owned()
+        };
+
+        let params = [0.3, -1.1, 0.8, 2.0];
+        let perm = [2, 0, 1];
+        let original = evaluate(builder(), &params);
+        let relabeled = evaluate(builder().relabel(&perm), &params);
+
+        // Qudit q of the original circuit is qudit perm[q] after relabeling
+        let relabel_index = |idx: usize| -> usize {
+            (0..3).map(|q| ((idx >> (2 - q)) & 1) << (2 - perm[q])).sum()
+        };
+        for r in 0..8 {
+            for c in 0..8 {
+                let diff = relabeled[(relabel_index(r), relabel_index(c))] - original[(r, c)];
+                assert!(diff.norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder_from_locations() {
+        let builder = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2, 2]),
+            vec![vec![2, 3], vec![1, 2], vec![0, 1]],
+            cx,
+        );
+
+        assert_eq!(builder.index_counter, 3);
+        assert_eq!(builder.num_qudits, 4);
+        assert_eq!(builder.dag.len(), 3);
+        assert_eq!(builder.dag[&0].qudits, vec![2, 3]);
+        assert_eq!(builder.dag[&0].next, vec![Some(1), None]);
+        assert_eq!(builder.dag[&0].prev, vec![None, None]);
+        assert_eq!(builder.dag[&1].qudits, vec![1, 2]);
+        assert_eq!(builder.dag[&1].next, vec![Some(2), None]);
+        assert_eq!(builder.dag[&1].prev, vec![None, Some(0)]);
+        assert_eq!(builder.dag[&2].qudits, vec![0, 1]);
+        assert_eq!(builder.dag[&2].next, vec![None, None]);
+        assert_eq!(builder.dag[&2].prev, vec![None, Some(1)]);
+    }
+
+    #[test]
+    fn test_param_map_evaluates_in_circuit_order() {
+        let ry = fixtures::ry();
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        let gate_for = |loc: &[usize]| match loc {
+            [0] => ry.clone(),
+            [1] => p.clone(),
+            _ => cry.clone(),
+        };
+        let (tree, param_map) = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2]),
+            vec![vec![1], vec![0, 1], vec![0]],
+            gate_for,
+        )
+        .build_tree_with_param_map();
+        assert_eq!(param_map.num_global_params(), 3);
+
+        // The same circuit built with combinators takes its parameters in
+        // circuit order.
+        let leaf = |expr: &UnitaryExpression| ExpressionTree::Leaf(expr.clone());
+        let expected = leaf(&p)
+            .on(&[1], 2)
+            .then(leaf(&cry))
+            .then(leaf(&ry).on(&[0], 2));
+
+        let params = [0.3, -1.1, 0.8];
+        let mut qvm = QVM::<c64>::new(
+            compile_with_parameter_map(&tree, param_map),
+            DifferentiationLevel::Gradient,
+        );
+        let mut expected_qvm = QVM::<c64>::new(compile(&expected), DifferentiationLevel::Gradient);
+        let (utry, grad) = qvm.get_unitary_and_gradient(&params);
+        let (expected_utry, expected_grad) = expected_qvm.get_unitary_and_gradient(&params);
+
+        assert_eq!(grad.nmats(), 3);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((utry[(r, c)] - expected_utry[(r, c)]).norm() < 1e-10);
+                for i in 0..3 {
+                    let diff = grad.mat_ref(i)[(r, c)] - expected_grad.mat_ref(i)[(r, c)];
+                    assert!(diff.norm() < 1e-10);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_tree_with_template_reuses_plans() {
+        let build = |template: &mut ContractTemplate| {
+            TreeBuilder::from_locations(
+                QuditRadices::from_iter([2, 2, 2]),
+                vec![vec![0, 1], vec![1, 2]],
+                cx,
+            )
+            .build_tree_with_template(template)
+        };
+
+        let mut template = ContractTemplate::new();
+        let first = build(&mut template);
+        let num_plans = template.len();
+        assert!(num_plans > 0);
+
+        let second = build(&mut template);
+        assert_eq!(template.len(), num_plans);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_cut_point_is_kept_as_contraction() {
+        let build = |cuts: Vec<(usize, usize)>| {
+            TreeBuilder::from_locations(
+                QuditRadices::from_iter([2, 2, 2, 2]),
+                vec![vec![0, 1], vec![1, 2], vec![2, 3]],
+                cx,
+            )
+            .with_cut_points(cuts)
+            .build_tree()
+        };
+
+        // The first gate is the only one acting on qudit 0, so the cut
+        // between qudits 0 and 2 leaves it to be contracted last.
+        let tree = build(vec![(0, 2)]);
+        match &tree {
+            ExpressionTree::Contract(n) => {
+                assert_eq!(n.left_qudits, vec![0, 1]);
+                assert_eq!(n.right_qudits, vec![1, 2, 3]);
+            },
+            _ => panic!("Expected the cut to be the root contraction"),
+        }
+
+        let mut cut = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let mut uncut = QVM::<c64>::new(compile(&build(vec![])), DifferentiationLevel::None);
+        assert_eq!(cut.get_unitary(&[]), uncut.get_unitary(&[]));
+    }
+
+    #[test]
+    fn test_disjoint_gates_kron_around_idle_qudit() {
+        let gate_for = |loc: &[usize]| match loc[0] {
+            0 => UnitaryExpression::new("X() { [[0, 1], [1, 0]] }"),
+            _ => UnitaryExpression::new("Z() { [[1, 0], [0, ~1]] }"),
+        };
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0], vec![2]],
+            gate_for,
+        )
+        .build_tree();
+        assert_eq!(tree.num_qudits(), 3);
+
+        // The same operator, written as two overlapping gates
+        let padded_for = |loc: &[usize]| match loc[0] {
+            0 => UnitaryExpression::new(
+                "XI() { [[0, 0, 1, 0], [0, 0, 0, 1], [1, 0, 0, 0], [0, 1, 0, 0]] }",
+            ),
+            _ => UnitaryExpression::new(
+                "IZ() { [[1, 0, 0, 0], [0, ~1, 0, 0], [0, 0, 1, 0], [0, 0, 0, ~1]] }",
+            ),
+        };
+        let expected = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2]],
+            padded_for,
+        )
+        .build_tree();
+
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let mut expected_qvm = QVM::<c64>::new(compile(&expected), DifferentiationLevel::None);
+        assert_eq!(qvm.get_unitary(&[]), expected_qvm.get_unitary(&[]));
+
+        // Without given radices, the idle qudit cannot be covered
+        let unknown_radix = TreeBuilder::new(
+            3,
+            vec![
+                BuilderExpressionInput::Unitary(gate_for(&[0])),
+                BuilderExpressionInput::Unitary(gate_for(&[2])),
+            ],
+            vec![vec![0], vec![2]],
+            vec![vec![None], vec![None]],
+            vec![vec![None], vec![None]],
+        );
+        assert_eq!(unknown_radix.try_build_tree().err(), Some(QuditTreeError::UnknownIdleRadix(1)));
+    }
+
+    #[test]
+    fn test_tensor_intermediates_remove_frprs() {
+        let build = |tensor_intermediates: bool| {
+            let builder = TreeBuilder::from_locations(
+                QuditRadices::from_iter([2, 2, 2, 2, 2]),
+                vec![vec![0, 1], vec![1, 2], vec![2, 3], vec![3, 4]],
+                cx,
+            );
+            if tensor_intermediates {
+                builder.with_tensor_intermediates().build_tree()
+            } else {
+                builder.build_tree()
+            }
+        };
+        let num_frprs = |code: &Bytecode| {
+            code.static_code
+                .iter()
+                .chain(code.dynamic_code.iter())
+                .filter(|inst| matches!(inst, GeneralizedInstruction::FRPR(..)))
+                .count()
+        };
+
+        let matrix = build(false);
+        let tensor = build(true);
+        assert_eq!(matrix.contraction_path().len(), 3);
+
+        let matrix_code = compile(&matrix);
+        let tensor_code = compile(&tensor);
+        assert!(num_frprs(&tensor_code) < num_frprs(&matrix_code));
+
+        let mut matrix_qvm = QVM::<c64>::new(matrix_code, DifferentiationLevel::None);
+        let mut tensor_qvm = QVM::<c64>::new(tensor_code, DifferentiationLevel::None);
+        assert_eq!(matrix_qvm.get_unitary(&[]), tensor_qvm.get_unitary(&[]));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid location")]
+    fn test_builder_from_locations_rejects_repeated_qudit() {
+        TreeBuilder::from_locations(QuditRadices::from_iter([2, 2]), vec![vec![1, 1]], cx);
+    }
+
+    #[test]
+    #[should_panic(expected = "Inconsistent radix for qudit 1: operation 0 uses radix 2 but operation 1 uses radix 3")]
+    fn test_builder_rejects_qubit_used_as_qutrit() {
+        let p3 = UnitaryExpression::new("P3(a) { [[1, 0, 0], [0, e^(i*a), 0], [0, 0, 1]] }");
+        TreeBuilder::new(
+            2,
+            vec![BuilderExpressionInput::Unitary(cx(&[])), BuilderExpressionInput::Unitary(p3)],
+            vec![vec![0, 1], vec![1]],
+            vec![vec![None, Some(1)], vec![None]],
+            vec![vec![None, None], vec![Some(0)]],
+        );
+    }
+
+    #[test]
+    fn test_try_new_reports_invalid_input() {
+        let cx_op = || BuilderExpressionInput::Unitary(cx(&[]));
+        let p = BuilderExpressionInput::Unitary(UnitaryExpression::new("P3(a) { [[1, 0, 0], [0, e^(i*a), 0], [0, 0, 1]] }"));
+
+        assert_eq!(
+            TreeBuilder::try_new(0, vec![cx_op()], vec![vec![0, 1]], vec![vec![None, None]], vec![vec![None, None]]).err(),
+            Some(QuditTreeError::NoQudits),
+        );
+        assert_eq!(
+            TreeBuilder::try_new(2, vec![], vec![], vec![], vec![]).err(),
+            Some(QuditTreeError::NoOperations),
+        );
+        assert_eq!(
+            TreeBuilder::try_new(2, vec![cx_op()], vec![vec![0, 1]], vec![], vec![]).err(),
+            Some(QuditTreeError::InvalidInputLengths),
+        );
+        assert_eq!(
+            TreeBuilder::try_new(2, vec![cx_op()], vec![vec![0]], vec![vec![None]], vec![vec![None]]).err(),
+            Some(QuditTreeError::InvalidOperationQudits(0)),
+        );
+        assert_eq!(
+            TreeBuilder::try_new(
+                2,
+                vec![cx_op(), p],
+                vec![vec![0, 1], vec![1]],
+                vec![vec![None, Some(1)], vec![None]],
+                vec![vec![None, None], vec![Some(0)]],
+            ).err(),
+            Some(QuditTreeError::InconsistentRadix { qudit: 1, first_op: 0, first_radix: 2, op: 1, radix: 3 }),
+        );
+
+        // A state after a gate is not at the left boundary of the circuit
+        let state = BuilderExpressionInput::State(RuntimeConstantNode::new(0, QuditRadices::from_iter([2])));
+        assert_eq!(
+            TreeBuilder::try_new(
+                2,
+                vec![cx_op(), state],
+                vec![vec![0, 1], vec![1]],
+                vec![vec![None, Some(1)], vec![None]],
+                vec![vec![None, None], vec![Some(0)]],
+            ).err(),
+            Some(QuditTreeError::StateNotAtBoundary(1)),
+        );
+    }
+
+    #[test]
+    fn test_hardware_profile_changes_contraction_order() {
+        // X has two predecessors, so only one of its contractions can happen
+        // first. Contracting with A performs fewer flops, but moves more data.
+        let identity = |radices: [u8; 2]| {
+            BuilderExpressionInput::Tree(ExpressionTree::Identity(IdentityNode::new(
+                QuditRadices::from_iter(radices),
+            )))
+        };
+        let builder = || TreeBuilder::new(
+            4,
+            vec![identity([3, 2]), identity([5, 2]), identity([2, 5])],
+            vec![vec![0, 1], vec![2, 3], vec![1, 2]],
+            vec![vec![None, Some(2)], vec![Some(2), None], vec![None, None]],
+            vec![vec![None, None], vec![None, None], vec![Some(0), Some(1)]],
+        );
+
+        let compute_bound = HardwareProfile::new(1e9, 1e12);
+        let memory_bound = HardwareProfile::new(1e12, 1e9);
+        let compute_path = builder().with_hardware_profile(compute_bound).build_tree().contraction_path();
+        let memory_path = builder().with_hardware_profile(memory_bound).build_tree().contraction_path();
+
+        assert_eq!(compute_path[0].left_qudits, vec![0, 1]);
+        assert_eq!(memory_path[0].left_qudits, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_cost_function_changes_contraction_order() {
+        // X has two predecessors, so only one of its contractions can happen
+        // first, and each cost function prefers a different one.
+        let builder = || TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2, 2]),
+            vec![vec![0, 1], vec![2, 3], vec![1, 2]],
+            cx,
+        );
+        let prefer = |qudits: Vec<usize>| move |c: &ContractCandidate| -> u64 {
+            assert_eq!(c.dimension, 8);
+            if c.left_qudits == qudits { 0 } else { 1 }
+        };
+
+        let top = builder().build_tree_with_cost(prefer(vec![0, 1]));
+        let bottom = builder().build_tree_with_cost(prefer(vec![2, 3]));
+        assert_eq!(top.contraction_path()[0].left_qudits, vec![0, 1]);
+        assert_eq!(bottom.contraction_path()[0].left_qudits, vec![2, 3]);
+        assert_ne!(top, bottom);
+
+        let mut top_qvm = QVM::<c64>::new(compile(&top), DifferentiationLevel::None);
+        let mut bottom_qvm = QVM::<c64>::new(compile(&bottom), DifferentiationLevel::None);
+        assert_eq!(top_qvm.get_unitary(&[]), bottom_qvm.get_unitary(&[]));
+    }
+
+    #[test]
+    fn test_build_conditional_branches() {
+        let p = fixtures::p();
+        let cry = fixtures::cry();
+        let branches = TreeBuilder::new(
+            2,
+            vec![
+                BuilderExpressionInput::Unitary(cry.clone()),
+                BuilderExpressionInput::Unitary(p),
+                BuilderExpressionInput::Unitary(cry),
+            ],
+            vec![vec![0, 1], vec![0], vec![0, 1]],
+            vec![vec![Some(1), Some(2)], vec![Some(2)], vec![None, None]],
+            vec![vec![None, None], vec![Some(0)], vec![Some(1), Some(0)]],
+        )
+        .with_classical_control(1, 0)
+        .build_conditional();
+
+        assert_eq!(branches.len(), 2);
+        let (skipped_condition, skipped) = &branches[0];
+        let (applied_condition, applied) = &branches[1];
+        assert_eq!(skipped_condition.value(0), Some(false));
+        assert_eq!(applied_condition.value(0), Some(true));
+        assert_eq!(skipped.num_params(), 2);
+        assert_eq!(applied.num_params(), 3);
+
+        // Both branches share the gates before and after the controlled one
+        let (ExpressionTree::Mul(skipped), ExpressionTree::Mul(applied)) = (skipped, applied) else {
+            panic!("Branches should multiply the shared prefix and suffix.");
+        };
+        let ExpressionTree::Mul(applied_prefix) = applied.left.as_ref() else {
+            panic!("The applied branch should multiply the controlled gate onto the prefix.");
+        };
+        assert_eq!(applied_prefix.left, skipped.left);
+        assert_eq!(applied.right, skipped.right);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot enumerate the branches")]
+    fn test_build_conditional_rejects_too_many_bits() {
+        let p = fixtures::p();
+        let num_ops = usize::BITS as usize;
+        let mut builder = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2]),
+            vec![vec![0]; num_ops],
+            |_| p.clone(),
+        );
+        for op in 0..num_ops {
+            builder = builder.with_classical_control(op, op);
+        }
+        builder.build_conditional();
+    }
+
+    #[test]
+    fn test_max_params_exceeded() {
+        let p = fixtures::p();
+        let builder = || {
+            TreeBuilder::new(
+                1,
+                vec![
+                    BuilderExpressionInput::Unitary(p.clone()),
+                    BuilderExpressionInput::Unitary(p.clone()),
+                ],
+                vec![vec![0], vec![0]],
+                vec![vec![Some(1)], vec![None]],
+                vec![vec![None], vec![Some(0)]],
+            )
+        };
+
+        assert_eq!(
+            builder().with_max_params(1).try_build_tree().err(),
+            Some(QuditTreeError::TooManyParams { num_params: 2, max_params: 1 }),
+        );
+        assert_eq!(builder().with_max_params(2).try_build_tree().unwrap().num_params(), 2);
+    }
 
 //     use super::strategies::builder_from_locations;
 //     use super::*;
@@ -1067,58 +2654,6 @@ mod tests {
 //     use crate::QuditRadices;
 //     use proptest::prelude::*;
 
-//     #[test]
-//     fn test_builder_from_locations() {
-//         let mut dag = HashMap::new();
-//         dag.insert(
-//             0,
-//             NodeDAGNode {
-//                 node: ExpressionTree::Leaf(Gate::CX()),
-//                 location: CircuitLocation::pure(vec![2, 3]),
-//                 next: vec![Some(1), None],
-//                 prev: vec![None, None],
-//             },
-//         );
-//         dag.insert(
-//             1,
-//             NodeDAGNode {
-//                 node: ExpressionTree::Leaf(Gate::CX()),
-//                 location: CircuitLocation::pure(vec![1, 2]),
-//                 next: vec![Some(2), None],
-//                 prev: vec![None, Some(0)],
-//             },
-//         );
-//         dag.insert(
-//             2,
-//             NodeDAGNode {
-//                 node: ExpressionTree::Leaf(Gate::CX()),
-//                 location: CircuitLocation::pure(vec![0, 1]),
-//                 next: vec![None, None],
-//                 prev: vec![None, Some(1)],
-//             },
-//         );
-
-//         let builder_1 = TreeBuilder {
-//             num_qudits: 4,
-//             dag,
-//             index_counter: 3,
-//         };
-
-//         let builder_2 = builder_from_locations(
-//             radices![2, 2, 2, 2],
-//             vec![vec![2, 3], vec![1, 2], vec![0, 1]],
-//         );
-
-//         assert_eq!(builder_1.index_counter, builder_2.index_counter);
-//         assert_eq!(builder_1.num_qudits, builder_2.num_qudits);
-//         assert_eq!(builder_1.dag.len(), builder_2.dag.len());
-//         for (idx, node) in builder_1.dag.iter() {
-//             assert_eq!(builder_2.dag[idx].location, node.location);
-//             assert_eq!(builder_2.dag[idx].next, node.next);
-//             assert_eq!(builder_2.dag[idx].prev, node.prev);
-//         }
-//     }
-
 //     #[test]
 //     fn test_has_non_direct_dependency_simple() {
 //         let builder = builder_from_locations(
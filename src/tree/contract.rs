@@ -3,14 +3,21 @@ use std::collections::HashSet;
 use std::hash::Hash;
 use std::ops::Deref;
 
+use faer::Mat;
+use qudit_core::ComplexScalar;
 use qudit_core::HasPeriods;
 use qudit_core::HasParams;
 use qudit_core::RealScalar;
 use qudit_core::QuditRadices;
 use qudit_core::QuditSystem;
+use qudit_expr::DifferentiationLevel;
 
 use super::fmt::PrintTree;
+use super::runtime::RuntimeConstantNode;
 use super::tree::ExpressionTree;
+use crate::compiler::compile;
+use crate::error::QuditTreeError;
+use crate::qvm::QVM;
 
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
 pub struct ContractNode {
@@ -66,14 +73,291 @@ pub struct ContractNode {
     pub out_matrix_shape: (usize, usize),
 
     // If the left node is already properly permuted, we skip the
-    // left pre-permutation. This is always initially false and can be
-    // set by the [TreeOptimizer](struct.TreeOptimizer).
+    // left pre-permutation. This is initially true only when the left
+    // pre-permutation is trivial and can be set by the
+    // [TreeOptimizer](struct.TreeOptimizer).
     pub skip_left: bool,
 
     // If the right node is already properly permuted, we skip the
-    // right pre-permutation. This is always initially false and can be
-    // set by the [TreeOptimizer](struct.TreeOptimizer).
+    // right pre-permutation. This is initially true only when the right
+    // pre-permutation is trivial and can be set by the
+    // [TreeOptimizer](struct.TreeOptimizer).
     pub skip_right: bool,
+
+    // If set, the right node is complex conjugated before contraction.
+    pub conjugate_right: bool,
+}
+
+/// Returns true if `perm` contains each of `0..perm.len()` exactly once.
+fn is_permutation(perm: &[usize]) -> bool {
+    let mut seen = vec![false; perm.len()];
+    for &i in perm {
+        if i >= perm.len() || seen[i] {
+            return false;
+        }
+        seen[i] = true;
+    }
+    true
+}
+
+/// Returns true if `perm` maps every index to itself.
+fn is_identity(perm: &[usize]) -> bool {
+    perm.iter().enumerate().all(|(i, &p)| i == p)
+}
+
+/// The qudits of a tensor's indices, each given as a qudit and whether it
+/// is a row index, if the indices are the rows followed by the columns of
+/// the same qudits in the same order.
+fn qudit_order(labels: &[(usize, bool)]) -> Option<Vec<usize>> {
+    let (rows, cols) = labels.split_at(labels.len() / 2);
+    let is_operator = rows
+        .iter()
+        .zip(cols.iter())
+        .all(|(&(row_qudit, is_row), &(col_qudit, col_is_row))| {
+            is_row && !col_is_row && row_qudit == col_qudit
+        });
+    if is_operator {
+        Some(rows.iter().map(|&(q, _)| q).collect())
+    } else {
+        None
+    }
+}
+
+/// The contraction computed by `tree`, looking through constant markers.
+pub(super) fn as_contraction(tree: &ExpressionTree) -> Option<&ContractNode> {
+    match tree {
+        ExpressionTree::Contract(n) => Some(n),
+        ExpressionTree::Constant(n) => as_contraction(&n.child),
+        _ => None,
+    }
+}
+
+/// The contraction computed by `tree`; see [as_contraction].
+fn as_contraction_mut(tree: &mut ExpressionTree) -> Option<&mut ContractNode> {
+    match tree {
+        ExpressionTree::Contract(n) => Some(n),
+        ExpressionTree::Constant(n) => as_contraction_mut(&mut n.child),
+        _ => None,
+    }
+}
+
+/// The output qudits of `operand`, in the order a contraction reading it
+/// with `radices` and pre-permutation `perm` sees them, if it is a
+/// contraction over those radices.
+fn operand_output_qudits(
+    operand: &ExpressionTree,
+    perm: &[usize],
+    radices: QuditRadices,
+) -> Option<Vec<usize>> {
+    let n = as_contraction(operand)?;
+    if n.radices() != radices {
+        return None;
+    }
+    if let Some(qudits) = n.output_qudits() {
+        return Some(qudits);
+    }
+
+    // A fused operand writes its unfused output index perm[i] to index i
+    let labels = n.pre_out_labels();
+    let mut unfused = labels.clone();
+    for (i, &p) in perm.iter().enumerate() {
+        unfused[p] = labels[n.pre_out_perm[i]];
+    }
+    qudit_order(&unfused)
+}
+
+/// Merge tensor indices that stay adjacent and in order through a permutation.
+///
+/// Output index `i` of the permutation is input index `perm[i]`. Whenever
+/// `perm[i + 1] == perm[i] + 1`, the two indices always move together, so
+/// they can be treated as a single larger index. This produces an equivalent
+/// reshape-permute with fewer indices.
+///
+/// # Returns
+///
+/// The grouped input tensor shape and the permutation of the groups.
+pub fn group_adjacent_indices(
+    shape: &[usize],
+    perm: &[usize],
+) -> (Vec<usize>, Vec<usize>) {
+    // Each group is a (first input index, number of indices) run
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for (i, &p) in perm.iter().enumerate() {
+        if i > 0 && p == perm[i - 1] + 1 {
+            groups.last_mut().unwrap().1 += 1;
+        } else {
+            groups.push((p, 1));
+        }
+    }
+
+    let mut sorted_groups = groups.clone();
+    sorted_groups.sort();
+
+    let grouped_shape = sorted_groups
+        .iter()
+        .map(|&(start, len)| shape[start..start + len].iter().product())
+        .collect();
+    let grouped_perm = groups
+        .iter()
+        .map(|g| sorted_groups.iter().position(|s| s == g).unwrap())
+        .collect();
+
+    (grouped_shape, grouped_perm)
+}
+
+/// The metadata needed to combine the evaluated operands of a contraction.
+///
+/// This mirrors the reshape-permute-matmul plan of a [ContractNode] without
+/// its children, so two halves of a tree can be evaluated separately (e.g.
+/// on different machines) and combined afterwards.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct ContractMeta {
+    /// The qudit indices of the left operand in circuit space.
+    pub left_qudits: Vec<usize>,
+
+    /// The qudit indices of the right operand in circuit space.
+    pub right_qudits: Vec<usize>,
+
+    /// The shape of the left operand as a tensor.
+    pub left_tensor_shape: Vec<u8>,
+
+    /// The permutation of the left operand's indices as a tensor.
+    pub left_perm: Vec<usize>,
+
+    /// The shape of the left operand after permutation before contraction.
+    pub left_contraction_shape: (usize, usize),
+
+    /// The shape of the right operand as a tensor.
+    pub right_tensor_shape: Vec<u8>,
+
+    /// The permutation of the right operand's indices as a tensor.
+    pub right_perm: Vec<usize>,
+
+    /// The shape of the right operand after permutation before contraction.
+    pub right_contraction_shape: (usize, usize),
+
+    /// The output tensor shape after contraction before final permutation.
+    pub pre_out_tensor_shape: Vec<usize>,
+
+    /// The final permutation of the output indices as a tensor.
+    pub pre_out_perm: Vec<usize>,
+
+    /// The shape of the output matrix.
+    pub out_matrix_shape: (usize, usize),
+
+    /// The output dimension of the contraction.
+    ///
+    /// This differs from `out_matrix_shape` when the contraction is fused
+    /// into its parent and outputs a tensor in the parent's layout.
+    pub dimension: usize,
+
+    /// The output tensor shape of the contraction, before any fusion.
+    pub out_tensor_shape: Vec<u8>,
+
+    /// Whether the left pre-permutation can be skipped.
+    pub skip_left: bool,
+
+    /// Whether the right pre-permutation can be skipped.
+    pub skip_right: bool,
+
+    /// Whether the right operand is conjugated before contraction.
+    pub conjugate_right: bool,
+}
+
+impl ContractMeta {
+    /// Combine the evaluated operands of a split contraction.
+    ///
+    /// `left` and `right` are the unitaries of the two trees returned with
+    /// this plan by [ExpressionTree::split_at_contraction]. They are
+    /// contracted with this plan, exactly as in the unsplit tree, so the
+    /// result matches evaluating the whole tree.
+    pub fn combine<C: ComplexScalar>(&self, left: Mat<C>, right: Mat<C>) -> Mat<C> {
+        let num_left = self.left_tensor_shape.len() / 2;
+        let num_right = self.right_tensor_shape.len() / 2;
+        let left_radices = QuditRadices::from_iter(self.left_tensor_shape[..num_left].iter().copied());
+        let right_radices = QuditRadices::from_iter(self.right_tensor_shape[..num_right].iter().copied());
+
+        let node = ContractNode::from_meta(
+            ExpressionTree::RuntimeConstant(RuntimeConstantNode::new(0, left_radices)),
+            ExpressionTree::RuntimeConstant(RuntimeConstantNode::new(1, right_radices)),
+            self,
+        );
+        let mut qvm = QVM::new_with_runtime_constants(
+            compile(&ExpressionTree::Contract(node)),
+            DifferentiationLevel::None,
+            HashMap::from([(0, left), (1, right)]),
+        );
+        qvm.get_unitary(&[]).to_owned()
+    }
+}
+
+/// A cache of contraction plans, keyed by the qudit structure of each
+/// contraction.
+///
+/// The permutation schedule of a [ContractNode] depends only on its
+/// operands' qudits and radices, not on the expressions being contracted.
+/// Tools that repeatedly build the same circuit structure with different
+/// gates can share a template between builds to compute each plan once.
+///
+/// See [crate::TreeBuilder::build_tree_with_template].
+#[derive(Clone, Debug, Default)]
+pub struct ContractTemplate {
+    plans: HashMap<(Vec<usize>, Vec<usize>, Vec<u8>, Vec<u8>), ContractMeta>,
+}
+
+impl ContractTemplate {
+    /// Create an empty template.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of distinct contraction plans cached.
+    pub fn len(&self) -> usize {
+        self.plans.len()
+    }
+
+    /// Whether no contraction plans are cached.
+    pub fn is_empty(&self) -> bool {
+        self.plans.is_empty()
+    }
+
+    /// Contract two nodes as in [ContractNode::new], reusing a cached plan
+    /// for the same qudit structure if there is one.
+    pub fn contract(
+        &mut self,
+        left: ExpressionTree,
+        right: ExpressionTree,
+        left_qudits: Vec<usize>,
+        right_qudits: Vec<usize>,
+    ) -> ContractNode {
+        let key = (
+            left_qudits.clone(),
+            right_qudits.clone(),
+            left.radices().iter().map(|&r| r).collect(),
+            right.radices().iter().map(|&r| r).collect(),
+        );
+
+        if let Some(meta) = self.plans.get(&key) {
+            return ContractNode::from_meta(left, right, meta);
+        }
+
+        let node = ContractNode::new(left, right, left_qudits, right_qudits);
+        self.plans.insert(key, node.meta());
+        node
+    }
+}
+
+/// A single contraction in a tree's contraction path.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct ContractionStep {
+    /// The qudit indices of the left operand in circuit space.
+    pub left_qudits: Vec<usize>,
+
+    /// The qudit indices of the right operand in circuit space.
+    pub right_qudits: Vec<usize>,
+
+    /// The qudit indices of the result in circuit space, in output order.
+    pub result_qudits: Vec<usize>,
 }
 
 impl ContractNode {
@@ -97,9 +381,74 @@ impl ContractNode {
         left_qudits: Vec<usize>, // Change to CircuitLocation
         right_qudits: Vec<usize>,
     ) -> ContractNode {
-        // The radices of each node
+        Self::try_new(left, right, left_qudits, right_qudits)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates a new ContractNode as in [ContractNode::new], returning an
+    /// error instead of panicking if the nodes cannot be contracted.
+    pub fn try_new(
+        left: ExpressionTree,
+        right: ExpressionTree,
+        left_qudits: Vec<usize>,
+        right_qudits: Vec<usize>,
+    ) -> Result<ContractNode, QuditTreeError> {
         let left_radices = left.radices();
         let right_radices = right.radices();
+        Self::try_new_factored(left, right, left_qudits, right_qudits, left_radices, right_radices)
+    }
+
+    /// Creates a new ContractNode whose operands are reshaped to finer
+    /// radices before contracting.
+    ///
+    /// This contracts operators defined over different factorizations of
+    /// the same qudits, such as a radix-4 operator with a pair of radix-2
+    /// operators: the radix-4 operand is given the radices `[2, 2]` and two
+    /// qudits. The reshape is free, since it only changes the tensor shape
+    /// the operand's matrix is read as.
+    ///
+    /// # Arguments
+    ///
+    /// * `left_radices` - The radices the left node is read as, one per
+    ///   qudit in `left_qudits`, with the same dimension as the left node.
+    /// * `right_radices` - The radices the right node is read as.
+    ///
+    /// See [ContractNode::new] for the other arguments.
+    ///
+    /// # Panics
+    ///
+    /// * If either radices do not factor the dimension of their node, or do
+    ///   not have one radix per qudit of the node.
+    /// * In the same cases as [ContractNode::new].
+    pub fn new_factored(
+        left: ExpressionTree,
+        right: ExpressionTree,
+        left_qudits: Vec<usize>,
+        right_qudits: Vec<usize>,
+        left_radices: QuditRadices,
+        right_radices: QuditRadices,
+    ) -> ContractNode {
+        Self::try_new_factored(left, right, left_qudits, right_qudits, left_radices, right_radices)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates a new ContractNode as in [ContractNode::new_factored],
+    /// returning an error instead of panicking.
+    pub fn try_new_factored(
+        left: ExpressionTree,
+        right: ExpressionTree,
+        left_qudits: Vec<usize>,
+        right_qudits: Vec<usize>,
+        left_radices: QuditRadices,
+        right_radices: QuditRadices,
+    ) -> Result<ContractNode, QuditTreeError> {
+        if left_radices.dimension() != left.dimension()
+            || left_radices.len() != left_qudits.len()
+            || right_radices.dimension() != right.dimension()
+            || right_radices.len() != right_qudits.len()
+        {
+            return Err(QuditTreeError::IncompatibleFactorization);
+        }
 
         // The qudits shared in left_qudits and right_qudits will be contracted.
         let left_qudit_set =
@@ -117,7 +466,7 @@ impl ContractNode {
         all_qudits.sort();
 
         if contracting_qudits.len() == 0 {
-            panic!("There must be at least one overlapping qudit between the left and right nodes.")
+            return Err(QuditTreeError::NoContractedQudits);
         }
 
         // The radix_map maps qudit indices in circuit space to their radix.
@@ -132,7 +481,7 @@ impl ContractNode {
                 let right_radix = &right_radices[right_qudit_index];
 
                 if left_radix != right_radix {
-                    panic!("The indices being contracted must have the same dimension/radix.")
+                    return Err(QuditTreeError::ContractedRadixMismatch(*q));
                 }
 
                 radix_map.insert(*q, *left_radix);
@@ -299,7 +648,15 @@ impl ContractNode {
 
         let out_matrix_shape = (dimension, dimension);
 
-        ContractNode {
+        // When one operand's qudits are a subset of the other's, all of its
+        // qudits are contracted and its pre-permutation is the identity with
+        // an unchanged matrix shape, so it can be skipped entirely.
+        let skip_left = is_identity(&left_perm)
+            && left_contraction_shape == (left_dimension, left_dimension);
+        let skip_right = is_identity(&right_perm)
+            && right_contraction_shape == (right_dimension, right_dimension);
+
+        let node = ContractNode {
             left: Box::new(left),
             right: Box::new(right),
             left_qudits,
@@ -321,11 +678,210 @@ impl ContractNode {
             pre_out_perm,
             out_matrix_shape,
 
-            skip_left: false,
-            skip_right: false,
+            skip_left,
+            skip_right,
+            conjugate_right: false,
+        };
+
+        debug_assert!(
+            node.has_valid_permutations(),
+            "Contraction produced a non-bijective index permutation."
+        );
+
+        Ok(node)
+    }
+
+    /// Create a ContractNode from a previously computed contraction plan.
+    ///
+    /// The plan must have been computed for operands with the same qudits
+    /// and radices as `left` and `right`; see [ContractTemplate].
+    fn from_meta(
+        left: ExpressionTree,
+        right: ExpressionTree,
+        meta: &ContractMeta,
+    ) -> ContractNode {
+        ContractNode {
+            left_params: left.num_params(),
+            right_params: right.num_params(),
+            left: Box::new(left),
+            right: Box::new(right),
+            left_qudits: meta.left_qudits.clone(),
+            right_qudits: meta.right_qudits.clone(),
+            dimension: meta.dimension,
+            out_tensor_shape: meta.out_tensor_shape.clone(),
+
+            left_tensor_shape: meta.left_tensor_shape.clone(),
+            left_perm: meta.left_perm.clone(),
+            left_contraction_shape: meta.left_contraction_shape,
+
+            right_tensor_shape: meta.right_tensor_shape.clone(),
+            right_perm: meta.right_perm.clone(),
+            right_contraction_shape: meta.right_contraction_shape,
+
+            pre_out_tensor_shape: meta.pre_out_tensor_shape.clone(),
+            pre_out_perm: meta.pre_out_perm.clone(),
+            out_matrix_shape: meta.out_matrix_shape,
+
+            skip_left: meta.skip_left,
+            skip_right: meta.skip_right,
+            conjugate_right: meta.conjugate_right,
+        }
+    }
+
+    /// Returns true if `left_perm`, `right_perm`, and `pre_out_perm` are all
+    /// genuine permutations of their index ranges.
+    pub fn has_valid_permutations(&self) -> bool {
+        is_permutation(&self.left_perm)
+            && is_permutation(&self.right_perm)
+            && is_permutation(&self.pre_out_perm)
+    }
+
+    /// Extract the contraction plan of this node, without its children.
+    pub fn meta(&self) -> ContractMeta {
+        ContractMeta {
+            left_qudits: self.left_qudits.clone(),
+            right_qudits: self.right_qudits.clone(),
+            left_tensor_shape: self.left_tensor_shape.clone(),
+            left_perm: self.left_perm.clone(),
+            left_contraction_shape: self.left_contraction_shape,
+            right_tensor_shape: self.right_tensor_shape.clone(),
+            right_perm: self.right_perm.clone(),
+            right_contraction_shape: self.right_contraction_shape,
+            pre_out_tensor_shape: self.pre_out_tensor_shape.clone(),
+            pre_out_perm: self.pre_out_perm.clone(),
+            out_matrix_shape: self.out_matrix_shape,
+            dimension: self.dimension,
+            out_tensor_shape: self.out_tensor_shape.clone(),
+            skip_left: self.skip_left,
+            skip_right: self.skip_right,
+            conjugate_right: self.conjugate_right,
         }
     }
 
+    /// The qudits contracted by this node, as a step of a contraction path.
+    ///
+    /// `result_qudits` is the output qudit order of this node, which a
+    /// node fused into its parent only knows through the parent; see
+    /// [ContractNode::left_operand_qudits].
+    pub(super) fn step(&self, result_qudits: Vec<usize>) -> ContractionStep {
+        ContractionStep {
+            left_qudits: self.left_qudits.clone(),
+            right_qudits: self.right_qudits.clone(),
+            result_qudits,
+        }
+    }
+
+    /// The circuit qudit of each index of the product, before the final
+    /// permutation, and whether it is a row index.
+    fn pre_out_labels(&self) -> Vec<(usize, bool)> {
+        let label = |qudits: &[usize], perm: &[usize]| -> Vec<(usize, bool)> {
+            perm.iter()
+                .map(|&i| {
+                    if i < qudits.len() {
+                        (qudits[i], true)
+                    } else {
+                        (qudits[i - qudits.len()], false)
+                    }
+                })
+                .collect()
+        };
+        let num_contracted = self
+            .left_qudits
+            .iter()
+            .filter(|q| self.right_qudits.contains(q))
+            .count();
+
+        // The product keeps the right operand's rows and the left
+        // operand's columns, contracting the rest.
+        let left = label(&self.left_qudits, &self.left_perm);
+        let right = label(&self.right_qudits, &self.right_perm);
+        right[..right.len() - num_contracted]
+            .iter()
+            .chain(left[num_contracted..].iter())
+            .copied()
+            .collect()
+    }
+
+    /// The qudits of this node's output in circuit space, in output order.
+    ///
+    /// This is the ascending union of the left and right qudits, unless an
+    /// output permutation was applied, e.g. by
+    /// [crate::TreeBuilder::with_output_order]. Returns `None` if the output
+    /// was fused into a parent contraction's pre-permutation, since it is
+    /// then a tensor in the parent's contraction shape rather than an
+    /// operator over qudits.
+    pub fn output_qudits(&self) -> Option<Vec<usize>> {
+        if self.out_matrix_shape != (self.dimension, self.dimension) {
+            return None;
+        }
+        let labels = self.pre_out_labels();
+        let out: Vec<(usize, bool)> = self.pre_out_perm.iter().map(|&i| labels[i]).collect();
+        qudit_order(&out)
+    }
+
+    /// The output qudits of the left node in the order this node reads
+    /// them, if it is a contraction that is not reshaped.
+    ///
+    /// Unlike [ContractNode::output_qudits], this also recovers the order
+    /// of a left node fused into this node's pre-permutation.
+    pub(super) fn left_operand_qudits(&self) -> Option<Vec<usize>> {
+        operand_output_qudits(&self.left, &self.left_perm, self.left_radices())
+    }
+
+    /// The output qudits of the right node in the order this node reads
+    /// them; see [ContractNode::left_operand_qudits].
+    pub(super) fn right_operand_qudits(&self) -> Option<Vec<usize>> {
+        operand_output_qudits(&self.right, &self.right_perm, self.right_radices())
+    }
+
+    /// The radices the left node is read as during contraction.
+    ///
+    /// These are the left node's radices, unless it was reshaped to finer
+    /// radices by [ContractNode::new_factored].
+    pub fn left_radices(&self) -> QuditRadices {
+        let num_qudits = self.left_tensor_shape.len() / 2;
+        QuditRadices::from_iter(self.left_tensor_shape[..num_qudits].iter().copied())
+    }
+
+    /// The radices the right node is read as during contraction; see
+    /// [ContractNode::left_radices].
+    pub fn right_radices(&self) -> QuditRadices {
+        let num_qudits = self.right_tensor_shape.len() / 2;
+        QuditRadices::from_iter(self.right_tensor_shape[..num_qudits].iter().copied())
+    }
+
+    /// Conjugate the right node during contraction.
+    ///
+    /// The right node's entries are complex conjugated elementwise, not
+    /// transposed, before it is contracted with the left node. This builds
+    /// factors such as `U ⊗ conj(U)`, which act on a vectorized density
+    /// matrix, without a separate conjugated tree for the right operand.
+    pub fn with_conjugated_right(mut self) -> Self {
+        self.conjugate_right = true;
+        self
+    }
+
+    /// The output tensor shape and permutation with adjacent indices grouped.
+    ///
+    /// Qudits passing through the contraction unpermuted keep their row and
+    /// column indices next to each other, so these can be merged to shrink
+    /// the final reshape-permute; see [group_adjacent_indices].
+    pub fn grouped_pre_out(&self) -> (Vec<usize>, Vec<usize>) {
+        group_adjacent_indices(&self.pre_out_tensor_shape, &self.pre_out_perm)
+    }
+
+    /// Whether the final permutation is a no-op.
+    ///
+    /// This holds when the grouped output permutation is trivial and the
+    /// product already has the output matrix shape, in which case the
+    /// product is the output.
+    pub fn skips_output_permutation(&self) -> bool {
+        let (_, pre_out_perm) = self.grouped_pre_out();
+        let pre_out_matrix_shape =
+            (self.right_contraction_shape.0, self.left_contraction_shape.1);
+        pre_out_perm.len() == 1 && pre_out_matrix_shape == self.out_matrix_shape
+    }
+
     pub(super) fn skip_left_permutation(&mut self) {
         self.skip_left = true;
     }
@@ -346,6 +902,107 @@ impl ContractNode {
         self.out_matrix_shape = new_shape;
     }
 
+    /// Have operand contractions write their outputs already permuted for
+    /// this contraction, so this node skips their pre-permutations.
+    ///
+    /// The fused operands then output tensors in this node's contraction
+    /// shape, rather than matrices over their qudits. Operands read with
+    /// factored radices are left unchanged.
+    pub(super) fn fuse_operand_permutations(&mut self) {
+        let left_radices = self.left_radices();
+        if let ExpressionTree::Contract(left) = self.left.as_mut() {
+            if left.radices() == left_radices {
+                left.fuse_output_perm(self.left_perm.clone(), self.left_contraction_shape);
+                self.skip_left_permutation();
+            }
+        }
+
+        let right_radices = self.right_radices();
+        if let ExpressionTree::Contract(right) = self.right.as_mut() {
+            if right.radices() == right_radices {
+                right.fuse_output_perm(self.right_perm.clone(), self.right_contraction_shape);
+                self.skip_right_permutation();
+            }
+        }
+    }
+
+    /// Undo [ContractNode::fuse_operand_permutations], so operand
+    /// contractions output matrices over their qudits again and this node
+    /// pre-permutes them itself.
+    ///
+    /// Constant propagation may have since wrapped a fused operand in a
+    /// constant, which is looked through.
+    pub(super) fn unfuse_operand_permutations(&mut self) {
+        if let Some(left) = as_contraction_mut(&mut self.left) {
+            if self.skip_left && left.output_qudits().is_none() {
+                left.unfuse_output_perm(&self.left_perm);
+                self.skip_left = is_identity(&self.left_perm)
+                    && self.left_contraction_shape == (left.dimension, left.dimension);
+            }
+        }
+
+        if let Some(right) = as_contraction_mut(&mut self.right) {
+            if self.skip_right && right.output_qudits().is_none() {
+                right.unfuse_output_perm(&self.right_perm);
+                self.skip_right = is_identity(&self.right_perm)
+                    && self.right_contraction_shape == (right.dimension, right.dimension);
+            }
+        }
+    }
+
+    /// Undo [ContractNode::fuse_output_perm] of `perm`.
+    fn unfuse_output_perm(&mut self, perm: &[usize]) {
+        let mut unfused = self.pre_out_perm.clone();
+        for (i, &p) in perm.iter().enumerate() {
+            unfused[p] = self.pre_out_perm[i];
+        }
+        self.pre_out_perm = unfused;
+        self.out_matrix_shape = (self.dimension, self.dimension);
+    }
+
+    /// Reorder the output so it acts on `qudits` in order.
+    ///
+    /// This restores the output order of a node rebuilt from the qudits of
+    /// another, which always outputs its qudits in ascending order.
+    ///
+    /// # Panics
+    ///
+    /// If `qudits` is not an ordering of this node's output qudits.
+    pub(super) fn with_output_qudits(mut self, qudits: &[usize]) -> Self {
+        let current = self
+            .output_qudits()
+            .expect("A contraction fused into its parent has no qudit order of its own.");
+        let order: Vec<usize> = qudits
+            .iter()
+            .map(|q| {
+                current
+                    .iter()
+                    .position(|c| c == q)
+                    .expect("Output qudits must be reordered, not replaced.")
+            })
+            .collect();
+        if !is_identity(&order) {
+            self.apply_output_permutation(&order);
+        }
+        self
+    }
+
+    /// Reorder the output qudits, so output qudit `i` is current output
+    /// qudit `order[i]`.
+    pub(super) fn apply_output_permutation(&mut self, order: &[usize]) {
+        let num_qudits = order.len();
+        let tensor_perm: Vec<usize> = order
+            .iter()
+            .copied()
+            .chain(order.iter().map(|&i| i + num_qudits))
+            .collect();
+
+        self.pre_out_perm =
+            tensor_perm.iter().map(|&i| self.pre_out_perm[i]).collect();
+        self.out_tensor_shape =
+            tensor_perm.iter().map(|&i| self.out_tensor_shape[i]).collect();
+    }
+
     // TODO: Optimize permutation shape (consecutive indices do not need to be
     // split)
 }
@@ -368,6 +1025,10 @@ impl<R: RealScalar> HasPeriods<R> for ContractNode {
 }
 
 impl QuditSystem for ContractNode {
+    fn num_qudits(&self) -> usize {
+        self.out_tensor_shape.len() / 2
+    }
+
     fn radices(&self) -> QuditRadices {
         QuditRadices::from_iter(
             (0..(self.out_tensor_shape.len() / 2))
@@ -384,10 +1045,11 @@ impl PrintTree for ContractNode {
     fn write_tree(&self, prefix: &str, fmt: &mut std::fmt::Formatter<'_>) {
         writeln!(
             fmt,
-            "{}Contract({:?} + {:?}; {}, {})",
+            "{}Contract({:?} + {:?}{}; {}, {})",
             prefix,
             self.left_qudits,
             self.right_qudits,
+            if self.conjugate_right { "*" } else { "" },
             self.skip_left,
             self.skip_right
         )
@@ -401,6 +1063,281 @@ impl PrintTree for ContractNode {
 
 #[cfg(test)]
 mod tests {
+    use faer::Mat;
+    use faer::MatRef;
+    use proptest::prelude::*;
+    use qudit_core::c64;
+    use qudit_core::HasParams;
+    use qudit_core::QuditRadices;
+    use qudit_core::QuditSystem;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use super::group_adjacent_indices;
+    use super::is_identity;
+    use super::is_permutation;
+    use super::ContractNode;
+    use super::ContractTemplate;
+    use super::super::constant::ConstantNode;
+    use super::ExpressionTree;
+    use crate::bytecode::BytecodeGenerator;
+    use crate::bytecode::GeneralizedInstruction;
+    use crate::compiler::compile;
+    use crate::error::QuditTreeError;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+
+    /// Split `idx` into its digits, with the first radix most significant.
+    fn digits(mut idx: usize, radices: &[usize]) -> Vec<usize> {
+        let mut out = vec![0; radices.len()];
+        for k in (0..radices.len()).rev() {
+            out[k] = idx % radices[k];
+            idx /= radices[k];
+        }
+        out
+    }
+
+    /// Embed `op`, acting on `op_qudits` in that order, into the space of
+    /// `all_qudits`, acting as identity on the remaining qudits.
+    fn embed(
+        op: MatRef<c64>,
+        op_qudits: &[usize],
+        all_qudits: &[usize],
+        radices: &[usize],
+    ) -> Mat<c64> {
+        let dim = radices.iter().product();
+        let positions: Vec<usize> = op_qudits
+            .iter()
+            .map(|q| all_qudits.iter().position(|x| x == q).unwrap())
+            .collect();
+
+        Mat::from_fn(dim, dim, |r, c| {
+            let rd = digits(r, radices);
+            let cd = digits(c, radices);
+            for k in 0..all_qudits.len() {
+                if !positions.contains(&k) && rd[k] != cd[k] {
+                    return c64::new(0.0, 0.0);
+                }
+            }
+            let (r_sub, c_sub) = positions.iter().fold((0, 0), |(r_acc, c_acc), &k| {
+                (r_acc * radices[k] + rd[k], c_acc * radices[k] + cd[k])
+            });
+            op[(r_sub, c_sub)]
+        })
+    }
+
+    fn evaluate(tree: &ExpressionTree, params: &[f64]) -> Mat<c64> {
+        QVM::<c64>::new(compile(tree), DifferentiationLevel::None)
+            .get_unitary(params)
+            .to_owned()
+    }
+
+    /// Compare the compiled contraction against a dense reference that
+    /// embeds both operands into the full space and multiplies them.
+    fn verify_contract(node: &ContractNode, params: &[f64]) -> bool {
+        let left_params = node.left.num_params();
+        let left = evaluate(&node.left, &params[..left_params]);
+        let mut right = evaluate(&node.right, &params[left_params..]);
+        if node.conjugate_right {
+            right = Mat::from_fn(right.nrows(), right.ncols(), |r, c| right[(r, c)].conj());
+        }
+
+        let mut all_qudits = node.left_qudits.clone();
+        for q in node.right_qudits.iter() {
+            if !all_qudits.contains(q) {
+                all_qudits.push(*q);
+            }
+        }
+        all_qudits.sort();
+        let radices: Vec<usize> = node.radices().iter().map(|&r| r as usize).collect();
+
+        let left = embed(left.as_ref(), &node.left_qudits, &all_qudits, &radices);
+        let right = embed(right.as_ref(), &node.right_qudits, &all_qudits, &radices);
+        let expected = &right * &left;
+
+        let actual = evaluate(&ExpressionTree::Contract(node.clone()), params);
+        (0..expected.nrows()).all(|r| {
+            (0..expected.ncols()).all(|c| (expected[(r, c)] - actual[(r, c)]).norm() < 1e-10)
+        })
+    }
+
+    fn ry() -> ExpressionTree {
+        ExpressionTree::Leaf(fixtures::ry())
+    }
+
+    fn cry() -> ExpressionTree {
+        ExpressionTree::Leaf(fixtures::cry())
+    }
+
+    /// A two-qubit left operand and an overlapping one- or two-qubit right
+    /// operand on a three-qubit register, in arbitrary qudit order.
+    fn small_contractions() -> impl Strategy<Value = (Vec<usize>, Vec<usize>)> {
+        let pair = (0..3usize, 0..3usize).prop_filter("Distinct qudits", |(a, b)| a != b);
+        (pair.clone(), prop_oneof![
+            (0..3usize).prop_map(|a| vec![a]),
+            pair.prop_map(|(a, b)| vec![a, b]),
+        ])
+        .prop_map(|((a, b), right)| (vec![a, b], right))
+        .prop_filter("Operands must overlap", |(left, right)| {
+            right.iter().any(|q| left.contains(q))
+        })
+    }
+
+    #[test]
+    fn test_trivial_output_permutation_allocates_no_buffer() {
+        let node = ContractNode::new(cry(), cry(), vec![0, 1], vec![0, 1]);
+        let code = BytecodeGenerator::new().generate(&ExpressionTree::Contract(node));
+
+        // Two leaf buffers and the product, with no pre- or post-permutation
+        assert_eq!(code.matrix_buffers.len(), 3);
+    }
+
+    #[test]
+    fn test_subset_operand_skips_pre_permutation() {
+        let num_frprs = |node: &ContractNode| {
+            BytecodeGenerator::new()
+                .generate(&ExpressionTree::Contract(node.clone()))
+                .dynamic_code
+                .iter()
+                .filter(|inst| matches!(inst, GeneralizedInstruction::FRPR(..)))
+                .count()
+        };
+
+        let right_subset = ContractNode::new(cry(), ry(), vec![0, 1], vec![1]);
+        let left_subset = ContractNode::new(ry(), cry(), vec![1], vec![0, 1]);
+        assert!(right_subset.skip_right);
+        assert!(left_subset.skip_left);
+
+        for node in [right_subset, left_subset] {
+            let mut unskipped = node.clone();
+            unskipped.skip_left = false;
+            unskipped.skip_right = false;
+            assert!(num_frprs(&node) < num_frprs(&unskipped));
+            assert!(verify_contract(&node, &[0.8, -0.3]));
+            assert!(verify_contract(&unskipped, &[0.8, -0.3]));
+        }
+    }
+
+    #[test]
+    fn test_contract_template_reuses_plan() {
+        let mut template = ContractTemplate::new();
+        let first = template.contract(cry(), ry(), vec![2, 0], vec![0]);
+        assert_eq!(template.len(), 1);
+
+        // Different gates on the same qudit structure reuse the plan
+        let cx = ExpressionTree::Leaf(fixtures::cx());
+        let p = ExpressionTree::Leaf(fixtures::p());
+        let second = template.contract(cx.clone(), p.clone(), vec![2, 0], vec![0]);
+        assert_eq!(template.len(), 1);
+        assert_eq!(second.meta(), first.meta());
+        assert_eq!(second, ContractNode::new(cx, p, vec![2, 0], vec![0]));
+        assert!(verify_contract(&second, &[0.8]));
+    }
+
+    #[test]
+    fn test_fused_plan_rebuilds_node() {
+        let child = ContractNode::new(cry(), ry(), vec![0, 1], vec![0]);
+        let mut parent = ContractNode::new(ExpressionTree::Contract(child), ry(), vec![0, 1], vec![1]);
+        parent.fuse_operand_permutations();
+        let ExpressionTree::Contract(fused) = parent.left.as_ref() else {
+            panic!("The left operand should still be a contraction.");
+        };
+        assert!(parent.skip_left);
+        assert_ne!(fused.out_matrix_shape, (4, 4));
+
+        let rebuilt = ContractNode::from_meta(
+            fused.left.as_ref().clone(),
+            fused.right.as_ref().clone(),
+            &fused.meta(),
+        );
+        assert_eq!(&rebuilt, fused);
+        assert_eq!(rebuilt.dimension(), 4);
+    }
+
+    #[test]
+    fn test_contract_radix_four_with_two_qubits() {
+        let shift = ExpressionTree::Leaf(UnitaryExpression::new(
+            "X4(a) { [[0, 0, 0, 1], [1, 0, 0, 0], [0, 1, 0, 0], [0, 0, e^(i*a), 0]] }",
+        ));
+        let p = ExpressionTree::Leaf(fixtures::p());
+        let qubits = QuditRadices::from_iter([2, 2]);
+
+        // The radix-4 gate acts on qubits 0 and 1, between gates on each
+        let first = ContractNode::new_factored(
+            ry(), shift.clone(), vec![0], vec![0, 1], QuditRadices::from_iter([2]), qubits.clone(),
+        );
+        assert_eq!(first.radices(), qubits);
+        assert_eq!(first.right_radices(), qubits);
+        let tree = ExpressionTree::Contract(
+            ContractNode::new(ExpressionTree::Contract(first), p.clone(), vec![0, 1], vec![1]),
+        );
+
+        let params = [0.7, 1.3, -0.4];
+        let ry_mat = ry().evaluate_ref::<c64>(&params[..1]);
+        let shift_mat = shift.evaluate_ref::<c64>(&params[1..2]);
+        let p_mat = p.evaluate_ref::<c64>(&params[2..]);
+        let kron = |a: &Mat<c64>, b: &Mat<c64>| Mat::from_fn(4, 4, |r, c| {
+            a[(r / b.nrows(), c / b.ncols())] * b[(r % b.nrows(), c % b.ncols())]
+        });
+        let id = Mat::<c64>::identity(2, 2);
+        let expected = kron(&id, &p_mat) * &shift_mat * kron(&ry_mat, &id);
+
+        let reference = tree.evaluate_ref::<c64>(&params);
+        let actual = evaluate(&tree, &params);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((reference[(r, c)] - expected[(r, c)]).norm() < 1e-10);
+                assert!((actual[(r, c)] - expected[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_new_reports_uncontractable_operands() {
+        let p3 = ExpressionTree::Leaf(UnitaryExpression::new(
+            "P3(a) { [[1, 0, 0], [0, e^(i*a), 0], [0, 0, 1]] }",
+        ));
+        assert_eq!(
+            ContractNode::try_new(ry(), ry(), vec![0], vec![1]).err(),
+            Some(QuditTreeError::NoContractedQudits),
+        );
+        assert_eq!(
+            ContractNode::try_new(ry(), p3, vec![0], vec![0]).err(),
+            Some(QuditTreeError::ContractedRadixMismatch(0)),
+        );
+    }
+
+    #[test]
+    fn test_constant_operand_permutation_is_static() {
+        let cx = ExpressionTree::Leaf(fixtures::cx());
+        let left = ExpressionTree::Constant(ConstantNode::new(cx));
+        let tree = ExpressionTree::Contract(ContractNode::new(left, cry(), vec![0, 1], vec![1, 2]));
+
+        let code = BytecodeGenerator::new().generate(&tree);
+        assert!(code.static_code.iter().any(|inst| matches!(inst, GeneralizedInstruction::FRPR(..))));
+
+        let expected = tree.evaluate_ref::<c64>(&[0.6]);
+        let actual = evaluate(&tree, &[0.6]);
+        assert!((0..8).all(|r| (0..8).all(|c| (expected[(r, c)] - actual[(r, c)]).norm() < 1e-10)));
+    }
+
+    proptest! {
+        #[test]
+        fn test_contract_matches_dense_reference(
+            (left_qudits, right_qudits) in small_contractions(),
+            params in proptest::collection::vec(0.0..std::f64::consts::TAU, 2),
+            conjugate in any::<bool>(),
+        ) {
+            let right = if right_qudits.len() == 1 { ry() } else { cry() };
+            let mut node = ContractNode::new(cry(), right, left_qudits, right_qudits);
+            if conjugate {
+                node = node.with_conjugated_right();
+            }
+            prop_assert!(node.has_valid_permutations());
+            prop_assert!(verify_contract(&node, &params));
+        }
+    }
+
     // use super::*;
     // use crate::math::UnitaryBuilder;
     // use crate::sim::kron::KronNode;
@@ -469,4 +1406,75 @@ mod tests {
     //     let ans_utry = builder.get_unitary();
     //     assert!((contract_utry - ans_utry).opnorm_fro().unwrap() < 1e-8);
     // }
+
+    #[test]
+    fn test_is_permutation() {
+        assert!(is_permutation(&[]));
+        assert!(is_permutation(&[0]));
+        assert!(is_permutation(&[2, 0, 3, 1]));
+        assert!(!is_permutation(&[0, 0]));
+        assert!(!is_permutation(&[1, 2]));
+        assert!(!is_permutation(&[0, 1, 3, 3]));
+    }
+
+    #[test]
+    fn test_corrupted_permutations_are_caught() {
+        let node = ContractNode::new(cry(), ry(), vec![2, 0], vec![0]);
+        assert!(node.has_valid_permutations());
+
+        let mut repeated = node.clone();
+        repeated.left_perm[0] = repeated.left_perm[1];
+        assert!(!repeated.has_valid_permutations());
+
+        let mut out_of_range = node.clone();
+        out_of_range.pre_out_perm[0] = out_of_range.pre_out_perm.len();
+        assert!(!out_of_range.has_valid_permutations());
+    }
+
+    #[test]
+    fn test_conjugated_right_is_elementwise() {
+        let p = || ExpressionTree::Leaf(fixtures::p());
+        let node = ContractNode::new(p(), p(), vec![0], vec![0]).with_conjugated_right();
+        let tree = ExpressionTree::Contract(node);
+
+        // P(a) · conj(P(b)) = diag(1, e^(i(a - b)))
+        let (a, b) = (0.7, 0.2);
+        let expected = c64::new((a - b).cos(), (a - b).sin());
+        for actual in [evaluate(&tree, &[a, b]), tree.evaluate_ref::<c64>(&[a, b])] {
+            assert!((actual[(0, 0)] - c64::new(1.0, 0.0)).norm() < 1e-10);
+            assert!(actual[(0, 1)].norm() < 1e-10);
+            assert!(actual[(1, 0)].norm() < 1e-10);
+            assert!((actual[(1, 1)] - expected).norm() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_is_identity() {
+        assert!(is_identity(&[]));
+        assert!(is_identity(&[0, 1, 2, 3]));
+        assert!(!is_identity(&[1, 0, 2, 3]));
+    }
+
+    #[test]
+    fn test_group_adjacent_indices() {
+        // Identity collapses to a single index
+        let (shape, perm) = group_adjacent_indices(&[2, 3, 4], &[0, 1, 2]);
+        assert_eq!(shape, vec![24]);
+        assert_eq!(perm, vec![0]);
+
+        // Swapping two blocks of adjacent indices
+        let (shape, perm) = group_adjacent_indices(&[2, 3, 4, 5], &[2, 3, 0, 1]);
+        assert_eq!(shape, vec![6, 20]);
+        assert_eq!(perm, vec![1, 0]);
+
+        // Only the trailing pair is grouped
+        let (shape, perm) = group_adjacent_indices(&[2, 3, 4], &[2, 0, 1]);
+        assert_eq!(shape, vec![6, 4]);
+        assert_eq!(perm, vec![1, 0]);
+
+        // Nothing to group
+        let (shape, perm) = group_adjacent_indices(&[2, 3, 4], &[1, 0, 2]);
+        assert_eq!(shape, vec![2, 3, 4]);
+        assert_eq!(perm, vec![1, 0, 2]);
+    }
 }
@@ -0,0 +1,63 @@
+/// The throughput of the hardware a tree will be evaluated on.
+///
+/// A [crate::TreeBuilder] given a profile orders contractions by their
+/// estimated run time on this hardware, rather than by size alone. On
+/// memory-bound hardware this favors contractions that move less data
+/// through permutations, and on compute-bound hardware contractions that
+/// perform fewer multiplications.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HardwareProfile {
+    /// The sustained rate of floating-point operations, per second.
+    pub flops_per_second: f64,
+
+    /// The sustained memory bandwidth, in bytes per second.
+    pub bytes_per_second: f64,
+}
+
+impl HardwareProfile {
+    /// Create a new hardware profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `flops_per_second` - The sustained floating-point operation rate.
+    /// * `bytes_per_second` - The sustained memory bandwidth.
+    ///
+    /// # Panics
+    ///
+    /// If either rate is not positive.
+    pub fn new(flops_per_second: f64, bytes_per_second: f64) -> Self {
+        if !(flops_per_second > 0.0 && bytes_per_second > 0.0) {
+            panic!("Hardware rates must be positive.");
+        }
+        Self { flops_per_second, bytes_per_second }
+    }
+
+    /// Estimate the time to contract two operators, in seconds.
+    ///
+    /// The matmul performs `8 * dim^2 * overlap_dimension` floating-point
+    /// operations on complex entries. The pre- and post-permutations each
+    /// read and write every entry of their matrix once.
+    ///
+    /// # Arguments
+    ///
+    /// * `left_dimension` - The dimension of the left operator.
+    /// * `right_dimension` - The dimension of the right operator.
+    /// * `overlap_dimension` - The dimension of the contracted qudits.
+    pub fn contraction_time(
+        &self,
+        left_dimension: usize,
+        right_dimension: usize,
+        overlap_dimension: usize,
+    ) -> f64 {
+        let left = left_dimension as f64;
+        let right = right_dimension as f64;
+        let overlap = overlap_dimension as f64;
+        let dim = left * right / overlap;
+
+        let flops = 8.0 * dim * dim * overlap;
+        let entries_moved = left * left + right * right + dim * dim;
+        let bytes = 2.0 * 16.0 * entries_moved;
+
+        flops / self.flops_per_second + bytes / self.bytes_per_second
+    }
+}
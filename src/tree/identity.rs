@@ -6,6 +6,20 @@ use qudit_core::HasParams;
 use qudit_core::RealScalar;
 use qudit_core::QuditRadices;
 use qudit_core::QuditSystem;
+use qudit_expr::UnitaryExpression;
+
+/// The identity expression on `radices`.
+///
+/// Expressions are compiled into a module and looked up by name, so the
+/// name spells out the radices (e.g. `I_2_2_3`) to keep identities of
+/// different shapes from colliding.
+pub fn identity_expression(radices: QuditRadices) -> UnitaryExpression {
+    let name = std::iter::once("I".to_string())
+        .chain(radices.iter().map(|r| r.to_string()))
+        .collect::<Vec<_>>()
+        .join("_");
+    UnitaryExpression::identity(&name, radices)
+}
 
 /// A leaf node in the computation tree that wraps an individual gate.
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
@@ -32,6 +46,11 @@ impl IdentityNode {
     pub fn new(radices: QuditRadices) -> IdentityNode {
         IdentityNode { radices }
     }
+
+    /// The identity expression this node is written as.
+    pub fn expression(&self) -> UnitaryExpression {
+        identity_expression(self.radices.clone())
+    }
 }
 
 impl QuditSystem for IdentityNode {
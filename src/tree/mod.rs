@@ -6,11 +6,27 @@ mod kron;
 mod mul;
 mod optimizer;
 mod fmt;
+mod hardware;
 mod perm;
+mod reference;
+mod runtime;
 mod tree;
 
 pub use builder::BuilderExpressionInput;
+pub use builder::ClassicalCondition;
+pub use builder::ContractCandidate;
+pub use builder::PairBlocker;
+pub use builder::PairDecision;
 pub use builder::TreeBuilder;
+pub use contract::ContractMeta;
+pub use contract::ContractNode;
+pub use contract::ContractTemplate;
+pub use contract::ContractionStep;
+pub use hardware::HardwareProfile;
+pub(crate) use identity::identity_expression;
 pub use optimizer::TreeOptimizer;
+pub use runtime::RuntimeConstantNode;
+pub use tree::ContractionStats;
 pub use tree::ExpressionTree;
+pub use tree::NodeKind;
 
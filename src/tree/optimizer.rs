@@ -1,10 +1,178 @@
 use super::constant::ConstantNode;
+use super::identity::identity_expression;
 use super::contract::ContractNode;
 use super::kron::KronNode;
 use super::mul::MulNode;
 use super::perm::PermNode;
 use super::ExpressionTree;
+use crate::bytecode::ParameterMap;
 use qudit_core::HasParams;
+use qudit_core::QuditPermutation;
+use qudit_core::QuditRadices;
+use qudit_core::QuditSystem;
+
+/// The estimated fixed cost of dispatching one instruction, in FLOPs.
+///
+/// Small instructions are dominated by setup rather than arithmetic,
+/// especially FRPRs, which must compute their index strides.
+const INSTRUCTION_OVERHEAD: u128 = 256;
+
+/// Estimate the cost of the instructions a contraction node generates,
+/// excluding its operands.
+fn contraction_cost(n: &ContractNode) -> u128 {
+    let (lr, lc) = (n.left_contraction_shape.0 as u128, n.left_contraction_shape.1 as u128);
+    let (rr, rc) = (n.right_contraction_shape.0 as u128, n.right_contraction_shape.1 as u128);
+
+    let mut cost = 8 * rr * rc * lc + INSTRUCTION_OVERHEAD;
+    if !n.skip_left {
+        cost += lr * lc + INSTRUCTION_OVERHEAD;
+    }
+    if !n.skip_right {
+        cost += rr * rc + INSTRUCTION_OVERHEAD;
+    }
+    if !n.skips_output_permutation() {
+        cost += (n.out_matrix_shape.0 * n.out_matrix_shape.1) as u128 + INSTRUCTION_OVERHEAD;
+    }
+    cost
+}
+
+/// Extend `tree`, acting on `qudits`, with the identity on the rest of
+/// `all_qudits`, so it acts on `all_qudits` in order.
+///
+/// # Returns
+///
+/// The extended tree and the estimated cost of the instructions added.
+fn embed(
+    tree: ExpressionTree,
+    qudits: &[usize],
+    all_qudits: &[usize],
+    radix_of: impl Fn(usize) -> u8,
+) -> (ExpressionTree, u128) {
+    let missing: Vec<usize> =
+        all_qudits.iter().copied().filter(|q| !qudits.contains(q)).collect();
+    let kron_qudits: Vec<usize> = qudits.iter().chain(missing.iter()).copied().collect();
+    let dim = all_qudits.iter().map(|&q| radix_of(q) as u128).product::<u128>();
+    let mut cost = 0;
+
+    let tree = if missing.is_empty() {
+        tree
+    } else {
+        let radices = QuditRadices::from_iter(missing.iter().map(|&q| radix_of(q)));
+        let identity = identity_expression(radices);
+        match tree {
+            // Leaves fuse with the identity, so no kron is executed
+            ExpressionTree::Leaf(expr) => ExpressionTree::Leaf(expr.otimes(&identity)),
+            tree => {
+                cost += 6 * dim * dim + INSTRUCTION_OVERHEAD;
+                ExpressionTree::Kron(KronNode::new(tree, ExpressionTree::Leaf(identity)))
+            },
+        }
+    };
+
+    let order: Vec<usize> = all_qudits
+        .iter()
+        .map(|q| kron_qudits.iter().position(|x| x == q).unwrap())
+        .collect();
+    if order.iter().enumerate().all(|(i, &o)| i == o) {
+        (tree, cost)
+    } else {
+        cost += dim * dim + INSTRUCTION_OVERHEAD;
+        (tree.apply_output_permutation(order), cost)
+    }
+}
+
+/// Rewrite a contraction as a product of its operands extended to the full
+/// set of qudits, if that is estimated to be cheaper.
+///
+/// Contracting small operands spends most of its time in the pre- and
+/// post-permutations, so krons with the identity and a plain multiply can
+/// win despite the larger matmul.
+fn contraction_to_mul(n: ContractNode) -> ExpressionTree {
+    // Reshaped operands cannot be padded with identities or permuted by
+    // qudit, since their own radices differ from the contracted ones.
+    if n.conjugate_right
+        || n.left_radices() != n.left.radices()
+        || n.right_radices() != n.right.radices()
+    {
+        return ExpressionTree::Contract(n);
+    }
+
+    // The product is laid out in the contraction's output order
+    let all_qudits = n.output_qudits().expect("Contractions are unfused before optimizing.");
+
+    let left_radices = n.left_radices();
+    let right_radices = n.right_radices();
+    let radix_of = |q: usize| match n.left_qudits.iter().position(|&x| x == q) {
+        Some(i) => left_radices[i],
+        None => right_radices[n.right_qudits.iter().position(|&x| x == q).unwrap()],
+    };
+
+    let (left, left_cost) = embed(*n.left.clone(), &n.left_qudits, &all_qudits, radix_of);
+    let (right, right_cost) = embed(*n.right.clone(), &n.right_qudits, &all_qudits, radix_of);
+    let dim = n.dimension() as u128;
+    let mul_cost = left_cost + right_cost + 8 * dim * dim * dim + INSTRUCTION_OVERHEAD;
+
+    if mul_cost < contraction_cost(&n) {
+        ExpressionTree::Mul(MulNode::new(left, right))
+    } else {
+        ExpressionTree::Contract(n)
+    }
+}
+
+/// Permute the output of `tree` so output qudit `i` is qudit `perm[i]` of
+/// `tree`, pushing the permutation into kron nodes where their operands can
+/// be swapped instead.
+///
+/// Returns the permuted tree and, for each of its parameters, the index of
+/// the parameter of `tree` it reads, offset by `param_offset`.
+fn permute_into_layout(
+    tree: ExpressionTree,
+    perm: Vec<usize>,
+    param_offset: usize,
+) -> (ExpressionTree, Vec<usize>) {
+    if perm.iter().enumerate().all(|(i, &p)| i == p) {
+        let params = (param_offset..param_offset + tree.num_params()).collect();
+        return (tree, params);
+    }
+
+    match tree {
+        ExpressionTree::Perm(n) => {
+            let composed = perm.iter().map(|&p| n.perm[p]).collect();
+            permute_into_layout(*n.child, composed, param_offset)
+        },
+        ExpressionTree::Kron(n) => {
+            let num_left = n.left.num_qudits();
+            let num_right = n.right.num_qudits();
+            let right_offset = param_offset + n.left.num_params();
+
+            if perm[..num_left].iter().all(|&p| p < num_left) {
+                let left_perm = perm[..num_left].to_vec();
+                let right_perm = perm[num_left..].iter().map(|&p| p - num_left).collect();
+                let (left, mut params) = permute_into_layout(*n.left, left_perm, param_offset);
+                let (right, right_params) = permute_into_layout(*n.right, right_perm, right_offset);
+                params.extend(right_params);
+                (ExpressionTree::Kron(KronNode::new(left, right)), params)
+            } else if perm[..num_right].iter().all(|&p| p >= num_left) {
+                let right_perm = perm[..num_right].iter().map(|&p| p - num_left).collect();
+                let left_perm = perm[num_right..].to_vec();
+                let (right, mut params) = permute_into_layout(*n.right, right_perm, right_offset);
+                let (left, left_params) = permute_into_layout(*n.left, left_perm, param_offset);
+                params.extend(left_params);
+                (ExpressionTree::Kron(KronNode::new(right, left)), params)
+            } else {
+                let tree = ExpressionTree::Kron(n);
+                let params = (param_offset..param_offset + tree.num_params()).collect();
+                let perm = QuditPermutation::new(tree.radices(), perm);
+                (ExpressionTree::Perm(PermNode::new(tree, perm)), params)
+            }
+        },
+        _ => {
+            let params = (param_offset..param_offset + tree.num_params()).collect();
+            let perm = QuditPermutation::new(tree.radices(), perm);
+            (ExpressionTree::Perm(PermNode::new(tree, perm)), params)
+        },
+    }
+}
 
 pub struct TreeOptimizer {}
 
@@ -14,17 +182,98 @@ impl TreeOptimizer {
     }
 
     pub fn optimize(&self, mut tree: ExpressionTree) -> ExpressionTree {
+        // Contractions are rebuilt in their output order below, which a
+        // contraction fused by a previous optimization only knows through
+        // its parent, so fusion is undone first and redone at the end.
+        tree.traverse_mut(&|n| self.unfuse_contraction_pre_post_permutations(n));
+        tree = self.remove_identity_permutations(tree);
         tree = self.fuse_common_operations(tree);
         tree.traverse_mut(&|n| self.fuse_contraction_pre_post_permutations(n));
         self.constant_propagation(&mut tree);
         tree
     }
 
+    /// Lay out the output of `tree` in `layout`, reordering kron operands
+    /// so as few permutations as possible are needed.
+    ///
+    /// The returned tree computes the unitary of `tree` with output qudit
+    /// `i` being qudit `layout[i]` of `tree`. Kron operands are swapped to
+    /// match the layout where possible, and a permutation is only inserted
+    /// above a node whose operands cannot be swapped into place.
+    ///
+    /// Swapping kron operands also swaps the order of their parameters, so
+    /// the returned map relates the two orders: parameter `i` of the
+    /// returned tree reads parameter `map.global_index(i)` of `tree`.
+    /// Compile the returned tree with [crate::compile_with_parameter_map]
+    /// to evaluate it with the original order.
+    ///
+    /// # Panics
+    ///
+    /// If `layout` is not a permutation of the qudits of `tree`.
+    pub fn order_kron_operands(
+        &self,
+        tree: ExpressionTree,
+        layout: &[usize],
+    ) -> (ExpressionTree, ParameterMap) {
+        let mut seen = vec![false; tree.num_qudits()];
+        if layout.len() != tree.num_qudits()
+            || layout.iter().any(|&q| q >= seen.len() || std::mem::replace(&mut seen[q], true))
+        {
+            panic!("Layout must be a permutation of the qudits of the tree.");
+        }
+        let (tree, indices) = permute_into_layout(tree, layout.to_vec(), 0);
+        (tree, ParameterMap::new(indices))
+    }
+
+    /// Replace every permutation node that leaves its qudits in place with
+    /// its child, so no FRPR is generated for it.
+    fn remove_identity_permutations(&self, tree: ExpressionTree) -> ExpressionTree {
+        match tree {
+            ExpressionTree::Identity(_)
+            | ExpressionTree::Leaf(_)
+            | ExpressionTree::RuntimeConstant(_) => tree,
+            ExpressionTree::Kron(n) => {
+                let left = self.remove_identity_permutations(*n.left);
+                let right = self.remove_identity_permutations(*n.right);
+                ExpressionTree::Kron(KronNode::new(left, right))
+            },
+            ExpressionTree::Mul(n) => {
+                let left = self.remove_identity_permutations(*n.left);
+                let right = self.remove_identity_permutations(*n.right);
+                ExpressionTree::Mul(MulNode::new(left, right))
+            },
+            ExpressionTree::Constant(n) => {
+                ExpressionTree::Constant(ConstantNode::new(self.remove_identity_permutations(*n.child)))
+            },
+            ExpressionTree::Perm(n) => {
+                let child = self.remove_identity_permutations(*n.child);
+                if (0..child.num_qudits()).all(|i| n.perm[i] == i) {
+                    child
+                } else {
+                    ExpressionTree::Perm(PermNode::new(child, n.perm))
+                }
+            },
+            ExpressionTree::Contract(n) => {
+                let output = n.output_qudits().expect("Contractions are unfused before optimizing.");
+                let (left_radices, right_radices) = (n.left_radices(), n.right_radices());
+                let left = self.remove_identity_permutations(*n.left);
+                let right = self.remove_identity_permutations(*n.right);
+                let mut node = ContractNode::new_factored(
+                    left, right, n.left_qudits, n.right_qudits, left_radices, right_radices,
+                )
+                .with_output_qudits(&output);
+                node.conjugate_right = n.conjugate_right;
+                ExpressionTree::Contract(node)
+            },
+        }
+    }
+
     fn fuse_common_operations(&self, tree: ExpressionTree) -> ExpressionTree {
         // traverse the tree, if all children of a kron or mul node or also kron, mul, or leaf then
         // fuse; not a good algorithm; TODO: be better...
         match tree {
             ExpressionTree::Identity(_) => tree,
+            ExpressionTree::RuntimeConstant(_) => tree,
             ExpressionTree::Kron(n) => {
                 let left = self.fuse_common_operations(*n.left);
                 let right = self.fuse_common_operations(*n.right);
@@ -46,15 +295,25 @@ impl TreeOptimizer {
                 }
             },
             ExpressionTree::Leaf(_) => tree,
-            ExpressionTree::Constant(_) => tree,
+            // Constant markers are stripped here and re-applied by constant
+            // propagation, so re-optimizing a tree does not fuse its
+            // contractions twice.
+            ExpressionTree::Constant(n) => self.fuse_common_operations(*n.child),
             ExpressionTree::Perm(n) => {
                 let child = self.fuse_common_operations(*n.child);
                 ExpressionTree::Perm(PermNode::new(child, n.perm))
             },
             ExpressionTree::Contract(n) => {
+                let output = n.output_qudits().expect("Contractions are unfused before optimizing.");
+                let (left_radices, right_radices) = (n.left_radices(), n.right_radices());
                 let left = self.fuse_common_operations(*n.left);
                 let right = self.fuse_common_operations(*n.right);
-                ExpressionTree::Contract(ContractNode::new(left, right, n.left_qudits, n.right_qudits))
+                let mut node = ContractNode::new_factored(
+                    left, right, n.left_qudits, n.right_qudits, left_radices, right_radices,
+                )
+                .with_output_qudits(&output);
+                node.conjugate_right = n.conjugate_right;
+                contraction_to_mul(node)
             },
         }
     }
@@ -66,28 +325,33 @@ impl TreeOptimizer {
     ) {
         if let ExpressionTree::Contract(node) = tree {
             // TODO: Double-check im getting the permutations correct
-            let left_perm = node.left_perm.clone();
-            let right_perm = node.right_perm.clone();
-            let left_contraction_shape = node.left_contraction_shape.clone();
-            let right_contraction_shape = node.right_contraction_shape.clone();
-
-            if let ExpressionTree::Contract(left) = node.left.as_mut() {
-                left.fuse_output_perm(left_perm, left_contraction_shape);
-                node.skip_left_permutation();
-            }
-            if let ExpressionTree::Contract(right) = node.right.as_mut() {
-                right.fuse_output_perm(right_perm, right_contraction_shape);
-                node.skip_right_permutation();
-            }
+            node.fuse_operand_permutations();
+        }
+    }
+
+    fn unfuse_contraction_pre_post_permutations(
+        &self,
+        tree: &mut ExpressionTree,
+    ) {
+        if let ExpressionTree::Contract(node) = tree {
+            node.unfuse_operand_permutations();
         }
     }
 
     fn constant_propagation(&self, tree: &mut ExpressionTree) {
-        if tree.num_params() == 0 {
+        if let ExpressionTree::Constant(n) = tree {
+            // Flatten nested constants, a constant of a constant is redundant
+            let mut child = n.child.as_ref().clone();
+            while let ExpressionTree::Constant(inner) = child {
+                child = *inner.child;
+            }
+            *tree = ExpressionTree::Constant(ConstantNode::new(child));
+        } else if tree.num_params() == 0 {
             *tree = ExpressionTree::Constant(ConstantNode::new(tree.clone()));
         } else {
             match tree {
                 ExpressionTree::Identity(_) => {},
+                ExpressionTree::RuntimeConstant(_) => {},
                 ExpressionTree::Kron(n) => {
                     self.constant_propagation(&mut n.left);
                     self.constant_propagation(&mut n.right);
@@ -118,3 +382,221 @@ impl TreeOptimizer {
     // remove permute and add to contract
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::c64;
+    use qudit_expr::UnitaryExpression;
+
+    use qudit_core::QuditPermutation;
+    use qudit_core::QuditRadices;
+    use qudit_core::QuditSystem;
+
+    use qudit_expr::DifferentiationLevel;
+
+    use super::contraction_cost;
+    use super::contraction_to_mul;
+    use super::embed;
+    use super::ConstantNode;
+    use super::ContractNode;
+    use super::ExpressionTree;
+    use super::KronNode;
+    use super::MulNode;
+    use super::PermNode;
+    use super::TreeOptimizer;
+    use crate::bytecode::GeneralizedInstruction;
+    use crate::bytecode::ParameterMap;
+    use crate::compiler::compile;
+    use crate::compiler::compile_with_parameter_map;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+    use crate::tree::TreeBuilder;
+
+    #[test]
+    fn test_optimize_is_idempotent() {
+        let cx = fixtures::cx();
+        let ry = fixtures::ry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0, 1], vec![1, 2], vec![1], vec![0, 1]],
+            |loc| if loc.len() == 1 { ry.clone() } else { cx.clone() },
+        )
+        .build_tree();
+
+        let optimizer = TreeOptimizer::new();
+        let once = optimizer.optimize(tree);
+        let mut has_constant = false;
+        once.traverse(&mut |n| has_constant |= matches!(n, ExpressionTree::Constant(_)));
+        assert!(has_constant);
+        assert_eq!(optimizer.optimize(once.clone()), once);
+
+        // Nested constants flatten to a single one and still compile
+        let leaf = ExpressionTree::Leaf(cx);
+        let nested = ExpressionTree::Constant(ConstantNode::new(
+            ExpressionTree::Constant(ConstantNode::new(leaf.clone())),
+        ));
+        assert_eq!(optimizer.optimize(nested.clone()), optimizer.optimize(leaf.clone()));
+        let mut qvm = QVM::<c64>::new(compile(&nested), DifferentiationLevel::None);
+        assert_eq!(qvm.get_unitary(&[]), leaf.evaluate_ref::<c64>(&[]).as_ref());
+    }
+
+    #[test]
+    fn test_optimize_keeps_output_order() {
+        let ry = fixtures::ry();
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0], vec![0, 1], vec![2], vec![1, 2]],
+            |loc| if loc.len() == 1 { ry.clone() } else { cry.clone() },
+        )
+        .with_output_order(vec![1, 2, 0])
+        .build_tree();
+        assert!(matches!(tree, ExpressionTree::Contract(_)));
+
+        let unitary = |tree: &ExpressionTree, params: &[f64]| {
+            QVM::<c64>::new(compile(tree), DifferentiationLevel::None)
+                .get_unitary(params)
+                .to_owned()
+        };
+        let params = [0.7, 1.3, 0.4, -0.9];
+        let expected = unitary(&tree, &params);
+
+        // Optimizing again rebuilds contractions fused by the first pass
+        let optimizer = TreeOptimizer::new();
+        let once = optimizer.optimize(tree);
+        let twice = optimizer.optimize(once.clone());
+        for optimized in [&once, &twice] {
+            let actual = unitary(optimized, &params);
+            for r in 0..8 {
+                for c in 0..8 {
+                    assert!((expected[(r, c)] - actual[(r, c)]).norm() < 1e-10);
+                }
+            }
+        }
+
+        // A contraction considered for rewriting as a multiply
+        let node = ContractNode::new(
+            ExpressionTree::Leaf(ry),
+            ExpressionTree::Leaf(cry),
+            vec![0],
+            vec![0, 1],
+        );
+        let contract = ExpressionTree::Contract(node).apply_output_permutation(vec![1, 0]);
+        let ExpressionTree::Contract(node) = contract.clone() else {
+            panic!("The output order should be absorbed by the contraction.");
+        };
+        let params = [0.3, 1.2];
+        let expected = unitary(&contract, &params);
+        let actual = unitary(&contraction_to_mul(node), &params);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((expected[(r, c)] - actual[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_identity_permutation_is_removed() {
+        let cry = ExpressionTree::Leaf(fixtures::cry());
+        let perm = QuditPermutation::new(cry.radices(), vec![0, 1]);
+        let tree = ExpressionTree::Perm(PermNode::new(cry, perm));
+
+        let optimized = TreeOptimizer::new().optimize(tree);
+        assert!(matches!(optimized, ExpressionTree::Leaf(_)));
+
+        let code = compile(&optimized);
+        let has_frpr = code
+            .static_code
+            .iter()
+            .chain(code.dynamic_code.iter())
+            .any(|inst| matches!(inst, GeneralizedInstruction::FRPR(..)));
+        assert!(!has_frpr);
+    }
+
+    #[test]
+    fn test_kron_order_matching_layout_needs_no_frpr() {
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let p = ExpressionTree::Leaf(UnitaryExpression::new(
+            "P3(a) { [[1, 0, 0], [0, e^(i*a), 0], [0, 0, 1]] }",
+        ));
+        let tree = ExpressionTree::Kron(KronNode::new(ry, p));
+        let layout = [1, 0];
+
+        let has_frpr = |tree: &ExpressionTree| {
+            let code = compile(tree);
+            code.static_code
+                .iter()
+                .chain(code.dynamic_code.iter())
+                .any(|inst| matches!(inst, GeneralizedInstruction::FRPR(..)))
+        };
+        let perm = QuditPermutation::new(tree.radices(), layout.to_vec());
+        let permuted = ExpressionTree::Perm(PermNode::new(tree.clone(), perm));
+        assert!(has_frpr(&permuted));
+
+        let (ordered, param_map) = TreeOptimizer::new().order_kron_operands(tree, &layout);
+        assert!(matches!(ordered, ExpressionTree::Kron(_)));
+        assert!(!has_frpr(&ordered));
+        assert_eq!(param_map, ParameterMap::new(vec![1, 0]));
+
+        let params = [0.7, 1.9];
+        let expected = permuted.evaluate_ref::<c64>(&params);
+        let code = compile_with_parameter_map(&ordered, param_map);
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let actual = qvm.get_unitary(&params);
+        for r in 0..6 {
+            for c in 0..6 {
+                assert!((expected[(r, c)] - actual[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_contraction_becomes_mul() {
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let cry = ExpressionTree::Leaf(fixtures::cry());
+        let node = ContractNode::new(ry, cry, vec![0], vec![0, 1]);
+        let contract = ExpressionTree::Contract(node.clone());
+        let contract_cost = contraction_cost(&node);
+
+        let rewritten = contraction_to_mul(node);
+        let ExpressionTree::Mul(mul) = &rewritten else {
+            panic!("Expected the contraction to be rewritten as a multiply.");
+        };
+        assert!(8 * 4 * 4 * 4 + super::INSTRUCTION_OVERHEAD < contract_cost);
+        assert!(matches!(*mul.left, ExpressionTree::Leaf(_)));
+
+        let params = [0.3, 1.2];
+        let expected = contract.evaluate_ref::<c64>(&params);
+        let actual = rewritten.evaluate_ref::<c64>(&params);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((expected[(r, c)] - actual[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_padding_identities_are_named_by_radices() {
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let mul = ExpressionTree::Mul(MulNode::new(ry.clone(), ry));
+        let (small, _) = embed(mul.clone(), &[0], &[0, 1], |_| 2);
+        let (large, _) = embed(mul, &[0], &[0, 1, 2], |_| 2);
+        let tree = ExpressionTree::Kron(KronNode::new(small, large));
+
+        let mut names = Vec::new();
+        tree.for_each_leaf(|expr| names.push(expr.name()));
+        assert!(names.iter().any(|n| n == "I_2"));
+        assert!(names.iter().any(|n| n == "I_2_2"));
+
+        // Both identities are compiled into one module
+        let params = [0.3, 1.1, 2.0, 0.7];
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let expected = tree.evaluate_ref::<c64>(&params);
+        let actual = qvm.get_unitary(&params);
+        for r in 0..32 {
+            for c in 0..32 {
+                assert!((expected[(r, c)] - actual[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use faer::Mat;
+use qudit_core::ComplexScalar;
+use qudit_core::HasParams;
+use qudit_core::QuditSystem;
+use qudit_expr::DifferentiationLevel;
+
+use super::tree::ExpressionTree;
+use crate::compiler::compile;
+use crate::qvm::QVM;
+
+/// Split `idx` into its digits, with the first radix most significant.
+fn digits(mut idx: usize, radices: &[usize]) -> Vec<usize> {
+    let mut out = vec![0; radices.len()];
+    for k in (0..radices.len()).rev() {
+        out[k] = idx % radices[k];
+        idx /= radices[k];
+    }
+    out
+}
+
+/// Combine `digits` into an index, with the first radix most significant.
+fn undigits(digits: impl Iterator<Item = (usize, usize)>) -> usize {
+    digits.fold(0, |acc, (d, r)| acc * r + d)
+}
+
+/// The plain matrix product `a * b`.
+fn matmul<C: ComplexScalar>(a: &Mat<C>, b: &Mat<C>) -> Mat<C> {
+    Mat::from_fn(a.nrows(), b.ncols(), |r, c| {
+        let mut acc = C::zero();
+        for k in 0..a.ncols() {
+            acc = acc + a[(r, k)] * b[(k, c)];
+        }
+        acc
+    })
+}
+
+/// Embed `op`, acting on `op_qudits` in that order, into the space of
+/// `all_qudits` in that order, acting as identity on the remaining qudits.
+fn embed<C: ComplexScalar>(
+    op: &Mat<C>,
+    op_qudits: &[usize],
+    all_qudits: &[usize],
+    radices: &[usize],
+) -> Mat<C> {
+    let dim = radices.iter().product();
+    let positions: Vec<usize> = op_qudits
+        .iter()
+        .map(|q| all_qudits.iter().position(|x| x == q).unwrap())
+        .collect();
+
+    Mat::from_fn(dim, dim, |r, c| {
+        let rd = digits(r, radices);
+        let cd = digits(c, radices);
+        for k in 0..all_qudits.len() {
+            if !positions.contains(&k) && rd[k] != cd[k] {
+                return C::zero();
+            }
+        }
+        let r_sub = undigits(positions.iter().map(|&k| (rd[k], radices[k])));
+        let c_sub = undigits(positions.iter().map(|&k| (cd[k], radices[k])));
+        op[(r_sub, c_sub)]
+    })
+}
+
+impl ExpressionTree {
+    /// Evaluate the tree directly, without compiling it to bytecode.
+    ///
+    /// Each node's matrix is computed densely from its children: kron and
+    /// multiplication by their definitions, permutations by relabeling
+    /// indices, and contractions by embedding both operands in the joint
+    /// space. Only leaves are evaluated through a QVM, since expressions
+    /// can only be evaluated through their JIT-compiled functions.
+    ///
+    /// This is slow and intended as a golden reference for validating the
+    /// compiler on small circuits.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters of the tree, in tree order.
+    ///
+    /// # Panics
+    ///
+    /// If the tree contains a runtime constant.
+    pub fn evaluate_ref<C: ComplexScalar>(&self, params: &[C::R]) -> Mat<C> {
+        // Contractions are embedded by their output qudits
+        self.unfused().evaluate_unfused(params)
+    }
+
+    fn evaluate_unfused<C: ComplexScalar>(&self, params: &[C::R]) -> Mat<C> {
+        match self {
+            ExpressionTree::Identity(n) => {
+                let dim = n.dimension();
+                Mat::from_fn(dim, dim, |r, c| if r == c { C::one() } else { C::zero() })
+            },
+            ExpressionTree::Leaf(_) => {
+                QVM::<C>::new(compile(self), DifferentiationLevel::None)
+                    .get_unitary(params)
+                    .to_owned()
+            },
+            ExpressionTree::Constant(n) => n.child.evaluate_unfused(&[]),
+            ExpressionTree::RuntimeConstant(_) => {
+                panic!("Runtime constants cannot be evaluated without their matrices.")
+            },
+            ExpressionTree::Kron(n) => {
+                let left_params = n.left.num_params();
+                let left = n.left.evaluate_unfused::<C>(&params[..left_params]);
+                let right = n.right.evaluate_unfused::<C>(&params[left_params..]);
+                let (rr, rc) = (right.nrows(), right.ncols());
+                Mat::from_fn(left.nrows() * rr, left.ncols() * rc, |r, c| {
+                    left[(r / rr, c / rc)] * right[(r % rr, c % rc)]
+                })
+            },
+            ExpressionTree::Mul(n) => {
+                let left_params = n.left.num_params();
+                let left = n.left.evaluate_unfused::<C>(&params[..left_params]);
+                let right = n.right.evaluate_unfused::<C>(&params[left_params..]);
+                matmul(&right, &left)
+            },
+            ExpressionTree::Perm(n) => {
+                // Output qudit i is input qudit perm[i]
+                let child = n.child.evaluate_unfused::<C>(params);
+                let in_radices: Vec<usize> =
+                    n.child.radices().iter().map(|&r| r as usize).collect();
+                let num_qudits = in_radices.len();
+                let out_radices: Vec<usize> =
+                    (0..num_qudits).map(|i| in_radices[n.perm[i]]).collect();
+
+                let to_input = |idx: usize| {
+                    let out_digits = digits(idx, &out_radices);
+                    let mut in_digits = vec![0; num_qudits];
+                    for i in 0..num_qudits {
+                        in_digits[n.perm[i]] = out_digits[i];
+                    }
+                    undigits(in_digits.into_iter().zip(in_radices.iter().copied()))
+                };
+
+                Mat::from_fn(child.nrows(), child.ncols(), |r, c| {
+                    child[(to_input(r), to_input(c))]
+                })
+            },
+            ExpressionTree::Contract(n) => {
+                let left_params = n.left.num_params();
+                let left = n.left.evaluate_unfused::<C>(&params[..left_params]);
+                let mut right = n.right.evaluate_unfused::<C>(&params[left_params..]);
+                if n.conjugate_right {
+                    right = Mat::from_fn(right.nrows(), right.ncols(), |r, c| {
+                        right[(r, c)].conj()
+                    });
+                }
+
+                let all_qudits = n.output_qudits().expect("Contractions are unfused before evaluating.");
+                let radices: Vec<usize> =
+                    n.radices().iter().map(|&r| r as usize).collect();
+
+                let left = embed(&left, &n.left_qudits, &all_qudits, &radices);
+                let right = embed(&right, &n.right_qudits, &all_qudits, &radices);
+                matmul(&right, &left)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qudit_core::c64;
+    use qudit_core::QuditRadices;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use super::super::contract::ContractNode;
+    use super::super::kron::KronNode;
+    use super::super::mul::MulNode;
+    use super::ExpressionTree;
+    use crate::compiler::compile;
+    use crate::fixtures;
+    use crate::qvm::QVM;
+    use crate::tree::TreeBuilder;
+
+    fn ry() -> ExpressionTree {
+        ExpressionTree::Leaf(fixtures::ry())
+    }
+
+    fn cry() -> ExpressionTree {
+        ExpressionTree::Leaf(fixtures::cry())
+    }
+
+    /// A parameterized qutrit cyclic shift.
+    fn shift3() -> ExpressionTree {
+        ExpressionTree::Leaf(UnitaryExpression::new(
+            "X3(a) { [[0, 0, e^(i*a)], [1, 0, 0], [0, 1, 0]] }",
+        ))
+    }
+
+    fn assert_matches_qvm(tree: ExpressionTree, params: &[f64]) {
+        let expected = tree.evaluate_ref::<c64>(params);
+        let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+        let actual = qvm.get_unitary(params);
+        for r in 0..expected.nrows() {
+            for c in 0..expected.ncols() {
+                assert!((expected[(r, c)] - actual[(r, c)]).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_ref_matches_qvm() {
+        assert_matches_qvm(ExpressionTree::Kron(KronNode::new(ry(), cry())), &[0.4, 1.1]);
+        assert_matches_qvm(ExpressionTree::Mul(MulNode::new(cry(), cry())), &[0.4, 1.1]);
+        assert_matches_qvm(
+            ExpressionTree::Contract(ContractNode::new(cry(), cry(), vec![0, 1], vec![2, 1])),
+            &[0.4, 1.1],
+        );
+    }
+
+    #[test]
+    fn test_mixed_radix_kron_matches_qvm() {
+        assert_matches_qvm(ExpressionTree::Kron(KronNode::new(ry(), shift3())), &[0.4, 1.1]);
+        assert_matches_qvm(ExpressionTree::Kron(KronNode::new(shift3(), ry())), &[0.4, 1.1]);
+    }
+
+    #[test]
+    fn test_permuted_contraction_matches_qvm() {
+        let contract =
+            ExpressionTree::Contract(ContractNode::new(cry(), cry(), vec![0, 1], vec![2, 1]));
+        assert_matches_qvm(contract.apply_output_permutation(vec![2, 0, 1]), &[0.4, 1.1]);
+
+        // Fused intermediates only know their order through their parents
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0], vec![0, 1], vec![2], vec![1, 2]],
+            |loc| if loc.len() == 1 { fixtures::ry() } else { fixtures::cry() },
+        )
+        .with_output_order(vec![1, 2, 0])
+        .with_tensor_intermediates()
+        .build_tree();
+        assert_matches_qvm(tree, &[0.7, 1.3, 0.4, -0.9]);
+    }
+}
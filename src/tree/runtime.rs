@@ -0,0 +1,62 @@
+use std::hash::Hash;
+
+use super::fmt::PrintTree;
+use qudit_core::HasPeriods;
+use qudit_core::HasParams;
+use qudit_core::RealScalar;
+use qudit_core::QuditRadices;
+use qudit_core::QuditSystem;
+
+/// A leaf node whose matrix is only known when the QVM is constructed.
+///
+/// Runtime constants stand in for matrices that are fixed across every
+/// evaluation but unavailable at compile time, such as calibration data.
+/// The matrix for each id is supplied to [crate::QVM::new_with_runtime_constants].
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct RuntimeConstantNode {
+    /// The id used to look up this node's matrix at QVM construction.
+    pub id: usize,
+
+    /// The radices of the qudit system this constant acts on.
+    radices: QuditRadices,
+}
+
+impl RuntimeConstantNode {
+    /// Create a new runtime constant node.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the matrix to supply at QVM construction.
+    /// * `radices` - The radices of the qudit system the matrix acts on.
+    pub fn new(id: usize, radices: QuditRadices) -> RuntimeConstantNode {
+        RuntimeConstantNode { id, radices }
+    }
+}
+
+impl QuditSystem for RuntimeConstantNode {
+    fn radices(&self) -> QuditRadices {
+        self.radices.clone()
+    }
+
+    fn dimension(&self) -> usize {
+        self.radices.dimension()
+    }
+}
+
+impl HasParams for RuntimeConstantNode {
+    fn num_params(&self) -> usize {
+        0
+    }
+}
+
+impl<R: RealScalar> HasPeriods<R> for RuntimeConstantNode {
+    fn periods(&self) -> Vec<std::ops::Range<R>> {
+        vec![]
+    }
+}
+
+impl PrintTree for RuntimeConstantNode {
+    fn write_tree(&self, prefix: &str, fmt: &mut std::fmt::Formatter<'_>) {
+        writeln!(fmt, "{}RuntimeConstant({}; {})", prefix, self.id, self.radices).unwrap();
+    }
+}
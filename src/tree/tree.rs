@@ -1,16 +1,27 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::hash::Hasher;
+
 
 use super::constant::ConstantNode;
+use super::contract::as_contraction;
+use super::contract::ContractMeta;
+use super::contract::ContractionStep;
 use super::contract::ContractNode;
 use super::fmt::PrintTree;
 use super::identity::IdentityNode;
 use super::kron::KronNode;
 use super::mul::MulNode;
 use super::perm::PermNode;
+use super::runtime::RuntimeConstantNode;
+use crate::bytecode::ParameterMap;
 
 use qudit_core::HasPeriods;
 use qudit_core::HasParams;
 use qudit_core::RealScalar;
 use qudit_expr::UnitaryExpression;
+use qudit_core::QuditPermutation;
 use qudit_core::QuditRadices;
 use qudit_core::QuditSystem;
 
@@ -24,13 +35,146 @@ pub enum ExpressionTree {
     Leaf(UnitaryExpression),
     Mul(MulNode),
     Perm(PermNode),
+    RuntimeConstant(RuntimeConstantNode),
+}
+
+/// The kind of an [ExpressionTree] node, without its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NodeKind {
+    Constant,
+    Contract,
+    Identity,
+    Kron,
+    Leaf,
+    Mul,
+    Perm,
+    RuntimeConstant,
+}
+
+impl NodeKind {
+    /// Every node kind, in declaration order.
+    pub const ALL: [NodeKind; 8] = [
+        NodeKind::Constant,
+        NodeKind::Contract,
+        NodeKind::Identity,
+        NodeKind::Kron,
+        NodeKind::Leaf,
+        NodeKind::Mul,
+        NodeKind::Perm,
+        NodeKind::RuntimeConstant,
+    ];
+}
+
+/// Metrics describing the contractions performed by an [ExpressionTree].
+///
+/// See [ExpressionTree::contraction_stats].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContractionStats {
+    /// The largest dimension of any node in the tree.
+    pub max_dimension: usize,
+
+    /// The number of contraction nodes.
+    pub num_contract: usize,
+
+    /// The number of kronecker product nodes.
+    pub num_kron: usize,
+
+    /// The number of multiplication nodes.
+    pub num_mul: usize,
+
+    /// The total number of elements in the outputs of all contractions.
+    pub total_contract_size: usize,
 }
 
 impl ExpressionTree {
+    /// The kind of this node.
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            ExpressionTree::Constant(_) => NodeKind::Constant,
+            ExpressionTree::Contract(_) => NodeKind::Contract,
+            ExpressionTree::Identity(_) => NodeKind::Identity,
+            ExpressionTree::Kron(_) => NodeKind::Kron,
+            ExpressionTree::Leaf(_) => NodeKind::Leaf,
+            ExpressionTree::Mul(_) => NodeKind::Mul,
+            ExpressionTree::Perm(_) => NodeKind::Perm,
+            ExpressionTree::RuntimeConstant(_) => NodeKind::RuntimeConstant,
+        }
+    }
+
+    /// Count the nodes of each kind in the tree.
+    ///
+    /// Nodes inside constant subtrees are counted too. This is intended for
+    /// telemetry, e.g. tracking how optimizations change a tree's shape.
+    ///
+    /// # Returns
+    ///
+    /// The count of every kind, in the order of [NodeKind::ALL].
+    pub fn node_kind_counts(&self) -> [(NodeKind, usize); 8] {
+        let mut counts = NodeKind::ALL.map(|kind| (kind, 0));
+        self.traverse(&mut |node| counts[node.kind() as usize].1 += 1);
+        counts
+    }
+
+    /// Measure the contractions performed by the tree.
+    ///
+    /// This is intended for comparing contraction orders, e.g. those chosen
+    /// by [crate::TreeBuilder] against externally computed ones. Nodes
+    /// inside constant subtrees are included.
+    pub fn contraction_stats(&self) -> ContractionStats {
+        let mut stats = ContractionStats::default();
+        self.traverse(&mut |node| {
+            stats.max_dimension = stats.max_dimension.max(node.dimension());
+            match node {
+                ExpressionTree::Kron(_) => stats.num_kron += 1,
+                ExpressionTree::Mul(_) => stats.num_mul += 1,
+                ExpressionTree::Contract(n) => {
+                    stats.num_contract += 1;
+                    stats.total_contract_size += n.out_matrix_shape.0 * n.out_matrix_shape.1;
+                },
+                _ => {},
+            }
+        });
+        stats
+    }
+
+    /// The direct children of this node, left before right.
+    fn children(&self) -> Vec<&ExpressionTree> {
+        match self {
+            ExpressionTree::Identity(_)
+            | ExpressionTree::Leaf(_)
+            | ExpressionTree::RuntimeConstant(_) => vec![],
+            ExpressionTree::Kron(n) => vec![&*n.left, &*n.right],
+            ExpressionTree::Mul(n) => vec![&*n.left, &*n.right],
+            ExpressionTree::Contract(n) => vec![&*n.left, &*n.right],
+            ExpressionTree::Perm(n) => vec![&*n.child],
+            ExpressionTree::Constant(n) => vec![&*n.child],
+        }
+    }
+
+    /// Call `f` on every node of the tree, parents before their children
+    /// and left subtrees before right ones.
+    pub fn traverse(&self, f: &mut impl FnMut(&Self)) {
+        f(self);
+        for child in self.children() {
+            child.traverse(f);
+        }
+    }
+
+    /// Call `f` on every expression leaf of the tree, in left-to-right
+    /// tree order.
+    pub fn for_each_leaf(&self, mut f: impl FnMut(&UnitaryExpression)) {
+        self.traverse(&mut |node| {
+            if let ExpressionTree::Leaf(expr) = node {
+                f(expr);
+            }
+        });
+    }
+
     pub fn traverse_mut(&mut self, f: &impl Fn(&mut Self)) {
         f(self);
         match self {
             ExpressionTree::Identity(_) => {},
+            ExpressionTree::RuntimeConstant(_) => {},
             ExpressionTree::Kron(n) => {
                 n.left.traverse_mut(f);
                 n.right.traverse_mut(f);
@@ -52,6 +196,549 @@ impl ExpressionTree {
             },
         }
     }
+
+    /// A copy of the tree with every contraction unfused.
+    ///
+    /// Each contraction then outputs a matrix over its own qudits, whose
+    /// order a contraction fused into its parent only knows through the
+    /// parent.
+    pub(crate) fn unfused(&self) -> ExpressionTree {
+        let mut unfused = self.clone();
+        unfused.traverse_mut(&|node| {
+            if let ExpressionTree::Contract(n) = node {
+                n.unfuse_operand_permutations();
+            }
+        });
+        unfused
+    }
+
+    /// The number of nodes on the longest path from the root to a leaf.
+    pub fn depth(&self) -> usize {
+        1 + self.children().into_iter().map(|child| child.depth()).max().unwrap_or(0)
+    }
+
+    /// The total number of nodes in the tree.
+    pub fn node_count(&self) -> usize {
+        let mut count = 0;
+        self.traverse(&mut |_| count += 1);
+        count
+    }
+
+    /// The number of expression leaves in the tree.
+    ///
+    /// Identities and runtime constants are not counted.
+    pub fn leaf_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_leaf(|_| count += 1);
+        count
+    }
+
+    /// Measure how balanced the tree is.
+    ///
+    /// # Returns
+    ///
+    /// The ratio of the tree's depth to `log2(node_count + 1)`, the depth of
+    /// a perfectly balanced binary tree with the same number of nodes. A
+    /// perfectly balanced tree has a balance factor of 1.0; larger values
+    /// indicate a more lopsided tree with less available parallelism.
+    pub fn balance_factor(&self) -> f64 {
+        let optimal_depth = ((self.node_count() + 1) as f64).log2();
+        self.depth() as f64 / optimal_depth
+    }
+
+    /// Estimate the risk of accumulated rounding error in evaluating the tree.
+    ///
+    /// Every entry of a matrix product is a sum of as many terms as the
+    /// inner dimension, each carrying its own rounding error, and that error
+    /// compounds through the products above it. The estimate is the largest
+    /// product of inner dimensions along any leaf-to-root path; kronecker
+    /// products and permutations are treated as exact.
+    ///
+    /// This is a heuristic meant for comparing contraction orders of the
+    /// same circuit, not a bound on the actual error.
+    pub fn estimate_condition(&self) -> f64 {
+        let inner = match self {
+            ExpressionTree::Mul(n) => n.dimension() as f64,
+            ExpressionTree::Contract(n) => n.left_contraction_shape.0 as f64,
+            _ => 1.0,
+        };
+        let worst_child = self
+            .children()
+            .into_iter()
+            .map(|child| child.estimate_condition())
+            .fold(1.0, f64::max);
+        inner * worst_child
+    }
+
+    /// Estimate the floating-point operations needed to evaluate the tree.
+    ///
+    /// This is the sum of [ExpressionTree::flops_by_kind].
+    pub fn flops(&self) -> u128 {
+        self.flops_by_kind().values().sum()
+    }
+
+    /// Estimate the floating-point operations needed to evaluate the tree,
+    /// broken down by the kind of node performing them.
+    ///
+    /// A complex multiply-add counts as 8 operations and a complex multiply
+    /// as 6. Permutations perform no arithmetic, so each element moved
+    /// counts as one operation. A contraction's pre- and post-permutations
+    /// are attributed to the contraction. Leaves, which are evaluated by
+    /// their compiled expressions, and constant subtrees, which are
+    /// evaluated once, do not contribute.
+    ///
+    /// # Returns
+    ///
+    /// The estimated operations keyed by "Contract", "Mul", "Kron", and
+    /// "Perm". Every key is present, even if the tree has no such node.
+    pub fn flops_by_kind(&self) -> HashMap<&'static str, u128> {
+        let mut flops = HashMap::from([("Contract", 0), ("Mul", 0), ("Kron", 0), ("Perm", 0)]);
+        self.count_flops(&mut flops);
+        flops
+    }
+
+    fn count_flops(&self, flops: &mut HashMap<&'static str, u128>) {
+        let (kind, cost) = match self {
+            // Constant subtrees are evaluated once, so they are not descended
+            ExpressionTree::Constant(_)
+            | ExpressionTree::Identity(_)
+            | ExpressionTree::Leaf(_)
+            | ExpressionTree::RuntimeConstant(_) => return,
+            ExpressionTree::Kron(n) => {
+                let dim = n.dimension() as u128;
+                ("Kron", 6 * dim * dim)
+            },
+            ExpressionTree::Mul(n) => {
+                let dim = n.dimension() as u128;
+                ("Mul", 8 * dim * dim * dim)
+            },
+            ExpressionTree::Perm(n) => {
+                let dim = n.dimension() as u128;
+                ("Perm", dim * dim)
+            },
+            ExpressionTree::Contract(n) => {
+                let (lr, lc) = (n.left_contraction_shape.0 as u128, n.left_contraction_shape.1 as u128);
+                let (rr, rc) = (n.right_contraction_shape.0 as u128, n.right_contraction_shape.1 as u128);
+
+                let mut total = 8 * rr * rc * lc;
+                if !n.skip_left {
+                    total += lr * lc;
+                }
+                if !n.skip_right {
+                    total += rr * rc;
+                }
+                if n.conjugate_right {
+                    total += rr * rc;
+                }
+                if !n.skips_output_permutation() {
+                    total += (n.out_matrix_shape.0 * n.out_matrix_shape.1) as u128;
+                }
+                ("Contract", total)
+            },
+        };
+        *flops.get_mut(kind).unwrap() += cost;
+        for child in self.children() {
+            child.count_flops(flops);
+        }
+    }
+
+    /// The contractions performed by the tree, in execution order.
+    ///
+    /// Operands are evaluated before the nodes that consume them, so each
+    /// step's operands are available when it runs.
+    pub fn contraction_path(&self) -> Vec<ContractionStep> {
+        let mut path = Vec::new();
+        self.push_contraction_steps(&mut path);
+        path
+    }
+
+    fn push_contraction_steps(&self, path: &mut Vec<ContractionStep>) {
+        match self {
+            ExpressionTree::Contract(n) => {
+                let output = n
+                    .output_qudits()
+                    .expect("A contraction fused into its parent has no qudit order of its own.");
+                push_contraction_steps_of(n, output, path);
+            },
+            _ => {
+                for child in self.children() {
+                    child.push_contraction_steps(path);
+                }
+            },
+        }
+    }
+
+    /// Count the occurrences of each distinct subtree.
+    ///
+    /// Subtrees are identified by their structural hash, so a count above
+    /// one reveals an opportunity for common subexpression elimination.
+    ///
+    /// # Returns
+    ///
+    /// A map from each distinct subtree hash to its number of occurrences,
+    /// including the tree itself.
+    pub fn subtree_hashes(&self) -> HashMap<u64, usize> {
+        let mut counts = HashMap::new();
+        self.traverse(&mut |node| {
+            let mut hasher = DefaultHasher::new();
+            node.hash(&mut hasher);
+            *counts.entry(hasher.finish()).or_insert(0) += 1;
+        });
+        counts
+    }
+
+    /// Reorder the output qudits of the tree.
+    ///
+    /// A contraction at the root absorbs the permutation into its final
+    /// reshape-permute, otherwise the tree is wrapped in a permutation node.
+    ///
+    /// # Arguments
+    ///
+    /// * `order` - Output qudit `i` of the new tree is output qudit
+    ///   `order[i]` of this tree.
+    pub fn apply_output_permutation(self, order: Vec<usize>) -> ExpressionTree {
+        match self {
+            ExpressionTree::Contract(mut n) => {
+                n.apply_output_permutation(&order);
+                ExpressionTree::Contract(n)
+            },
+            tree => {
+                let perm = QuditPermutation::new(tree.radices(), order);
+                ExpressionTree::Perm(PermNode::new(tree, perm))
+            },
+        }
+    }
+
+    /// Apply `other` after this tree.
+    ///
+    /// The parameters of this tree come before those of `other`.
+    ///
+    /// # Panics
+    ///
+    /// If the trees do not act on systems with the same radices.
+    pub fn then(self, other: ExpressionTree) -> ExpressionTree {
+        ExpressionTree::Mul(MulNode::new(self, other))
+    }
+
+    /// Place `other` beside this tree, on the qudits following this tree's.
+    ///
+    /// The parameters of this tree come before those of `other`.
+    pub fn beside(self, other: ExpressionTree) -> ExpressionTree {
+        ExpressionTree::Kron(KronNode::new(self, other))
+    }
+
+    /// Embed this tree into a larger system of qudits.
+    ///
+    /// The other qudits of the system are left idle, and take the common
+    /// radix of this tree's qudits.
+    ///
+    /// # Arguments
+    ///
+    /// * `qudits` - Qudit `i` of this tree acts on qudit `qudits[i]` of the
+    ///   larger system.
+    /// * `total_qudits` - The number of qudits in the larger system.
+    ///
+    /// # Panics
+    ///
+    /// - If `qudits` does not have one distinct entry below `total_qudits`
+    ///   for each qudit of this tree.
+    /// - If there are idle qudits and this tree's qudits have mixed radices.
+    pub fn on(self, qudits: &[usize], total_qudits: usize) -> ExpressionTree {
+        if qudits.len() != self.num_qudits() {
+            panic!("Tree must be placed on one qudit per tree qudit");
+        }
+        for (i, &q) in qudits.iter().enumerate() {
+            if q >= total_qudits || qudits[..i].contains(&q) {
+                panic!("Tree cannot be placed on qudits {:?} of {}", qudits, total_qudits);
+            }
+        }
+
+        let radices = self.radices();
+        let idle: Vec<usize> = (0..total_qudits).filter(|q| !qudits.contains(q)).collect();
+        let tree = if idle.is_empty() {
+            self
+        } else {
+            let radix = radices[0];
+            if (0..radices.len()).any(|i| radices[i] != radix) {
+                panic!("Idle qudits have no radix when the tree's radices are mixed");
+            }
+            let identity = IdentityNode::new(QuditRadices::from_iter(idle.iter().map(|_| radix)));
+            self.beside(ExpressionTree::Identity(identity))
+        };
+
+        let kron_qudits: Vec<usize> = qudits.iter().chain(idle.iter()).copied().collect();
+        let order: Vec<usize> = (0..total_qudits)
+            .map(|q| kron_qudits.iter().position(|&x| x == q).unwrap())
+            .collect();
+        if order.iter().enumerate().all(|(i, &o)| i == o) {
+            tree
+        } else {
+            tree.apply_output_permutation(order)
+        }
+    }
+
+    /// Decompose the tree into independent blocks kroneckered at its root.
+    ///
+    /// # Returns
+    ///
+    /// The factors of the outermost kronecker products, in tensor order. A
+    /// tree whose root is not a kronecker product is a single block.
+    pub fn kron_blocks(&self) -> Vec<&ExpressionTree> {
+        match self {
+            ExpressionTree::Kron(n) => {
+                let mut blocks = n.left.kron_blocks();
+                blocks.extend(n.right.kron_blocks());
+                blocks
+            },
+            _ => vec![self],
+        }
+    }
+
+    /// Split the tree at its root contraction.
+    ///
+    /// # Returns
+    ///
+    /// The left and right operands of the root contraction, along with the
+    /// metadata required to combine their evaluated results, or `None` if the
+    /// root of this tree is not a contraction.
+    pub fn split_at_contraction(&self) -> Option<(ExpressionTree, ExpressionTree, ContractMeta)> {
+        match self {
+            ExpressionTree::Contract(n) => {
+                Some((n.left.as_ref().clone(), n.right.as_ref().clone(), n.meta()))
+            },
+            _ => None,
+        }
+    }
+
+    /// Find all leaves whose subtree acts on a given qudit.
+    ///
+    /// # Arguments
+    ///
+    /// * `qudit` - The index of the qudit, relative to this tree's qudits.
+    ///
+    /// # Returns
+    ///
+    /// The leaf expressions that act on `qudit`, in left-to-right tree order.
+    /// Returns an empty vector if `qudit` is out of range.
+    pub fn leaf_covering(&self, qudit: usize) -> Vec<&UnitaryExpression> {
+        let mut leaves = Vec::new();
+        self.collect_leaves_covering(qudit, &mut leaves);
+        leaves
+    }
+
+    fn collect_leaves_covering<'a>(
+        &'a self,
+        qudit: usize,
+        leaves: &mut Vec<&'a UnitaryExpression>,
+    ) {
+        if qudit >= self.num_qudits() {
+            return;
+        }
+
+        match self {
+            ExpressionTree::Identity(_) => {},
+            ExpressionTree::RuntimeConstant(_) => {},
+            ExpressionTree::Leaf(expr) => leaves.push(expr),
+            ExpressionTree::Kron(n) => {
+                let left_num_qudits = n.left.num_qudits();
+                if qudit < left_num_qudits {
+                    n.left.collect_leaves_covering(qudit, leaves);
+                } else {
+                    n.right.collect_leaves_covering(qudit - left_num_qudits, leaves);
+                }
+            },
+            ExpressionTree::Mul(n) => {
+                n.left.collect_leaves_covering(qudit, leaves);
+                n.right.collect_leaves_covering(qudit, leaves);
+            },
+            ExpressionTree::Perm(n) => {
+                // Output qudit i of a permutation node is the child's qudit perm[i]
+                n.child.collect_leaves_covering(n.perm[qudit], leaves);
+            },
+            ExpressionTree::Contract(n) => {
+                let output = n
+                    .output_qudits()
+                    .expect("A contraction fused into its parent has no qudit order of its own.");
+                collect_contraction_leaves(n, output[qudit], leaves);
+            },
+            ExpressionTree::Constant(n) => {
+                n.child.collect_leaves_covering(qudit, leaves);
+            },
+        }
+    }
+
+    /// The conjugate transpose of the tree.
+    ///
+    /// Multiplications and contractions apply their operands in reverse
+    /// order, which also reverses the order of their parameters; see
+    /// [ExpressionTree::dagger_with_param_map].
+    ///
+    /// # Panics
+    ///
+    /// If the tree contains a runtime constant, whose matrix is unknown
+    /// until it is supplied to the QVM.
+    pub fn dagger(&self) -> ExpressionTree {
+        self.dagger_with_param_map().0
+    }
+
+    /// The conjugate transpose of the tree, along with its parameter
+    /// ordering.
+    ///
+    /// Parameter `i` of the returned tree reads parameter
+    /// `map.global_index(i)` of this tree. Compile the adjoint with
+    /// [crate::compile_with_parameter_map] to evaluate it, and its
+    /// gradient, with this tree's parameters.
+    ///
+    /// # Panics
+    ///
+    /// If the tree contains a runtime constant.
+    pub fn dagger_with_param_map(&self) -> (ExpressionTree, ParameterMap) {
+        // The adjoint rebuilds each contraction in its output order
+        let (tree, indices) = self.unfused().dagger_from(0);
+        (tree, ParameterMap::new(indices))
+    }
+
+    fn dagger_from(&self, param_offset: usize) -> (ExpressionTree, Vec<usize>) {
+        let in_order = |tree: ExpressionTree| {
+            let params = (param_offset..param_offset + tree.num_params()).collect();
+            (tree, params)
+        };
+
+        match self {
+            ExpressionTree::Identity(_) => in_order(self.clone()),
+            ExpressionTree::RuntimeConstant(_) => {
+                panic!("Cannot take the adjoint of a runtime constant.")
+            },
+            ExpressionTree::Leaf(expr) => in_order(ExpressionTree::Leaf(expr.dagger())),
+            ExpressionTree::Constant(n) => {
+                in_order(ExpressionTree::Constant(ConstantNode::new(n.child.dagger())))
+            },
+            ExpressionTree::Perm(n) => {
+                // Permuting rows and columns alike commutes with the adjoint
+                let (child, params) = n.child.dagger_from(param_offset);
+                (ExpressionTree::Perm(PermNode::new(child, n.perm.clone())), params)
+            },
+            ExpressionTree::Kron(n) => {
+                let (left, mut params) = n.left.dagger_from(param_offset);
+                let (right, right_params) = n.right.dagger_from(param_offset + n.left.num_params());
+                params.extend(right_params);
+                (ExpressionTree::Kron(KronNode::new(left, right)), params)
+            },
+            ExpressionTree::Mul(n) => {
+                // (R L)† = L† R†, so the daggered right operand is applied first
+                let (left, mut params) = n.right.dagger_from(param_offset + n.left.num_params());
+                let (right, left_params) = n.left.dagger_from(param_offset);
+                params.extend(left_params);
+                (ExpressionTree::Mul(MulNode::new(left, right)), params)
+            },
+            ExpressionTree::Contract(n) => {
+                // The right operand, conjugated if requested, is applied
+                // after the left; the adjoint applies it first. Its
+                // conjugate transpose after conjugation is its transpose.
+                let (mut left, mut params) = n.right.dagger_from(param_offset + n.left.num_params());
+                if n.conjugate_right {
+                    left = left.conjugate();
+                }
+                let (right, left_params) = n.left.dagger_from(param_offset);
+                params.extend(left_params);
+
+                let output = n.output_qudits().expect("Contractions are unfused before taking the adjoint.");
+                let node = ContractNode::new_factored(
+                    left,
+                    right,
+                    n.right_qudits.clone(),
+                    n.left_qudits.clone(),
+                    n.right_radices(),
+                    n.left_radices(),
+                );
+                (ExpressionTree::Contract(node.with_output_qudits(&output)), params)
+            },
+        }
+    }
+
+    /// The elementwise complex conjugate of the tree, with its parameters
+    /// in the same order.
+    fn conjugate(&self) -> ExpressionTree {
+        match self {
+            ExpressionTree::Identity(_) => self.clone(),
+            ExpressionTree::RuntimeConstant(_) => {
+                panic!("Cannot conjugate a runtime constant.")
+            },
+            ExpressionTree::Leaf(expr) => ExpressionTree::Leaf(expr.conjugate()),
+            ExpressionTree::Constant(n) => {
+                ExpressionTree::Constant(ConstantNode::new(n.child.conjugate()))
+            },
+            ExpressionTree::Perm(n) => {
+                ExpressionTree::Perm(PermNode::new(n.child.conjugate(), n.perm.clone()))
+            },
+            ExpressionTree::Kron(n) => {
+                ExpressionTree::Kron(KronNode::new(n.left.conjugate(), n.right.conjugate()))
+            },
+            ExpressionTree::Mul(n) => {
+                ExpressionTree::Mul(MulNode::new(n.left.conjugate(), n.right.conjugate()))
+            },
+            ExpressionTree::Contract(n) => {
+                // Conjugating the right operand again undoes its conjugation
+                let output = n.output_qudits().expect("Contractions are unfused before taking the adjoint.");
+                let mut node = ContractNode::new_factored(
+                    n.left.conjugate(),
+                    n.right.as_ref().clone(),
+                    n.left_qudits.clone(),
+                    n.right_qudits.clone(),
+                    n.left_radices(),
+                    n.right_radices(),
+                );
+                node.conjugate_right = !n.conjugate_right;
+                ExpressionTree::Contract(node.with_output_qudits(&output))
+            },
+        }
+    }
+}
+
+/// Push the steps of the contraction `n`, whose output qudits are
+/// `result_qudits`, after the steps of its operands.
+fn push_contraction_steps_of(
+    n: &ContractNode,
+    result_qudits: Vec<usize>,
+    path: &mut Vec<ContractionStep>,
+) {
+    let operands = [
+        (n.left.as_ref(), n.left_operand_qudits()),
+        (n.right.as_ref(), n.right_operand_qudits()),
+    ];
+    for (operand, operand_output) in operands {
+        match (as_contraction(operand), operand_output) {
+            (Some(m), Some(output)) => push_contraction_steps_of(m, output, path),
+            (operand, _) => operand.push_contraction_steps(path),
+        }
+    }
+    path.push(n.step(result_qudits));
+}
+
+/// Collect the leaves of the contraction `n` that act on `circuit_qudit`,
+/// one of its output qudits.
+///
+/// Operands that are contractions are followed by circuit qudit, so the
+/// order of their outputs is taken into account, even when they are fused
+/// into `n`.
+fn collect_contraction_leaves<'a>(
+    n: &'a ContractNode,
+    circuit_qudit: usize,
+    leaves: &mut Vec<&'a UnitaryExpression>,
+) {
+    let operands = [
+        (n.left.as_ref(), &n.left_qudits, n.left_operand_qudits()),
+        (n.right.as_ref(), &n.right_qudits, n.right_operand_qudits()),
+    ];
+    for (operand, qudits, operand_output) in operands {
+        let Some(i) = qudits.iter().position(|&q| q == circuit_qudit) else {
+            continue;
+        };
+        match (as_contraction(operand), operand_output) {
+            (Some(m), Some(output)) => collect_contraction_leaves(m, output[i], leaves),
+            (operand, _) => operand.collect_leaves_covering(i, leaves),
+        }
+    }
 }
 
 impl QuditSystem for ExpressionTree {
@@ -64,6 +751,20 @@ impl QuditSystem for ExpressionTree {
             Self::Perm(s) => s.dimension(),
             Self::Contract(s) => s.dimension(),
             Self::Constant(s) => s.dimension(),
+            Self::RuntimeConstant(s) => s.dimension(),
+        }
+    }
+
+    fn num_qudits(&self) -> usize {
+        match self {
+            Self::Identity(s) => s.num_qudits(),
+            Self::Kron(s) => s.num_qudits(),
+            Self::Mul(s) => s.num_qudits(),
+            Self::Leaf(s) => s.num_qudits(),
+            Self::Perm(s) => s.num_qudits(),
+            Self::Contract(s) => s.num_qudits(),
+            Self::Constant(s) => s.num_qudits(),
+            Self::RuntimeConstant(s) => s.num_qudits(),
         }
     }
 
@@ -76,6 +777,7 @@ impl QuditSystem for ExpressionTree {
             Self::Perm(s) => s.radices(),
             Self::Contract(s) => s.radices(),
             Self::Constant(s) => s.radices(),
+            Self::RuntimeConstant(s) => s.radices(),
         }
     }
 }
@@ -90,6 +792,7 @@ impl HasParams for ExpressionTree {
             Self::Perm(s) => s.num_params(),
             Self::Contract(s) => s.num_params(),
             Self::Constant(s) => s.num_params(),
+            Self::RuntimeConstant(s) => s.num_params(),
         }
     }
 }
@@ -104,6 +807,7 @@ impl<R: RealScalar> HasPeriods<R> for ExpressionTree {
             Self::Perm(s) => s.periods(),
             Self::Contract(s) => s.periods(),
             Self::Constant(s) => s.periods(),
+            Self::RuntimeConstant(s) => s.periods(),
         }
     }
 }
@@ -124,6 +828,7 @@ impl std::hash::Hash for ExpressionTree {
             Self::Perm(s) => s.hash(state),
             Self::Contract(s) => s.hash(state),
             Self::Constant(s) => s.hash(state),
+            Self::RuntimeConstant(s) => s.hash(state),
         }
     }
 }
@@ -142,6 +847,7 @@ impl PrintTree for ExpressionTree {
             Self::Perm(s) => s.write_tree(prefix, fmt),
             Self::Contract(s) => s.write_tree(prefix, fmt),
             Self::Constant(s) => s.write_tree(prefix, fmt),
+            Self::RuntimeConstant(s) => s.write_tree(prefix, fmt),
         }
     }
 }
@@ -209,6 +915,466 @@ impl std::fmt::Debug for ExpressionTree {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+    use std::hash::Hasher;
+
+    use qudit_core::c64;
+    use qudit_core::HasParams;
+    use qudit_core::QuditPermutation;
+    use qudit_core::QuditRadices;
+    use qudit_core::QuditSystem;
+    use qudit_expr::DifferentiationLevel;
+    use qudit_expr::UnitaryExpression;
+
+    use super::super::constant::ConstantNode;
+    use super::super::contract::ContractNode;
+    use super::super::identity::IdentityNode;
+    use super::super::kron::KronNode;
+    use super::super::mul::MulNode;
+    use super::super::perm::PermNode;
+    use super::ExpressionTree;
+    use super::NodeKind;
+    use crate::compiler::compile;
+    use crate::compiler::compile_with_parameter_map;
+    use crate::fixtures;
+    use crate::tree::TreeBuilder;
+    use crate::tree::TreeOptimizer;
+    use crate::qvm::QVM;
+
+    fn identity(radices: &[u8]) -> ExpressionTree {
+        let radices = QuditRadices::from_iter(radices.iter().copied());
+        ExpressionTree::Identity(IdentityNode::new(radices))
+    }
+
+    #[test]
+    fn test_num_qudits_per_variant() {
+        assert_eq!(identity(&[2, 3]).num_qudits(), 2);
+
+        let kron = ExpressionTree::Kron(KronNode::new(identity(&[2]), identity(&[3, 2])));
+        assert_eq!(kron.num_qudits(), 3);
+
+        let mul = ExpressionTree::Mul(MulNode::new(identity(&[2, 2]), identity(&[2, 2])));
+        assert_eq!(mul.num_qudits(), 2);
+
+        let child = identity(&[2, 3]);
+        let perm = QuditPermutation::locally_invert_location(child.radices(), &vec![1, 0]);
+        let perm = ExpressionTree::Perm(PermNode::new(child, perm));
+        assert_eq!(perm.num_qudits(), 2);
+
+        let contract = ExpressionTree::Contract(ContractNode::new(
+            identity(&[2, 3]),
+            identity(&[3, 2]),
+            vec![0, 1],
+            vec![1, 2],
+        ));
+        assert_eq!(contract.num_qudits(), 3);
+        assert_eq!(contract.num_qudits(), contract.radices().len());
+
+        let constant = ExpressionTree::Constant(ConstantNode::new(contract));
+        assert_eq!(constant.num_qudits(), 3);
+    }
+
+    #[test]
+    fn test_contraction_path_order() {
+        let inner = ExpressionTree::Contract(ContractNode::new(
+            identity(&[2, 2]),
+            identity(&[2, 2]),
+            vec![0, 1],
+            vec![1, 2],
+        ));
+        let outer = ExpressionTree::Contract(ContractNode::new(
+            inner,
+            identity(&[2, 2]),
+            vec![0, 1, 2],
+            vec![2, 3],
+        ));
+
+        let path = outer.contraction_path();
+        assert_eq!(path.len(), 2);
+        assert_eq!(path[0].left_qudits, vec![0, 1]);
+        assert_eq!(path[0].right_qudits, vec![1, 2]);
+        assert_eq!(path[0].result_qudits, vec![0, 1, 2]);
+        assert_eq!(path[1].left_qudits, vec![0, 1, 2]);
+        assert_eq!(path[1].right_qudits, vec![2, 3]);
+        assert_eq!(path[1].result_qudits, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_contraction_path_of_permuted_root() {
+        let build = |tensor_intermediates: bool| {
+            let builder = TreeBuilder::from_locations(
+                QuditRadices::from_iter([2, 2, 2, 2]),
+                vec![vec![0, 1], vec![1, 2], vec![2, 3]],
+                |_| fixtures::cx(),
+            )
+            .with_output_order(vec![3, 1, 0, 2]);
+            if tensor_intermediates {
+                builder.with_tensor_intermediates().build_tree()
+            } else {
+                builder.build_tree()
+            }
+        };
+
+        let path = build(false).contraction_path();
+        assert_eq!(path.len(), 2);
+        assert_eq!(path[1].result_qudits, vec![3, 1, 0, 2]);
+
+        // The inner contraction keeps its qudits in ascending order
+        let mut inner = path[0].result_qudits.clone();
+        inner.sort();
+        assert_eq!(path[0].result_qudits, inner);
+
+        // Fusing the inner contraction into the root changes its output
+        // layout, but not the qudits it produces
+        assert_eq!(build(true).contraction_path(), path);
+    }
+
+    #[test]
+    fn test_subtree_hashes_count_shared_subtrees() {
+        let mul = || ExpressionTree::Mul(MulNode::new(identity(&[2]), identity(&[2])));
+        let tree = ExpressionTree::Kron(KronNode::new(mul(), mul()));
+
+        let mut hasher = DefaultHasher::new();
+        mul().hash(&mut hasher);
+        let counts = tree.subtree_hashes();
+        assert_eq!(counts[&hasher.finish()], 2);
+        assert_eq!(counts.values().sum::<usize>(), 7);
+    }
+
+    #[test]
+    fn test_flops_by_kind() {
+        let contract = ExpressionTree::Contract(ContractNode::new(
+            identity(&[2, 2]),
+            identity(&[2, 2]),
+            vec![0, 1],
+            vec![1, 2],
+        ));
+        let mul = ExpressionTree::Mul(MulNode::new(contract, identity(&[2, 2, 2])));
+        let child = ExpressionTree::Kron(KronNode::new(mul, identity(&[3])));
+        let perm = QuditPermutation::locally_invert_location(child.radices(), &vec![3, 0, 1, 2]);
+        let tree = ExpressionTree::Perm(PermNode::new(child, perm));
+
+        // The contraction over qudit 1 permutes both operands into 2 x 8
+        // and 8 x 2 matrices, multiplies them into an 8 x 8 matrix, and
+        // permutes the product back into qudit order.
+        let contract = 8 * 8 * 2 * 8 + 2 * 8 + 8 * 2 + 8 * 8;
+        let by_kind = tree.flops_by_kind();
+        assert_eq!(by_kind["Contract"], contract);
+        assert_eq!(by_kind["Mul"], 8 * 8 * 8 * 8);
+        assert_eq!(by_kind["Kron"], 6 * 24 * 24);
+        assert_eq!(by_kind["Perm"], 24 * 24);
+        assert_eq!(tree.flops(), 1120 + 4096 + 3456 + 576);
+    }
+
+    #[test]
+    fn test_node_kind_counts() {
+        let contract = ExpressionTree::Contract(ContractNode::new(
+            identity(&[2, 2]),
+            identity(&[2, 2]),
+            vec![0, 1],
+            vec![1, 2],
+        ));
+        let mul = ExpressionTree::Mul(MulNode::new(contract, identity(&[2, 2, 2])));
+        let constant = ExpressionTree::Constant(ConstantNode::new(identity(&[3])));
+        let tree = ExpressionTree::Kron(KronNode::new(mul, constant));
+
+        let counts = tree.node_kind_counts();
+        assert_eq!(counts, [
+            (NodeKind::Constant, 1),
+            (NodeKind::Contract, 1),
+            (NodeKind::Identity, 4),
+            (NodeKind::Kron, 1),
+            (NodeKind::Leaf, 0),
+            (NodeKind::Mul, 1),
+            (NodeKind::Perm, 0),
+            (NodeKind::RuntimeConstant, 0),
+        ]);
+        assert_eq!(counts.iter().map(|(_, n)| n).sum::<usize>(), tree.node_count());
+    }
+
+    #[test]
+    fn test_deep_tree_is_worse_conditioned() {
+        let mul = |a, b| ExpressionTree::Mul(MulNode::new(a, b));
+        let deep = mul(mul(mul(identity(&[2]), identity(&[2])), identity(&[2])), identity(&[2]));
+        let balanced = mul(mul(identity(&[2]), identity(&[2])), mul(identity(&[2]), identity(&[2])));
+        assert!(deep.estimate_condition() > balanced.estimate_condition());
+    }
+
+    #[test]
+    fn test_dagger_is_conjugate_transpose() {
+        let ry = || ExpressionTree::Leaf(fixtures::ry());
+        let p = || ExpressionTree::Leaf(fixtures::p());
+        let cry = || ExpressionTree::Leaf(fixtures::cry());
+        let kron = ExpressionTree::Kron(KronNode::new(ry(), p()));
+        let contract = ExpressionTree::Contract(ContractNode::new(p(), cry(), vec![1], vec![0, 1]));
+        let conjugated = ExpressionTree::Contract(
+            ContractNode::new(ry(), ExpressionTree::Kron(KronNode::new(p(), ry())), vec![0], vec![0, 1])
+                .with_conjugated_right(),
+        );
+        let tree = ExpressionTree::Mul(MulNode::new(
+            ExpressionTree::Mul(MulNode::new(kron, contract)),
+            conjugated,
+        ));
+
+        let params = [0.3, -1.1, 0.8, 2.4, 1.7, -0.6, 0.9];
+        let (dagger, param_map) = tree.dagger_with_param_map();
+
+        let utry = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None)
+            .get_unitary(&params)
+            .to_owned();
+        let code = compile_with_parameter_map(&dagger, param_map);
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let adjoint = qvm.get_unitary(&params);
+        for r in 0..4 {
+            for c in 0..4 {
+                assert!((adjoint[(r, c)] - utry[(c, r)].conj()).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dagger_of_optimized_tree() {
+        let ry = fixtures::ry();
+        let cry = fixtures::cry();
+        let tree = TreeBuilder::from_locations(
+            QuditRadices::from_iter([2, 2, 2]),
+            vec![vec![0], vec![0, 1], vec![2], vec![1, 2]],
+            |loc| if loc.len() == 1 { ry.clone() } else { cry.clone() },
+        )
+        .with_output_order(vec![1, 2, 0])
+        .with_tensor_intermediates()
+        .build_tree();
+        let tree = TreeOptimizer::new().optimize(tree);
+
+        let mut fused = false;
+        tree.traverse(&mut |node| {
+            if let ExpressionTree::Contract(n) = node {
+                fused |= n.skip_left || n.skip_right;
+            }
+        });
+        assert!(fused);
+
+        let params = [0.7, 1.3, 0.4, -0.9];
+        let (dagger, param_map) = tree.dagger_with_param_map();
+        let utry = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None)
+            .get_unitary(&params)
+            .to_owned();
+        let code = compile_with_parameter_map(&dagger, param_map);
+        let mut qvm = QVM::<c64>::new(code, DifferentiationLevel::None);
+        let adjoint = qvm.get_unitary(&params);
+        for r in 0..8 {
+            for c in 0..8 {
+                assert!((adjoint[(r, c)] - utry[(c, r)].conj()).norm() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_structural_metrics() {
+        let p = || ExpressionTree::Leaf(fixtures::p());
+        let kron = ExpressionTree::Kron(KronNode::new(p(), identity(&[2])));
+        let perm = ExpressionTree::Perm(PermNode::new(
+            kron,
+            QuditPermutation::new(QuditRadices::from_iter([2, 2]), vec![1, 0]),
+        ));
+        let constant = ExpressionTree::Constant(ConstantNode::new(identity(&[2, 2])));
+        let tree = ExpressionTree::Mul(MulNode::new(perm, constant));
+
+        // Mul -> Perm -> Kron -> P is the longest path
+        assert_eq!(tree.depth(), 4);
+        assert_eq!(tree.node_count(), 7);
+        assert_eq!(tree.leaf_count(), 1);
+        assert_eq!(p().depth(), 1);
+        assert_eq!(p().node_count(), 1);
+        assert_eq!(identity(&[2]).leaf_count(), 0);
+    }
+
+    #[test]
+    fn test_traverse_counts_leaves() {
+        let p = || ExpressionTree::Leaf(fixtures::p());
+        let cx = ExpressionTree::Leaf(fixtures::cx());
+        let kron = ExpressionTree::Kron(KronNode::new(p(), identity(&[2])));
+        let contract = ExpressionTree::Contract(ContractNode::new(p(), cx, vec![1], vec![0, 1]));
+        let tree = ExpressionTree::Mul(MulNode::new(kron, contract));
+
+        let mut nodes = 0;
+        let mut leaves = 0;
+        tree.traverse(&mut |n| {
+            nodes += 1;
+            if let ExpressionTree::Leaf(_) = n {
+                leaves += 1;
+            }
+        });
+        assert_eq!(nodes, 7);
+        assert_eq!(leaves, 3);
+
+        let mut num_params = Vec::new();
+        tree.for_each_leaf(|expr| num_params.push(expr.num_params()));
+        assert_eq!(num_params, vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn test_contraction_stats() {
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let p = ExpressionTree::Leaf(fixtures::p());
+        let cry = ExpressionTree::Leaf(fixtures::cry());
+        let kron = ExpressionTree::Kron(KronNode::new(ry, p.clone()));
+        let contract = ExpressionTree::Contract(ContractNode::new(p, cry.clone(), vec![1], vec![0, 1]));
+        let mul = ExpressionTree::Mul(MulNode::new(kron, contract));
+        let tree = ExpressionTree::Contract(ContractNode::new(mul, cry, vec![0, 1], vec![1, 2]));
+
+        let stats = tree.contraction_stats();
+        assert_eq!(stats.max_dimension, 8);
+        assert_eq!(stats.num_contract, 2);
+        assert_eq!(stats.num_kron, 1);
+        assert_eq!(stats.num_mul, 1);
+        assert_eq!(stats.total_contract_size, 4 * 4 + 8 * 8);
+    }
+
+    #[test]
+    fn test_combinators_match_builder() {
+        let radices = QuditRadices::from_iter([2, 2, 2]);
+        let cx = fixtures::cx();
+        let ry = fixtures::ry();
+        let gate_for = |loc: &[usize]| if loc.len() == 1 { ry.clone() } else { cx.clone() };
+        let leaf = |expr: &UnitaryExpression| ExpressionTree::Leaf(expr.clone());
+
+        let cases = [
+            (
+                leaf(&ry).beside(leaf(&ry)).on(&[0, 1], 3).then(leaf(&cx).on(&[2, 0], 3)),
+                vec![vec![0], vec![1], vec![2, 0]],
+            ),
+            (
+                leaf(&cx).on(&[0, 1], 3).then(leaf(&ry).on(&[2], 3)).then(leaf(&cx).on(&[1, 2], 3)),
+                vec![vec![0, 1], vec![2], vec![1, 2]],
+            ),
+        ];
+
+        let params = [0.4, -1.3];
+        for (tree, locations) in cases {
+            let expected = TreeBuilder::from_locations(radices.clone(), locations, gate_for)
+                .build_tree_with_param_map();
+            let expected_params: Vec<f64> = (0..expected.1.num_tree_params())
+                .map(|i| params[expected.1.global_index(i)])
+                .collect();
+            let num_params = expected_params.len();
+
+            let mut qvm = QVM::<c64>::new(compile(&tree), DifferentiationLevel::None);
+            let mut expected_qvm = QVM::<c64>::new(compile(&expected.0), DifferentiationLevel::None);
+            let utry = qvm.get_unitary(&params[..num_params]).to_owned();
+            let expected_utry = expected_qvm.get_unitary(&expected_params);
+            for r in 0..8 {
+                for c in 0..8 {
+                    assert!((utry[(r, c)] - expected_utry[(r, c)]).norm() < 1e-10);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_balance_factor_of_chain_and_balanced_tree() {
+        let p = || ExpressionTree::Leaf(fixtures::p());
+        let mul = |a, b| ExpressionTree::Mul(MulNode::new(a, b));
+
+        // Seven nodes either way: depth 3 when balanced, 4 as a chain
+        let balanced = mul(mul(p(), p()), mul(p(), p()));
+        let chain = mul(mul(mul(p(), p()), p()), p());
+        assert_eq!(balanced.node_count(), chain.node_count());
+        assert!((balanced.balance_factor() - 1.0).abs() < 1e-12);
+        assert!((chain.balance_factor() - 4.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_split_halves_combine_to_full_unitary() {
+        let cry = ExpressionTree::Leaf(fixtures::cry());
+        let ry = ExpressionTree::Leaf(fixtures::ry());
+        let left = ExpressionTree::Contract(ContractNode::new(cry.clone(), ry, vec![2, 0], vec![0]));
+        let tree = ExpressionTree::Contract(ContractNode::new(left, cry, vec![0, 1, 2], vec![1, 2]));
+        let evaluate = |tree: &ExpressionTree, params: &[f64]| {
+            QVM::<c64>::new(compile(tree), DifferentiationLevel::None).get_unitary(params).to_owned()
+        };
+
+        let params = [0.4, -1.2, 2.5];
+        let (left, right, meta) = tree.split_at_contraction().unwrap();
+        let num_left = left.num_params();
+        let combined = meta.combine(evaluate(&left, &params[..num_left]), evaluate(&right, &params[num_left..]));
+
+        let expected = evaluate(&tree, &params);
+        for r in 0..8 {
+            for c in 0..8 {
+                assert!((combined[(r, c)] - expected[(r, c)]).norm() < 1e-10);
+            }
+        }
+        assert!(right.split_at_contraction().is_none());
+    }
+
+    #[test]
+    fn test_leaf_covering_through_contractions() {
+        let gate = |name: &str| {
+            ExpressionTree::Leaf(UnitaryExpression::new(&format!(
+                "{}() {{ [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 0, 1], [0, 0, 1, 0]] }}",
+                name,
+            )))
+        };
+        let p = ExpressionTree::Leaf(fixtures::p());
+        let names = |tree: &ExpressionTree, qudit| -> Vec<String> {
+            tree.leaf_covering(qudit).iter().map(|e| e.name()).collect()
+        };
+
+        // A on (2, 0) then B on (0, 1): the output spans qudits 0, 1, 2
+        let inner = ExpressionTree::Contract(ContractNode::new(gate("A"), gate("B"), vec![2, 0], vec![0, 1]));
+        assert_eq!(names(&inner, 0), vec!["A", "B"]);
+        assert_eq!(names(&inner, 1), vec!["B"]);
+        assert_eq!(names(&inner, 2), vec!["A"]);
+
+        // A phase on the last qudit of the contraction
+        let tree = ExpressionTree::Contract(ContractNode::new(inner, p, vec![0, 1, 2], vec![2]));
+        assert_eq!(names(&tree, 0), vec!["A", "B"]);
+        assert_eq!(names(&tree, 1), vec!["B"]);
+        assert_eq!(names(&tree, 2), vec!["A", "P"]);
+        assert!(tree.leaf_covering(3).is_empty());
+    }
+
+    #[test]
+    fn test_leaf_covering_follows_output_order() {
+        let build = |tensor_intermediates: bool| {
+            let builder = TreeBuilder::from_locations(
+                QuditRadices::from_iter([2, 2, 2, 2]),
+                vec![vec![0, 1], vec![1, 2], vec![2, 3]],
+                |location| {
+                    UnitaryExpression::new(&format!(
+                        "G{}{}() {{ [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 0, 1], [0, 0, 1, 0]] }}",
+                        location[0], location[1],
+                    ))
+                },
+            )
+            .with_output_order(vec![3, 1, 0, 2]);
+            if tensor_intermediates {
+                builder.with_tensor_intermediates().build_tree()
+            } else {
+                builder.build_tree()
+            }
+        };
+        let names = |tree: &ExpressionTree, qudit| -> Vec<String> {
+            let mut names: Vec<String> = tree.leaf_covering(qudit).iter().map(|e| e.name()).collect();
+            names.sort();
+            names
+        };
+
+        for tree in [build(false), build(true)] {
+            let ExpressionTree::Contract(root) = &tree else {
+                panic!("The output order should be absorbed by the root contraction.");
+            };
+            assert_eq!(root.output_qudits(), Some(vec![3, 1, 0, 2]));
+
+            assert_eq!(names(&tree, 0), vec!["G23"]);
+            assert_eq!(names(&tree, 1), vec!["G01", "G12"]);
+            assert_eq!(names(&tree, 2), vec!["G01"]);
+            assert_eq!(names(&tree, 3), vec!["G12", "G23"]);
+        }
+    }
+
     // use std::time::Instant;
     // use crate::math::c64;
 